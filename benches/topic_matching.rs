@@ -0,0 +1,69 @@
+//! Compares the topic trie against a linear scan over every topic filter,
+//! the way subscriptions were matched before the trie was introduced.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use dumq_mqtt::server::{MessageRouter, TopicTrie};
+
+/// Device subscriptions like `site/<n>/device/<m>/cmd` plus a few wildcards
+fn filters(count: usize) -> Vec<String> {
+    let mut filters: Vec<String> = (0..count)
+        .map(|i| format!("site/{}/device/{}/cmd", i % 100, i))
+        .collect();
+    filters.push("site/+/device/+/status".to_string());
+    filters.push("site/7/#".to_string());
+    filters.push("#".to_string());
+    filters
+}
+
+fn match_topic(c: &mut Criterion) {
+    let mut group = c.benchmark_group("match_topic");
+    for count in [1_000, 10_000, 100_000] {
+        let filters = filters(count);
+        let mut trie = TopicTrie::new();
+        for filter in &filters {
+            trie.insert(filter, filter.clone());
+        }
+        let topic = "site/42/device/42/cmd".to_string();
+
+        group.bench_with_input(BenchmarkId::new("linear_scan", count), &topic, |b, topic| {
+            b.iter(|| {
+                filters.iter()
+                    .filter(|filter| MessageRouter::topic_matches(filter, black_box(topic)))
+                    .count()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("trie", count), &topic, |b, topic| {
+            b.iter(|| trie.match_topic(black_box(topic)).len())
+        });
+    }
+    group.finish();
+}
+
+fn match_filter(c: &mut Criterion) {
+    let mut group = c.benchmark_group("match_filter");
+    for count in [1_000, 10_000, 100_000] {
+        let topics: Vec<String> = (0..count)
+            .map(|i| format!("site/{}/device/{}/status", i % 100, i))
+            .collect();
+        let mut trie = TopicTrie::new();
+        for topic in &topics {
+            trie.insert(topic, topic.clone());
+        }
+        let filter = "site/42/device/+/status".to_string();
+
+        group.bench_with_input(BenchmarkId::new("linear_scan", count), &filter, |b, filter| {
+            b.iter(|| {
+                topics.iter()
+                    .filter(|topic| MessageRouter::topic_matches(black_box(filter), topic))
+                    .count()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("trie", count), &filter, |b, filter| {
+            b.iter(|| trie.match_filter(black_box(filter)).len())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, match_topic, match_filter);
+criterion_main!(benches);
@@ -50,8 +50,13 @@ async fn main() -> Result<()> {
     println!("Receiving messages for 10 seconds...");
     let start = std::time::Instant::now();
     
-    while start.elapsed() < Duration::from_secs(10) {
-        match client.recv().await {
+    while let Some(remaining) = Duration::from_secs(10).checked_sub(start.elapsed()) {
+        // recv() waits for the next message, so bound it by the time left
+        let Ok(received) = tokio::time::timeout(remaining, client.recv()).await else {
+            break;
+        };
+
+        match received {
             Ok(Some(message)) => {
                 println!("Received message:");
                 println!("  Topic: {}", message.topic);
@@ -65,8 +70,8 @@ async fn main() -> Result<()> {
                 println!();
             }
             Ok(None) => {
-                // No message available, continue
-                tokio::time::sleep(Duration::from_millis(100)).await;
+                println!("Connection closed by broker");
+                break;
             }
             Err(e) => {
                 eprintln!("Error receiving message: {}", e);
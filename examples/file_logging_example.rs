@@ -91,8 +91,12 @@ async fn main() -> Result<()> {
     log::info!("Receiving messages for 3 seconds...");
     let start = std::time::Instant::now();
     
-    while start.elapsed() < Duration::from_secs(3) {
-        match client.recv().await {
+    while let Some(remaining) = Duration::from_secs(3).checked_sub(start.elapsed()) {
+        let Ok(received) = tokio::time::timeout(remaining, client.recv()).await else {
+            break;
+        };
+
+        match received {
             Ok(Some(message)) => {
                 log::info!("Received message on topic '{}': {}", 
                     message.topic, 
@@ -100,7 +104,8 @@ async fn main() -> Result<()> {
                 );
             }
             Ok(None) => {
-                tokio::time::sleep(Duration::from_millis(100)).await;
+                log::warn!("Connection closed by broker");
+                break;
             }
             Err(e) => {
                 log::error!("Error receiving message: {}", e);
@@ -5,7 +5,6 @@ use dumq_mqtt::{
     types::Message,
 };
 use log::{info, error};
-use std::collections::HashMap;
 use tokio::time::{sleep, Duration};
 
 #[tokio::main]
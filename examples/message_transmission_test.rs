@@ -79,7 +79,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Try to receive message for a few seconds
     for i in 0..20 {
         println!("Attempt {}: Checking for message...", i + 1);
-        match subscriber.recv().await {
+        let Ok(received) = tokio::time::timeout(Duration::from_millis(200), subscriber.recv()).await else {
+            println!("No message available yet...");
+            continue;
+        };
+
+        match received {
             Ok(Some(message)) => {
                 println!("✅ Message received!");
                 println!("  Topic: {}", message.topic);
@@ -90,13 +95,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 break;
             }
             Ok(None) => {
-                println!("No message available yet...");
+                println!("Connection closed by broker");
+                break;
             }
             Err(e) => {
                 println!("Error receiving message: {}", e);
+                break;
             }
         }
-        sleep(Duration::from_millis(200)).await;
     }
     
     if !message_received {
@@ -47,7 +47,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let options2 = ConnectOptions::new("retain_subscriber")
         .clean_session(true);
     
-    let mut client2 = client2.connect(options2).await?;
+    let client2 = client2.connect(options2).await?;
     println!("Client 2 (subscriber) connected");
 
     // Subscribe to topic before publishing
@@ -94,7 +94,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let start_time = std::time::Instant::now();
     
     while received_count < 1 && start_time.elapsed() < Duration::from_secs(5) {
-        if let Ok(Some(message)) = client3.recv().await {
+        if let Ok(Ok(Some(message))) = tokio::time::timeout(Duration::from_millis(100), client3.recv()).await {
             println!("Client 3 received: {:?}", message);
             if message.retain {
                 println!("✓ Received retained message: {}", String::from_utf8_lossy(&message.payload));
@@ -144,7 +144,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let start_time = std::time::Instant::now();
     
     while !received_after_clear && start_time.elapsed() < Duration::from_secs(3) {
-        if let Ok(Some(message)) = client4.recv().await {
+        if let Ok(Ok(Some(message))) = tokio::time::timeout(Duration::from_millis(100), client4.recv()).await {
             println!("Client 4 received: {:?}", message);
             if message.retain {
                 received_after_clear = true;
@@ -0,0 +1,93 @@
+//! # Async Client Handle
+//!
+//! [`AsyncClient`] is a cheap, cloneable handle to a running client event loop.
+//! Every clone talks to the same connection, and each operation resolves once
+//! the broker has acknowledged it.
+
+use crate::error::{Error, Result};
+use crate::protocol::{PublishOptions, QoS};
+use crate::topic;
+use tokio::sync::{mpsc, oneshot, watch};
+
+use super::event_loop::{AckSender, Request};
+use super::state::ConnectionState;
+
+/// Cloneable handle for issuing requests to a client event loop
+#[derive(Debug, Clone)]
+pub struct AsyncClient {
+    requests: mpsc::UnboundedSender<Request>,
+    state: watch::Receiver<ConnectionState>,
+}
+
+impl AsyncClient {
+    pub(crate) fn new(
+        requests: mpsc::UnboundedSender<Request>,
+        state: watch::Receiver<ConnectionState>,
+    ) -> Self {
+        Self { requests, state }
+    }
+
+    /// Publish a message
+    ///
+    /// Resolves once the message has been sent for QoS 0, on PUBACK for QoS 1
+    /// and on PUBCOMP for QoS 2.
+    pub async fn publish(&self, options: PublishOptions) -> Result<()> {
+        topic::validate_topic_name(&options.topic)?;
+        self.request(|ack| Request::Publish(options, ack)).await
+    }
+
+    /// Subscribe to a topic, resolving on SUBACK
+    pub async fn subscribe(&self, topic: impl Into<String>, qos: QoS) -> Result<()> {
+        let topic = topic.into();
+        topic::validate_topic_filter(&topic)?;
+        self.request(|ack| Request::Subscribe(topic, qos, ack)).await
+    }
+
+    /// Unsubscribe from a topic, resolving on UNSUBACK
+    pub async fn unsubscribe(&self, topic: impl Into<String>) -> Result<()> {
+        let topic = topic.into();
+        topic::validate_topic_filter(&topic)?;
+        self.request(|ack| Request::Unsubscribe(topic, ack)).await
+    }
+
+    /// Disconnect from the broker and stop the event loop
+    pub async fn disconnect(&self) -> Result<()> {
+        if self.state().is_disconnected() {
+            return Ok(());
+        }
+
+        match self.request(Request::Disconnect).await {
+            Err(Error::Disconnected) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Repeat the enhanced authentication exchange on the current connection
+    ///
+    /// Requires an MQTT 5.0 connection and a [`ChallengeHandler`](super::ChallengeHandler)
+    /// in the client configuration. Resolves once the broker accepts the client again;
+    /// a broker that rejects it closes the connection.
+    pub async fn reauthenticate(&self) -> Result<()> {
+        self.request(Request::Reauthenticate).await
+    }
+
+    /// Get connection state
+    pub fn state(&self) -> ConnectionState {
+        self.state.borrow().clone()
+    }
+
+    /// Check if client is connected
+    pub fn is_connected(&self) -> bool {
+        self.state.borrow().is_connected()
+    }
+
+    /// Queue a request and wait for the event loop to complete it
+    async fn request<F>(&self, build: F) -> Result<()>
+    where
+        F: FnOnce(AckSender) -> Request,
+    {
+        let (ack_tx, ack_rx) = oneshot::channel();
+        self.requests.send(build(ack_tx)).map_err(|_| Error::Disconnected)?;
+        ack_rx.await.map_err(|_| Error::Disconnected)?
+    }
+}
@@ -0,0 +1,180 @@
+//! Enhanced authentication for the client
+//!
+//! A [`ChallengeHandler`] answers the challenges an MQTT 5.0 broker sends in
+//! AUTH packets, both while connecting and when the client re-authenticates.
+//! Each exchange gets a fresh [`ChallengeExchange`] holding its state.
+
+use crate::error::{Error, Result};
+use crate::protocol::scram::{self, Key};
+use crate::protocol::SCRAM_SHA_256;
+use bytes::Bytes;
+use std::fmt;
+
+/// Client side of an authentication method
+pub trait ChallengeHandler: Send + Sync + fmt::Debug {
+    /// Authentication method name, as carried in the Authentication Method property
+    fn method(&self) -> &str;
+
+    /// Start an exchange, for a new connection or a re-authentication
+    fn begin(&self) -> Box<dyn ChallengeExchange>;
+}
+
+/// State of a single authentication exchange
+pub trait ChallengeExchange: Send {
+    /// Authentication Data sent with the CONNECT or the AUTH starting re-authentication
+    fn initial_data(&mut self) -> Result<Option<Bytes>>;
+
+    /// Answer a challenge from the broker
+    fn respond(&mut self, challenge: Option<&[u8]>) -> Result<Option<Bytes>>;
+
+    /// Check the data the broker sent along with its final success
+    fn finish(&mut self, data: Option<&[u8]>) -> Result<()>;
+}
+
+/// SCRAM-SHA-256 challenge handler
+#[derive(Clone)]
+pub struct ScramSha256 {
+    username: String,
+    password: String,
+}
+
+impl ScramSha256 {
+    pub fn new(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Self {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+}
+
+impl fmt::Debug for ScramSha256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScramSha256")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ChallengeHandler for ScramSha256 {
+    fn method(&self) -> &str {
+        SCRAM_SHA_256
+    }
+
+    fn begin(&self) -> Box<dyn ChallengeExchange> {
+        Box::new(ScramClientExchange {
+            username: self.username.clone(),
+            password: self.password.clone(),
+            client_nonce: scram::nonce(),
+            client_first_bare: String::new(),
+            server_signature: None,
+        })
+    }
+}
+
+struct ScramClientExchange {
+    username: String,
+    password: String,
+    client_nonce: String,
+    client_first_bare: String,
+    /// Signature the server has to prove it knows, once the client final message is sent
+    server_signature: Option<Key>,
+}
+
+impl ChallengeExchange for ScramClientExchange {
+    fn initial_data(&mut self) -> Result<Option<Bytes>> {
+        self.client_first_bare = format!(
+            "n={},r={}",
+            scram::escape_username(&self.username),
+            self.client_nonce
+        );
+        Ok(Some(Bytes::from(format!("{}{}", scram::GS2_HEADER, self.client_first_bare))))
+    }
+
+    fn respond(&mut self, challenge: Option<&[u8]>) -> Result<Option<Bytes>> {
+        let server_first = scram::message(challenge)?;
+        let nonce = scram::attribute(server_first, 'r')?;
+        if !nonce.starts_with(&self.client_nonce) || self.server_signature.is_some() {
+            return Err(Error::Authentication("Unexpected SCRAM challenge".to_string()));
+        }
+        let salt = scram::decode_base64(scram::attribute(server_first, 's')?)?;
+        let iterations = scram::attribute(server_first, 'i')?
+            .parse::<u32>()
+            .map_err(|_| Error::Authentication("Invalid SCRAM iteration count".to_string()))?;
+
+        let salted = scram::salted_password(&self.password, &salt, iterations);
+        let client_key = scram::client_key(&salted);
+        let without_proof = format!(
+            "c={},r={}",
+            scram::encode_base64(scram::GS2_HEADER.as_bytes()),
+            nonce
+        );
+        let auth_message = format!("{},{},{}", self.client_first_bare, server_first, without_proof);
+        let client_signature = scram::hmac(&scram::sha256(&client_key), auth_message.as_bytes());
+        let proof = scram::xor(&client_key, &client_signature);
+        self.server_signature = Some(scram::hmac(&scram::server_key(&salted), auth_message.as_bytes()));
+
+        Ok(Some(Bytes::from(format!("{},p={}", without_proof, scram::encode_base64(&proof)))))
+    }
+
+    fn finish(&mut self, data: Option<&[u8]>) -> Result<()> {
+        let expected = self.server_signature
+            .ok_or_else(|| Error::Authentication("SCRAM exchange finished early".to_string()))?;
+        let verifier = scram::decode_base64(scram::attribute(scram::message(data)?, 'v')?)?;
+        match Key::try_from(verifier) {
+            Ok(verifier) if scram::keys_equal(&verifier, &expected) => Ok(()),
+            _ => Err(Error::Authentication("Server signature mismatch".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Exchange with the RFC 7677 example user and client nonce
+    fn rfc7677_exchange() -> ScramClientExchange {
+        ScramClientExchange {
+            username: "user".to_string(),
+            password: "pencil".to_string(),
+            client_nonce: "rOprNGfwEbeRWgbNEkqO".to_string(),
+            client_first_bare: String::new(),
+            server_signature: None,
+        }
+    }
+
+    const SERVER_FIRST: &[u8] = b"r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+
+    #[test]
+    fn test_scram_client_rfc7677_exchange() {
+        let mut exchange = rfc7677_exchange();
+
+        assert_eq!(exchange.initial_data().unwrap(), Some(Bytes::from_static(b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO")));
+        assert_eq!(
+            exchange.respond(Some(SERVER_FIRST)).unwrap(),
+            Some(Bytes::from_static(
+                b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+            ))
+        );
+        exchange.finish(Some(b"v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=")).unwrap();
+    }
+
+    #[test]
+    fn test_scram_client_rejects_forged_server() {
+        let mut exchange = rfc7677_exchange();
+        exchange.initial_data().unwrap();
+
+        // The server must extend our nonce, not replace it
+        assert!(exchange.respond(Some(b"r=other,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096")).is_err());
+
+        exchange.respond(Some(SERVER_FIRST)).unwrap();
+        let forged = format!("v={}", scram::encode_base64(&[0u8; 32]));
+        assert!(matches!(exchange.finish(Some(forged.as_bytes())), Err(Error::Authentication(_))));
+    }
+
+    #[test]
+    fn test_scram_client_requires_challenge_before_success() {
+        let mut exchange = ScramSha256::new("user", "pencil").begin();
+        exchange.initial_data().unwrap();
+        assert!(exchange.finish(Some(b"v=AAAA")).is_err());
+    }
+}
@@ -1,5 +1,108 @@
+use crate::protocol::DEFAULT_RECEIVE_MAXIMUM;
+use rand::Rng;
+use std::sync::Arc;
 use std::time::Duration;
 
+use super::auth::ChallengeHandler;
+use super::tls::TlsConfig;
+
+/// Policy for re-establishing a lost connection
+///
+/// The delay before attempt `n` is `initial_delay * multiplier^(n - 1)`, capped
+/// at `max_delay` and then randomized by up to `jitter` (a fraction of the
+/// delay) in either direction so that many clients do not reconnect in lockstep.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectPolicy {
+    pub enabled: bool,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f64,
+    pub jitter: f64,
+    /// Give up after this many failed attempts; `None` retries forever
+    pub max_attempts: Option<u32>,
+}
+
+impl ReconnectPolicy {
+    /// Create an enabled policy with default backoff settings
+    pub fn new() -> Self {
+        Self {
+            enabled: true,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+
+    /// Create a policy that never reconnects
+    pub fn disabled() -> Self {
+        Self {
+            enabled: false,
+            ..Self::new()
+        }
+    }
+
+    /// Set delay before the first attempt
+    pub fn initial_delay(mut self, delay: Duration) -> Self {
+        self.initial_delay = delay;
+        self
+    }
+
+    /// Set upper bound for the delay between attempts
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    /// Set growth factor applied to the delay after each failed attempt
+    pub fn multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    /// Set jitter as a fraction of the delay, between 0.0 and 1.0
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Set maximum number of attempts
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    /// Check whether another attempt is allowed after `attempts` have failed
+    pub fn allows_attempt(&self, attempts: u32) -> bool {
+        self.enabled && self.max_attempts.is_none_or(|max| attempts < max)
+    }
+
+    /// Backoff delay before the given attempt, without jitter
+    pub fn base_delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let delay = self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent);
+        Duration::from_secs_f64(delay.min(self.max_delay.as_secs_f64()))
+    }
+
+    /// Backoff delay before the given attempt, with jitter applied
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let base = self.base_delay(attempt);
+        if self.jitter == 0.0 {
+            return base;
+        }
+
+        let factor = rand::thread_rng().gen_range(1.0 - self.jitter..=1.0 + self.jitter);
+        base.mul_f64(factor)
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self::disabled()
+    }
+}
+
 /// MQTT client configuration
 #[derive(Debug, Clone)]
 pub struct ClientConfig {
@@ -8,8 +111,18 @@ pub struct ClientConfig {
     pub read_timeout: Duration,
     pub write_timeout: Duration,
     pub keep_alive_interval: Duration,
+    /// How long to wait for PINGRESP before treating the connection as dead
+    pub ping_timeout: Duration,
     pub max_packet_size: usize,
     pub protocol_version: u8,
+    /// Most QoS 1/2 publishes awaiting acknowledgment at once; the broker's
+    /// Receive Maximum lowers it further
+    pub max_inflight: u16,
+    pub reconnect: ReconnectPolicy,
+    /// Enhanced authentication method used with MQTT 5.0 brokers
+    pub challenge_handler: Option<Arc<dyn ChallengeHandler>>,
+    /// Connect over TLS instead of plain TCP
+    pub tls: Option<TlsConfig>,
 }
 
 impl ClientConfig {
@@ -21,8 +134,13 @@ impl ClientConfig {
             read_timeout: Duration::from_secs(30),
             write_timeout: Duration::from_secs(30),
             keep_alive_interval: Duration::from_secs(60),
+            ping_timeout: Duration::from_secs(10),
             max_packet_size: 1024 * 1024, // 1MB
             protocol_version: 4, // MQTT 3.1.1
+            max_inflight: DEFAULT_RECEIVE_MAXIMUM,
+            reconnect: ReconnectPolicy::disabled(),
+            challenge_handler: None,
+            tls: None,
         }
     }
 
@@ -45,11 +163,20 @@ impl ClientConfig {
     }
 
     /// Set keep-alive interval
+    ///
+    /// The client sends PINGREQ whenever it has sent nothing else for this
+    /// long. A zero interval disables keep-alive.
     pub fn keep_alive_interval(mut self, interval: Duration) -> Self {
         self.keep_alive_interval = interval;
         self
     }
 
+    /// Set how long to wait for PINGRESP
+    pub fn ping_timeout(mut self, timeout: Duration) -> Self {
+        self.ping_timeout = timeout;
+        self
+    }
+
     /// Set maximum packet size
     pub fn max_packet_size(mut self, size: usize) -> Self {
         self.max_packet_size = size;
@@ -61,6 +188,36 @@ impl ClientConfig {
         self.protocol_version = version;
         self
     }
+
+    /// Set the inflight window for QoS 1/2 publishes
+    ///
+    /// Publishes beyond the window are held back until an acknowledgment frees
+    /// a slot. The window is never larger than the broker's Receive Maximum.
+    pub fn max_inflight(mut self, max: u16) -> Self {
+        self.max_inflight = max.max(1);
+        self
+    }
+
+    /// Set automatic reconnect policy
+    pub fn reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect = policy;
+        self
+    }
+
+    /// Authenticate with an MQTT 5.0 enhanced authentication method
+    ///
+    /// The method and its initial data are sent in every CONNECT, overriding
+    /// any set in the connect options.
+    pub fn challenge_handler(mut self, handler: Arc<dyn ChallengeHandler>) -> Self {
+        self.challenge_handler = Some(handler);
+        self
+    }
+
+    /// Connect to the broker over TLS
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
 }
 
 #[cfg(test)]
@@ -75,8 +232,13 @@ mod tests {
         assert_eq!(config.read_timeout, Duration::from_secs(30));
         assert_eq!(config.write_timeout, Duration::from_secs(30));
         assert_eq!(config.keep_alive_interval, Duration::from_secs(60));
+        assert_eq!(config.ping_timeout, Duration::from_secs(10));
         assert_eq!(config.max_packet_size, 1024 * 1024);
         assert_eq!(config.protocol_version, 4);
+        assert_eq!(config.max_inflight, 65535);
+        assert!(!config.reconnect.enabled);
+        assert!(config.challenge_handler.is_none());
+        assert!(config.tls.is_none());
     }
 
     #[test]
@@ -86,15 +248,26 @@ mod tests {
             .read_timeout(Duration::from_secs(45))
             .write_timeout(Duration::from_secs(45))
             .keep_alive_interval(Duration::from_secs(120))
+            .ping_timeout(Duration::from_secs(5))
             .max_packet_size(2 * 1024 * 1024)
-            .protocol_version(5);
+            .protocol_version(5)
+            .max_inflight(20)
+            .reconnect(ReconnectPolicy::new().max_attempts(3))
+            .challenge_handler(Arc::new(crate::client::ScramSha256::new("user", "pencil")))
+            .tls(TlsConfig::new().ca_file("ca.pem").server_name("mqtt.example.com"));
 
         assert_eq!(config.connect_timeout, Duration::from_secs(60));
         assert_eq!(config.read_timeout, Duration::from_secs(45));
         assert_eq!(config.write_timeout, Duration::from_secs(45));
         assert_eq!(config.keep_alive_interval, Duration::from_secs(120));
+        assert_eq!(config.ping_timeout, Duration::from_secs(5));
         assert_eq!(config.max_packet_size, 2 * 1024 * 1024);
         assert_eq!(config.protocol_version, 5);
+        assert_eq!(config.max_inflight, 20);
+        assert!(config.reconnect.enabled);
+        assert_eq!(config.reconnect.max_attempts, Some(3));
+        assert_eq!(config.challenge_handler.unwrap().method(), "SCRAM-SHA-256");
+        assert_eq!(config.tls.unwrap().server_name.as_deref(), Some("mqtt.example.com"));
     }
 
     #[test]
@@ -105,4 +278,46 @@ mod tests {
         assert_eq!(config1.connect_timeout, config2.connect_timeout);
         assert_eq!(config1.max_packet_size, config2.max_packet_size);
     }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let policy = ReconnectPolicy::new()
+            .initial_delay(Duration::from_millis(100))
+            .max_delay(Duration::from_secs(1))
+            .multiplier(2.0)
+            .jitter(0.0);
+
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(200));
+        assert_eq!(policy.delay(3), Duration::from_millis(400));
+        assert_eq!(policy.delay(4), Duration::from_millis(800));
+        // Capped at the maximum delay
+        assert_eq!(policy.delay(5), Duration::from_secs(1));
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_reconnect_jitter() {
+        let policy = ReconnectPolicy::new()
+            .initial_delay(Duration::from_secs(1))
+            .jitter(0.5);
+
+        for _ in 0..100 {
+            let delay = policy.delay(1);
+            assert!(delay >= Duration::from_millis(500));
+            assert!(delay <= Duration::from_millis(1500));
+        }
+    }
+
+    #[test]
+    fn test_reconnect_max_attempts() {
+        let policy = ReconnectPolicy::new().max_attempts(2);
+        assert!(policy.allows_attempt(0));
+        assert!(policy.allows_attempt(1));
+        assert!(!policy.allows_attempt(2));
+
+        assert!(ReconnectPolicy::new().allows_attempt(u32::MAX - 1));
+        assert!(!ReconnectPolicy::disabled().allows_attempt(0));
+        assert_eq!(ReconnectPolicy::default(), ReconnectPolicy::disabled());
+    }
 }
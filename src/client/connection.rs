@@ -1,39 +1,104 @@
 use crate::codec::MqttCodec;
 use crate::error::{Error, Result};
-use crate::protocol::{ConnectOptions, QoS, PublishOptions};
+use crate::protocol::{ConnectOptions, QoS, PublishOptions, ReasonCode, DEFAULT_RECEIVE_MAXIMUM};
 use crate::types::*;
 use bytes::{Bytes, BytesMut};
-use log::{info, debug, warn};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::time::{timeout, Duration};
+use std::time::Duration;
+use tokio::time::{timeout, Instant};
+
+use super::auth::ChallengeExchange;
+use super::transport;
+use crate::transport::Transport;
 
 /// MQTT client connection handler
-pub struct ClientConnection {
-    stream: TcpStream,
+///
+/// Generic over the byte stream it runs on; connections opened from a
+/// `ClientConfig` use a boxed [`Transport`] so TCP and TLS look the same.
+pub struct ClientConnection<S = Box<dyn Transport>> {
+    stream: S,
     config: crate::client::config::ClientConfig,
     codec: MqttCodec,
     read_buffer: BytesMut,
-    _write_buffer: BytesMut,
-    packet_id_counter: u16,
+    last_write: Instant,
+    keep_alive: Duration,
+    receive_maximum: u16,
 }
 
 impl ClientConnection {
+    /// Connect to the broker and complete the MQTT handshake
+    pub async fn open(
+        config: &crate::client::config::ClientConfig,
+        options: ConnectOptions,
+    ) -> Result<(Self, ConnAckPacket)> {
+        let stream = transport::connect(config).await?;
+
+        let mut connection = Self::new(stream, config.clone());
+        let connack = connection.connect(options).await?;
+
+        if connack.return_code != ConnectReturnCode::Accepted {
+            return Err(Error::Connection(format!(
+                "Connection rejected: {:?}",
+                connack.return_code
+            )));
+        }
+
+        Ok((connection, connack))
+    }
+
+    /// Answer an AUTH packet asking to continue the authentication exchange
+    pub(crate) fn answer_challenge(exchange: &mut dyn ChallengeExchange, auth: AuthPacket) -> Result<Option<Bytes>> {
+        if auth.reason_code != ReasonCode::ContinueAuthentication as u8 {
+            return Err(Error::Protocol(format!("Unexpected AUTH reason code: {:#04x}", auth.reason_code)));
+        }
+        let challenge = auth.properties.and_then(|p| p.authentication_data);
+        exchange.respond(challenge.as_deref())
+    }
+}
+
+impl<S: Transport + 'static> ClientConnection<S> {
     /// Create a new client connection
-    pub fn new(stream: TcpStream, config: crate::client::config::ClientConfig) -> Self {
+    pub fn new(stream: S, config: crate::client::config::ClientConfig) -> Self {
         let codec = MqttCodec::new(config.protocol_version);
         Self {
             stream,
-            config,
             codec,
             read_buffer: BytesMut::new(),
-            _write_buffer: BytesMut::new(),
-            packet_id_counter: 1,
+            last_write: Instant::now(),
+            keep_alive: config.keep_alive_interval,
+            receive_maximum: DEFAULT_RECEIVE_MAXIMUM,
+            config,
+        }
+    }
+
+    /// Erase the stream type, as the event loop expects
+    pub fn boxed(self) -> ClientConnection {
+        ClientConnection {
+            stream: Box::new(self.stream),
+            config: self.config,
+            codec: self.codec,
+            read_buffer: self.read_buffer,
+            last_write: self.last_write,
+            keep_alive: self.keep_alive,
+            receive_maximum: self.receive_maximum,
         }
     }
 
     /// Establish MQTT connection
+    ///
+    /// With a challenge handler configured, this also runs the enhanced
+    /// authentication exchange that precedes the CONNACK.
     pub async fn connect(&mut self, options: ConnectOptions) -> Result<ConnAckPacket> {
+        let mut properties = if self.config.protocol_version == 5 { options.properties } else { None };
+        let mut exchange = None;
+        if let (5, Some(handler)) = (self.config.protocol_version, &self.config.challenge_handler) {
+            let mut started = handler.begin();
+            let props = properties.get_or_insert_with(ConnectProperties::default);
+            props.authentication_method = Some(handler.method().to_string());
+            props.authentication_data = started.initial_data()?;
+            exchange = Some(started);
+        }
+
         // Create CONNECT packet
         let connect = ConnectPacket {
             protocol_name: match self.config.protocol_version {
@@ -52,9 +117,10 @@ impl ClientConnection {
             client_id: options.client_id,
             will_topic: options.will_topic,
             will_message: options.will_message.map(Bytes::from),
+            will_properties: options.will_properties,
             username: options.username,
             password: options.password,
-            properties: None, // TODO: Support MQTT 5.0 properties
+            properties,
         };
 
         let packet = Packet {
@@ -69,17 +135,60 @@ impl ClientConnection {
         };
 
         // Send CONNECT packet
-        let data = self.codec.encode(&packet)?;
-        self.write_all(&data).await?;
-
-        // Receive CONNACK packet
-        let connack_packet = self.read_packet().await?;
-        match connack_packet.payload {
-            PacketPayload::ConnAck(connack) => Ok(connack),
-            _ => Err(Error::Protocol("Expected CONNACK packet".to_string())),
+        self.send_packet(&packet).await?;
+
+        // Receive CONNACK packet, answering any authentication challenges first
+        loop {
+            let packet = timeout(self.config.read_timeout, self.read_packet()).await
+                .map_err(|_| Error::Timeout)??;
+            match (packet.payload, exchange.as_mut()) {
+                (PacketPayload::ConnAck(connack), exchange) => {
+                    if let (ConnectReturnCode::Accepted, Some(exchange)) = (connack.return_code, exchange) {
+                        let data = connack.properties.as_ref().and_then(|p| p.authentication_data.as_deref());
+                        exchange.finish(data)?;
+                    }
+                    // An MQTT 5.0 broker may impose its own keep-alive
+                    if let Some(server_keep_alive) = connack.properties.as_ref().and_then(|p| p.server_keep_alive) {
+                        self.keep_alive = Duration::from_secs(server_keep_alive as u64);
+                    }
+                    if let Some(receive_maximum) = connack.properties.as_ref().and_then(|p| p.receive_maximum) {
+                        self.receive_maximum = receive_maximum;
+                    }
+                    return Ok(connack);
+                }
+                (PacketPayload::Auth(auth), Some(exchange)) => {
+                    let response = ClientConnection::answer_challenge(exchange.as_mut(), auth)?;
+                    self.send_auth(ReasonCode::ContinueAuthentication, response).await?;
+                }
+                _ => return Err(Error::Protocol("Expected CONNACK packet".to_string())),
+            }
         }
     }
 
+    /// Send AUTH with the configured authentication method
+    pub async fn send_auth(&mut self, reason_code: ReasonCode, data: Option<Bytes>) -> Result<()> {
+        let handler = self.config.challenge_handler.as_ref()
+            .ok_or_else(|| Error::Client("No challenge handler configured".to_string()))?;
+        let mut properties = AuthProperties::new().authentication_method(handler.method().to_string());
+        properties.authentication_data = data;
+
+        let packet = Packet {
+            header: PacketHeader {
+                packet_type: PacketType::Auth,
+                dup: false,
+                qos: 0,
+                retain: false,
+                remaining_length: 0,
+            },
+            payload: PacketPayload::Auth(AuthPacket {
+                reason_code: reason_code as u8,
+                properties: Some(properties),
+            }),
+        };
+
+        self.send_packet(&packet).await
+    }
+
     /// Disconnect from MQTT broker
     pub async fn disconnect(&mut self) -> Result<()> {
         let packet = Packet {
@@ -96,12 +205,10 @@ impl ClientConnection {
             }),
         };
 
-        let data = self.codec.encode(&packet)?;
-        self.write_all(&data).await?;
-        Ok(())
+        self.send_packet(&packet).await
     }
 
-    /// Subscribe to a topic
+    /// Send a SUBSCRIBE packet; the SUBACK is matched up by the event loop
     pub async fn subscribe(&mut self, topic: &str, qos: QoS, packet_id: u16) -> Result<()> {
         let topic_filter = TopicFilter {
             topic: topic.to_string(),
@@ -128,18 +235,10 @@ impl ClientConnection {
             payload: PacketPayload::Subscribe(subscribe),
         };
 
-        let data = self.codec.encode(&packet)?;
-        self.write_all(&data).await?;
-
-        // Wait for SUBACK
-        let suback_packet = self.read_packet().await?;
-        match suback_packet.payload {
-            PacketPayload::SubAck(_suback) => Ok(()),
-            _ => Err(Error::Protocol("Expected SUBACK packet".to_string())),
-        }
+        self.send_packet(&packet).await
     }
 
-    /// Unsubscribe from a topic
+    /// Send an UNSUBSCRIBE packet; the UNSUBACK is matched up by the event loop
     pub async fn unsubscribe(&mut self, topic: &str, packet_id: u16) -> Result<()> {
         let unsubscribe = UnsubscribePacket {
             packet_id,
@@ -158,18 +257,10 @@ impl ClientConnection {
             payload: PacketPayload::Unsubscribe(unsubscribe),
         };
 
-        let data = self.codec.encode(&packet)?;
-        self.write_all(&data).await?;
-
-        // Wait for UNSUBACK
-        let unsuback_packet = self.read_packet().await?;
-        match unsuback_packet.payload {
-            PacketPayload::UnsubAck(_unsuback) => Ok(()),
-            _ => Err(Error::Protocol("Expected UNSUBACK packet".to_string())),
-        }
+        self.send_packet(&packet).await
     }
 
-    /// Publish a message
+    /// Send a PUBLISH packet; QoS 1 and 2 acknowledgments are matched up by the event loop
     pub async fn publish(&mut self, options: PublishOptions) -> Result<()> {
         let publish = PublishPacket {
             topic_name: options.topic,
@@ -189,106 +280,14 @@ impl ClientConnection {
             payload: PacketPayload::Publish(publish),
         };
 
-        let data = self.codec.encode(&packet)?;
-        self.write_all(&data).await?;
-
-        // Handle QoS 1 and 2 acknowledgments
-        if options.qos != QoS::AtMostOnce {
-            let packet_id = options.packet_id.unwrap_or_else(|| self.next_packet_id());
-            
-            match options.qos {
-                QoS::AtLeastOnce => {
-                    // Wait for PUBACK
-                    let ack_packet = self.read_packet().await?;
-                    match ack_packet.payload {
-                        PacketPayload::PubAck(puback) => {
-                            if puback.packet_id != packet_id {
-                                return Err(Error::Protocol("Mismatched PUBACK packet ID".to_string()));
-                            }
-                        }
-                        _ => return Err(Error::Protocol("Expected PUBACK packet".to_string())),
-                    }
-                }
-                QoS::ExactlyOnce => {
-                    // Wait for PUBREC
-                    let rec_packet = self.read_packet().await?;
-                    match rec_packet.payload {
-                        PacketPayload::PubRec(pubrec) => {
-                            if pubrec.packet_id != packet_id {
-                                return Err(Error::Protocol("Mismatched PUBREC packet ID".to_string()));
-                            }
-                            // Send PUBREL
-                            self.send_pubrel(packet_id).await?;
-                            
-                            // Wait for PUBCOMP
-                            let comp_packet = self.read_packet().await?;
-                            match comp_packet.payload {
-                                PacketPayload::PubComp(pubcomp) => {
-                                    if pubcomp.packet_id != packet_id {
-                                        return Err(Error::Protocol("Mismatched PUBCOMP packet ID".to_string()));
-                                    }
-                                }
-                                _ => return Err(Error::Protocol("Expected PUBCOMP packet".to_string())),
-                            }
-                        }
-                        _ => return Err(Error::Protocol("Expected PUBREC packet".to_string())),
-                    }
-                }
-                _ => {}
-            }
-        }
-
-        Ok(())
-    }
-
-    /// Receive a message
-    pub async fn recv(&mut self) -> Result<Option<Message>> {
-        let packet = self.read_packet().await?;
-        
-        match packet.payload {
-            PacketPayload::Publish(publish) => {
-                let message = Message {
-                    topic: publish.topic_name,
-                    payload: publish.payload,
-                    qos: packet.header.qos,
-                    retain: packet.header.retain,
-                    dup: packet.header.dup,
-                    packet_id: publish.packet_id,
-                };
-
-                // Send acknowledgment for QoS 1 and 2
-                if packet.header.qos > 0 {
-                    match packet.header.qos {
-                        1 => {
-                            // QoS 1: Send PUBACK
-                            self.send_puback(publish.packet_id.unwrap()).await?;
-                        }
-                        2 => {
-                            // QoS 2: Send PUBREC
-                            self.send_pubrec(publish.packet_id.unwrap()).await?;
-                        }
-                        _ => {}
-                    }
-                }
-
-                Ok(Some(message))
-            }
-            PacketPayload::PubRel(pubrel) => {
-                // Handle PUBREL for QoS 2
-                self.send_pubcomp(pubrel.packet_id).await?;
-                Ok(None)
-            }
-            PacketPayload::PingReq => {
-                // Send PINGRESP
-                self.send_pingresp().await?;
-                Ok(None)
-            }
-            _ => Ok(None),
-        }
+        self.send_packet(&packet).await
     }
 
-    /// Read a packet from the stream
-    async fn read_packet(&mut self) -> Result<Packet> {
+    /// Read the next packet from the stream
+    ///
+    /// Cancel safe: bytes are only consumed once a whole packet has been decoded,
+    /// so the event loop can race this against its request queue.
+    pub async fn read_packet(&mut self) -> Result<Packet> {
         loop {
             // Try to decode a packet from the buffer
             if let Some(packet) = self.codec.decode(&mut self.read_buffer)? {
@@ -297,8 +296,7 @@ impl ClientConnection {
 
             // Read more data from the stream
             let mut buf = vec![0u8; 1024];
-            let n = timeout(self.config.read_timeout, self.stream.read(&mut buf)).await
-                .map_err(|_| Error::Timeout)?
+            let n = self.stream.read(&mut buf).await
                 .map_err(Error::Io)?;
 
             if n == 0 {
@@ -309,6 +307,12 @@ impl ClientConnection {
         }
     }
 
+    /// Encode and send a packet
+    pub async fn send_packet(&mut self, packet: &Packet) -> Result<()> {
+        let data = self.codec.encode(packet)?;
+        self.write_all(&data).await
+    }
+
     /// Write data to the stream
     async fn write_all(&mut self, data: &[u8]) -> Result<()> {
         timeout(self.config.write_timeout, self.stream.write_all(data)).await
@@ -316,11 +320,27 @@ impl ClientConnection {
             .map_err(Error::Io)?;
         
         self.stream.flush().await.map_err(Error::Io)?;
+        self.last_write = Instant::now();
         Ok(())
     }
 
+    /// Keep-alive interval in effect, as negotiated with the broker
+    pub fn keep_alive(&self) -> Duration {
+        self.keep_alive
+    }
+
+    /// QoS 1/2 publishes the broker accepts unacknowledged at once
+    pub fn receive_maximum(&self) -> u16 {
+        self.receive_maximum
+    }
+
+    /// Time of the last successful write, used for keep-alive scheduling
+    pub fn last_write(&self) -> Instant {
+        self.last_write
+    }
+
     /// Send PUBACK packet
-    async fn send_puback(&mut self, packet_id: u16) -> Result<()> {
+    pub async fn send_puback(&mut self, packet_id: u16) -> Result<()> {
         let puback = PubAckPacket {
             packet_id,
             reason_code: None,
@@ -338,12 +358,11 @@ impl ClientConnection {
             payload: PacketPayload::PubAck(puback),
         };
 
-        let data = self.codec.encode(&packet)?;
-        self.write_all(&data).await
+        self.send_packet(&packet).await
     }
 
     /// Send PUBREL packet
-    async fn send_pubrel(&mut self, packet_id: u16) -> Result<()> {
+    pub async fn send_pubrel(&mut self, packet_id: u16) -> Result<()> {
         let pubrel = PubRelPacket {
             packet_id,
             reason_code: None,
@@ -361,12 +380,11 @@ impl ClientConnection {
             payload: PacketPayload::PubRel(pubrel),
         };
 
-        let data = self.codec.encode(&packet)?;
-        self.write_all(&data).await
+        self.send_packet(&packet).await
     }
 
     /// Send PUBREC packet
-    async fn send_pubrec(&mut self, packet_id: u16) -> Result<()> {
+    pub async fn send_pubrec(&mut self, packet_id: u16) -> Result<()> {
         let pubrec = PubRecPacket {
             packet_id,
             reason_code: None,
@@ -384,12 +402,11 @@ impl ClientConnection {
             payload: PacketPayload::PubRec(pubrec),
         };
 
-        let data = self.codec.encode(&packet)?;
-        self.write_all(&data).await
+        self.send_packet(&packet).await
     }
 
     /// Send PUBCOMP packet
-    async fn send_pubcomp(&mut self, packet_id: u16) -> Result<()> {
+    pub async fn send_pubcomp(&mut self, packet_id: u16) -> Result<()> {
         let pubcomp = PubCompPacket {
             packet_id,
             reason_code: None,
@@ -407,32 +424,22 @@ impl ClientConnection {
             payload: PacketPayload::PubComp(pubcomp),
         };
 
-        let data = self.codec.encode(&packet)?;
-        self.write_all(&data).await
+        self.send_packet(&packet).await
     }
 
-    /// Send PINGRESP packet
-    async fn send_pingresp(&mut self) -> Result<()> {
+    /// Send PINGREQ packet
+    pub async fn send_pingreq(&mut self) -> Result<()> {
         let packet = Packet {
             header: PacketHeader {
-                packet_type: PacketType::PingResp,
+                packet_type: PacketType::PingReq,
                 dup: false,
                 qos: 0,
                 retain: false,
                 remaining_length: 0,
             },
-            payload: PacketPayload::PingResp,
+            payload: PacketPayload::PingReq,
         };
 
-        let data = self.codec.encode(&packet)?;
-        self.write_all(&data).await
-    }
-
-    /// Get next packet ID
-    fn next_packet_id(&mut self) -> u16 {
-        // Use instance counter for thread safety
-        let id = self.packet_id_counter;
-        self.packet_id_counter = self.packet_id_counter.wrapping_add(1);
-        id
+        self.send_packet(&packet).await
     }
 }
@@ -0,0 +1,1268 @@
+//! # Client Event Loop
+//!
+//! The event loop is a background task that owns the broker connection. It
+//! multiplexes requests coming from [`AsyncClient`] handles with packets
+//! arriving from the broker, so an incoming PUBLISH never gets in the way of an
+//! acknowledgment the application is waiting for.
+//!
+//! When the connection drops and [`ReconnectPolicy`](super::ReconnectPolicy) is
+//! enabled, the event loop reconnects with backoff, restores subscriptions and
+//! retransmits every unacknowledged publish with the DUP flag set. Requests made
+//! in the meantime are held and sent once the connection is back.
+//!
+//! QoS 1/2 publishes are tracked in an inflight window no larger than
+//! [`ClientConfig::max_inflight`] and the broker's Receive Maximum. Publishes
+//! beyond it wait in order until an acknowledgment frees a slot.
+//!
+//! Keep-alive is scheduled here too: a PINGREQ goes out whenever nothing else
+//! has been sent for the keep-alive interval, and a PINGRESP that does not
+//! arrive within the ping timeout marks the connection as dead.
+
+use crate::error::{Error, Result};
+use crate::protocol::{InflightStore, PacketIdAllocator, PublishOptions, QoS, ReasonCode};
+use crate::types::*;
+use log::{debug, info, warn};
+use crate::protocol::ConnectOptions;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::Instant;
+
+use super::async_client::AsyncClient;
+use super::auth::ChallengeExchange;
+use super::config::ClientConfig;
+use super::connection::ClientConnection;
+use super::state::ConnectionState;
+
+/// Completion channel for a request, resolved once the broker acknowledges it
+pub(crate) type AckSender = oneshot::Sender<Result<()>>;
+
+/// Operation queued by an [`AsyncClient`] for the event loop
+pub(crate) enum Request {
+    Publish(PublishOptions, AckSender),
+    Subscribe(String, QoS, AckSender),
+    Unsubscribe(String, AckSender),
+    Reauthenticate(AckSender),
+    Disconnect(AckSender),
+}
+
+/// QoS 1/2 publish in the inflight window
+///
+/// The options are kept so the publish can be retransmitted after a reconnect.
+struct InflightPublish {
+    options: PublishOptions,
+    ack: AckSender,
+    /// PUBACK, PUBREC, or PUBCOMP once PUBREL was sent
+    awaiting: PacketType,
+}
+
+/// Subscription change the event loop is waiting for, keyed by packet ID
+enum PendingAck {
+    /// SUBSCRIBE waiting for SUBACK; restored subscriptions have no waiter
+    SubAck(String, QoS, Option<AckSender>),
+    /// UNSUBSCRIBE waiting for UNSUBACK
+    UnsubAck(String, AckSender),
+}
+
+/// Background task driving a client connection
+pub struct EventLoop {
+    connection: ClientConnection,
+    config: ClientConfig,
+    options: ConnectOptions,
+    requests: mpsc::UnboundedReceiver<Request>,
+    incoming: mpsc::UnboundedSender<Message>,
+    state: watch::Sender<ConnectionState>,
+    pending: HashMap<u16, PendingAck>,
+    inflight: InflightStore<InflightPublish>,
+    /// Publishes waiting for a slot in the inflight window
+    queued_publishes: VecDeque<(PublishOptions, AckSender)>,
+    /// QoS 2 packet IDs received from the broker that are waiting for PUBREL
+    awaiting_pubrel: HashSet<u16>,
+    subscriptions: HashMap<String, QoS>,
+    /// Requests received while reconnecting, sent once the connection is back
+    deferred: VecDeque<Request>,
+    /// Deadline for the PINGRESP to an outstanding PINGREQ
+    ping_deadline: Option<Instant>,
+    /// Packet IDs of publishes and subscription changes in progress
+    packet_ids: PacketIdAllocator,
+    /// Re-authentication waiting for the broker's verdict
+    reauth: Option<(Box<dyn ChallengeExchange>, AckSender)>,
+}
+
+impl EventLoop {
+    /// Spawn the event loop for an established connection
+    ///
+    /// Returns the handle used to issue requests and the channel on which
+    /// messages published by the broker are delivered.
+    pub fn spawn(
+        connection: ClientConnection,
+        config: ClientConfig,
+        options: ConnectOptions,
+    ) -> (AsyncClient, mpsc::UnboundedReceiver<Message>) {
+        let (request_tx, request_rx) = mpsc::unbounded_channel();
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        let (state_tx, state_rx) = watch::channel(ConnectionState::Connected);
+
+        let event_loop = Self::new(connection, config, options, request_rx, incoming_tx, state_tx);
+        tokio::spawn(async move {
+            if let Err(e) = event_loop.run().await {
+                warn!("Client event loop stopped: {}", e);
+            }
+        });
+
+        (AsyncClient::new(request_tx, state_rx), incoming_rx)
+    }
+
+    fn new(
+        connection: ClientConnection,
+        config: ClientConfig,
+        options: ConnectOptions,
+        requests: mpsc::UnboundedReceiver<Request>,
+        incoming: mpsc::UnboundedSender<Message>,
+        state: watch::Sender<ConnectionState>,
+    ) -> Self {
+        let window = config.max_inflight.min(connection.receive_maximum());
+        Self {
+            connection,
+            config,
+            options,
+            requests,
+            incoming,
+            state,
+            pending: HashMap::new(),
+            inflight: InflightStore::new(window),
+            queued_publishes: VecDeque::new(),
+            awaiting_pubrel: HashSet::new(),
+            subscriptions: HashMap::new(),
+            deferred: VecDeque::new(),
+            ping_deadline: None,
+            packet_ids: PacketIdAllocator::new(),
+            reauth: None,
+        }
+    }
+
+    /// Run until the client disconnects or the connection is lost for good
+    ///
+    /// Requests still waiting for an acknowledgment are dropped on exit, which
+    /// resolves their futures with [`Error::Disconnected`].
+    pub async fn run(mut self) -> Result<()> {
+        let result = loop {
+            let error = match self.drive().await {
+                Ok(()) => break Ok(()),
+                Err(e) => e,
+            };
+
+            if !self.config.reconnect.enabled {
+                break Err(error);
+            }
+
+            warn!("Connection to MQTT broker lost: {}", error);
+            match self.reconnect().await {
+                Ok(true) => info!("Reconnected to MQTT broker"),
+                Ok(false) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+
+        let _ = self.state.send(ConnectionState::Disconnected);
+        info!("Disconnected from MQTT broker");
+        result
+    }
+
+    /// Serve the current connection
+    ///
+    /// Returns `Ok` when the client disconnects and the error that ended the
+    /// connection otherwise.
+    async fn drive(&mut self) -> Result<()> {
+        while let Some(request) = self.deferred.pop_front() {
+            self.handle_request(request).await?;
+        }
+
+        let keep_alive = !self.connection.keep_alive().is_zero();
+        loop {
+            let keep_alive_deadline = self.keep_alive_deadline();
+            tokio::select! {
+                packet = self.connection.read_packet() => {
+                    self.handle_packet(packet?).await?;
+                }
+                _ = tokio::time::sleep_until(keep_alive_deadline), if keep_alive => {
+                    self.handle_keep_alive().await?;
+                }
+                request = self.requests.recv() => match request {
+                    Some(Request::Disconnect(ack)) => {
+                        let _ = self.state.send(ConnectionState::Disconnecting);
+                        let result = self.connection.disconnect().await;
+                        let _ = ack.send(result);
+                        return Ok(());
+                    }
+                    Some(request) => self.handle_request(request).await?,
+                    None => {
+                        // Every handle has been dropped; leave cleanly
+                        let _ = self.state.send(ConnectionState::Disconnecting);
+                        return self.connection.disconnect().await;
+                    }
+                },
+            }
+        }
+    }
+
+    /// When the keep-alive timer next needs attention
+    fn keep_alive_deadline(&self) -> Instant {
+        match self.ping_deadline {
+            Some(deadline) => deadline,
+            None => self.connection.last_write() + self.connection.keep_alive(),
+        }
+    }
+
+    /// Send PINGREQ on an idle connection, or give up on a silent broker
+    async fn handle_keep_alive(&mut self) -> Result<()> {
+        if self.ping_deadline.is_some() {
+            warn!("No PINGRESP from broker within {:?}", self.config.ping_timeout);
+            return Err(Error::Timeout);
+        }
+
+        debug!("Connection idle, sending PINGREQ");
+        self.connection.send_pingreq().await?;
+        self.ping_deadline = Some(Instant::now() + self.config.ping_timeout);
+        Ok(())
+    }
+
+    /// Re-establish the connection according to the reconnect policy
+    ///
+    /// Returns `Ok(false)` if the client asked to disconnect in the meantime.
+    async fn reconnect(&mut self) -> Result<bool> {
+        let _ = self.state.send(ConnectionState::Reconnecting);
+        let policy = self.config.reconnect.clone();
+
+        let mut failed_attempts = 0;
+        while policy.allows_attempt(failed_attempts) {
+            let attempt = failed_attempts + 1;
+            let delay = policy.delay(attempt);
+            info!("Reconnecting to MQTT broker in {:?} (attempt {})", delay, attempt);
+
+            if !self.wait(delay).await {
+                return Ok(false);
+            }
+
+            match self.restore_connection().await {
+                Ok(()) => {
+                    let _ = self.state.send(ConnectionState::Connected);
+                    return Ok(true);
+                }
+                Err(e) => {
+                    warn!("Reconnect attempt {} failed: {}", attempt, e);
+                    failed_attempts = attempt;
+                }
+            }
+        }
+
+        Err(Error::Connection(format!(
+            "Gave up reconnecting after {} attempts",
+            failed_attempts
+        )))
+    }
+
+    /// Wait out a backoff delay while holding on to incoming requests
+    ///
+    /// Returns `false` if the client asked to disconnect while waiting.
+    async fn wait(&mut self, delay: Duration) -> bool {
+        let sleep = tokio::time::sleep(delay);
+        tokio::pin!(sleep);
+
+        loop {
+            tokio::select! {
+                _ = &mut sleep => return true,
+                request = self.requests.recv() => match request {
+                    Some(Request::Disconnect(ack)) => {
+                        let _ = ack.send(Ok(()));
+                        return false;
+                    }
+                    Some(request) => self.deferred.push_back(request),
+                    None => return false,
+                },
+            }
+        }
+    }
+
+    /// Connect again, restore subscriptions and retransmit unacknowledged packets
+    async fn restore_connection(&mut self) -> Result<()> {
+        let (connection, connack) = ClientConnection::open(&self.config, self.options.clone()).await?;
+        self.connection = connection;
+        self.ping_deadline = None;
+        // The new connection was authenticated from scratch
+        self.reauth = None;
+        debug!("Reconnected with session present: {}", connack.session_present);
+
+        // A fresh session on the broker has forgotten the QoS 2 messages it sent us
+        if !connack.session_present {
+            self.awaiting_pubrel.clear();
+        }
+
+        // Drop restores left over from an attempt that failed part way through
+        self.pending.retain(|_, pending| !matches!(pending, PendingAck::SubAck(_, _, None)));
+
+        let subscriptions: Vec<(String, QoS)> = self.subscriptions
+            .iter()
+            .map(|(topic, qos)| (topic.clone(), *qos))
+            .collect();
+        for (topic, qos) in subscriptions {
+            info!("Restoring subscription to topic '{}'", topic);
+            let packet_id = self.next_packet_id()?;
+            self.pending.insert(packet_id, PendingAck::SubAck(topic.clone(), qos, None));
+            self.connection.subscribe(&topic, qos, packet_id).await?;
+        }
+
+        let mut packet_ids: Vec<u16> = self.pending.keys().copied().collect();
+        packet_ids.sort_unstable();
+        for packet_id in packet_ids {
+            match &self.pending[&packet_id] {
+                PendingAck::SubAck(topic, qos, Some(_)) => {
+                    self.connection.subscribe(topic, *qos, packet_id).await?;
+                }
+                // Restored subscriptions were just sent
+                PendingAck::SubAck(_, _, None) => {}
+                PendingAck::UnsubAck(topic, _) => {
+                    self.connection.unsubscribe(topic, packet_id).await?;
+                }
+            }
+        }
+
+        // Unacknowledged publishes go out again in the order they were first sent,
+        // even if the broker now allows fewer of them
+        self.inflight.set_window(self.config.max_inflight.min(self.connection.receive_maximum()));
+        let retransmits: Vec<(u16, PacketType, PublishOptions)> = self.inflight.iter()
+            .map(|(packet_id, publish)| (packet_id, publish.awaiting, publish.options.clone()))
+            .collect();
+        for (packet_id, awaiting, options) in retransmits {
+            if awaiting == PacketType::PubComp {
+                self.connection.send_pubrel(packet_id).await?;
+            } else {
+                debug!("Retransmitting PUBLISH with packet ID: {}", packet_id);
+                self.connection.publish(PublishOptions { dup: true, ..options }).await?;
+            }
+        }
+
+        self.send_queued_publishes().await
+    }
+
+    async fn handle_request(&mut self, request: Request) -> Result<()> {
+        match request {
+            Request::Publish(options, ack) => {
+                info!("Publishing message to topic '{}'", options.topic);
+                if options.qos == QoS::AtMostOnce {
+                    self.connection.publish(PublishOptions { packet_id: None, ..options }).await?;
+                    let _ = ack.send(Ok(()));
+                    return Ok(());
+                }
+
+                // Earlier publishes still waiting for a slot go first
+                if !self.inflight.has_capacity() || !self.queued_publishes.is_empty() {
+                    debug!("Inflight window full, holding back publish to '{}'", options.topic);
+                    self.queued_publishes.push_back((options, ack));
+                    return Ok(());
+                }
+                self.send_publish(options, ack).await
+            }
+            Request::Subscribe(topic, qos, ack) => {
+                info!("Subscribing to topic '{}' with QoS {:?}", topic, qos);
+                let packet_id = match self.next_packet_id() {
+                    Ok(packet_id) => packet_id,
+                    Err(e) => {
+                        let _ = ack.send(Err(e));
+                        return Ok(());
+                    }
+                };
+                self.pending.insert(packet_id, PendingAck::SubAck(topic.clone(), qos, Some(ack)));
+                self.connection.subscribe(&topic, qos, packet_id).await
+            }
+            Request::Unsubscribe(topic, ack) => {
+                info!("Unsubscribing from topic '{}'", topic);
+                let packet_id = match self.next_packet_id() {
+                    Ok(packet_id) => packet_id,
+                    Err(e) => {
+                        let _ = ack.send(Err(e));
+                        return Ok(());
+                    }
+                };
+                self.pending.insert(packet_id, PendingAck::UnsubAck(topic.clone(), ack));
+                self.connection.unsubscribe(&topic, packet_id).await
+            }
+            Request::Reauthenticate(ack) => {
+                let handler = match (self.config.protocol_version, &self.config.challenge_handler) {
+                    (5, Some(handler)) => Arc::clone(handler),
+                    _ => {
+                        let _ = ack.send(Err(Error::Client("Re-authentication needs MQTT 5.0 and a challenge handler".to_string())));
+                        return Ok(());
+                    }
+                };
+                if self.reauth.is_some() {
+                    let _ = ack.send(Err(Error::Client("Re-authentication already in progress".to_string())));
+                    return Ok(());
+                }
+
+                info!("Re-authenticating with method '{}'", handler.method());
+                let mut exchange = handler.begin();
+                let data = match exchange.initial_data() {
+                    Ok(data) => data,
+                    Err(e) => {
+                        let _ = ack.send(Err(e));
+                        return Ok(());
+                    }
+                };
+                self.reauth = Some((exchange, ack));
+                self.connection.send_auth(ReasonCode::ReAuthenticate, data).await
+            }
+            Request::Disconnect(_) => unreachable!("DISCONNECT is handled by the run loop"),
+        }
+    }
+
+    async fn handle_packet(&mut self, packet: Packet) -> Result<()> {
+        match packet.payload {
+            PacketPayload::Publish(publish) => self.handle_publish(publish, &packet.header).await,
+            PacketPayload::PubAck(puback) => self.complete_publish(PacketType::PubAck, puback.packet_id, Ok(())).await,
+            PacketPayload::PubRec(pubrec) => {
+                if !self.awaiting(PacketType::PubRec, pubrec.packet_id) {
+                    return Ok(());
+                }
+                // A failure reason code ends the exchange without PUBREL
+                if let Some(code) = pubrec.reason_code.filter(|code| *code >= 0x80) {
+                    let error = Error::Client(format!("Publish was rejected with reason code {:#04x}", code));
+                    return self.complete_publish(PacketType::PubRec, pubrec.packet_id, Err(error)).await;
+                }
+                if let Some(publish) = self.inflight.get_mut(pubrec.packet_id) {
+                    publish.awaiting = PacketType::PubComp;
+                }
+                self.connection.send_pubrel(pubrec.packet_id).await
+            }
+            PacketPayload::PubComp(pubcomp) => self.complete_publish(PacketType::PubComp, pubcomp.packet_id, Ok(())).await,
+            PacketPayload::PubRel(pubrel) => {
+                debug!("Received PUBREL for packet ID: {}", pubrel.packet_id);
+                self.awaiting_pubrel.remove(&pubrel.packet_id);
+                self.connection.send_pubcomp(pubrel.packet_id).await
+            }
+            PacketPayload::SubAck(suback) => {
+                match self.pending.remove(&suback.packet_id) {
+                    Some(PendingAck::SubAck(topic, qos, ack)) => {
+                        let result = if suback.return_codes.iter().any(|code| *code >= 0x80) {
+                            warn!("Subscription to '{}' was rejected", topic);
+                            self.subscriptions.remove(&topic);
+                            Err(Error::Client(format!("Subscription to '{}' was rejected", topic)))
+                        } else {
+                            self.subscriptions.insert(topic, qos);
+                            Ok(())
+                        };
+                        if let Some(ack) = ack {
+                            let _ = ack.send(result);
+                        }
+                    }
+                    other => self.unexpected_ack("SUBACK", suback.packet_id, other),
+                }
+                Ok(())
+            }
+            PacketPayload::UnsubAck(unsuback) => {
+                match self.pending.remove(&unsuback.packet_id) {
+                    Some(PendingAck::UnsubAck(topic, ack)) => {
+                        self.subscriptions.remove(&topic);
+                        let _ = ack.send(Ok(()));
+                    }
+                    other => self.unexpected_ack("UNSUBACK", unsuback.packet_id, other),
+                }
+                Ok(())
+            }
+            PacketPayload::PingResp => {
+                debug!("Received PINGRESP");
+                self.ping_deadline = None;
+                Ok(())
+            }
+            PacketPayload::Auth(auth) => self.handle_auth(auth).await,
+            PacketPayload::Disconnect(disconnect) => {
+                let reason_code = disconnect.reason_code
+                    .map_or(ReasonCode::Success, |code| ReasonCode::from_u8(code).unwrap_or(ReasonCode::UnspecifiedError));
+                let reason_string = disconnect.properties.and_then(|properties| properties.reason_string);
+                info!("Broker closed the connection: {:?}", reason_code);
+                Err(Error::ServerDisconnected { reason_code, reason_string })
+            }
+            _ => {
+                warn!("Unhandled packet type: {:?}", packet.header.packet_type);
+                Ok(())
+            }
+        }
+    }
+
+    /// Continue or complete a re-authentication
+    async fn handle_auth(&mut self, auth: AuthPacket) -> Result<()> {
+        let Some((mut exchange, ack)) = self.reauth.take() else {
+            return Err(Error::Protocol("Unexpected AUTH packet".to_string()));
+        };
+
+        if auth.reason_code == ReasonCode::Success as u8 {
+            let data = auth.properties.and_then(|p| p.authentication_data);
+            return match exchange.finish(data.as_deref()) {
+                Ok(()) => {
+                    info!("Re-authenticated with MQTT broker");
+                    let _ = ack.send(Ok(()));
+                    Ok(())
+                }
+                Err(e) => {
+                    // A broker that cannot prove itself is not talked to any further
+                    let reason = e.to_string();
+                    let _ = ack.send(Err(e));
+                    Err(Error::Authentication(reason))
+                }
+            };
+        }
+
+        match ClientConnection::answer_challenge(exchange.as_mut(), auth) {
+            Ok(response) => {
+                self.reauth = Some((exchange, ack));
+                self.connection.send_auth(ReasonCode::ContinueAuthentication, response).await
+            }
+            Err(e) => {
+                let _ = ack.send(Err(e));
+                Err(Error::Protocol("Re-authentication exchange failed".to_string()))
+            }
+        }
+    }
+
+    async fn handle_publish(&mut self, publish: PublishPacket, header: &PacketHeader) -> Result<()> {
+        debug!("Received message on topic: {}", publish.topic_name);
+
+        match (header.qos, publish.packet_id) {
+            (1, Some(packet_id)) => {
+                self.deliver(publish, header);
+                self.connection.send_puback(packet_id).await
+            }
+            (2, Some(packet_id)) => {
+                // A retransmission whose PUBREL has not arrived yet was already delivered
+                if self.awaiting_pubrel.insert(packet_id) {
+                    self.deliver(publish, header);
+                }
+                self.connection.send_pubrec(packet_id).await
+            }
+            _ => {
+                self.deliver(publish, header);
+                Ok(())
+            }
+        }
+    }
+
+    /// Hand an incoming message to the application
+    fn deliver(&self, publish: PublishPacket, header: &PacketHeader) {
+        let message = Message {
+            topic: publish.topic_name,
+            payload: publish.payload,
+            qos: header.qos,
+            retain: header.retain,
+            dup: header.dup,
+            packet_id: publish.packet_id,
+            properties: publish.properties,
+            expires_at: None,
+        };
+
+        if self.incoming.send(message).is_err() {
+            debug!("Incoming message receiver has been dropped");
+        }
+    }
+
+    /// Send a QoS 1/2 publish, taking a slot in the inflight window
+    ///
+    /// The publish is recorded before it is sent so it is retransmitted if the
+    /// connection drops before the broker acknowledges it.
+    async fn send_publish(&mut self, options: PublishOptions, ack: AckSender) -> Result<()> {
+        let packet_id = match self.next_packet_id() {
+            Ok(packet_id) => packet_id,
+            Err(e) => {
+                let _ = ack.send(Err(e));
+                return Ok(());
+            }
+        };
+        let options = PublishOptions { packet_id: Some(packet_id), ..options };
+        let awaiting = match options.qos {
+            QoS::AtLeastOnce => PacketType::PubAck,
+            _ => PacketType::PubRec,
+        };
+        self.inflight.insert(packet_id, InflightPublish { options: options.clone(), ack, awaiting });
+        self.connection.publish(options).await
+    }
+
+    /// Send held back publishes while the inflight window has room
+    async fn send_queued_publishes(&mut self) -> Result<()> {
+        while self.inflight.has_capacity() {
+            let Some((options, ack)) = self.queued_publishes.pop_front() else {
+                break;
+            };
+            self.send_publish(options, ack).await?;
+        }
+        Ok(())
+    }
+
+    /// Check that an acknowledgment matches the publish inflight with its packet ID
+    fn awaiting(&self, kind: PacketType, packet_id: u16) -> bool {
+        let expected = self.inflight.get(packet_id).is_some_and(|publish| publish.awaiting == kind);
+        if !expected {
+            warn!("Unexpected {:?} for packet ID: {}", kind, packet_id);
+        }
+        expected
+    }
+
+    /// Resolve a publish whose exchange has ended and fill its slot in the window
+    async fn complete_publish(&mut self, kind: PacketType, packet_id: u16, result: Result<()>) -> Result<()> {
+        if !self.awaiting(kind, packet_id) {
+            return Ok(());
+        }
+        if let Some(publish) = self.inflight.remove(packet_id) {
+            let _ = publish.ack.send(result);
+        }
+        self.send_queued_publishes().await
+    }
+
+    /// Log an acknowledgment that does not match the request waiting on its packet ID
+    fn unexpected_ack(&mut self, kind: &str, packet_id: u16, pending: Option<PendingAck>) {
+        warn!("Unexpected {} for packet ID: {}", kind, packet_id);
+        if let Some(pending) = pending {
+            self.pending.insert(packet_id, pending);
+        }
+    }
+
+    /// Get a packet ID not used by any publish or subscription change in progress
+    fn next_packet_id(&mut self) -> Result<u16> {
+        let (pending, inflight) = (&self.pending, &self.inflight);
+        self.packet_ids.allocate(|packet_id| pending.contains_key(&packet_id) || inflight.contains(packet_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::config::ReconnectPolicy;
+    use crate::codec::MqttCodec;
+    use bytes::BytesMut;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Broker side of a loopback connection, scripted by the test
+    struct FakeBroker {
+        stream: TcpStream,
+        codec: MqttCodec,
+        buffer: BytesMut,
+    }
+
+    impl FakeBroker {
+        async fn recv(&mut self) -> Packet {
+            tokio::time::timeout(Duration::from_secs(5), async {
+                loop {
+                    if let Some(packet) = self.codec.decode(&mut self.buffer).unwrap() {
+                        return packet;
+                    }
+                    let mut buf = vec![0u8; 1024];
+                    let n = self.stream.read(&mut buf).await.unwrap();
+                    assert!(n > 0, "Client closed the connection");
+                    self.buffer.extend_from_slice(&buf[..n]);
+                }
+            }).await.expect("Timed out waiting for packet")
+        }
+
+        async fn send(&mut self, packet_type: PacketType, qos: u8, payload: PacketPayload) {
+            let packet = Packet {
+                header: PacketHeader { packet_type, dup: false, qos, retain: false, remaining_length: 0 },
+                payload,
+            };
+            let data = self.codec.encode(&packet).unwrap();
+            self.stream.write_all(&data).await.unwrap();
+        }
+    }
+
+    /// Open a loopback TCP connection and return both ends
+    async fn loopback() -> (ClientConnection, FakeBroker) {
+        loopback_with(ClientConfig::new("127.0.0.1:0")).await
+    }
+
+    /// Open a loopback TCP connection for a client with the given configuration
+    async fn loopback_with(config: ClientConfig) -> (ClientConnection, FakeBroker) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, broker) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let broker = FakeBroker {
+            stream: broker.unwrap().0,
+            codec: MqttCodec::new(config.protocol_version),
+            buffer: BytesMut::new(),
+        };
+        (ClientConnection::new(client.unwrap(), config).boxed(), broker)
+    }
+
+    /// Complete the CONNECT handshake and start an event loop on the connection
+    async fn connected() -> (AsyncClient, mpsc::UnboundedReceiver<Message>, FakeBroker) {
+        let (mut connection, mut broker) = loopback().await;
+        let (connack, connect) = tokio::join!(
+            connection.connect(ConnectOptions::new("event_loop_test")),
+            async {
+                let connect = broker.recv().await;
+                broker.send(PacketType::ConnAck, 0, PacketPayload::ConnAck(ConnAckPacket {
+                    session_present: false,
+                    return_code: ConnectReturnCode::Accepted,
+                    properties: None,
+                })).await;
+                connect
+            }
+        );
+        assert!(matches!(connect.payload, PacketPayload::Connect(_)));
+        assert_eq!(connack.unwrap().return_code, ConnectReturnCode::Accepted);
+
+        let config = ClientConfig::new("127.0.0.1:0");
+        let (client, incoming) = EventLoop::spawn(connection, config, ConnectOptions::new("event_loop_test"));
+        (client, incoming, broker)
+    }
+
+    fn new_event_loop(connection: ClientConnection) -> EventLoop {
+        new_event_loop_with(connection, ClientConfig::new("127.0.0.1:0"))
+    }
+
+    fn new_event_loop_with(connection: ClientConnection, config: ClientConfig) -> EventLoop {
+        let (_, requests) = mpsc::unbounded_channel();
+        let (incoming, _) = mpsc::unbounded_channel();
+        let (state, _) = watch::channel(ConnectionState::Connected);
+        EventLoop::new(connection, config, ConnectOptions::new("event_loop_test"), requests, incoming, state)
+    }
+
+    #[tokio::test]
+    async fn test_next_packet_id() {
+        let (connection, _broker) = loopback().await;
+        let mut event_loop = new_event_loop(connection);
+
+        // Test packet ID incrementing
+        assert_eq!(event_loop.next_packet_id().unwrap(), 1);
+        assert_eq!(event_loop.next_packet_id().unwrap(), 2);
+        assert_eq!(event_loop.next_packet_id().unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_packet_ids_in_use_skipped_after_wrap() {
+        let (connection, mut broker) = loopback().await;
+        let mut event_loop = new_event_loop(connection);
+
+        // An unacknowledged publish and subscription hold IDs 1 and 2
+        let (ack, _publish_ack) = oneshot::channel();
+        let options = PublishOptions::new("out/topic", "held").qos(QoS::AtLeastOnce);
+        event_loop.handle_request(Request::Publish(options, ack)).await.unwrap();
+        assert_eq!(publish_payload(broker.recv().await).0, 1);
+        let (ack, _subscribe_ack) = oneshot::channel();
+        event_loop.handle_request(Request::Subscribe("in/topic".to_string(), QoS::AtLeastOnce, ack)).await.unwrap();
+        broker.recv().await;
+
+        for packet_id in 3..=u16::MAX {
+            assert_eq!(event_loop.next_packet_id().unwrap(), packet_id);
+        }
+        let (ack, _) = oneshot::channel();
+        let options = PublishOptions::new("out/topic", "wrapped").qos(QoS::AtLeastOnce);
+        event_loop.handle_request(Request::Publish(options, ack)).await.unwrap();
+        assert_eq!(publish_payload(broker.recv().await).0, 3);
+        assert_eq!(event_loop.inflight.get(1).unwrap().options.payload, b"held");
+    }
+
+    #[tokio::test]
+    async fn test_publish_fails_when_packet_ids_exhausted() {
+        let (connection, _broker) = loopback().await;
+        let mut event_loop = new_event_loop(connection);
+        for packet_id in 1..=u16::MAX {
+            event_loop.pending.insert(packet_id, PendingAck::SubAck(format!("topic/{}", packet_id), QoS::AtLeastOnce, None));
+        }
+
+        let (ack, ack_rx) = oneshot::channel();
+        let options = PublishOptions::new("out/topic", "late").qos(QoS::AtLeastOnce);
+        event_loop.handle_request(Request::Publish(options, ack)).await.unwrap();
+        assert!(matches!(ack_rx.await.unwrap(), Err(Error::PacketIdsExhausted)));
+        assert!(event_loop.inflight.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_packet_id_counter_per_connection() {
+        let (connection1, _broker1) = loopback().await;
+        let (connection2, _broker2) = loopback().await;
+        let mut event_loop1 = new_event_loop(connection1);
+        let mut event_loop2 = new_event_loop(connection2);
+
+        // Each connection should have its own packet ID counter
+        assert_eq!(event_loop1.next_packet_id().unwrap(), 1);
+        assert_eq!(event_loop2.next_packet_id().unwrap(), 1);
+        assert_eq!(event_loop1.next_packet_id().unwrap(), 2);
+        assert_eq!(event_loop2.next_packet_id().unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_subscriptions_tracked_on_ack() {
+        let (connection, mut broker) = loopback().await;
+        let mut event_loop = new_event_loop(connection);
+
+        let (ack, mut ack_rx) = oneshot::channel();
+        event_loop.handle_request(Request::Subscribe("home/temp".to_string(), QoS::AtLeastOnce, ack)).await.unwrap();
+        let packet_id = match broker.recv().await.payload {
+            PacketPayload::Subscribe(subscribe) => subscribe.packet_id,
+            other => panic!("Expected SUBSCRIBE, got {:?}", other),
+        };
+
+        // Not recorded until the broker grants it
+        assert!(event_loop.subscriptions.is_empty());
+        assert!(ack_rx.try_recv().is_err());
+
+        event_loop.handle_packet(Packet {
+            header: PacketHeader { packet_type: PacketType::SubAck, dup: false, qos: 0, retain: false, remaining_length: 0 },
+            payload: PacketPayload::SubAck(SubAckPacket { packet_id, return_codes: vec![1], properties: None }),
+        }).await.unwrap();
+
+        assert!(ack_rx.try_recv().unwrap().is_ok());
+        assert_eq!(event_loop.subscriptions.get("home/temp"), Some(&QoS::AtLeastOnce));
+    }
+
+    #[tokio::test]
+    async fn test_publish_resolves_despite_interleaved_message() {
+        let (client, mut incoming, mut broker) = connected().await;
+
+        let publish = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client.publish(PublishOptions::new("out/topic", "ping").qos(QoS::AtLeastOnce)).await
+            }
+        });
+
+        let packet_id = match broker.recv().await.payload {
+            PacketPayload::Publish(publish) => publish.packet_id.unwrap(),
+            other => panic!("Expected PUBLISH, got {:?}", other),
+        };
+
+        // The broker delivers a message before acknowledging ours
+        broker.send(PacketType::Publish, 1, PacketPayload::Publish(PublishPacket {
+            topic_name: "in/topic".to_string(),
+            packet_id: Some(500),
+            payload: bytes::Bytes::from("pong"),
+            properties: None,
+        })).await;
+        match broker.recv().await.payload {
+            PacketPayload::PubAck(puback) => assert_eq!(puback.packet_id, 500),
+            other => panic!("Expected PUBACK, got {:?}", other),
+        }
+        assert!(!publish.is_finished());
+
+        broker.send(PacketType::PubAck, 0, PacketPayload::PubAck(PubAckPacket {
+            packet_id,
+            reason_code: None,
+            properties: None,
+        })).await;
+
+        publish.await.unwrap().unwrap();
+        let message = incoming.recv().await.unwrap();
+        assert_eq!(message.topic, "in/topic");
+        assert_eq!(message.payload, bytes::Bytes::from("pong"));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_topics_rejected_before_sending() {
+        let (client, _incoming, mut broker) = connected().await;
+
+        let publish = client.publish(PublishOptions::new("out/+", "wild")).await;
+        assert!(matches!(publish, Err(Error::InvalidTopic(_))));
+        let subscribe = client.subscribe("a/#/b", QoS::AtMostOnce).await;
+        assert!(matches!(subscribe, Err(Error::InvalidTopic(_))));
+        let unsubscribe = client.unsubscribe("").await;
+        assert!(matches!(unsubscribe, Err(Error::InvalidTopic(_))));
+
+        // Nothing reached the broker
+        let nothing = tokio::time::timeout(Duration::from_millis(100), broker.recv()).await;
+        assert!(nothing.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_qos2_publish_resolves_on_pubcomp() {
+        let (client, _incoming, mut broker) = connected().await;
+
+        let publish = tokio::spawn(async move {
+            client.publish(PublishOptions::new("out/exact", "once").qos(QoS::ExactlyOnce)).await
+        });
+
+        let packet_id = match broker.recv().await.payload {
+            PacketPayload::Publish(publish) => publish.packet_id.unwrap(),
+            other => panic!("Expected PUBLISH, got {:?}", other),
+        };
+        broker.send(PacketType::PubRec, 0, PacketPayload::PubRec(PubRecPacket {
+            packet_id,
+            reason_code: None,
+            properties: None,
+        })).await;
+        match broker.recv().await.payload {
+            PacketPayload::PubRel(pubrel) => assert_eq!(pubrel.packet_id, packet_id),
+            other => panic!("Expected PUBREL, got {:?}", other),
+        }
+        assert!(!publish.is_finished());
+
+        broker.send(PacketType::PubComp, 0, PacketPayload::PubComp(PubCompPacket {
+            packet_id,
+            reason_code: None,
+            properties: None,
+        })).await;
+        publish.await.unwrap().unwrap();
+    }
+
+    fn publish_payload(packet: Packet) -> (u16, bytes::Bytes) {
+        match packet.payload {
+            PacketPayload::Publish(publish) => (publish.packet_id.unwrap(), publish.payload),
+            other => panic!("Expected PUBLISH, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publishes_beyond_receive_maximum_held_back() {
+        let config = ClientConfig::new("127.0.0.1:0").protocol_version(5).max_inflight(3);
+        let (mut connection, mut broker) = loopback_with(config.clone()).await;
+        let (connack, _) = tokio::join!(
+            connection.connect(ConnectOptions::new("inflight_test")),
+            async {
+                broker.recv().await;
+                broker.send(PacketType::ConnAck, 0, PacketPayload::ConnAck(ConnAckPacket {
+                    session_present: false,
+                    return_code: ConnectReturnCode::Accepted,
+                    properties: Some(ConnAckProperties::new().receive_maximum(2)),
+                })).await;
+            }
+        );
+        connack.unwrap();
+        assert_eq!(connection.receive_maximum(), 2);
+        let (client, _incoming) = EventLoop::spawn(connection, config, ConnectOptions::new("inflight_test"));
+
+        let publishes: Vec<_> = ["one", "two", "three"].into_iter()
+            .map(|payload| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    client.publish(PublishOptions::new("out/topic", payload).qos(QoS::AtLeastOnce)).await
+                })
+            })
+            .collect();
+
+        // The broker's Receive Maximum is smaller than the configured window
+        let (first_id, first) = publish_payload(broker.recv().await);
+        let (second_id, second) = publish_payload(broker.recv().await);
+        let nothing = tokio::time::timeout(Duration::from_millis(100), broker.recv()).await;
+        assert!(nothing.is_err());
+
+        broker.send(PacketType::PubAck, 0, PacketPayload::PubAck(PubAckPacket {
+            packet_id: first_id,
+            reason_code: None,
+            properties: None,
+        })).await;
+        let (third_id, third) = publish_payload(broker.recv().await);
+        let mut payloads = vec![first, second, third];
+        payloads.sort();
+        assert_eq!(payloads, ["one", "three", "two"]);
+
+        for packet_id in [second_id, third_id] {
+            broker.send(PacketType::PubAck, 0, PacketPayload::PubAck(PubAckPacket {
+                packet_id,
+                reason_code: None,
+                properties: None,
+            })).await;
+        }
+        for publish in publishes {
+            publish.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_pubrec_frees_window() {
+        let (connection, mut broker) = loopback().await;
+        let mut event_loop = new_event_loop_with(connection, ClientConfig::new("127.0.0.1:0").max_inflight(1));
+
+        let (first_ack, mut first_rx) = oneshot::channel();
+        let (second_ack, mut second_rx) = oneshot::channel();
+        for (payload, ack) in [("first", first_ack), ("second", second_ack)] {
+            let options = PublishOptions::new("out/exact", payload).qos(QoS::ExactlyOnce);
+            event_loop.handle_request(Request::Publish(options, ack)).await.unwrap();
+        }
+        let (packet_id, _) = publish_payload(broker.recv().await);
+        assert_eq!(event_loop.queued_publishes.len(), 1);
+
+        event_loop.handle_packet(Packet {
+            header: PacketHeader { packet_type: PacketType::PubRec, dup: false, qos: 0, retain: false, remaining_length: 0 },
+            payload: PacketPayload::PubRec(PubRecPacket {
+                packet_id,
+                reason_code: Some(ReasonCode::QuotaExceeded as u8),
+                properties: None,
+            }),
+        }).await.unwrap();
+
+        assert!(matches!(first_rx.try_recv(), Ok(Err(Error::Client(_)))));
+        let (_, payload) = publish_payload(broker.recv().await);
+        assert_eq!(payload, "second");
+        assert!(second_rx.try_recv().is_err());
+        assert_eq!(event_loop.inflight.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pending_requests_fail_when_connection_drops() {
+        let (client, mut incoming, broker) = connected().await;
+
+        let publish = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client.publish(PublishOptions::new("out/topic", "lost").qos(QoS::AtLeastOnce)).await
+            }
+        });
+
+        // Give the request time to reach the broker, then drop the connection
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        drop(broker);
+
+        assert!(matches!(publish.await.unwrap(), Err(Error::Disconnected)));
+        assert!(incoming.recv().await.is_none());
+        assert_eq!(client.state(), ConnectionState::Disconnected);
+    }
+
+    /// Accept a client on the listener and answer its CONNECT
+    async fn accept(listener: &TcpListener) -> FakeBroker {
+        let (stream, _) = tokio::time::timeout(Duration::from_secs(5), listener.accept())
+            .await
+            .expect("Timed out waiting for client")
+            .unwrap();
+        let mut broker = FakeBroker { stream, codec: MqttCodec::new(4), buffer: BytesMut::new() };
+        assert!(matches!(broker.recv().await.payload, PacketPayload::Connect(_)));
+        broker.send(PacketType::ConnAck, 0, PacketPayload::ConnAck(ConnAckPacket {
+            session_present: false,
+            return_code: ConnectReturnCode::Accepted,
+            properties: None,
+        })).await;
+        broker
+    }
+
+    fn reconnecting_config(addr: std::net::SocketAddr) -> ClientConfig {
+        ClientConfig::new(addr.to_string()).reconnect(
+            ReconnectPolicy::new()
+                .initial_delay(Duration::from_millis(10))
+                .max_delay(Duration::from_millis(50))
+                .jitter(0.0)
+                .max_attempts(3),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_restores_subscriptions_and_resends_publishes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = reconnecting_config(listener.local_addr().unwrap());
+        let options = ConnectOptions::new("reconnect_test");
+
+        let (opened, mut broker) = tokio::join!(ClientConnection::open(&config, options.clone()), accept(&listener));
+        let (client, mut incoming) = EventLoop::spawn(opened.unwrap().0, config, options);
+
+        let subscribe = tokio::spawn({
+            let client = client.clone();
+            async move { client.subscribe("devices/+/status", QoS::AtLeastOnce).await }
+        });
+        let packet_id = match broker.recv().await.payload {
+            PacketPayload::Subscribe(subscribe) => subscribe.packet_id,
+            other => panic!("Expected SUBSCRIBE, got {:?}", other),
+        };
+        broker.send(PacketType::SubAck, 0, PacketPayload::SubAck(SubAckPacket {
+            packet_id,
+            return_codes: vec![1],
+            properties: None,
+        })).await;
+        subscribe.await.unwrap().unwrap();
+
+        let publish = tokio::spawn({
+            let client = client.clone();
+            async move { client.publish(PublishOptions::new("devices/1/status", "online").qos(QoS::AtLeastOnce)).await }
+        });
+        let first = broker.recv().await;
+        assert!(!first.header.dup);
+        let publish_id = match first.payload {
+            PacketPayload::Publish(publish) => publish.packet_id.unwrap(),
+            other => panic!("Expected PUBLISH, got {:?}", other),
+        };
+
+        // The connection drops before the broker acknowledges the publish
+        drop(broker);
+        let mut broker = accept(&listener).await;
+
+        let resubscribe = broker.recv().await;
+        let resubscribe_id = match resubscribe.payload {
+            PacketPayload::Subscribe(subscribe) => {
+                assert_eq!(subscribe.topic_filters[0].topic, "devices/+/status");
+                assert_eq!(subscribe.topic_filters[0].qos, 1);
+                subscribe.packet_id
+            }
+            other => panic!("Expected SUBSCRIBE, got {:?}", other),
+        };
+
+        let resent = broker.recv().await;
+        assert!(resent.header.dup);
+        assert_eq!(resent.header.qos, 1);
+        match resent.payload {
+            PacketPayload::Publish(publish) => {
+                assert_eq!(publish.packet_id, Some(publish_id));
+                assert_eq!(publish.payload, bytes::Bytes::from("online"));
+            }
+            other => panic!("Expected PUBLISH, got {:?}", other),
+        }
+        assert!(!publish.is_finished());
+
+        broker.send(PacketType::SubAck, 0, PacketPayload::SubAck(SubAckPacket {
+            packet_id: resubscribe_id,
+            return_codes: vec![1],
+            properties: None,
+        })).await;
+        broker.send(PacketType::PubAck, 0, PacketPayload::PubAck(PubAckPacket {
+            packet_id: publish_id,
+            reason_code: None,
+            properties: None,
+        })).await;
+        publish.await.unwrap().unwrap();
+        assert_eq!(client.state(), ConnectionState::Connected);
+
+        // Messages keep flowing on the same channel after the reconnect
+        broker.send(PacketType::Publish, 0, PacketPayload::Publish(PublishPacket {
+            topic_name: "devices/2/status".to_string(),
+            packet_id: None,
+            payload: bytes::Bytes::from("offline"),
+            properties: None,
+        })).await;
+        assert_eq!(incoming.recv().await.unwrap().topic, "devices/2/status");
+    }
+
+    #[tokio::test]
+    async fn test_requests_held_while_reconnecting() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = reconnecting_config(listener.local_addr().unwrap()).reconnect(
+            ReconnectPolicy::new().initial_delay(Duration::from_millis(200)).jitter(0.0),
+        );
+        let options = ConnectOptions::new("deferred_test");
+
+        let (opened, broker) = tokio::join!(ClientConnection::open(&config, options.clone()), accept(&listener));
+        let (client, _incoming) = EventLoop::spawn(opened.unwrap().0, config, options);
+
+        drop(broker);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !client.state().is_reconnecting() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        }).await.expect("Client never started reconnecting");
+
+        let publish = tokio::spawn({
+            let client = client.clone();
+            async move { client.publish(PublishOptions::new("queued/topic", "later")).await }
+        });
+
+        let mut broker = accept(&listener).await;
+        match broker.recv().await.payload {
+            PacketPayload::Publish(publish) => assert_eq!(publish.topic_name, "queued/topic"),
+            other => panic!("Expected PUBLISH, got {:?}", other),
+        }
+        publish.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_gives_up_after_max_attempts() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = reconnecting_config(listener.local_addr().unwrap());
+        let options = ConnectOptions::new("give_up_test");
+
+        let (opened, broker) = tokio::join!(ClientConnection::open(&config, options.clone()), accept(&listener));
+        let (client, mut incoming) = EventLoop::spawn(opened.unwrap().0, config, options);
+
+        // Nobody is listening any more
+        drop(listener);
+        drop(broker);
+
+        assert!(tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().is_none());
+        assert_eq!(client.state(), ConnectionState::Disconnected);
+        assert!(matches!(client.publish(PublishOptions::new("a/b", "x")).await, Err(Error::Disconnected)));
+    }
+
+    #[tokio::test]
+    async fn test_pingreq_sent_when_idle() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = ClientConfig::new(listener.local_addr().unwrap().to_string())
+            .keep_alive_interval(Duration::from_secs(1));
+        let options = ConnectOptions::new("keep_alive_test");
+
+        let (opened, mut broker) = tokio::join!(ClientConnection::open(&config, options.clone()), accept(&listener));
+        let (client, _incoming) = EventLoop::spawn(opened.unwrap().0, config, options);
+
+        let started = Instant::now();
+        assert!(matches!(broker.recv().await.payload, PacketPayload::PingReq));
+        assert!(started.elapsed() >= Duration::from_millis(900));
+        broker.send(PacketType::PingResp, 0, PacketPayload::PingResp).await;
+
+        // Answered pings keep the connection alive
+        assert!(matches!(broker.recv().await.payload, PacketPayload::PingReq));
+        assert_eq!(client.state(), ConnectionState::Connected);
+    }
+
+    #[tokio::test]
+    async fn test_silent_broker_disconnects_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = ClientConfig::new(listener.local_addr().unwrap().to_string())
+            .keep_alive_interval(Duration::from_secs(1))
+            .ping_timeout(Duration::from_millis(200));
+        let options = ConnectOptions::new("dead_broker_test");
+
+        let (opened, mut broker) = tokio::join!(ClientConnection::open(&config, options.clone()), accept(&listener));
+        let (client, mut incoming) = EventLoop::spawn(opened.unwrap().0, config, options);
+
+        // The broker keeps the socket open but never answers
+        assert!(matches!(broker.recv().await.payload, PacketPayload::PingReq));
+        assert!(tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().is_none());
+        assert_eq!(client.state(), ConnectionState::Disconnected);
+    }
+
+    #[tokio::test]
+    async fn test_silent_broker_triggers_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = reconnecting_config(listener.local_addr().unwrap())
+            .keep_alive_interval(Duration::from_secs(1))
+            .ping_timeout(Duration::from_millis(200));
+        let options = ConnectOptions::new("dead_broker_reconnect_test");
+
+        let (opened, mut broker) = tokio::join!(ClientConnection::open(&config, options.clone()), accept(&listener));
+        let (client, _incoming) = EventLoop::spawn(opened.unwrap().0, config, options);
+
+        assert!(matches!(broker.recv().await.payload, PacketPayload::PingReq));
+        let _broker = accept(&listener).await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !client.is_connected() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        }).await.expect("Client never reconnected");
+    }
+
+    #[tokio::test]
+    async fn test_server_disconnect_reason_returned() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ClientConfig::new(addr.to_string()).protocol_version(5);
+        let (client, broker) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let mut broker = FakeBroker { stream: broker.unwrap().0, codec: MqttCodec::new(5), buffer: BytesMut::new() };
+        // The request sender stays alive so the loop does not leave on its own
+        let (_requests_tx, requests) = mpsc::unbounded_channel();
+        let (incoming, _) = mpsc::unbounded_channel();
+        let (state, _) = watch::channel(ConnectionState::Connected);
+        let event_loop = EventLoop::new(
+            ClientConnection::new(client.unwrap(), config.clone()).boxed(),
+            config,
+            ConnectOptions::new("event_loop_test"),
+            requests,
+            incoming,
+            state,
+        );
+
+        broker.send(PacketType::Disconnect, 0, PacketPayload::Disconnect(DisconnectPacket {
+            reason_code: Some(ReasonCode::SessionTakenOver as u8),
+            properties: Some(DisconnectProperties::new().reason_string("replaced".to_string())),
+        })).await;
+
+        match tokio::time::timeout(Duration::from_secs(5), event_loop.run()).await.unwrap() {
+            Err(Error::ServerDisconnected { reason_code, reason_string }) => {
+                assert_eq!(reason_code, ReasonCode::SessionTakenOver);
+                assert_eq!(reason_string.as_deref(), Some("replaced"));
+            }
+            other => panic!("Expected ServerDisconnected, got {:?}", other),
+        }
+    }
+}
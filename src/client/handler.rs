@@ -98,6 +98,8 @@ use crate::protocol::QoS;
             retain: false,
             dup: false,
             packet_id: Some(1),
+            properties: None,
+            expires_at: None,
         };
 
         // Should not panic
@@ -121,6 +123,8 @@ use crate::protocol::QoS;
             retain: false,
             dup: false,
             packet_id: None,
+            properties: None,
+            expires_at: None,
         };
 
         // Initially enabled
@@ -157,6 +161,8 @@ use crate::protocol::QoS;
             retain: false,
             dup: false,
             packet_id: Some(1),
+            properties: None,
+            expires_at: None,
         };
 
         processor.process(message);
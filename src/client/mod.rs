@@ -8,12 +8,22 @@ pub mod config;
 pub mod connection;
 pub mod state;
 pub mod handler;
+pub mod event_loop;
+pub mod async_client;
+pub mod auth;
+pub mod tls;
+pub mod transport;
 
 // Re-export main components for easy access
-pub use config::ClientConfig;
+pub use config::{ClientConfig, ReconnectPolicy};
 pub use connection::ClientConnection;
 pub use state::ConnectionState;
 pub use handler::MessageHandler;
+pub use event_loop::EventLoop;
+pub use async_client::AsyncClient;
+pub use auth::{ChallengeExchange, ChallengeHandler, ScramSha256};
+pub use tls::TlsConfig;
+pub use crate::transport::Transport;
 
 // Re-export types that are commonly used with the client
 pub use crate::protocol::{ConnectOptions, QoS, PublishOptions};
@@ -21,17 +31,17 @@ pub use crate::types::Message;
 
 use crate::error::{Error, Result};
 use log::{info, debug, warn};
-use std::collections::HashMap;
-use tokio::net::TcpStream;
-use tokio::time::timeout;
+use tokio::sync::mpsc;
 
 /// MQTT client
+///
+/// Connecting spawns an [`EventLoop`] that owns the socket. The client keeps an
+/// [`AsyncClient`] handle to it, which can be cloned with [`Client::handle`] and
+/// shared between tasks, and receives incoming messages through [`Client::recv`].
 pub struct Client {
     config: ClientConfig,
-    connection: Option<ClientConnection>,
-    state: ConnectionState,
-    packet_id_counter: u16,
-    subscriptions: HashMap<String, QoS>,
+    handle: Option<AsyncClient>,
+    incoming: Option<mpsc::UnboundedReceiver<Message>>,
     message_handler: Option<MessageHandler>,
 }
 
@@ -40,10 +50,8 @@ impl Client {
     pub fn new(config: ClientConfig) -> Self {
         Self {
             config,
-            connection: None,
-            state: ConnectionState::Disconnected,
-            packet_id_counter: 1,
-            subscriptions: HashMap::new(),
+            handle: None,
+            incoming: None,
             message_handler: None,
         }
     }
@@ -59,232 +67,148 @@ impl Client {
 
     /// Connect to MQTT broker
     pub async fn connect(mut self, options: ConnectOptions) -> Result<Self> {
-        if !self.state.is_disconnected() {
+        if !self.is_disconnected() {
             return Err(Error::Client("Client is not in disconnected state".to_string()));
         }
 
-        self.state = ConnectionState::Connecting;
         info!("Connecting to MQTT broker at {}", self.config.server_addr);
 
-        // Establish TCP connection
-        let stream = timeout(
-            self.config.connect_timeout,
-            TcpStream::connect(&self.config.server_addr)
-        ).await
-            .map_err(|_| Error::Connection("Connection timeout".to_string()))?
-            .map_err(|e| Error::Connection(format!("Failed to connect: {}", e)))?;
-
-        info!("TCP connection established");
-
-        // Create connection handler
-        let mut connection = ClientConnection::new(stream, self.config.clone());
-
-        // Send CONNECT packet
-        let connack = connection.connect(options).await?;
-        
-        if connack.return_code != crate::types::ConnectReturnCode::Accepted {
-            return Err(Error::Connection(format!(
-                "Connection rejected: {:?}",
-                connack.return_code
-            )));
-        }
+        // Establish TCP connection and send CONNECT packet
+        let (connection, _connack) = ClientConnection::open(&self.config, options.clone()).await?;
 
         info!("MQTT connection established successfully");
-        self.connection = Some(connection);
-        self.state = ConnectionState::Connected;
+
+        // Hand the connection over to the event loop
+        let (handle, incoming) = EventLoop::spawn(connection, self.config.clone(), options);
+        self.handle = Some(handle);
+        self.incoming = Some(incoming);
 
         Ok(self)
     }
 
+    /// Get a cloneable handle to the connection
+    ///
+    /// Returns `None` until the client has connected.
+    pub fn handle(&self) -> Option<AsyncClient> {
+        self.handle.clone()
+    }
+
     /// Disconnect from MQTT broker
     pub async fn disconnect(&mut self) -> Result<()> {
-        if !self.state.is_connected() {
+        if self.is_disconnected() {
             return Ok(());
         }
 
-        self.state = ConnectionState::Disconnecting;
         info!("Disconnecting from MQTT broker");
 
-        if let Some(ref mut connection) = self.connection {
-            connection.disconnect().await?;
+        if let Some(ref handle) = self.handle {
+            handle.disconnect().await?;
         }
 
-        self.connection = None;
-        self.state = ConnectionState::Disconnected;
-        info!("Disconnected from MQTT broker");
-
         Ok(())
     }
 
     /// Subscribe to a topic
-    pub async fn subscribe(&mut self, topic: impl Into<String>, qos: QoS) -> Result<()> {
-        if !self.state.is_connected() {
-            return Err(Error::Client("Client is not connected".to_string()));
-        }
-
-        let topic = topic.into();
-        let packet_id = self.next_packet_id();
-        
-        info!("Subscribing to topic '{}' with QoS {:?}", topic, qos);
-
-        if let Some(ref mut connection) = self.connection {
-            connection.subscribe(&topic, qos, packet_id).await?;
-            self.subscriptions.insert(topic, qos);
-        }
-
-        Ok(())
+    pub async fn subscribe(&self, topic: impl Into<String>, qos: QoS) -> Result<()> {
+        self.connected_handle()?.subscribe(topic, qos).await
     }
 
     /// Unsubscribe from a topic
-    pub async fn unsubscribe(&mut self, topic: impl Into<String>) -> Result<()> {
-        if !self.state.is_connected() {
-            return Err(Error::Client("Client is not connected".to_string()));
-        }
-
-        let topic = topic.into();
-        let packet_id = self.next_packet_id();
-        
-        info!("Unsubscribing from topic '{}'", topic);
-
-        if let Some(ref mut connection) = self.connection {
-            connection.unsubscribe(&topic, packet_id).await?;
-            self.subscriptions.remove(&topic);
-        }
-
-        Ok(())
+    pub async fn unsubscribe(&self, topic: impl Into<String>) -> Result<()> {
+        self.connected_handle()?.unsubscribe(topic).await
     }
 
     /// Publish a message
-    pub async fn publish(&mut self, options: PublishOptions) -> Result<()> {
-        if !self.state.is_connected() {
-            return Err(Error::Client("Client is not connected".to_string()));
-        }
-
-        let packet_id = if options.qos != QoS::AtMostOnce {
-            Some(self.next_packet_id())
-        } else {
-            None
-        };
-
-        let publish_options = PublishOptions {
-            packet_id,
-            ..options
-        };
-
-        info!("Publishing message to topic '{}'", publish_options.topic);
-
-        if let Some(ref mut connection) = self.connection {
-            connection.publish(publish_options).await?;
-        }
+    pub async fn publish(&self, options: PublishOptions) -> Result<()> {
+        self.connected_handle()?.publish(options).await
+    }
 
-        Ok(())
+    /// Repeat the MQTT 5.0 enhanced authentication exchange
+    pub async fn reauthenticate(&self) -> Result<()> {
+        self.connected_handle()?.reauthenticate().await
     }
 
-    /// Receive a message
+    /// Receive the next message published by the broker
+    ///
+    /// Returns `None` once the client has disconnected for good, after any
+    /// reconnect attempts, and every message received before that has been
+    /// consumed.
     pub async fn recv(&mut self) -> Result<Option<Message>> {
-        if !self.state.is_connected() {
-            return Err(Error::Client("Client is not connected".to_string()));
-        }
-
-        if let Some(ref mut connection) = self.connection {
-            connection.recv().await
-        } else {
-            Ok(None)
+        match self.incoming {
+            Some(ref mut incoming) => Ok(incoming.recv().await),
+            None => Err(Error::Client("Client is not connected".to_string())),
         }
     }
 
     /// Start listening for messages
     pub async fn listen(&mut self) -> Result<()> {
-        if !self.state.is_connected() {
+        if !self.is_connected() {
             return Err(Error::Client("Client is not connected".to_string()));
         }
 
         info!("Starting message listener");
         
-        loop {
-            match self.recv().await {
-                Ok(Some(message)) => {
-                    debug!("Received message on topic: {}", message.topic);
-                    
-                    // Call message handler if set
-                    if let Some(ref handler) = self.message_handler {
-                        handler(message);
-                    }
-                }
-                Ok(None) => {
-                    // No message available, continue
-                    continue;
-                }
-                Err(e) => {
-                    warn!("Error receiving message: {}", e);
-                    if let Error::Disconnected = e {
-                        break;
-                    }
-                }
+        while let Some(message) = self.recv().await? {
+            debug!("Received message on topic: {}", message.topic);
+
+            // Call message handler if set
+            if let Some(ref handler) = self.message_handler {
+                handler(message);
             }
         }
-        
+
+        warn!("Message listener stopped: connection closed");
         Ok(())
     }
 
     /// Get connection state
     pub fn state(&self) -> ConnectionState {
-        self.state.clone()
+        self.handle
+            .as_ref()
+            .map_or(ConnectionState::Disconnected, AsyncClient::state)
     }
 
     /// Check if client is connected
     pub fn is_connected(&self) -> bool {
-        self.state.is_connected()
+        self.state().is_connected()
     }
 
     /// Check if client is disconnected
     pub fn is_disconnected(&self) -> bool {
-        self.state.is_disconnected()
+        self.state().is_disconnected()
     }
 
-    /// Get next packet ID
-    fn next_packet_id(&mut self) -> u16 {
-        let id = self.packet_id_counter;
-        self.packet_id_counter = self.packet_id_counter.wrapping_add(1);
-        if self.packet_id_counter == 0 {
-            self.packet_id_counter = 1;
+    /// Get the handle of a connected client
+    ///
+    /// Requests made while reconnecting are accepted and sent once the
+    /// connection is back.
+    fn connected_handle(&self) -> Result<&AsyncClient> {
+        match self.handle {
+            Some(ref handle) if !handle.state().is_disconnected() => Ok(handle),
+            _ => Err(Error::Client("Client is not connected".to_string())),
         }
-        id
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::server::session::SessionManager;
+    use crate::server::{MessageRouter, ScramSha256Provider, ServerConfig, ServerConnection};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
 
     #[test]
     fn test_client_new() {
         let config = ClientConfig::new("localhost:1883");
         let client = Client::new(config);
-        assert!(client.connection.is_none());
-        assert_eq!(client.state, ConnectionState::Disconnected);
-        assert_eq!(client.packet_id_counter, 1);
-        assert!(client.subscriptions.is_empty());
+        assert!(client.handle.is_none());
+        assert!(client.incoming.is_none());
+        assert!(client.handle().is_none());
         assert!(client.message_handler.is_none());
     }
 
-    #[test]
-    fn test_client_next_packet_id() {
-        let config = ClientConfig::new("localhost:1883");
-        let mut client = Client::new(config);
-        
-        // Test packet ID incrementing
-        assert_eq!(client.next_packet_id(), 1);
-        assert_eq!(client.next_packet_id(), 2);
-        assert_eq!(client.next_packet_id(), 3);
-        
-        // Test wrapping around u16::MAX
-        client.packet_id_counter = u16::MAX;
-        assert_eq!(client.next_packet_id(), u16::MAX);
-        assert_eq!(client.next_packet_id(), 1);
-        assert_eq!(client.next_packet_id(), 2);
-    }
-
     #[test]
     fn test_client_state() {
         let config = ClientConfig::new("localhost:1883");
@@ -301,37 +225,139 @@ mod tests {
         assert!(client.is_disconnected());
     }
 
-    #[test]
-    fn test_client_add_subscription() {
+    #[tokio::test]
+    async fn test_client_requires_connection() {
         let config = ClientConfig::new("localhost:1883");
         let mut client = Client::new(config);
-        
-        client.subscriptions.insert("home/temp".to_string(), QoS::AtLeastOnce);
-        client.subscriptions.insert("home/humidity".to_string(), QoS::ExactlyOnce);
-        
-        assert_eq!(client.subscriptions.len(), 2);
-        assert_eq!(client.subscriptions.get("home/temp"), Some(&QoS::AtLeastOnce));
-        assert_eq!(client.subscriptions.get("home/humidity"), Some(&QoS::ExactlyOnce));
+
+        assert!(matches!(client.subscribe("home/temp", QoS::AtLeastOnce).await, Err(Error::Client(_))));
+        assert!(matches!(client.publish(PublishOptions::new("home/temp", "20")).await, Err(Error::Client(_))));
+        assert!(matches!(client.recv().await, Err(Error::Client(_))));
+        assert!(client.disconnect().await.is_ok());
     }
 
-    #[test]
-    fn test_packet_id_counter_thread_safety() {
-        let config = ClientConfig::new("localhost:1883");
-        let mut client1 = Client::new(config.clone());
-        let mut client2 = Client::new(config);
-        
-        // Each client should have its own packet ID counter
-        let id1 = client1.next_packet_id();
-        let id2 = client2.next_packet_id();
-        
-        assert_eq!(id1, 1);
-        assert_eq!(id2, 1);
-        
-        // Incrementing should not affect other clients
-        let id1_next = client1.next_packet_id();
-        let id2_next = client2.next_packet_id();
-        
-        assert_eq!(id1_next, 2);
-        assert_eq!(id2_next, 2);
+    /// Start a broker on an ephemeral port
+    async fn start_test_server() -> SocketAddr {
+        start_test_server_with(|config| config).await
+    }
+
+    /// Start a broker with a customised configuration
+    async fn start_test_server_with(configure: impl FnOnce(ServerConfig) -> ServerConfig) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = configure(ServerConfig::new(addr.to_string()));
+        let session_manager = Arc::new(SessionManager::new());
+        let message_router = Arc::new(MessageRouter::new());
+
+        tokio::spawn(async move {
+            while let Ok((stream, peer)) = listener.accept().await {
+                tokio::spawn(ServerConnection::handle_connection(
+                    stream,
+                    peer.into(),
+                    config.clone(),
+                    Arc::clone(&session_manager),
+                    Arc::clone(&message_router),
+                ));
+            }
+        });
+
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_cloned_handles_share_connection() {
+        let addr = start_test_server().await;
+        let mut client = Client::new(ClientConfig::new(addr.to_string()))
+            .connect(ConnectOptions::new("handle_client"))
+            .await
+            .unwrap();
+        assert!(client.is_connected());
+
+        client.subscribe("loop/#", QoS::AtLeastOnce).await.unwrap();
+
+        // Publish from several tasks at once, each through its own clone
+        let mut tasks = Vec::new();
+        for i in 0..5 {
+            let handle = client.handle().unwrap();
+            tasks.push(tokio::spawn(async move {
+                handle.publish(PublishOptions::new(format!("loop/{}", i), "data").qos(QoS::AtLeastOnce)).await
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+
+        // Our own messages come back while acknowledgments are in flight
+        let mut topics = Vec::new();
+        for _ in 0..5 {
+            let message = tokio::time::timeout(Duration::from_secs(5), client.recv())
+                .await
+                .expect("Timed out waiting for message")
+                .unwrap()
+                .unwrap();
+            topics.push(message.topic);
+        }
+        topics.sort();
+        assert_eq!(topics, vec!["loop/0", "loop/1", "loop/2", "loop/3", "loop/4"]);
+
+        let handle = client.handle().unwrap();
+        client.disconnect().await.unwrap();
+        assert!(client.is_disconnected());
+        assert!(matches!(handle.publish(PublishOptions::new("loop/x", "late")).await, Err(Error::Disconnected)));
+    }
+
+    /// Broker offering SCRAM-SHA-256 with a single user
+    async fn start_scram_server() -> SocketAddr {
+        start_test_server_with(|config| {
+            config
+                .protocol_version(5)
+                .authentication_provider(Arc::new(ScramSha256Provider::new().add_user("alice", "wonderland")))
+        }).await
+    }
+
+    fn scram_config(addr: SocketAddr, password: &str) -> ClientConfig {
+        ClientConfig::new(addr.to_string())
+            .protocol_version(5)
+            .challenge_handler(Arc::new(ScramSha256::new("alice", password)))
+    }
+
+    #[tokio::test]
+    async fn test_scram_authentication_and_reauthentication() {
+        let addr = start_scram_server().await;
+        let mut client = Client::new(scram_config(addr, "wonderland"))
+            .connect(ConnectOptions::new("scram_client").protocol_version(5))
+            .await
+            .unwrap();
+        assert!(client.is_connected());
+
+        client.reauthenticate().await.unwrap();
+        client.reauthenticate().await.unwrap();
+
+        // The connection keeps working after re-authenticating
+        client.subscribe("scram/topic", QoS::AtLeastOnce).await.unwrap();
+        client.publish(PublishOptions::new("scram/topic", "hi").qos(QoS::AtLeastOnce)).await.unwrap();
+        let message = tokio::time::timeout(Duration::from_secs(5), client.recv()).await.unwrap().unwrap().unwrap();
+        assert_eq!(message.payload, bytes::Bytes::from("hi"));
+        client.disconnect().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_scram_wrong_password_rejected() {
+        let addr = start_scram_server().await;
+        let result = Client::new(scram_config(addr, "looking-glass"))
+            .connect(ConnectOptions::new("scram_client").protocol_version(5))
+            .await;
+        assert!(matches!(result, Err(Error::Connection(_))));
+    }
+
+    #[tokio::test]
+    async fn test_reauthenticate_requires_challenge_handler() {
+        let addr = start_test_server().await;
+        let client = Client::new(ClientConfig::new(addr.to_string()))
+            .connect(ConnectOptions::new("plain_client"))
+            .await
+            .unwrap();
+        assert!(matches!(client.reauthenticate().await, Err(Error::Client(_))));
+        assert!(client.is_connected());
     }
 }
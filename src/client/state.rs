@@ -5,6 +5,8 @@ pub enum ConnectionState {
     Connecting,
     Connected,
     Disconnecting,
+    /// Connection was lost and the client is re-establishing it
+    Reconnecting,
 }
 
 impl ConnectionState {
@@ -18,9 +20,17 @@ impl ConnectionState {
         matches!(self, ConnectionState::Disconnected)
     }
 
+    /// Check if the client is re-establishing a lost connection
+    pub fn is_reconnecting(&self) -> bool {
+        matches!(self, ConnectionState::Reconnecting)
+    }
+
     /// Check if the client is in a transitional state
     pub fn is_transitional(&self) -> bool {
-        matches!(self, ConnectionState::Connecting | ConnectionState::Disconnecting)
+        matches!(
+            self,
+            ConnectionState::Connecting | ConnectionState::Disconnecting | ConnectionState::Reconnecting
+        )
     }
 
     /// Get a human-readable description of the state
@@ -30,6 +40,7 @@ impl ConnectionState {
             ConnectionState::Connecting => "Connecting",
             ConnectionState::Connected => "Connected",
             ConnectionState::Disconnecting => "Disconnecting",
+            ConnectionState::Reconnecting => "Reconnecting",
         }
     }
 }
@@ -44,6 +55,7 @@ mod tests {
         assert_eq!(ConnectionState::Connecting as u8, 1);
         assert_eq!(ConnectionState::Connected as u8, 2);
         assert_eq!(ConnectionState::Disconnecting as u8, 3);
+        assert_eq!(ConnectionState::Reconnecting as u8, 4);
     }
 
     #[test]
@@ -82,6 +94,12 @@ mod tests {
         assert!(disconnecting.is_transitional());
         assert!(!disconnecting.is_connected());
         assert!(!disconnecting.is_disconnected());
+
+        let reconnecting = ConnectionState::Reconnecting;
+        assert!(reconnecting.is_reconnecting());
+        assert!(reconnecting.is_transitional());
+        assert!(!reconnecting.is_connected());
+        assert!(!reconnecting.is_disconnected());
     }
 
     #[test]
@@ -90,5 +108,6 @@ mod tests {
         assert_eq!(ConnectionState::Connecting.description(), "Connecting");
         assert_eq!(ConnectionState::Connected.description(), "Connected");
         assert_eq!(ConnectionState::Disconnecting.description(), "Disconnecting");
+        assert_eq!(ConnectionState::Reconnecting.description(), "Reconnecting");
     }
 }
@@ -0,0 +1,253 @@
+//! Client TLS configuration
+//!
+//! [`TlsConfig`] set through `ClientConfig::tls` makes the client wrap its TCP
+//! connection in a rustls session. The broker certificate is checked against
+//! the configured CA bundles, or against the system trust store when none are
+//! given.
+
+use log::warn;
+use rustls::pki_types::ServerName;
+use rustls::RootCertStore;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio_rustls::TlsConnector;
+
+use crate::error::{Error, Result};
+use crate::tls;
+
+/// Trust, identity and handshake settings of a TLS connection to the broker
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsConfig {
+    /// PEM bundles of CAs trusted to sign the broker certificate
+    pub ca_paths: Vec<PathBuf>,
+    /// PEM certificate chain and private key presented for mutual TLS
+    pub client_cert: Option<(PathBuf, PathBuf)>,
+    /// Name checked against the broker certificate and sent as SNI; defaults to
+    /// the host of `ClientConfig::server_addr`
+    pub server_name: Option<String>,
+    /// ALPN protocols offered during the handshake, most preferred first
+    pub alpn_protocols: Vec<Vec<u8>>,
+}
+
+impl TlsConfig {
+    /// Verify the broker against the system trust store
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trust the CAs in a PEM bundle instead of the system trust store
+    pub fn ca_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.ca_paths.push(path.into());
+        self
+    }
+
+    /// Present a client certificate for mutual TLS
+    pub fn client_cert(mut self, cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        self.client_cert = Some((cert_path.into(), key_path.into()));
+        self
+    }
+
+    /// Override the server name used for SNI and certificate verification
+    pub fn server_name(mut self, name: impl Into<String>) -> Self {
+        self.server_name = Some(name.into());
+        self
+    }
+
+    /// Offer an ALPN protocol, such as `mqtt` or `x-amzn-mqtt-ca`
+    pub fn alpn(mut self, protocol: impl Into<Vec<u8>>) -> Self {
+        self.alpn_protocols.push(protocol.into());
+        self
+    }
+
+    /// Load the certificates and build the connector
+    pub fn connector(&self) -> Result<TlsConnector> {
+        let roots = self.root_store()?;
+        let builder = rustls::ClientConfig::builder_with_provider(tls::crypto_provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| Error::Tls(e.to_string()))?
+            .with_root_certificates(roots);
+
+        let mut config = match &self.client_cert {
+            Some((cert_path, key_path)) => builder
+                .with_client_auth_cert(tls::load_certs(cert_path)?, tls::load_private_key(key_path)?)
+                .map_err(|e| Error::Tls(format!("Invalid client certificate or key: {}", e)))?,
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = self.alpn_protocols.clone();
+
+        Ok(TlsConnector::from(Arc::new(config)))
+    }
+
+    /// Name to verify the broker certificate against
+    pub fn resolve_server_name(&self, server_addr: &str) -> Result<ServerName<'static>> {
+        let name = match &self.server_name {
+            Some(name) => name.as_str(),
+            None => host(server_addr),
+        };
+        ServerName::try_from(name.to_string())
+            .map_err(|_| Error::Tls(format!("Invalid server name: {}", name)))
+    }
+
+    fn root_store(&self) -> Result<RootCertStore> {
+        if !self.ca_paths.is_empty() {
+            let mut roots = RootCertStore::empty();
+            for path in &self.ca_paths {
+                roots.roots.extend(tls::load_root_store(path)?.roots);
+            }
+            return Ok(roots);
+        }
+
+        let native = rustls_native_certs::load_native_certs();
+        for error in &native.errors {
+            warn!("Failed to load system certificate: {}", error);
+        }
+        let mut roots = RootCertStore::empty();
+        let (added, _) = roots.add_parsable_certificates(native.certs);
+        if added == 0 {
+            return Err(Error::Tls("No trusted CA certificates found".to_string()));
+        }
+        Ok(roots)
+    }
+}
+
+/// Host part of a `host:port` address, without IPv6 brackets
+fn host(server_addr: &str) -> &str {
+    let host = match server_addr.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => server_addr,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, ClientConfig, ConnectOptions, PublishOptions, QoS};
+    use crate::server::session::SessionManager;
+    use crate::server::{MessageRouter, ServerConfig, ServerConnection};
+    use crate::tls::test_pki::TestPki;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    /// Broker accepting TLS connections from clients with a certificate signed by the test CA
+    async fn start_mtls_server(pki: &TestPki) -> SocketAddr {
+        let tls = crate::server::TlsConfig::new(pki.path("server.pem"), pki.path("server.key"))
+            .client_ca(pki.path("ca.pem"));
+        let acceptor = tls.acceptor().unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ServerConfig::new(addr.to_string()).tls(tls);
+        let session_manager = Arc::new(SessionManager::new());
+        let message_router = Arc::new(MessageRouter::new());
+
+        tokio::spawn(async move {
+            while let Ok((stream, peer)) = listener.accept().await {
+                let Ok(stream) = acceptor.accept(stream).await else {
+                    continue;
+                };
+                tokio::spawn(ServerConnection::handle_connection(
+                    stream,
+                    peer.into(),
+                    config.clone(),
+                    Arc::clone(&session_manager),
+                    Arc::clone(&message_router),
+                ));
+            }
+        });
+
+        addr
+    }
+
+    fn client_config(addr: SocketAddr, tls: TlsConfig) -> ClientConfig {
+        ClientConfig::new(addr.to_string())
+            .connect_timeout(Duration::from_secs(5))
+            .read_timeout(Duration::from_secs(5))
+            .tls(tls)
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls_round_trip() {
+        let pki = TestPki::generate("client-mtls");
+        let addr = start_mtls_server(&pki).await;
+
+        // The certificate is issued for localhost, so the IP address needs an SNI override
+        let tls = TlsConfig::new()
+            .ca_file(pki.path("ca.pem"))
+            .client_cert(pki.path("client.pem"), pki.path("client.key"))
+            .server_name("localhost")
+            .alpn("mqtt");
+        let mut client = Client::new(client_config(addr, tls))
+            .connect(ConnectOptions::new("mtls_client"))
+            .await
+            .unwrap();
+
+        client.subscribe("secure/#", QoS::AtLeastOnce).await.unwrap();
+        client.publish(PublishOptions::new("secure/data", "encrypted").qos(QoS::AtLeastOnce)).await.unwrap();
+        let message = tokio::time::timeout(Duration::from_secs(5), client.recv())
+            .await
+            .expect("Timed out waiting for message")
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.topic, "secure/data");
+        client.disconnect().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tls_verification_failures() {
+        let pki = TestPki::generate("client-verify");
+        let other = TestPki::generate("client-verify-other");
+        let addr = start_mtls_server(&pki).await;
+        let connect = |tls: TlsConfig| async move {
+            Client::new(client_config(addr, tls)).connect(ConnectOptions::new("rejected")).await
+        };
+
+        // Broker certificate signed by a CA we do not trust
+        let untrusted = TlsConfig::new()
+            .ca_file(other.path("ca.pem"))
+            .client_cert(pki.path("client.pem"), pki.path("client.key"))
+            .server_name("localhost");
+        assert!(matches!(connect(untrusted).await, Err(Error::Tls(_))));
+
+        // Name that does not match the broker certificate
+        let wrong_name = TlsConfig::new()
+            .ca_file(pki.path("ca.pem"))
+            .client_cert(pki.path("client.pem"), pki.path("client.key"))
+            .server_name("broker.example.com");
+        assert!(matches!(connect(wrong_name).await, Err(Error::Tls(_))));
+
+        // The broker requires a client certificate
+        let anonymous = TlsConfig::new().ca_file(pki.path("ca.pem")).server_name("localhost");
+        assert!(connect(anonymous).await.is_err());
+    }
+
+    #[test]
+    fn test_server_name_resolution() {
+        let tls = TlsConfig::new();
+        assert_eq!(tls.resolve_server_name("broker.example.com:8883").unwrap(),
+            ServerName::try_from("broker.example.com").unwrap());
+        assert_eq!(tls.resolve_server_name("[::1]:8883").unwrap(),
+            ServerName::try_from("::1").unwrap());
+        assert_eq!(tls.resolve_server_name("10.0.0.1").unwrap(),
+            ServerName::try_from("10.0.0.1").unwrap());
+
+        let tls = tls.server_name("mqtt.internal");
+        assert_eq!(tls.resolve_server_name("10.0.0.1:8883").unwrap(),
+            ServerName::try_from("mqtt.internal").unwrap());
+        assert!(TlsConfig::new().server_name("not a name").resolve_server_name("x:1").is_err());
+    }
+
+    #[test]
+    fn test_connector_settings() {
+        let pki = TestPki::generate("client-config");
+        let tls = TlsConfig::new()
+            .ca_file(pki.path("ca.pem"))
+            .client_cert(pki.path("client.pem"), pki.path("client.key"))
+            .alpn("mqtt");
+        assert_eq!(tls.alpn_protocols, vec![b"mqtt".to_vec()]);
+        assert!(tls.connector().is_ok());
+
+        let missing_key = TlsConfig::new().ca_file(pki.path("ca.pem")).client_cert(pki.path("client.pem"), pki.path("missing.key"));
+        assert!(matches!(missing_key.connector(), Err(Error::Tls(_))));
+        assert!(matches!(TlsConfig::new().ca_file(pki.path("client.key")).connector(), Err(Error::Tls(_))));
+    }
+}
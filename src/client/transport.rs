@@ -0,0 +1,178 @@
+//! Client transports
+//!
+//! `ClientConnection` runs over any [`Transport`]. [`connect`] opens the one
+//! `ClientConfig::server_addr` asks for:
+//!
+//! - `host:port`: plain TCP, or TLS when `ClientConfig::tls` is set
+//! - `ws://host[:port]/path`: MQTT over WebSocket
+//! - `wss://host[:port]/path`: MQTT over WebSocket over TLS, verified with
+//!   `ClientConfig::tls` or the system trust store
+
+use log::debug;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use super::config::ClientConfig;
+use super::tls::TlsConfig;
+use crate::error::{Error, Result};
+use crate::transport::Transport;
+use crate::websocket;
+
+/// Where and how to reach the broker, parsed from `ClientConfig::server_addr`
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Endpoint<'a> {
+    Tcp { addr: &'a str },
+    WebSocket { url: &'a str, authority: String, tls: bool },
+}
+
+impl<'a> Endpoint<'a> {
+    fn parse(server_addr: &'a str) -> Result<Self> {
+        let (rest, tls, default_port) = if let Some(rest) = server_addr.strip_prefix("ws://") {
+            (rest, false, 80)
+        } else if let Some(rest) = server_addr.strip_prefix("wss://") {
+            (rest, true, 443)
+        } else {
+            return Ok(Self::Tcp { addr: server_addr });
+        };
+
+        let authority = rest.split(['/', '?']).next().unwrap_or_default();
+        if authority.is_empty() {
+            return Err(Error::Client(format!("Missing host in {}", server_addr)));
+        }
+        let has_port = authority.rsplit_once(':').is_some_and(|(_, port)| port.chars().all(|c| c.is_ascii_digit()))
+            && !authority.ends_with(']');
+        let authority = if has_port {
+            authority.to_string()
+        } else {
+            format!("{}:{}", authority, default_port)
+        };
+
+        Ok(Self::WebSocket { url: server_addr, authority, tls })
+    }
+}
+
+/// Connect to the broker in `config.server_addr` within the connect timeout
+pub async fn connect(config: &ClientConfig) -> Result<Box<dyn Transport>> {
+    timeout(config.connect_timeout, open(config)).await
+        .map_err(|_| Error::Connection("Connection timeout".to_string()))?
+}
+
+async fn open(config: &ClientConfig) -> Result<Box<dyn Transport>> {
+    match Endpoint::parse(&config.server_addr)? {
+        Endpoint::Tcp { addr } => {
+            let stream = tcp_connect(addr).await?;
+            match &config.tls {
+                Some(tls) => Ok(Box::new(tls_connect(tls, addr, stream).await?)),
+                None => Ok(Box::new(stream)),
+            }
+        }
+        Endpoint::WebSocket { url, authority, tls: false } => {
+            let stream = tcp_connect(&authority).await?;
+            Ok(Box::new(websocket::connect(url, stream).await?))
+        }
+        Endpoint::WebSocket { url, authority, tls: true } => {
+            let stream = tcp_connect(&authority).await?;
+            let default_tls = TlsConfig::new();
+            let tls = config.tls.as_ref().unwrap_or(&default_tls);
+            let stream = tls_connect(tls, &authority, stream).await?;
+            Ok(Box::new(websocket::connect(url, stream).await?))
+        }
+    }
+}
+
+async fn tcp_connect(addr: &str) -> Result<TcpStream> {
+    let stream = TcpStream::connect(addr).await
+        .map_err(|e| Error::Connection(format!("Failed to connect: {}", e)))?;
+    debug!("TCP connection established");
+    Ok(stream)
+}
+
+async fn tls_connect(
+    tls: &TlsConfig,
+    addr: &str,
+    stream: TcpStream,
+) -> Result<tokio_rustls::client::TlsStream<TcpStream>> {
+    let server_name = tls.resolve_server_name(addr)?;
+    let stream = tls.connector()?.connect(server_name, stream).await
+        .map_err(|e| Error::Tls(format!("Handshake failed: {}", e)))?;
+    debug!("TLS session established");
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, ConnectOptions, PublishOptions, QoS};
+    use crate::server::{Server, ServerConfig};
+    use crate::tls::test_pki::TestPki;
+    use std::time::Duration;
+
+    /// Run a `Server` with a WebSocket listener, returning the listener's address
+    async fn start_websocket_server(configure: impl FnOnce(ServerConfig) -> ServerConfig) -> String {
+        let config = configure(ServerConfig::new("127.0.0.1:0").websocket("127.0.0.1:0"));
+        let mut server = Server::new(config);
+        server.bind().await.unwrap();
+        let websocket_addr = server.local_addrs()[1].to_string();
+        tokio::spawn(async move { server.start().await });
+        websocket_addr
+    }
+
+    async fn round_trip(config: ClientConfig) {
+        let mut client = Client::new(config.read_timeout(Duration::from_secs(5)))
+            .connect(ConnectOptions::new("ws_client"))
+            .await
+            .unwrap();
+        client.subscribe("browser/#", QoS::AtLeastOnce).await.unwrap();
+
+        // Spans several reads and WebSocket messages in both directions
+        let payload = "x".repeat(64 * 1024);
+        client.publish(PublishOptions::new("browser/chart", payload.as_str()).qos(QoS::AtLeastOnce)).await.unwrap();
+        let message = tokio::time::timeout(Duration::from_secs(5), client.recv())
+            .await
+            .expect("Timed out waiting for message")
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.topic, "browser/chart");
+        assert_eq!(message.payload.len(), payload.len());
+        client.disconnect().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_mqtt_over_websocket() {
+        let addr = start_websocket_server(|config| config).await;
+        round_trip(ClientConfig::new(format!("ws://{}/mqtt", addr))).await;
+    }
+
+    #[tokio::test]
+    async fn test_mqtt_over_secure_websocket() {
+        let pki = TestPki::generate("wss");
+        let tls = crate::server::TlsConfig::new(pki.path("server.pem"), pki.path("server.key"));
+        let addr = start_websocket_server(|config| config.tls(tls)).await;
+
+        let port = addr.rsplit_once(':').unwrap().1;
+        let config = ClientConfig::new(format!("wss://localhost:{}/mqtt", port))
+            .tls(TlsConfig::new().ca_file(pki.path("ca.pem")));
+        round_trip(config).await;
+    }
+
+    #[test]
+    fn test_endpoint_parsing() {
+        assert_eq!(Endpoint::parse("localhost:1883").unwrap(), Endpoint::Tcp { addr: "localhost:1883" });
+        assert_eq!(Endpoint::parse("ws://broker:8080/mqtt").unwrap(), Endpoint::WebSocket {
+            url: "ws://broker:8080/mqtt",
+            authority: "broker:8080".to_string(),
+            tls: false,
+        });
+        assert_eq!(Endpoint::parse("wss://broker.example.com/mqtt").unwrap(), Endpoint::WebSocket {
+            url: "wss://broker.example.com/mqtt",
+            authority: "broker.example.com:443".to_string(),
+            tls: true,
+        });
+        assert_eq!(Endpoint::parse("ws://[::1]").unwrap(), Endpoint::WebSocket {
+            url: "ws://[::1]",
+            authority: "[::1]:80".to_string(),
+            tls: false,
+        });
+        assert!(Endpoint::parse("ws:///mqtt").is_err());
+    }
+}
@@ -8,7 +8,10 @@ use crate::types::{PacketPayload, ConnectPacket, ConnAckPacket, ConnectReturnCod
 use bytes::{Buf, BufMut, BytesMut};
 
 use super::utils::{encode_string, encode_bytes, decode_string, decode_bytes};
-use super::properties::{encode_connect_properties, decode_connect_properties, encode_connack_properties, decode_connack_properties};
+use super::properties::{
+    encode_connect_properties, decode_connect_properties, encode_connack_properties, decode_connack_properties,
+    encode_will_properties, decode_will_properties,
+};
 
 /// Encode Connect packet payload
 pub fn encode_connect(connect: &ConnectPacket, buf: &mut BytesMut, protocol_version: u8) -> Result<()> {
@@ -41,11 +44,25 @@ pub fn encode_connect(connect: &ConnectPacket, buf: &mut BytesMut, protocol_vers
     // Keep alive
     buf.put_u16(connect.keep_alive);
     
+    // MQTT 5.0 properties close the variable header
+    if protocol_version == 5 {
+        match connect.properties {
+            Some(ref properties) => encode_connect_properties(properties, buf)?,
+            None => buf.put_u8(0),
+        }
+    }
+    
     // Client ID
     encode_string(&connect.client_id, buf)?;
     
-    // Will topic and message
+    // Will properties, topic and message
     if connect.will_flag {
+        if protocol_version == 5 {
+            match connect.will_properties {
+                Some(ref properties) => encode_will_properties(properties, buf)?,
+                None => buf.put_u8(0),
+            }
+        }
         if let Some(ref will_topic) = connect.will_topic {
             encode_string(will_topic, buf)?;
         }
@@ -66,13 +83,6 @@ pub fn encode_connect(connect: &ConnectPacket, buf: &mut BytesMut, protocol_vers
         }
     }
     
-    // MQTT 5.0 properties
-    if protocol_version == 5 {
-        if let Some(ref properties) = connect.properties {
-            encode_connect_properties(properties, buf)?;
-        }
-    }
-    
     Ok(())
 }
 
@@ -96,10 +106,23 @@ pub fn decode_connect(buf: &mut BytesMut, protocol_version: u8) -> Result<Packet
     // Keep alive
     let keep_alive = buf.get_u16();
     
+    // MQTT 5.0 properties
+    let properties = if protocol_version == 5 {
+        Some(decode_connect_properties(buf)?)
+    } else {
+        None
+    };
+    
     // Client ID
     let client_id = decode_string(buf)?;
     
-    // Will topic and message
+    // Will properties, topic and message
+    let will_properties = if will_flag && protocol_version == 5 {
+        Some(decode_will_properties(buf)?)
+    } else {
+        None
+    };
+    
     let will_topic = if will_flag {
         Some(decode_string(buf)?)
     } else {
@@ -127,13 +150,6 @@ pub fn decode_connect(buf: &mut BytesMut, protocol_version: u8) -> Result<Packet
         None
     };
     
-    // MQTT 5.0 properties
-    let properties = if protocol_version == 5 {
-        Some(decode_connect_properties(buf)?)
-    } else {
-        None
-    };
-    
     let connect = ConnectPacket {
         protocol_name,
         protocol_version: protocol_version_decoded,
@@ -147,6 +163,7 @@ pub fn decode_connect(buf: &mut BytesMut, protocol_version: u8) -> Result<Packet
         client_id,
         will_topic,
         will_message,
+        will_properties,
         username,
         password,
         properties,
@@ -169,8 +186,9 @@ pub fn encode_connack(connack: &ConnAckPacket, buf: &mut BytesMut, protocol_vers
     
     // MQTT 5.0 properties
     if protocol_version == 5 {
-        if let Some(ref properties) = connack.properties {
-            encode_connack_properties(properties, buf)?;
+        match connack.properties {
+            Some(ref properties) => encode_connack_properties(properties, buf)?,
+            None => buf.put_u8(0),
         }
     }
     
@@ -8,7 +8,8 @@ use crate::error::{Error, Result};
 use crate::types::{Packet, PacketType, PacketHeader, PacketPayload, DisconnectPacket, AuthPacket};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 
-use super::utils::decode_remaining_length;
+use super::utils::{decode_remaining_length, peek_fixed_header};
+use super::properties::{encode_disconnect_properties, decode_disconnect_properties, encode_auth_properties, decode_auth_properties};
 use super::connect::{encode_connect, decode_connect, encode_connack, decode_connack};
 use super::publish::{encode_publish, decode_publish, encode_puback, decode_puback, encode_pubrec, decode_pubrec, encode_pubrel, decode_pubrel, encode_pubcomp, decode_pubcomp};
 use super::subscribe::{encode_subscribe, decode_subscribe, encode_suback, decode_suback, encode_unsubscribe, decode_unsubscribe, encode_unsuback, decode_unsuback};
@@ -70,6 +71,15 @@ impl MqttCodec {
 
         log::debug!("Decoding packet, buffer size: {}", buf.len());
 
+        // Leave the buffer untouched until the whole packet has arrived
+        let Some((header_length, remaining_length)) = peek_fixed_header(buf)? else {
+            return Ok(None);
+        };
+        if buf.len() < header_length + remaining_length {
+            log::debug!("Insufficient data: need {}, have {}", header_length + remaining_length, buf.len());
+            return Ok(None);
+        }
+
         // Read fixed header
         let first_byte = buf.get_u8();
         let packet_type = PacketType::from_u8(first_byte >> 4)
@@ -81,16 +91,8 @@ impl MqttCodec {
 
         log::debug!("Packet type: {:?}, dup: {}, qos: {}, retain: {}", packet_type, dup, qos, retain);
 
-        // Read remaining length
         let remaining_length = decode_remaining_length(buf)?;
         log::debug!("Remaining length: {}", remaining_length);
-        
-        if buf.len() < remaining_length {
-            log::debug!("Insufficient data: need {}, have {}", remaining_length, buf.len());
-            return Ok(None);
-        }
-        
-        log::debug!("Buffer after remaining length: {:?}", buf);
 
         let header = PacketHeader {
             packet_type,
@@ -189,32 +191,79 @@ impl MqttCodec {
     }
 
     /// Encode Disconnect packet payload
-    fn encode_disconnect(&self, _disconnect: &DisconnectPacket, _buf: &mut BytesMut) -> Result<()> {
-        // DISCONNECT packet has no payload in MQTT 3.1.1
-        // TODO: Implement MQTT 5.0 disconnect properties
+    ///
+    /// A normal disconnection without properties is sent as an empty packet,
+    /// which is also all MQTT 3.1.1 allows.
+    fn encode_disconnect(&self, disconnect: &DisconnectPacket, buf: &mut BytesMut) -> Result<()> {
+        if self.protocol_version != 5 {
+            return Ok(());
+        }
+
+        let reason_code = disconnect.reason_code.unwrap_or(0);
+        match disconnect.properties {
+            Some(ref properties) => {
+                buf.put_u8(reason_code);
+                encode_disconnect_properties(properties, buf)?;
+            }
+            None if reason_code != 0 => buf.put_u8(reason_code),
+            None => {}
+        }
         Ok(())
     }
 
     /// Decode Disconnect packet payload
-    fn decode_disconnect(&self, _buf: &mut BytesMut) -> Result<PacketPayload> {
-        // DISCONNECT packet has no payload in MQTT 3.1.1
-        // TODO: Implement MQTT 5.0 disconnect properties
-        Ok(PacketPayload::Disconnect(DisconnectPacket {
+    fn decode_disconnect(&self, buf: &mut BytesMut) -> Result<PacketPayload> {
+        let mut disconnect = DisconnectPacket {
             reason_code: None,
             properties: None,
-        }))
+        };
+
+        if self.protocol_version == 5 && buf.has_remaining() {
+            disconnect.reason_code = Some(buf.get_u8());
+            if buf.has_remaining() {
+                disconnect.properties = Some(decode_disconnect_properties(buf)?)
+                    .filter(|properties| !properties.is_empty());
+            }
+        }
+        Ok(PacketPayload::Disconnect(disconnect))
     }
 
     /// Encode Auth packet payload
-    fn encode_auth(&self, _auth: &AuthPacket, _buf: &mut BytesMut) -> Result<()> {
-        // TODO: Implement auth encoding
-        Ok(())
+    ///
+    /// A successful AUTH without properties is sent as an empty packet.
+    fn encode_auth(&self, auth: &AuthPacket, buf: &mut BytesMut) -> Result<()> {
+        if auth.reason_code == 0 && auth.properties.is_none() {
+            return Ok(());
+        }
+
+        buf.put_u8(auth.reason_code);
+        match auth.properties {
+            Some(ref properties) => encode_auth_properties(properties, buf),
+            None => {
+                buf.put_u8(0);
+                Ok(())
+            }
+        }
     }
 
     /// Decode Auth packet payload
-    fn decode_auth(&self, _buf: &mut BytesMut) -> Result<PacketPayload> {
-        // TODO: Implement auth decoding
-        Err(Error::Protocol("Auth not implemented".to_string()))
+    fn decode_auth(&self, buf: &mut BytesMut) -> Result<PacketPayload> {
+        if self.protocol_version != 5 {
+            return Err(Error::Protocol("AUTH packets require MQTT 5.0".to_string()));
+        }
+
+        let mut auth = AuthPacket {
+            reason_code: 0,
+            properties: None,
+        };
+        if buf.has_remaining() {
+            auth.reason_code = buf.get_u8();
+            if buf.has_remaining() {
+                auth.properties = Some(decode_auth_properties(buf)?)
+                    .filter(|properties| !properties.is_empty());
+            }
+        }
+        Ok(PacketPayload::Auth(auth))
     }
 }
 
@@ -246,6 +295,7 @@ mod tests {
             client_id: "test_client".to_string(),
             will_topic: None,
             will_message: None,
+            will_properties: None,
             username: None,
             password: None,
             properties: None,
@@ -271,14 +321,14 @@ mod tests {
         let decoded = codec.decode(&mut buf).unwrap().unwrap();
         
         assert_eq!(decoded.header.packet_type, PacketType::Connect);
-        assert_eq!(decoded.header.dup, false);
+        assert!(!decoded.header.dup);
         assert_eq!(decoded.header.qos, 0);
-        assert_eq!(decoded.header.retain, false);
+        assert!(!decoded.header.retain);
         
         if let PacketPayload::Connect(decoded_connect) = decoded.payload {
             assert_eq!(decoded_connect.protocol_name, "MQTT");
             assert_eq!(decoded_connect.protocol_version, 4);
-            assert_eq!(decoded_connect.clean_session, true);
+            assert!(decoded_connect.clean_session);
             assert_eq!(decoded_connect.client_id, "test_client");
             assert_eq!(decoded_connect.keep_alive, 60);
         } else {
@@ -318,13 +368,295 @@ mod tests {
         assert_eq!(decoded.header.packet_type, PacketType::ConnAck);
         
         if let PacketPayload::ConnAck(decoded_connack) = decoded.payload {
-            assert_eq!(decoded_connack.session_present, false);
+            assert!(!decoded_connack.session_present);
             assert_eq!(decoded_connack.return_code, ConnectReturnCode::Accepted);
         } else {
             panic!("Expected ConnAck payload");
         }
     }
 
+    #[test]
+    fn test_encode_decode_v5_connect_and_connack() {
+        let codec = MqttCodec::new(5);
+
+        let connect = ConnectPacket {
+            protocol_name: "MQTT".to_string(),
+            protocol_version: 5,
+            clean_session: true,
+            will_flag: false,
+            will_qos: 0,
+            will_retain: false,
+            password_flag: false,
+            username_flag: true,
+            keep_alive: 30,
+            client_id: "v5_client".to_string(),
+            will_topic: None,
+            will_message: None,
+            will_properties: None,
+            username: Some("user".to_string()),
+            password: None,
+            properties: Some(ConnectProperties { session_expiry_interval: Some(120), ..Default::default() }),
+        };
+        let packet = Packet {
+            header: PacketHeader { packet_type: PacketType::Connect, dup: false, qos: 0, retain: false, remaining_length: 0 },
+            payload: PacketPayload::Connect(connect),
+        };
+        let encoded = codec.encode(&packet).unwrap();
+
+        // Properties follow the keep alive field, ahead of the client ID
+        let variable_header_end = 2 + 6 + 1 + 1 + 2;
+        assert_eq!(&encoded[variable_header_end..variable_header_end + 6], &[5, 0x11, 0, 0, 0, 120]);
+
+        let mut buf = BytesMut::from(encoded.as_ref());
+        match codec.decode(&mut buf).unwrap().unwrap().payload {
+            PacketPayload::Connect(decoded) => {
+                assert_eq!(decoded.client_id, "v5_client");
+                assert_eq!(decoded.username.as_deref(), Some("user"));
+                assert_eq!(decoded.properties.unwrap().session_expiry_interval, Some(120));
+            }
+            other => panic!("Expected Connect payload, got {:?}", other),
+        }
+
+        // Packets without properties still carry an empty property length
+        let connack = Packet {
+            header: PacketHeader { packet_type: PacketType::ConnAck, dup: false, qos: 0, retain: false, remaining_length: 0 },
+            payload: PacketPayload::ConnAck(ConnAckPacket {
+                session_present: true,
+                return_code: ConnectReturnCode::Accepted,
+                properties: None,
+            }),
+        };
+        let encoded = codec.encode(&connack).unwrap();
+        assert_eq!(encoded.as_ref(), &[0x20, 3, 1, 0, 0]);
+        let mut buf = BytesMut::from(encoded.as_ref());
+        match codec.decode(&mut buf).unwrap().unwrap().payload {
+            PacketPayload::ConnAck(decoded) => assert!(decoded.session_present),
+            other => panic!("Expected ConnAck payload, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_encode_decode_v5_connect_with_will_properties() {
+        let codec = MqttCodec::new(5);
+
+        let connect = ConnectPacket {
+            protocol_name: "MQTT".to_string(),
+            protocol_version: 5,
+            clean_session: true,
+            will_flag: true,
+            will_qos: 1,
+            will_retain: true,
+            password_flag: false,
+            username_flag: false,
+            keep_alive: 30,
+            client_id: "c".to_string(),
+            will_topic: Some("w".to_string()),
+            will_message: Some(Bytes::from("bye")),
+            will_properties: Some(WillProperties::new().will_delay_interval(5)),
+            username: None,
+            password: None,
+            properties: None,
+        };
+        let packet = Packet {
+            header: PacketHeader { packet_type: PacketType::Connect, dup: false, qos: 0, retain: false, remaining_length: 0 },
+            payload: PacketPayload::Connect(connect),
+        };
+        let encoded = codec.encode(&packet).unwrap();
+
+        // Will properties sit between the client ID and the will topic
+        let payload_start = 2 + 6 + 1 + 1 + 2 + 1;
+        assert_eq!(&encoded[payload_start..payload_start + 3], &[0, 1, b'c']);
+        assert_eq!(&encoded[payload_start + 3..payload_start + 9], &[5, 0x18, 0, 0, 0, 5]);
+        assert_eq!(&encoded[payload_start + 9..payload_start + 12], &[0, 1, b'w']);
+
+        let mut buf = BytesMut::from(encoded.as_ref());
+        match codec.decode(&mut buf).unwrap().unwrap().payload {
+            PacketPayload::Connect(decoded) => {
+                assert_eq!(decoded.will_topic.as_deref(), Some("w"));
+                assert_eq!(decoded.will_message, Some(Bytes::from("bye")));
+                assert_eq!(decoded.will_properties.unwrap().will_delay_interval, Some(5));
+            }
+            other => panic!("Expected Connect payload, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_encode_decode_v5_publish_with_properties() {
+        let codec = MqttCodec::new(5);
+        let header = PacketHeader { packet_type: PacketType::Publish, dup: false, qos: 1, retain: false, remaining_length: 0 };
+
+        let publish = Packet {
+            header: header.clone(),
+            payload: PacketPayload::Publish(PublishPacket {
+                topic_name: "a/b".to_string(),
+                packet_id: Some(10),
+                payload: Bytes::from("hi"),
+                properties: Some(PublishProperties::new()
+                    .message_expiry_interval(3600)
+                    .content_type("json".to_string())),
+            }),
+        };
+        let encoded = codec.encode(&publish).unwrap();
+        let expected: &[u8] = &[
+            0x32, 22,
+            0, 3, b'a', b'/', b'b',
+            0, 10,
+            12, 0x02, 0, 0, 0x0E, 0x10, 0x03, 0, 4, b'j', b's', b'o', b'n',
+            b'h', b'i',
+        ];
+        assert_eq!(encoded.as_ref(), expected);
+
+        let mut buf = BytesMut::from(expected);
+        match codec.decode(&mut buf).unwrap().unwrap().payload {
+            PacketPayload::Publish(decoded) => {
+                assert_eq!(decoded.topic_name, "a/b");
+                assert_eq!(decoded.packet_id, Some(10));
+                assert_eq!(decoded.payload, Bytes::from("hi"));
+                let properties = decoded.properties.unwrap();
+                assert_eq!(properties.message_expiry_interval, Some(3600));
+                assert_eq!(properties.content_type.as_deref(), Some("json"));
+            }
+            other => panic!("Expected Publish payload, got {:?}", other),
+        }
+
+        // Without properties the payload still follows a zero property length
+        let publish = Packet {
+            header: PacketHeader { qos: 0, ..header },
+            payload: PacketPayload::Publish(PublishPacket {
+                topic_name: "a".to_string(),
+                packet_id: None,
+                payload: Bytes::from("hi"),
+                properties: None,
+            }),
+        };
+        let encoded = codec.encode(&publish).unwrap();
+        assert_eq!(encoded.as_ref(), &[0x30, 6, 0, 1, b'a', 0, b'h', b'i']);
+        let mut buf = BytesMut::from(encoded.as_ref());
+        match codec.decode(&mut buf).unwrap().unwrap().payload {
+            PacketPayload::Publish(decoded) => {
+                assert_eq!(decoded.payload, Bytes::from("hi"));
+                assert!(decoded.properties.is_none());
+            }
+            other => panic!("Expected Publish payload, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_encode_decode_v5_subscribe_with_options() {
+        let codec = MqttCodec::new(5);
+        let subscribe = Packet {
+            header: PacketHeader { packet_type: PacketType::Subscribe, dup: false, qos: 1, retain: false, remaining_length: 0 },
+            payload: PacketPayload::Subscribe(SubscribePacket {
+                packet_id: 7,
+                topic_filters: vec![
+                    TopicFilter { topic: "a/#".to_string(), qos: 1, no_local: true, retain_as_published: false, retain_handling: 2 },
+                    TopicFilter { topic: "b".to_string(), qos: 2, no_local: false, retain_as_published: true, retain_handling: 0 },
+                ],
+                properties: Some(SubscribeProperties::new().subscription_identifier(200)),
+            }),
+        };
+
+        let encoded = codec.encode(&subscribe).unwrap();
+        let expected: &[u8] = &[
+            0x82, 16,
+            0, 7,
+            3, 0x0B, 0xC8, 0x01,
+            0, 3, b'a', b'/', b'#', 0x25,
+            0, 1, b'b', 0x0A,
+        ];
+        assert_eq!(encoded.as_ref(), expected);
+
+        let mut buf = BytesMut::from(expected);
+        match codec.decode(&mut buf).unwrap().unwrap().payload {
+            PacketPayload::Subscribe(decoded) => {
+                assert_eq!(decoded.packet_id, 7);
+                assert_eq!(decoded.properties.unwrap().subscription_identifier, Some(200));
+                let first = &decoded.topic_filters[0];
+                assert_eq!((first.topic.as_str(), first.qos, first.no_local, first.retain_as_published, first.retain_handling), ("a/#", 1, true, false, 2));
+                let second = &decoded.topic_filters[1];
+                assert_eq!((second.topic.as_str(), second.qos, second.no_local, second.retain_as_published, second.retain_handling), ("b", 2, false, true, 0));
+            }
+            other => panic!("Expected Subscribe payload, got {:?}", other),
+        }
+
+        // Reserved option bits make the packet malformed
+        let mut buf = BytesMut::from(&[0x82, 7, 0, 7, 0, 0, 1, b'a', 0xC0][..]);
+        assert!(codec.decode(&mut buf).is_err());
+
+        // MQTT 3.1.1 only carries the requested QoS
+        let encoded = MqttCodec::new(4).encode(&subscribe).unwrap();
+        assert_eq!(encoded.as_ref(), &[0x82, 12, 0, 7, 0, 3, b'a', b'/', b'#', 1, 0, 1, b'b', 2]);
+    }
+
+    #[test]
+    fn test_encode_decode_v5_subscription_acks() {
+        let codec = MqttCodec::new(5);
+
+        let suback = Packet {
+            header: PacketHeader { packet_type: PacketType::SubAck, dup: false, qos: 0, retain: false, remaining_length: 0 },
+            payload: PacketPayload::SubAck(SubAckPacket {
+                packet_id: 7,
+                return_codes: vec![1, 0x87],
+                properties: Some(SubAckProperties::new().reason_string("no".to_string())),
+            }),
+        };
+        let encoded = codec.encode(&suback).unwrap();
+        let expected: &[u8] = &[0x90, 10, 0, 7, 5, 0x1F, 0, 2, b'n', b'o', 1, 0x87];
+        assert_eq!(encoded.as_ref(), expected);
+        let mut buf = BytesMut::from(expected);
+        match codec.decode(&mut buf).unwrap().unwrap().payload {
+            PacketPayload::SubAck(decoded) => {
+                assert_eq!(decoded.return_codes, vec![1, 0x87]);
+                assert_eq!(decoded.properties.unwrap().reason_string.as_deref(), Some("no"));
+            }
+            other => panic!("Expected SubAck payload, got {:?}", other),
+        }
+
+        let unsubscribe = Packet {
+            header: PacketHeader { packet_type: PacketType::Unsubscribe, dup: false, qos: 1, retain: false, remaining_length: 0 },
+            payload: PacketPayload::Unsubscribe(UnsubscribePacket {
+                packet_id: 8,
+                topic_filters: vec!["a".to_string()],
+                properties: Some(UnsubscribeProperties::new().user_property("k".to_string(), "v".to_string())),
+            }),
+        };
+        let encoded = codec.encode(&unsubscribe).unwrap();
+        let expected: &[u8] = &[0xA2, 13, 0, 8, 7, 0x26, 0, 1, b'k', 0, 1, b'v', 0, 1, b'a'];
+        assert_eq!(encoded.as_ref(), expected);
+        let mut buf = BytesMut::from(expected);
+        match codec.decode(&mut buf).unwrap().unwrap().payload {
+            PacketPayload::Unsubscribe(decoded) => {
+                assert_eq!(decoded.topic_filters, vec!["a".to_string()]);
+                assert_eq!(decoded.properties.unwrap().user_properties.get("k").map(String::as_str), Some("v"));
+            }
+            other => panic!("Expected Unsubscribe payload, got {:?}", other),
+        }
+
+        let unsuback = Packet {
+            header: PacketHeader { packet_type: PacketType::UnsubAck, dup: false, qos: 0, retain: false, remaining_length: 0 },
+            payload: PacketPayload::UnsubAck(UnsubAckPacket {
+                packet_id: 8,
+                reason_codes: vec![0x00, 0x11],
+                properties: None,
+            }),
+        };
+        let encoded = codec.encode(&unsuback).unwrap();
+        let expected: &[u8] = &[0xB0, 5, 0, 8, 0, 0x00, 0x11];
+        assert_eq!(encoded.as_ref(), expected);
+        let mut buf = BytesMut::from(expected);
+        match codec.decode(&mut buf).unwrap().unwrap().payload {
+            PacketPayload::UnsubAck(decoded) => {
+                assert_eq!(decoded.reason_codes, vec![0x00, 0x11]);
+                assert!(decoded.properties.is_none());
+            }
+            other => panic!("Expected UnsubAck payload, got {:?}", other),
+        }
+
+        // MQTT 3.1.1 acknowledgements carry no properties or UNSUBACK reason codes
+        assert_eq!(MqttCodec::new(4).encode(&suback).unwrap().as_ref(), &[0x90, 4, 0, 7, 1, 0x87]);
+        assert_eq!(MqttCodec::new(4).encode(&unsuback).unwrap().as_ref(), &[0xB0, 2, 0, 8]);
+    }
+
     #[test]
     fn test_encode_decode_ping_packets() {
         let codec = MqttCodec::new(4);
@@ -381,4 +713,116 @@ mod tests {
         assert!(result.is_ok());
         assert!(result.unwrap().is_none()); // Should return None for incomplete packet
     }
+
+    #[test]
+    fn test_packet_arriving_in_pieces() {
+        let codec = MqttCodec::new(4);
+        let packet = Packet {
+            header: PacketHeader { packet_type: PacketType::Publish, dup: false, qos: 0, retain: false, remaining_length: 0 },
+            payload: PacketPayload::Publish(PublishPacket {
+                topic_name: "big/payload".to_string(),
+                packet_id: None,
+                payload: Bytes::from(vec![7u8; 20_000]),
+                properties: None,
+            }),
+        };
+        let encoded = codec.encode(&packet).unwrap();
+
+        // Feed the packet a few bytes at a time; nothing is consumed until it is complete
+        let mut buf = BytesMut::new();
+        for chunk in encoded.chunks(1000) {
+            assert!(codec.decode(&mut buf).unwrap().is_none());
+            assert_eq!(buf.len() % 1000, 0);
+            buf.extend_from_slice(chunk);
+        }
+        let decoded = codec.decode(&mut buf).unwrap().unwrap();
+        assert!(buf.is_empty());
+        match decoded.payload {
+            PacketPayload::Publish(publish) => assert_eq!(publish.payload.len(), 20_000),
+            other => panic!("Expected PUBLISH, got {:?}", other),
+        }
+    }
+
+    fn disconnect(reason_code: Option<u8>, properties: Option<DisconnectProperties>) -> Packet {
+        Packet {
+            header: PacketHeader { packet_type: PacketType::Disconnect, dup: false, qos: 0, retain: false, remaining_length: 0 },
+            payload: PacketPayload::Disconnect(DisconnectPacket { reason_code, properties }),
+        }
+    }
+
+    #[test]
+    fn test_encode_decode_v5_disconnect() {
+        let codec = MqttCodec::new(5);
+
+        // Normal disconnection may omit the reason code entirely
+        assert_eq!(codec.encode(&disconnect(None, None)).unwrap().as_ref(), &[0xE0, 0]);
+        assert_eq!(codec.encode(&disconnect(Some(0x8E), None)).unwrap().as_ref(), &[0xE0, 1, 0x8E]);
+
+        let properties = DisconnectProperties::new()
+            .session_expiry_interval(30)
+            .reason_string("bye".to_string());
+        let encoded = codec.encode(&disconnect(Some(0x04), Some(properties))).unwrap();
+        let expected: &[u8] = &[0xE0, 13, 0x04, 11, 0x11, 0, 0, 0, 30, 0x1F, 0, 3, b'b', b'y', b'e'];
+        assert_eq!(encoded.as_ref(), expected);
+
+        let mut buf = BytesMut::from(expected);
+        match codec.decode(&mut buf).unwrap().unwrap().payload {
+            PacketPayload::Disconnect(decoded) => {
+                assert_eq!(decoded.reason_code, Some(0x04));
+                let properties = decoded.properties.unwrap();
+                assert_eq!(properties.session_expiry_interval, Some(30));
+                assert_eq!(properties.reason_string.as_deref(), Some("bye"));
+            }
+            other => panic!("Expected Disconnect payload, got {:?}", other),
+        }
+
+        // A remaining length of zero means Normal disconnection
+        let mut buf = BytesMut::from(&[0xE0, 0][..]);
+        match codec.decode(&mut buf).unwrap().unwrap().payload {
+            PacketPayload::Disconnect(decoded) => {
+                assert_eq!(decoded.reason_code.unwrap_or(0), 0);
+                assert!(decoded.properties.is_none());
+            }
+            other => panic!("Expected Disconnect payload, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_v4_disconnect_has_no_body() {
+        let codec = MqttCodec::new(4);
+        let encoded = codec.encode(&disconnect(Some(0x8E), Some(DisconnectProperties::new().reason_string("x".to_string())))).unwrap();
+        assert_eq!(encoded.as_ref(), &[0xE0, 0]);
+    }
+
+    #[test]
+    fn test_encode_decode_auth() {
+        let codec = MqttCodec::new(5);
+        let auth = Packet {
+            header: PacketHeader { packet_type: PacketType::Auth, dup: false, qos: 0, retain: false, remaining_length: 0 },
+            payload: PacketPayload::Auth(AuthPacket {
+                reason_code: 0x18,
+                properties: Some(AuthProperties::new()
+                    .authentication_method("SCRAM".to_string())
+                    .authentication_data(Bytes::from_static(&[1, 2]))),
+            }),
+        };
+        let encoded = codec.encode(&auth).unwrap();
+        let expected: &[u8] = &[0xF0, 15, 0x18, 13, 0x15, 0, 5, b'S', b'C', b'R', b'A', b'M', 0x16, 0, 2, 1, 2];
+        assert_eq!(encoded.as_ref(), expected);
+
+        let mut buf = BytesMut::from(expected);
+        match codec.decode(&mut buf).unwrap().unwrap().payload {
+            PacketPayload::Auth(decoded) => {
+                assert_eq!(decoded.reason_code, 0x18);
+                let properties = decoded.properties.unwrap();
+                assert_eq!(properties.authentication_method.as_deref(), Some("SCRAM"));
+                assert_eq!(properties.authentication_data, Some(Bytes::from_static(&[1, 2])));
+            }
+            other => panic!("Expected Auth payload, got {:?}", other),
+        }
+
+        // AUTH does not exist before MQTT 5.0
+        let mut buf = BytesMut::from(expected);
+        assert!(MqttCodec::new(4).decode(&mut buf).is_err());
+    }
 }
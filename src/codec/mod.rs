@@ -54,6 +54,7 @@
 //!             client_id: "test_client".to_string(),
 //!             will_topic: None,
 //!             will_message: None,
+//!             will_properties: None,
 //!             username: None,
 //!             password: None,
 //!             properties: None,
@@ -1,83 +1,82 @@
 //! # MQTT 5.0 Properties Codec
 //! 
 //! This module handles the encoding and decoding of MQTT 5.0 properties
-//! for various packet types including Connect, ConnAck, and Publish packets.
+//! for various packet types including Connect, ConnAck, Publish, the
+//! subscription packets, Disconnect and Auth.
 
-use crate::error::Result;
-use crate::types::{ConnectProperties, ConnAckProperties, PublishProperties};
+use crate::error::{Error, Result};
+use crate::types::{
+    ConnectProperties, ConnAckProperties, PublishProperties, WillProperties,
+    SubscribeProperties, SubAckProperties, UnsubscribeProperties, UnsubAckProperties,
+    DisconnectProperties, AuthProperties,
+};
 use bytes::{Buf, BufMut, BytesMut};
 use std::collections::HashMap;
 
-use super::utils::{encode_string, encode_bytes, decode_string, decode_bytes};
+use super::utils::{
+    encode_string, encode_bytes, decode_string, decode_bytes, encode_remaining_length, decode_remaining_length,
+};
 
 /// Encode Connect packet properties
 pub fn encode_connect_properties(properties: &ConnectProperties, buf: &mut BytesMut) -> Result<()> {
-    // Properties length (will be calculated)
-    let properties_start = buf.len();
-    buf.put_u8(0); // Placeholder for properties length
+    let mut props = BytesMut::new();
     
     // Session Expiry Interval (0x11)
     if let Some(session_expiry) = properties.session_expiry_interval {
-        buf.put_u8(0x11);
-        buf.put_u32(session_expiry);
+        props.put_u8(0x11);
+        props.put_u32(session_expiry);
     }
     
     // Receive Maximum (0x21)
     if let Some(receive_max) = properties.receive_maximum {
-        buf.put_u8(0x21);
-        buf.put_u16(receive_max);
+        props.put_u8(0x21);
+        props.put_u16(receive_max);
     }
     
     // Maximum Packet Size (0x27)
     if let Some(max_packet_size) = properties.max_packet_size {
-        buf.put_u8(0x27);
-        buf.put_u32(max_packet_size);
+        props.put_u8(0x27);
+        props.put_u32(max_packet_size);
     }
     
     // Topic Alias Maximum (0x22)
     if let Some(topic_alias_max) = properties.topic_alias_maximum {
-        buf.put_u8(0x22);
-        buf.put_u16(topic_alias_max);
+        props.put_u8(0x22);
+        props.put_u16(topic_alias_max);
     }
     
     // Request Response Information (0x19)
     if let Some(request_response_info) = properties.request_response_information {
-        buf.put_u8(0x19);
-        buf.put_u8(if request_response_info { 1 } else { 0 });
+        props.put_u8(0x19);
+        props.put_u8(if request_response_info { 1 } else { 0 });
     }
     
     // Request Problem Information (0x17)
     if let Some(request_problem_info) = properties.request_problem_information {
-        buf.put_u8(0x17);
-        buf.put_u8(if request_problem_info { 1 } else { 0 });
+        props.put_u8(0x17);
+        props.put_u8(if request_problem_info { 1 } else { 0 });
     }
     
     // User Properties (0x26)
     for (key, value) in &properties.user_properties {
-        buf.put_u8(0x26);
-        encode_string(key, buf)?;
-        encode_string(value, buf)?;
+        props.put_u8(0x26);
+        encode_string(key, &mut props)?;
+        encode_string(value, &mut props)?;
     }
     
     // Authentication Method (0x15)
     if let Some(ref auth_method) = properties.authentication_method {
-        buf.put_u8(0x15);
-        encode_string(auth_method, buf)?;
+        props.put_u8(0x15);
+        encode_string(auth_method, &mut props)?;
     }
     
     // Authentication Data (0x16)
     if let Some(ref auth_data) = properties.authentication_data {
-        buf.put_u8(0x16);
-        encode_bytes(auth_data, buf)?;
+        props.put_u8(0x16);
+        encode_bytes(auth_data, &mut props)?;
     }
     
-    // Update properties length
-    let properties_len = buf.len() - properties_start - 1;
-    if properties_len > 0 {
-        buf[properties_start] = properties_len as u8;
-    }
-    
-    Ok(())
+    put_properties(&props, buf)
 }
 
 /// Decode Connect packet properties
@@ -94,14 +93,7 @@ pub fn decode_connect_properties(buf: &mut BytesMut) -> Result<ConnectProperties
         authentication_data: None,
     };
     
-    // Properties length
-    let properties_length = buf.get_u8() as usize;
-    if properties_length == 0 {
-        return Ok(properties);
-    }
-    
-    let _properties_end = buf.len() - properties_length;
-    let mut properties_buf = buf.split_to(properties_length);
+    let mut properties_buf = take_properties(buf)?;
     
     while properties_buf.has_remaining() {
         let property_id = properties_buf.get_u8();
@@ -150,122 +142,208 @@ pub fn decode_connect_properties(buf: &mut BytesMut) -> Result<ConnectProperties
     Ok(properties)
 }
 
+/// Encode Will properties
+pub fn encode_will_properties(properties: &WillProperties, buf: &mut BytesMut) -> Result<()> {
+    let mut props = BytesMut::new();
+    
+    // Will Delay Interval (0x18)
+    if let Some(will_delay) = properties.will_delay_interval {
+        props.put_u8(0x18);
+        props.put_u32(will_delay);
+    }
+    
+    // Payload Format Indicator (0x01)
+    if let Some(payload_format) = properties.payload_format_indicator {
+        props.put_u8(0x01);
+        props.put_u8(payload_format);
+    }
+    
+    // Message Expiry Interval (0x02)
+    if let Some(message_expiry) = properties.message_expiry_interval {
+        props.put_u8(0x02);
+        props.put_u32(message_expiry);
+    }
+    
+    // Content Type (0x03)
+    if let Some(ref content_type) = properties.content_type {
+        props.put_u8(0x03);
+        encode_string(content_type, &mut props)?;
+    }
+    
+    // Response Topic (0x08)
+    if let Some(ref response_topic) = properties.response_topic {
+        props.put_u8(0x08);
+        encode_string(response_topic, &mut props)?;
+    }
+    
+    // Correlation Data (0x09)
+    if let Some(ref correlation_data) = properties.correlation_data {
+        props.put_u8(0x09);
+        encode_bytes(correlation_data, &mut props)?;
+    }
+    
+    // User Properties (0x26)
+    for (key, value) in &properties.user_properties {
+        props.put_u8(0x26);
+        encode_string(key, &mut props)?;
+        encode_string(value, &mut props)?;
+    }
+    
+    put_properties(&props, buf)
+}
+
+/// Decode Will properties
+pub fn decode_will_properties(buf: &mut BytesMut) -> Result<WillProperties> {
+    let mut properties = WillProperties::default();
+    
+    let mut properties_buf = take_properties(buf)?;
+    
+    while properties_buf.has_remaining() {
+        let property_id = properties_buf.get_u8();
+        
+        match property_id {
+            0x18 => { // Will Delay Interval
+                properties.will_delay_interval = Some(properties_buf.get_u32());
+            }
+            0x01 => { // Payload Format Indicator
+                properties.payload_format_indicator = Some(properties_buf.get_u8());
+            }
+            0x02 => { // Message Expiry Interval
+                properties.message_expiry_interval = Some(properties_buf.get_u32());
+            }
+            0x03 => { // Content Type
+                properties.content_type = Some(decode_string(&mut properties_buf)?);
+            }
+            0x08 => { // Response Topic
+                properties.response_topic = Some(decode_string(&mut properties_buf)?);
+            }
+            0x09 => { // Correlation Data
+                properties.correlation_data = Some(decode_bytes(&mut properties_buf)?);
+            }
+            0x26 => { // User Properties
+                let key = decode_string(&mut properties_buf)?;
+                let value = decode_string(&mut properties_buf)?;
+                properties.user_properties.insert(key, value);
+            }
+            _ => {
+                // Unknown property, skip the rest of the block
+                log::warn!("Unknown will property ID: 0x{:02x}", property_id);
+                break;
+            }
+        }
+    }
+    
+    Ok(properties)
+}
+
 /// Encode ConnAck packet properties
 pub fn encode_connack_properties(properties: &ConnAckProperties, buf: &mut BytesMut) -> Result<()> {
-    // Properties length (will be calculated)
-    let properties_start = buf.len();
-    buf.put_u8(0); // Placeholder for properties length
+    let mut props = BytesMut::new();
     
     // Session Expiry Interval (0x11)
     if let Some(session_expiry) = properties.session_expiry_interval {
-        buf.put_u8(0x11);
-        buf.put_u32(session_expiry);
+        props.put_u8(0x11);
+        props.put_u32(session_expiry);
     }
     
     // Receive Maximum (0x21)
     if let Some(receive_max) = properties.receive_maximum {
-        buf.put_u8(0x21);
-        buf.put_u16(receive_max);
+        props.put_u8(0x21);
+        props.put_u16(receive_max);
     }
     
     // Maximum QoS (0x24)
     if let Some(max_qos) = properties.max_qos {
-        buf.put_u8(0x24);
-        buf.put_u8(max_qos);
+        props.put_u8(0x24);
+        props.put_u8(max_qos);
     }
     
     // Retain Available (0x25)
     if let Some(retain_available) = properties.retain_available {
-        buf.put_u8(0x25);
-        buf.put_u8(if retain_available { 1 } else { 0 });
+        props.put_u8(0x25);
+        props.put_u8(if retain_available { 1 } else { 0 });
     }
     
     // Maximum Packet Size (0x27)
     if let Some(max_packet_size) = properties.max_packet_size {
-        buf.put_u8(0x27);
-        buf.put_u32(max_packet_size);
+        props.put_u8(0x27);
+        props.put_u32(max_packet_size);
     }
     
     // Assigned Client Identifier (0x12)
     if let Some(ref assigned_client_id) = properties.assigned_client_identifier {
-        buf.put_u8(0x12);
-        encode_string(assigned_client_id, buf)?;
+        props.put_u8(0x12);
+        encode_string(assigned_client_id, &mut props)?;
     }
     
     // Topic Alias Maximum (0x22)
     if let Some(topic_alias_max) = properties.topic_alias_maximum {
-        buf.put_u8(0x22);
-        buf.put_u16(topic_alias_max);
+        props.put_u8(0x22);
+        props.put_u16(topic_alias_max);
     }
     
     // Reason String (0x1F)
     if let Some(ref reason_string) = properties.reason_string {
-        buf.put_u8(0x1F);
-        encode_string(reason_string, buf)?;
+        props.put_u8(0x1F);
+        encode_string(reason_string, &mut props)?;
     }
     
     // User Properties (0x26)
     for (key, value) in &properties.user_properties {
-        buf.put_u8(0x26);
-        encode_string(key, buf)?;
-        encode_string(value, buf)?;
+        props.put_u8(0x26);
+        encode_string(key, &mut props)?;
+        encode_string(value, &mut props)?;
     }
     
     // Wildcard Subscription Available (0x28)
     if let Some(wildcard_sub_available) = properties.wildcard_subscription_available {
-        buf.put_u8(0x28);
-        buf.put_u8(if wildcard_sub_available { 1 } else { 0 });
+        props.put_u8(0x28);
+        props.put_u8(if wildcard_sub_available { 1 } else { 0 });
     }
     
     // Subscription Identifiers Available (0x29)
     if let Some(sub_id_available) = properties.subscription_identifiers_available {
-        buf.put_u8(0x29);
-        buf.put_u8(if sub_id_available { 1 } else { 0 });
+        props.put_u8(0x29);
+        props.put_u8(if sub_id_available { 1 } else { 0 });
     }
     
     // Shared Subscription Available (0x2A)
     if let Some(shared_sub_available) = properties.shared_subscription_available {
-        buf.put_u8(0x2A);
-        buf.put_u8(if shared_sub_available { 1 } else { 0 });
+        props.put_u8(0x2A);
+        props.put_u8(if shared_sub_available { 1 } else { 0 });
     }
     
     // Server Keep Alive (0x13)
     if let Some(server_keep_alive) = properties.server_keep_alive {
-        buf.put_u8(0x13);
-        buf.put_u16(server_keep_alive);
+        props.put_u8(0x13);
+        props.put_u16(server_keep_alive);
     }
     
     // Response Information (0x1A)
     if let Some(ref response_info) = properties.response_information {
-        buf.put_u8(0x1A);
-        encode_string(response_info, buf)?;
+        props.put_u8(0x1A);
+        encode_string(response_info, &mut props)?;
     }
     
     // Server Reference (0x1C)
     if let Some(ref server_ref) = properties.server_reference {
-        buf.put_u8(0x1C);
-        encode_string(server_ref, buf)?;
+        props.put_u8(0x1C);
+        encode_string(server_ref, &mut props)?;
     }
     
     // Authentication Method (0x15)
     if let Some(ref auth_method) = properties.authentication_method {
-        buf.put_u8(0x15);
-        encode_string(auth_method, buf)?;
+        props.put_u8(0x15);
+        encode_string(auth_method, &mut props)?;
     }
     
     // Authentication Data (0x16)
     if let Some(ref auth_data) = properties.authentication_data {
-        buf.put_u8(0x16);
-        encode_bytes(auth_data, buf)?;
-    }
-    
-    // Update properties length
-    let properties_len = buf.len() - properties_start - 1;
-    if properties_len > 0 {
-        buf[properties_start] = properties_len as u8;
+        props.put_u8(0x16);
+        encode_bytes(auth_data, &mut props)?;
     }
     
-    Ok(())
+    put_properties(&props, buf)
 }
 
 /// Decode ConnAck packet properties
@@ -290,20 +368,7 @@ pub fn decode_connack_properties(buf: &mut BytesMut) -> Result<ConnAckProperties
         authentication_data: None,
     };
     
-    // Properties length
-    let properties_length = buf.get_u8() as usize;
-    if properties_length == 0 {
-        return Ok(properties);
-    }
-    
-    // Check if we have enough bytes for the properties
-    if buf.len() < properties_length {
-        return Err(crate::error::Error::InvalidPacket(
-            format!("Insufficient bytes for ConnAck properties: need {}, have {}", properties_length, buf.len())
-        ));
-    }
-    
-    let mut properties_buf = buf.split_to(properties_length);
+    let mut properties_buf = take_properties(buf)?;
     
     while properties_buf.has_remaining() {
         let property_id = properties_buf.get_u8();
@@ -313,7 +378,7 @@ pub fn decode_connack_properties(buf: &mut BytesMut) -> Result<ConnAckProperties
                 if properties_buf.remaining() >= 4 {
                     properties.session_expiry_interval = Some(properties_buf.get_u32());
                 } else {
-                    return Err(crate::error::Error::InvalidPacket(
+                    return Err(Error::InvalidPacket(
                         "Insufficient bytes for Session Expiry Interval".to_string()
                     ));
                 }
@@ -322,7 +387,7 @@ pub fn decode_connack_properties(buf: &mut BytesMut) -> Result<ConnAckProperties
                 if properties_buf.remaining() >= 2 {
                     properties.receive_maximum = Some(properties_buf.get_u16());
                 } else {
-                    return Err(crate::error::Error::InvalidPacket(
+                    return Err(Error::InvalidPacket(
                         "Insufficient bytes for Receive Maximum".to_string()
                     ));
                 }
@@ -331,7 +396,7 @@ pub fn decode_connack_properties(buf: &mut BytesMut) -> Result<ConnAckProperties
                 if properties_buf.remaining() >= 1 {
                     properties.max_qos = Some(properties_buf.get_u8());
                 } else {
-                    return Err(crate::error::Error::InvalidPacket(
+                    return Err(Error::InvalidPacket(
                         "Insufficient bytes for Maximum QoS".to_string()
                     ));
                 }
@@ -340,7 +405,7 @@ pub fn decode_connack_properties(buf: &mut BytesMut) -> Result<ConnAckProperties
                 if properties_buf.remaining() >= 1 {
                     properties.retain_available = Some(properties_buf.get_u8() != 0);
                 } else {
-                    return Err(crate::error::Error::InvalidPacket(
+                    return Err(Error::InvalidPacket(
                         "Insufficient bytes for Retain Available".to_string()
                     ));
                 }
@@ -349,7 +414,7 @@ pub fn decode_connack_properties(buf: &mut BytesMut) -> Result<ConnAckProperties
                 if properties_buf.remaining() >= 4 {
                     properties.max_packet_size = Some(properties_buf.get_u32());
                 } else {
-                    return Err(crate::error::Error::InvalidPacket(
+                    return Err(Error::InvalidPacket(
                         "Insufficient bytes for Maximum Packet Size".to_string()
                     ));
                 }
@@ -361,7 +426,7 @@ pub fn decode_connack_properties(buf: &mut BytesMut) -> Result<ConnAckProperties
                 if properties_buf.remaining() >= 2 {
                     properties.topic_alias_maximum = Some(properties_buf.get_u16());
                 } else {
-                    return Err(crate::error::Error::InvalidPacket(
+                    return Err(Error::InvalidPacket(
                         "Insufficient bytes for Topic Alias Maximum".to_string()
                     ));
                 }
@@ -378,7 +443,7 @@ pub fn decode_connack_properties(buf: &mut BytesMut) -> Result<ConnAckProperties
                 if properties_buf.remaining() >= 1 {
                     properties.wildcard_subscription_available = Some(properties_buf.get_u8() != 0);
                 } else {
-                    return Err(crate::error::Error::InvalidPacket(
+                    return Err(Error::InvalidPacket(
                         "Insufficient bytes for Wildcard Subscription Available".to_string()
                     ));
                 }
@@ -387,7 +452,7 @@ pub fn decode_connack_properties(buf: &mut BytesMut) -> Result<ConnAckProperties
                 if properties_buf.remaining() >= 1 {
                     properties.subscription_identifiers_available = Some(properties_buf.get_u8() != 0);
                 } else {
-                    return Err(crate::error::Error::InvalidPacket(
+                    return Err(Error::InvalidPacket(
                         "Insufficient bytes for Subscription Identifiers Available".to_string()
                     ));
                 }
@@ -396,7 +461,7 @@ pub fn decode_connack_properties(buf: &mut BytesMut) -> Result<ConnAckProperties
                 if properties_buf.remaining() >= 1 {
                     properties.shared_subscription_available = Some(properties_buf.get_u8() != 0);
                 } else {
-                    return Err(crate::error::Error::InvalidPacket(
+                    return Err(Error::InvalidPacket(
                         "Insufficient bytes for Shared Subscription Available".to_string()
                     ));
                 }
@@ -405,7 +470,7 @@ pub fn decode_connack_properties(buf: &mut BytesMut) -> Result<ConnAckProperties
                 if properties_buf.remaining() >= 2 {
                     properties.server_keep_alive = Some(properties_buf.get_u16());
                 } else {
-                    return Err(crate::error::Error::InvalidPacket(
+                    return Err(Error::InvalidPacket(
                         "Insufficient bytes for Server Keep Alive".to_string()
                     ));
                 }
@@ -437,22 +502,496 @@ pub fn decode_connack_properties(buf: &mut BytesMut) -> Result<ConnAckProperties
 }
 
 /// Encode Publish packet properties
-pub fn encode_publish_properties(_properties: &PublishProperties, _buf: &mut BytesMut) -> Result<()> {
-    // TODO: Implement MQTT 5.0 publish properties encoding
-    Ok(())
+pub fn encode_publish_properties(properties: &PublishProperties, buf: &mut BytesMut) -> Result<()> {
+    let mut props = BytesMut::new();
+    
+    // Payload Format Indicator (0x01)
+    if let Some(payload_format) = properties.payload_format_indicator {
+        props.put_u8(0x01);
+        props.put_u8(payload_format);
+    }
+    
+    // Message Expiry Interval (0x02)
+    if let Some(message_expiry) = properties.message_expiry_interval {
+        props.put_u8(0x02);
+        props.put_u32(message_expiry);
+    }
+    
+    // Topic Alias (0x23)
+    if let Some(topic_alias) = properties.topic_alias {
+        props.put_u8(0x23);
+        props.put_u16(topic_alias);
+    }
+    
+    // Response Topic (0x08)
+    if let Some(ref response_topic) = properties.response_topic {
+        props.put_u8(0x08);
+        encode_string(response_topic, &mut props)?;
+    }
+    
+    // Correlation Data (0x09)
+    if let Some(ref correlation_data) = properties.correlation_data {
+        props.put_u8(0x09);
+        encode_bytes(correlation_data, &mut props)?;
+    }
+    
+    // User Properties (0x26)
+    for (key, value) in &properties.user_properties {
+        props.put_u8(0x26);
+        encode_string(key, &mut props)?;
+        encode_string(value, &mut props)?;
+    }
+    
+    // Subscription Identifier (0x0B), a Variable Byte Integer
+    if let Some(subscription_id) = properties.subscription_identifier {
+        props.put_u8(0x0B);
+        encode_remaining_length(subscription_id as usize, &mut props)?;
+    }
+    
+    // Content Type (0x03)
+    if let Some(ref content_type) = properties.content_type {
+        props.put_u8(0x03);
+        encode_string(content_type, &mut props)?;
+    }
+    
+    put_properties(&props, buf)
 }
 
 /// Decode Publish packet properties
-pub fn decode_publish_properties(_buf: &mut BytesMut) -> Result<PublishProperties> {
-    // TODO: Implement MQTT 5.0 publish properties decoding
-    Ok(PublishProperties {
-        payload_format_indicator: None,
-        message_expiry_interval: None,
-        topic_alias: None,
-        response_topic: None,
-        correlation_data: None,
-        user_properties: HashMap::new(),
-        subscription_identifier: None,
-        content_type: None,
-    })
+pub fn decode_publish_properties(buf: &mut BytesMut) -> Result<PublishProperties> {
+    let mut properties = PublishProperties::default();
+    
+    let mut properties_buf = take_properties(buf)?;
+    
+    while properties_buf.has_remaining() {
+        let property_id = properties_buf.get_u8();
+        
+        match property_id {
+            0x01 => { // Payload Format Indicator
+                properties.payload_format_indicator = Some(get_u8(&mut properties_buf, "Payload Format Indicator")?);
+            }
+            0x02 => { // Message Expiry Interval
+                properties.message_expiry_interval = Some(get_u32(&mut properties_buf, "Message Expiry Interval")?);
+            }
+            0x23 => { // Topic Alias
+                properties.topic_alias = Some(get_u16(&mut properties_buf, "Topic Alias")?);
+            }
+            0x08 => { // Response Topic
+                properties.response_topic = Some(decode_string(&mut properties_buf)?);
+            }
+            0x09 => { // Correlation Data
+                properties.correlation_data = Some(decode_bytes(&mut properties_buf)?);
+            }
+            0x26 => { // User Properties
+                let key = decode_string(&mut properties_buf)?;
+                let value = decode_string(&mut properties_buf)?;
+                properties.user_properties.insert(key, value);
+            }
+            0x0B => { // Subscription Identifier
+                properties.subscription_identifier = Some(decode_remaining_length(&mut properties_buf)? as u32);
+            }
+            0x03 => { // Content Type
+                properties.content_type = Some(decode_string(&mut properties_buf)?);
+            }
+            _ => {
+                return Err(Error::InvalidPacket(format!("Unknown publish property ID: 0x{:02x}", property_id)));
+            }
+        }
+    }
+    
+    Ok(properties)
+}
+
+/// Encode Subscribe packet properties
+pub fn encode_subscribe_properties(properties: &SubscribeProperties, buf: &mut BytesMut) -> Result<()> {
+    let mut props = BytesMut::new();
+    
+    // Subscription Identifier (0x0B), a Variable Byte Integer
+    if let Some(subscription_id) = properties.subscription_identifier {
+        props.put_u8(0x0B);
+        encode_remaining_length(subscription_id as usize, &mut props)?;
+    }
+    
+    // User Properties (0x26)
+    encode_user_properties(&properties.user_properties, &mut props)?;
+    
+    put_properties(&props, buf)
+}
+
+/// Decode Subscribe packet properties
+pub fn decode_subscribe_properties(buf: &mut BytesMut) -> Result<SubscribeProperties> {
+    let mut properties = SubscribeProperties::default();
+    
+    let mut properties_buf = take_properties(buf)?;
+    
+    while properties_buf.has_remaining() {
+        let property_id = properties_buf.get_u8();
+        
+        match property_id {
+            0x0B => { // Subscription Identifier
+                let subscription_id = decode_remaining_length(&mut properties_buf)? as u32;
+                if subscription_id == 0 {
+                    return Err(Error::InvalidPacket("Subscription Identifier must not be 0".to_string()));
+                }
+                properties.subscription_identifier = Some(subscription_id);
+            }
+            0x26 => { // User Properties
+                let key = decode_string(&mut properties_buf)?;
+                let value = decode_string(&mut properties_buf)?;
+                properties.user_properties.insert(key, value);
+            }
+            _ => {
+                return Err(Error::InvalidPacket(format!("Unknown subscribe property ID: 0x{:02x}", property_id)));
+            }
+        }
+    }
+    
+    Ok(properties)
+}
+
+/// Encode SubAck packet properties
+pub fn encode_suback_properties(properties: &SubAckProperties, buf: &mut BytesMut) -> Result<()> {
+    let mut props = BytesMut::new();
+    encode_reason_string(properties.reason_string.as_deref(), &mut props)?;
+    encode_user_properties(&properties.user_properties, &mut props)?;
+    put_properties(&props, buf)
+}
+
+/// Decode SubAck packet properties
+pub fn decode_suback_properties(buf: &mut BytesMut) -> Result<SubAckProperties> {
+    let mut properties = SubAckProperties::default();
+    let mut properties_buf = take_properties(buf)?;
+    decode_reason_properties(&mut properties_buf, &mut properties.reason_string, &mut properties.user_properties, "SubAck")?;
+    Ok(properties)
+}
+
+/// Encode Unsubscribe packet properties
+pub fn encode_unsubscribe_properties(properties: &UnsubscribeProperties, buf: &mut BytesMut) -> Result<()> {
+    let mut props = BytesMut::new();
+    encode_user_properties(&properties.user_properties, &mut props)?;
+    put_properties(&props, buf)
+}
+
+/// Decode Unsubscribe packet properties
+pub fn decode_unsubscribe_properties(buf: &mut BytesMut) -> Result<UnsubscribeProperties> {
+    let mut properties = UnsubscribeProperties::default();
+    
+    let mut properties_buf = take_properties(buf)?;
+    
+    while properties_buf.has_remaining() {
+        let property_id = properties_buf.get_u8();
+        
+        match property_id {
+            0x26 => { // User Properties
+                let key = decode_string(&mut properties_buf)?;
+                let value = decode_string(&mut properties_buf)?;
+                properties.user_properties.insert(key, value);
+            }
+            _ => {
+                return Err(Error::InvalidPacket(format!("Unknown unsubscribe property ID: 0x{:02x}", property_id)));
+            }
+        }
+    }
+    
+    Ok(properties)
+}
+
+/// Encode UnsubAck packet properties
+pub fn encode_unsuback_properties(properties: &UnsubAckProperties, buf: &mut BytesMut) -> Result<()> {
+    let mut props = BytesMut::new();
+    encode_reason_string(properties.reason_string.as_deref(), &mut props)?;
+    encode_user_properties(&properties.user_properties, &mut props)?;
+    put_properties(&props, buf)
+}
+
+/// Decode UnsubAck packet properties
+pub fn decode_unsuback_properties(buf: &mut BytesMut) -> Result<UnsubAckProperties> {
+    let mut properties = UnsubAckProperties::default();
+    let mut properties_buf = take_properties(buf)?;
+    decode_reason_properties(&mut properties_buf, &mut properties.reason_string, &mut properties.user_properties, "UnsubAck")?;
+    Ok(properties)
+}
+
+/// Encode Disconnect packet properties
+pub fn encode_disconnect_properties(properties: &DisconnectProperties, buf: &mut BytesMut) -> Result<()> {
+    let mut props = BytesMut::new();
+    
+    // Session Expiry Interval (0x11)
+    if let Some(session_expiry) = properties.session_expiry_interval {
+        props.put_u8(0x11);
+        props.put_u32(session_expiry);
+    }
+    
+    encode_reason_string(properties.reason_string.as_deref(), &mut props)?;
+    encode_user_properties(&properties.user_properties, &mut props)?;
+    
+    // Server Reference (0x1C)
+    if let Some(ref server_ref) = properties.server_reference {
+        props.put_u8(0x1C);
+        encode_string(server_ref, &mut props)?;
+    }
+    
+    put_properties(&props, buf)
+}
+
+/// Decode Disconnect packet properties
+pub fn decode_disconnect_properties(buf: &mut BytesMut) -> Result<DisconnectProperties> {
+    let mut properties = DisconnectProperties::default();
+    
+    let mut properties_buf = take_properties(buf)?;
+    
+    while properties_buf.has_remaining() {
+        let property_id = properties_buf.get_u8();
+        
+        match property_id {
+            0x11 => { // Session Expiry Interval
+                properties.session_expiry_interval = Some(get_u32(&mut properties_buf, "Session Expiry Interval")?);
+            }
+            0x1F => { // Reason String
+                properties.reason_string = Some(decode_string(&mut properties_buf)?);
+            }
+            0x26 => { // User Properties
+                let key = decode_string(&mut properties_buf)?;
+                let value = decode_string(&mut properties_buf)?;
+                properties.user_properties.insert(key, value);
+            }
+            0x1C => { // Server Reference
+                properties.server_reference = Some(decode_string(&mut properties_buf)?);
+            }
+            _ => {
+                return Err(Error::InvalidPacket(format!("Unknown disconnect property ID: 0x{:02x}", property_id)));
+            }
+        }
+    }
+    
+    Ok(properties)
+}
+
+/// Encode Auth packet properties
+pub fn encode_auth_properties(properties: &AuthProperties, buf: &mut BytesMut) -> Result<()> {
+    let mut props = BytesMut::new();
+    
+    // Authentication Method (0x15)
+    if let Some(ref auth_method) = properties.authentication_method {
+        props.put_u8(0x15);
+        encode_string(auth_method, &mut props)?;
+    }
+    
+    // Authentication Data (0x16)
+    if let Some(ref auth_data) = properties.authentication_data {
+        props.put_u8(0x16);
+        encode_bytes(auth_data, &mut props)?;
+    }
+    
+    encode_reason_string(properties.reason_string.as_deref(), &mut props)?;
+    encode_user_properties(&properties.user_properties, &mut props)?;
+    
+    put_properties(&props, buf)
+}
+
+/// Decode Auth packet properties
+pub fn decode_auth_properties(buf: &mut BytesMut) -> Result<AuthProperties> {
+    let mut properties = AuthProperties::default();
+    
+    let mut properties_buf = take_properties(buf)?;
+    
+    while properties_buf.has_remaining() {
+        let property_id = properties_buf.get_u8();
+        
+        match property_id {
+            0x15 => { // Authentication Method
+                properties.authentication_method = Some(decode_string(&mut properties_buf)?);
+            }
+            0x16 => { // Authentication Data
+                properties.authentication_data = Some(decode_bytes(&mut properties_buf)?);
+            }
+            0x1F => { // Reason String
+                properties.reason_string = Some(decode_string(&mut properties_buf)?);
+            }
+            0x26 => { // User Properties
+                let key = decode_string(&mut properties_buf)?;
+                let value = decode_string(&mut properties_buf)?;
+                properties.user_properties.insert(key, value);
+            }
+            _ => {
+                return Err(Error::InvalidPacket(format!("Unknown auth property ID: 0x{:02x}", property_id)));
+            }
+        }
+    }
+    
+    Ok(properties)
+}
+
+/// Encode the Reason String (0x1F) property
+fn encode_reason_string(reason_string: Option<&str>, props: &mut BytesMut) -> Result<()> {
+    if let Some(reason_string) = reason_string {
+        props.put_u8(0x1F);
+        encode_string(reason_string, props)?;
+    }
+    Ok(())
+}
+
+/// Encode User Property (0x26) pairs
+fn encode_user_properties(user_properties: &HashMap<String, String>, props: &mut BytesMut) -> Result<()> {
+    for (key, value) in user_properties {
+        props.put_u8(0x26);
+        encode_string(key, props)?;
+        encode_string(value, props)?;
+    }
+    Ok(())
+}
+
+/// Decode a property block holding only a Reason String and User Properties,
+/// as carried by acknowledgements
+fn decode_reason_properties(
+    properties_buf: &mut BytesMut,
+    reason_string: &mut Option<String>,
+    user_properties: &mut HashMap<String, String>,
+    packet: &str,
+) -> Result<()> {
+    while properties_buf.has_remaining() {
+        let property_id = properties_buf.get_u8();
+        
+        match property_id {
+            0x1F => { // Reason String
+                *reason_string = Some(decode_string(properties_buf)?);
+            }
+            0x26 => { // User Properties
+                let key = decode_string(properties_buf)?;
+                let value = decode_string(properties_buf)?;
+                user_properties.insert(key, value);
+            }
+            _ => {
+                return Err(Error::InvalidPacket(format!("Unknown {} property ID: 0x{:02x}", packet, property_id)));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write a property block, prefixed with its Variable Byte Integer length
+fn put_properties(props: &BytesMut, buf: &mut BytesMut) -> Result<()> {
+    encode_remaining_length(props.len(), buf)?;
+    buf.extend_from_slice(props);
+    Ok(())
+}
+
+/// Split off a property block after reading its Variable Byte Integer length
+fn take_properties(buf: &mut BytesMut) -> Result<BytesMut> {
+    let properties_length = decode_remaining_length(buf)?;
+    if buf.len() < properties_length {
+        return Err(Error::InvalidPacket(
+            format!("Insufficient bytes for properties: need {}, have {}", properties_length, buf.len())
+        ));
+    }
+    Ok(buf.split_to(properties_length))
+}
+
+fn get_u8(buf: &mut BytesMut, property: &str) -> Result<u8> {
+    if buf.remaining() < 1 {
+        return Err(Error::InvalidPacket(format!("Insufficient bytes for {}", property)));
+    }
+    Ok(buf.get_u8())
+}
+
+fn get_u16(buf: &mut BytesMut, property: &str) -> Result<u16> {
+    if buf.remaining() < 2 {
+        return Err(Error::InvalidPacket(format!("Insufficient bytes for {}", property)));
+    }
+    Ok(buf.get_u16())
+}
+
+fn get_u32(buf: &mut BytesMut, property: &str) -> Result<u32> {
+    if buf.remaining() < 4 {
+        return Err(Error::InvalidPacket(format!("Insufficient bytes for {}", property)));
+    }
+    Ok(buf.get_u32())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_encode_decode_publish_properties() {
+        let properties = PublishProperties::new()
+            .payload_format_indicator(1)
+            .message_expiry_interval(60)
+            .topic_alias(5)
+            .response_topic("r".to_string())
+            .correlation_data(Bytes::from("c1"))
+            .user_property("k".to_string(), "v".to_string())
+            .subscription_identifier(300)
+            .content_type("t".to_string());
+
+        let mut buf = BytesMut::new();
+        encode_publish_properties(&properties, &mut buf).unwrap();
+        let expected: &[u8] = &[
+            33,
+            0x01, 1,
+            0x02, 0, 0, 0, 60,
+            0x23, 0, 5,
+            0x08, 0, 1, b'r',
+            0x09, 0, 2, b'c', b'1',
+            0x26, 0, 1, b'k', 0, 1, b'v',
+            0x0B, 0xAC, 0x02,
+            0x03, 0, 1, b't',
+        ];
+        assert_eq!(buf.as_ref(), expected);
+
+        let mut buf = BytesMut::from(expected);
+        buf.put_slice(b"payload");
+        let decoded = decode_publish_properties(&mut buf).unwrap();
+        assert_eq!(decoded.payload_format_indicator, Some(1));
+        assert_eq!(decoded.message_expiry_interval, Some(60));
+        assert_eq!(decoded.topic_alias, Some(5));
+        assert_eq!(decoded.response_topic.as_deref(), Some("r"));
+        assert_eq!(decoded.correlation_data, Some(Bytes::from("c1")));
+        assert_eq!(decoded.user_properties.get("k").map(String::as_str), Some("v"));
+        assert_eq!(decoded.subscription_identifier, Some(300));
+        assert_eq!(decoded.content_type.as_deref(), Some("t"));
+        assert_eq!(buf.as_ref(), b"payload");
+    }
+
+    #[test]
+    fn test_property_length_is_variable_byte_integer() {
+        let properties = PublishProperties::new().correlation_data(Bytes::from(vec![7u8; 200]));
+
+        let mut buf = BytesMut::new();
+        encode_publish_properties(&properties, &mut buf).unwrap();
+        // 203 bytes of properties need a two byte length
+        assert_eq!(&buf[..4], &[0xCB, 0x01, 0x09, 0]);
+        assert_eq!(buf.len(), 2 + 203);
+
+        buf.put_slice(b"payload");
+        let decoded = decode_publish_properties(&mut buf).unwrap();
+        assert_eq!(decoded.correlation_data.unwrap().len(), 200);
+        assert_eq!(buf.as_ref(), b"payload");
+    }
+
+    #[test]
+    fn test_empty_publish_properties() {
+        let mut buf = BytesMut::new();
+        encode_publish_properties(&PublishProperties::new(), &mut buf).unwrap();
+        assert_eq!(buf.as_ref(), &[0]);
+
+        assert!(decode_publish_properties(&mut buf).unwrap().is_empty());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_malformed_publish_properties() {
+        // Property block longer than the packet
+        let mut buf = BytesMut::from(&[10, 0x02, 0, 0][..]);
+        assert!(decode_publish_properties(&mut buf).is_err());
+
+        // Truncated Message Expiry Interval
+        let mut buf = BytesMut::from(&[3, 0x02, 0, 0][..]);
+        assert!(decode_publish_properties(&mut buf).is_err());
+
+        // Property not allowed in a PUBLISH
+        let mut buf = BytesMut::from(&[2, 0x11, 0][..]);
+        assert!(decode_publish_properties(&mut buf).is_err());
+    }
 }
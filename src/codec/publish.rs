@@ -20,14 +20,15 @@ pub fn encode_publish(publish: &PublishPacket, buf: &mut BytesMut, protocol_vers
     encode_string(&publish.topic_name, buf)?;
     
     // Packet ID (for QoS > 0)
-    if publish.packet_id.is_some() {
-        buf.put_u16(publish.packet_id.unwrap());
+    if let Some(packet_id) = publish.packet_id {
+        buf.put_u16(packet_id);
     }
     
-    // MQTT 5.0 properties
+    // MQTT 5.0 properties; a PUBLISH without any still carries a zero length
     if protocol_version == 5 {
-        if let Some(ref properties) = publish.properties {
-            encode_publish_properties(properties, buf)?;
+        match publish.properties {
+            Some(ref properties) => encode_publish_properties(properties, buf)?,
+            None => buf.put_u8(0),
         }
     }
     
@@ -51,7 +52,7 @@ pub fn decode_publish(header: &crate::types::PacketHeader, buf: &mut BytesMut, p
     
     // MQTT 5.0 properties
     let properties = if protocol_version == 5 {
-        Some(decode_publish_properties(buf)?)
+        Some(decode_publish_properties(buf)?).filter(|properties| !properties.is_empty())
     } else {
         None
     };
@@ -6,54 +6,93 @@
 //! - Unsubscribe: Client unsubscription requests
 //! - UnsubAck: Server unsubscription acknowledgments
 
-use crate::error::Result;
+use crate::error::{Error, Result};
 use crate::types::{PacketPayload, SubscribePacket, SubAckPacket, UnsubscribePacket, UnsubAckPacket, TopicFilter};
 use bytes::{Buf, BufMut, BytesMut};
 
 use super::utils::{encode_string, decode_string};
+use super::properties::{
+    encode_subscribe_properties, decode_subscribe_properties, encode_suback_properties, decode_suback_properties,
+    encode_unsubscribe_properties, decode_unsubscribe_properties, encode_unsuback_properties, decode_unsuback_properties,
+};
+
+/// Pack a topic filter's QoS and MQTT 5.0 subscription options into the options byte
+fn subscription_options(topic_filter: &TopicFilter, protocol_version: u8) -> u8 {
+    if protocol_version != 5 {
+        return topic_filter.qos;
+    }
+    (topic_filter.qos & 0x03)
+        | (topic_filter.no_local as u8) << 2
+        | (topic_filter.retain_as_published as u8) << 3
+        | (topic_filter.retain_handling & 0x03) << 4
+}
 
 /// Encode Subscribe packet payload
-pub fn encode_subscribe(subscribe: &SubscribePacket, buf: &mut BytesMut, _protocol_version: u8) -> Result<()> {
+pub fn encode_subscribe(subscribe: &SubscribePacket, buf: &mut BytesMut, protocol_version: u8) -> Result<()> {
     // Packet ID
     buf.put_u16(subscribe.packet_id);
     
-    // Topic filters
-    for topic_filter in &subscribe.topic_filters {
-        encode_string(&topic_filter.topic, buf)?;
-        buf.put_u8(topic_filter.qos);
+    // MQTT 5.0 properties
+    if protocol_version == 5 {
+        match subscribe.properties {
+            Some(ref properties) => encode_subscribe_properties(properties, buf)?,
+            None => buf.put_u8(0),
+        }
     }
     
-    // MQTT 5.0 properties - placeholder for now
-    if let Some(_properties) = &subscribe.properties {
-        // TODO: Implement properties encoding
+    // Topic filters with their subscription options
+    for topic_filter in &subscribe.topic_filters {
+        encode_string(&topic_filter.topic, buf)?;
+        buf.put_u8(subscription_options(topic_filter, protocol_version));
     }
     
     Ok(())
 }
 
 /// Decode Subscribe packet payload
-pub fn decode_subscribe(buf: &mut BytesMut, _protocol_version: u8) -> Result<PacketPayload> {
+pub fn decode_subscribe(buf: &mut BytesMut, protocol_version: u8) -> Result<PacketPayload> {
     // Packet ID
     let packet_id = buf.get_u16();
     
+    // MQTT 5.0 properties
+    let properties = if protocol_version == 5 {
+        Some(decode_subscribe_properties(buf)?).filter(|properties| !properties.is_empty())
+    } else {
+        None
+    };
+    
     // Topic filters
     let mut topic_filters = Vec::new();
     while buf.has_remaining() {
         let topic = decode_string(buf)?;
-        let qos = buf.get_u8();
+        if !buf.has_remaining() {
+            return Err(Error::InvalidPacket("Missing subscription options".to_string()));
+        }
+        let options = buf.get_u8();
         
-        topic_filters.push(TopicFilter {
-            topic,
-            qos,
-            no_local: false,
-            retain_as_published: false,
-            retain_handling: 0,
-        });
+        let topic_filter = if protocol_version == 5 {
+            if options & 0xC0 != 0 || (options >> 4) & 0x03 == 3 {
+                return Err(Error::InvalidPacket(format!("Invalid subscription options: 0x{:02x}", options)));
+            }
+            TopicFilter {
+                topic,
+                qos: options & 0x03,
+                no_local: options & 0x04 != 0,
+                retain_as_published: options & 0x08 != 0,
+                retain_handling: (options >> 4) & 0x03,
+            }
+        } else {
+            TopicFilter {
+                topic,
+                qos: options,
+                no_local: false,
+                retain_as_published: false,
+                retain_handling: 0,
+            }
+        };
+        topic_filters.push(topic_filter);
     }
     
-    // MQTT 5.0 properties - placeholder for now
-    let properties = None;
-    
     Ok(PacketPayload::Subscribe(SubscribePacket {
         packet_id,
         topic_filters,
@@ -62,37 +101,44 @@ pub fn decode_subscribe(buf: &mut BytesMut, _protocol_version: u8) -> Result<Pac
 }
 
 /// Encode SubAck packet payload
-pub fn encode_suback(suback: &SubAckPacket, buf: &mut BytesMut, _protocol_version: u8) -> Result<()> {
+pub fn encode_suback(suback: &SubAckPacket, buf: &mut BytesMut, protocol_version: u8) -> Result<()> {
     // Packet ID
     buf.put_u16(suback.packet_id);
     
-    // Return codes
-    for &return_code in &suback.return_codes {
-        buf.put_u8(return_code);
+    // MQTT 5.0 properties
+    if protocol_version == 5 {
+        match suback.properties {
+            Some(ref properties) => encode_suback_properties(properties, buf)?,
+            None => buf.put_u8(0),
+        }
     }
     
-    // MQTT 5.0 properties - placeholder for now
-    if let Some(_properties) = &suback.properties {
-        // TODO: Implement properties encoding
+    // Return codes (reason codes in MQTT 5.0)
+    for &return_code in &suback.return_codes {
+        buf.put_u8(return_code);
     }
     
     Ok(())
 }
 
 /// Decode SubAck packet payload
-pub fn decode_suback(buf: &mut BytesMut, _protocol_version: u8) -> Result<PacketPayload> {
+pub fn decode_suback(buf: &mut BytesMut, protocol_version: u8) -> Result<PacketPayload> {
     // Packet ID
     let packet_id = buf.get_u16();
     
+    // MQTT 5.0 properties
+    let properties = if protocol_version == 5 {
+        Some(decode_suback_properties(buf)?).filter(|properties| !properties.is_empty())
+    } else {
+        None
+    };
+    
     // Return codes
     let mut return_codes = Vec::new();
     while buf.has_remaining() {
         return_codes.push(buf.get_u8());
     }
     
-    // MQTT 5.0 properties - placeholder for now
-    let properties = None;
-    
     Ok(PacketPayload::SubAck(SubAckPacket {
         packet_id,
         return_codes,
@@ -101,37 +147,44 @@ pub fn decode_suback(buf: &mut BytesMut, _protocol_version: u8) -> Result<Packet
 }
 
 /// Encode Unsubscribe packet payload
-pub fn encode_unsubscribe(unsubscribe: &UnsubscribePacket, buf: &mut BytesMut, _protocol_version: u8) -> Result<()> {
+pub fn encode_unsubscribe(unsubscribe: &UnsubscribePacket, buf: &mut BytesMut, protocol_version: u8) -> Result<()> {
     // Packet ID
     buf.put_u16(unsubscribe.packet_id);
     
+    // MQTT 5.0 properties
+    if protocol_version == 5 {
+        match unsubscribe.properties {
+            Some(ref properties) => encode_unsubscribe_properties(properties, buf)?,
+            None => buf.put_u8(0),
+        }
+    }
+    
     // Topic filters
     for topic_filter in &unsubscribe.topic_filters {
         encode_string(topic_filter, buf)?;
     }
     
-    // MQTT 5.0 properties - placeholder for now
-    if let Some(_properties) = &unsubscribe.properties {
-        // TODO: Implement properties encoding
-    }
-    
     Ok(())
 }
 
 /// Decode Unsubscribe packet payload
-pub fn decode_unsubscribe(buf: &mut BytesMut, _protocol_version: u8) -> Result<PacketPayload> {
+pub fn decode_unsubscribe(buf: &mut BytesMut, protocol_version: u8) -> Result<PacketPayload> {
     // Packet ID
     let packet_id = buf.get_u16();
     
+    // MQTT 5.0 properties
+    let properties = if protocol_version == 5 {
+        Some(decode_unsubscribe_properties(buf)?).filter(|properties| !properties.is_empty())
+    } else {
+        None
+    };
+    
     // Topic filters
     let mut topic_filters = Vec::new();
     while buf.has_remaining() {
         topic_filters.push(decode_string(buf)?);
     }
     
-    // MQTT 5.0 properties - placeholder for now
-    let properties = None;
-    
     Ok(PacketPayload::Unsubscribe(UnsubscribePacket {
         packet_id,
         topic_filters,
@@ -140,22 +193,21 @@ pub fn decode_unsubscribe(buf: &mut BytesMut, _protocol_version: u8) -> Result<P
 }
 
 /// Encode UnsubAck packet payload
-pub fn encode_unsuback(unsuback: &UnsubAckPacket, buf: &mut BytesMut, _protocol_version: u8) -> Result<()> {
+pub fn encode_unsuback(unsuback: &UnsubAckPacket, buf: &mut BytesMut, protocol_version: u8) -> Result<()> {
     // Packet ID
     buf.put_u16(unsuback.packet_id);
     
-    // Reason codes (MQTT 5.0)
-    if _protocol_version == 5 {
+    // MQTT 5.0 properties and reason codes; MQTT 3.1.1 only carries the packet ID
+    if protocol_version == 5 {
+        match unsuback.properties {
+            Some(ref properties) => encode_unsuback_properties(properties, buf)?,
+            None => buf.put_u8(0),
+        }
         for &reason_code in &unsuback.reason_codes {
             buf.put_u8(reason_code);
         }
     }
     
-    // MQTT 5.0 properties - placeholder for now
-    if let Some(_properties) = &unsuback.properties {
-        // TODO: Implement properties encoding
-    }
-    
     Ok(())
 }
 
@@ -164,19 +216,15 @@ pub fn decode_unsuback(buf: &mut BytesMut, protocol_version: u8) -> Result<Packe
     // Packet ID
     let packet_id = buf.get_u16();
     
-    // Reason codes (MQTT 5.0) - empty for MQTT 3.1.1
-    let reason_codes = if protocol_version == 5 {
-        let mut codes = Vec::new();
+    // MQTT 5.0 properties and reason codes - empty for MQTT 3.1.1
+    let mut properties = None;
+    let mut reason_codes = Vec::new();
+    if protocol_version == 5 {
+        properties = Some(decode_unsuback_properties(buf)?).filter(|properties| !properties.is_empty());
         while buf.has_remaining() {
-            codes.push(buf.get_u8());
+            reason_codes.push(buf.get_u8());
         }
-        codes
-    } else {
-        Vec::new()
-    };
-    
-    // MQTT 5.0 properties - placeholder for now
-    let properties = None;
+    }
     
     Ok(PacketPayload::UnsubAck(UnsubAckPacket {
         packet_id,
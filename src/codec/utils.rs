@@ -112,6 +112,29 @@ pub fn decode_remaining_length(buf: &mut BytesMut) -> Result<usize> {
     Ok(value)
 }
 
+/// Peek at the fixed header without consuming it
+///
+/// Returns the length of the fixed header and the remaining length it
+/// announces, or `None` while the remaining length field is still incomplete.
+pub fn peek_fixed_header(buf: &[u8]) -> Result<Option<(usize, usize)>> {
+    let mut value = 0usize;
+    let mut multiplier = 1usize;
+
+    for (i, &byte) in buf.iter().skip(1).take(4).enumerate() {
+        value += ((byte & 0x7F) as usize) * multiplier;
+        multiplier *= 128;
+
+        if (byte & 0x80) == 0 {
+            return Ok(Some((i + 2, value)));
+        }
+    }
+
+    if buf.len() >= 5 {
+        return Err(Error::InvalidPacket("Malformed remaining length".to_string()));
+    }
+    Ok(None)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -171,6 +194,15 @@ mod tests {
         assert_eq!(decode_remaining_length(&mut buf).unwrap(), 268_435_455);
     }
 
+    #[test]
+    fn test_peek_fixed_header() {
+        assert_eq!(peek_fixed_header(&[0x30]).unwrap(), None);
+        assert_eq!(peek_fixed_header(&[0x30, 0x00]).unwrap(), Some((2, 0)));
+        assert_eq!(peek_fixed_header(&[0x30, 0x80]).unwrap(), None);
+        assert_eq!(peek_fixed_header(&[0x30, 0x80, 0x80, 0x01, 0xAA]).unwrap(), Some((4, 16_384)));
+        assert!(peek_fixed_header(&[0x30, 0xFF, 0xFF, 0xFF, 0xFF]).is_err());
+    }
+
     #[test]
     fn test_invalid_remaining_length() {
         let mut buf = BytesMut::new();
@@ -18,7 +18,9 @@
 //! ### Connection Errors
 //! - **`Connection`**: General connection-related failures
 //! - **`Disconnected`**: Unexpected disconnection from the broker
+//! - **`ServerDisconnected`**: The broker closed the connection with an MQTT 5.0 DISCONNECT
 //! - **`Timeout`**: Connection or operation timeouts
+//! - **`Tls`**: Invalid certificates or keys, or a failed TLS handshake
 //! 
 //! ### Protocol Errors
 //! - **`Protocol`**: MQTT protocol violations or malformed packets
@@ -131,6 +133,7 @@
 //! cargo test --package dumq-mqtt --lib error
 //! ```
 
+use crate::protocol::ReasonCode;
 use thiserror::Error;
 
 /// MQTT library error types
@@ -172,9 +175,21 @@ pub enum Error {
     #[error("Timeout")]
     Timeout,
     
+    #[error("TLS error: {0}")]
+    Tls(String),
+    
     #[error("Disconnected")]
     Disconnected,
     
+    #[error("All packet identifiers are in use")]
+    PacketIdsExhausted,
+    
+    #[error("Disconnected by server: {reason_code:?}{}", .reason_string.as_ref().map(|reason| format!(" ({})", reason)).unwrap_or_default())]
+    ServerDisconnected {
+        reason_code: ReasonCode,
+        reason_string: Option<String>,
+    },
+    
     #[error("Serialization error: {0}")]
     Serialization(String),
     
@@ -242,12 +257,15 @@ mod tests {
             Error::Server("Internal server error".to_string()),
             Error::Client("Client configuration error".to_string()),
             Error::Timeout,
+            Error::Tls("Handshake failed".to_string()),
             Error::Disconnected,
+            Error::PacketIdsExhausted,
+            Error::ServerDisconnected { reason_code: ReasonCode::SessionTakenOver, reason_string: None },
             Error::Serialization("Failed to serialize".to_string()),
             Error::Deserialization("Failed to deserialize".to_string()),
         ];
 
-        assert_eq!(errors.len(), 14); // Total number of error variants
+        assert_eq!(errors.len(), 17); // Total number of error variants
     }
 
     #[test]
@@ -319,6 +337,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::unnecessary_literal_unwrap)]
     fn test_result_type() {
         // Test successful result
         let success_result: Result<String> = Ok("Success".to_string());
@@ -359,6 +378,14 @@ mod tests {
             (Error::Authorization("test".to_string()), "Authorization failed: test"),
             (Error::Server("test".to_string()), "Server error: test"),
             (Error::Client("test".to_string()), "Client error: test"),
+            (
+                Error::ServerDisconnected { reason_code: ReasonCode::KeepAliveTimeout, reason_string: None },
+                "Disconnected by server: KeepAliveTimeout",
+            ),
+            (
+                Error::ServerDisconnected { reason_code: ReasonCode::ServerShuttingDown, reason_string: Some("maintenance".to_string()) },
+                "Disconnected by server: ServerShuttingDown (maintenance)",
+            ),
             (Error::Serialization("test".to_string()), "Serialization error: test"),
             (Error::Deserialization("test".to_string()), "Deserialization error: test"),
         ];
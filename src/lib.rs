@@ -29,7 +29,7 @@
 //! 
 //! ### Security & Reliability
 //! - **Authentication**: Username/password and certificate-based authentication
-//! - **TLS/SSL Support**: Encrypted communication (planned)
+//! - **TLS/SSL Support**: rustls-based TLS for the server and client, including mutual TLS
 //! - **Error Handling**: Comprehensive error types with detailed diagnostics
 //! - **Connection Resilience**: Automatic reconnection and keep-alive management
 //! 
@@ -42,6 +42,8 @@
 //! - **`protocol`**: Protocol-level abstractions, QoS handling, and connection options
 //! - **`codec`**: Binary packet encoding/decoding for MQTT wire protocol
 //! - **`types`**: Core data structures representing MQTT packets and messages
+//! - **`topic`**: Validated topic names and filters, and topic matching
+//! - **`transport`**: Byte streams connections run over, and peer addresses
 //! - **`error`**: Comprehensive error handling and result types
 //! 
 //! ## Quick Start
@@ -171,6 +173,10 @@ pub mod codec;
 pub mod error;
 pub mod types;
 pub mod logging;
+pub mod topic;
+pub mod transport;
+mod tls;
+mod websocket;
 
 // Re-export main client components
 pub use client::{Client, ClientConfig, ClientConnection, ConnectionState, MessageHandler};
@@ -3,7 +3,7 @@
 //! This module provides comprehensive connection configuration for MQTT clients,
 //! including authentication, will messages, keep-alive settings, and MQTT 5.0 properties.
 
-use crate::types::ConnectProperties;
+use crate::types::{ConnectProperties, WillProperties};
 use bytes::Bytes;
 use std::time::Duration;
 use super::qos::QoS;
@@ -20,6 +20,7 @@ pub struct ConnectOptions {
     pub will_message: Option<Vec<u8>>,
     pub will_qos: QoS,
     pub will_retain: bool,
+    pub will_properties: Option<WillProperties>,
     pub protocol_version: u8,
     pub properties: Option<ConnectProperties>,
 }
@@ -37,6 +38,7 @@ impl ConnectOptions {
             will_message: None,
             will_qos: QoS::AtMostOnce,
             will_retain: false,
+            will_properties: None,
             protocol_version: 4, // MQTT 3.1.1
             properties: None,
         }
@@ -75,6 +77,15 @@ impl ConnectOptions {
         self
     }
 
+    /// Set will delay interval in seconds (MQTT 5.0)
+    pub fn will_delay_interval(mut self, interval: u32) -> Self {
+        if self.protocol_version == 5 {
+            let props = self.will_properties.take().unwrap_or_default();
+            self.will_properties = Some(props.will_delay_interval(interval));
+        }
+        self
+    }
+
     /// Set protocol version
     pub fn protocol_version(mut self, version: u8) -> Self {
         self.protocol_version = version;
@@ -209,14 +220,14 @@ mod tests {
         let options = ConnectOptions::new("test_client");
         
         assert_eq!(options.client_id, "test_client");
-        assert_eq!(options.clean_session, true);
+        assert!(options.clean_session);
         assert_eq!(options.keep_alive, Duration::from_secs(60));
         assert_eq!(options.username, None);
         assert_eq!(options.password, None);
         assert_eq!(options.will_topic, None);
         assert_eq!(options.will_message, None);
         assert_eq!(options.will_qos, QoS::AtMostOnce);
-        assert_eq!(options.will_retain, false);
+        assert!(!options.will_retain);
         assert_eq!(options.protocol_version, 4);
         assert!(options.properties.is_none());
     }
@@ -231,6 +242,7 @@ mod tests {
             .will("test/will", b"will message", QoS::AtLeastOnce, true)
             .protocol_version(5)
             .session_expiry_interval(3600)
+            .will_delay_interval(30)
             .receive_maximum(100)
             .max_packet_size(1_000_000)
             .topic_alias_maximum(20)
@@ -241,15 +253,16 @@ mod tests {
             .authentication_data(b"test_data");
 
         assert_eq!(options.client_id, "test_client");
-        assert_eq!(options.clean_session, false);
+        assert!(!options.clean_session);
         assert_eq!(options.keep_alive, Duration::from_secs(120));
         assert_eq!(options.username, Some("test_user".to_string()));
         assert_eq!(options.password, Some("test_pass".to_string()));
         assert_eq!(options.will_topic, Some("test/will".to_string()));
         assert_eq!(options.will_message, Some(b"will message".to_vec()));
         assert_eq!(options.will_qos, QoS::AtLeastOnce);
-        assert_eq!(options.will_retain, true);
+        assert!(options.will_retain);
         assert_eq!(options.protocol_version, 5);
+        assert_eq!(options.will_properties.as_ref().unwrap().will_delay_interval, Some(30));
         assert_eq!(options.properties.as_ref().unwrap().session_expiry_interval, Some(3600));
         assert_eq!(options.properties.as_ref().unwrap().receive_maximum, Some(100));
         assert_eq!(options.properties.as_ref().unwrap().max_packet_size, Some(1_000_000));
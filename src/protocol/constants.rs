@@ -29,6 +29,9 @@ pub const DEFAULT_KEEP_ALIVE: u16 = 60;
 /// Maximum keep alive interval (18 hours)
 pub const MAX_KEEP_ALIVE: u16 = 65535;
 
+/// Receive Maximum assumed when the peer does not announce one
+pub const DEFAULT_RECEIVE_MAXIMUM: u16 = 65535;
+
 /// Default connection timeout
 pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
 
@@ -0,0 +1,164 @@
+//! Inflight window for QoS 1 and 2 messages
+//!
+//! An MQTT 5.0 peer announces through its Receive Maximum how many QoS 1 and 2
+//! PUBLISH packets it is willing to have unacknowledged at once. Both client and
+//! server keep the messages they sent in an [`InflightStore`] until the
+//! exchange completes, keyed by packet ID and remembered in the order they were
+//! sent, so that a resumed session retransmits them in that order.
+
+use std::collections::HashMap;
+
+use super::constants::DEFAULT_RECEIVE_MAXIMUM;
+
+/// Unacknowledged messages, limited to a window
+///
+/// The store itself never refuses an entry; senders check
+/// [`has_capacity`](Self::has_capacity) and hold back what does not fit.
+#[derive(Debug, Clone)]
+pub struct InflightStore<T> {
+    window: u16,
+    /// Entries with the sequence number they were sent with
+    entries: HashMap<u16, (u64, T)>,
+    next_seq: u64,
+}
+
+impl<T> Default for InflightStore<T> {
+    fn default() -> Self {
+        Self::new(DEFAULT_RECEIVE_MAXIMUM)
+    }
+}
+
+impl<T> InflightStore<T> {
+    /// Create an empty store allowing `window` entries at once
+    pub fn new(window: u16) -> Self {
+        Self {
+            window: window.max(1),
+            entries: HashMap::new(),
+            next_seq: 0,
+        }
+    }
+
+    /// Maximum number of entries in flight
+    pub fn window(&self) -> u16 {
+        self.window
+    }
+
+    /// Change the window, such as after the peer announced a new Receive Maximum
+    ///
+    /// Shrinking the window below the number of entries keeps them all; no new
+    /// entry fits until enough have been removed.
+    pub fn set_window(&mut self, window: u16) {
+        self.window = window.max(1);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Check whether another entry fits in the window
+    pub fn has_capacity(&self) -> bool {
+        self.entries.len() < self.window as usize
+    }
+
+    /// Check whether a packet ID is in use
+    pub fn contains(&self, packet_id: u16) -> bool {
+        self.entries.contains_key(&packet_id)
+    }
+
+    /// Add an entry sent with the given packet ID
+    ///
+    /// Returns the entry previously stored under the packet ID, if any.
+    pub fn insert(&mut self, packet_id: u16, value: T) -> Option<T> {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.entries.insert(packet_id, (seq, value)).map(|(_, value)| value)
+    }
+
+    pub fn get(&self, packet_id: u16) -> Option<&T> {
+        self.entries.get(&packet_id).map(|(_, value)| value)
+    }
+
+    /// Get an entry to update it in place, keeping its position in the send order
+    pub fn get_mut(&mut self, packet_id: u16) -> Option<&mut T> {
+        self.entries.get_mut(&packet_id).map(|(_, value)| value)
+    }
+
+    /// Remove a completed entry, freeing its slot in the window
+    pub fn remove(&mut self, packet_id: u16) -> Option<T> {
+        self.entries.remove(&packet_id).map(|(_, value)| value)
+    }
+
+    /// Entries with their packet IDs, in the order they were sent
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &T)> {
+        let mut entries: Vec<_> = self.entries.iter().collect();
+        entries.sort_unstable_by_key(|(_, (seq, _))| *seq);
+        entries.into_iter().map(|(packet_id, (_, value))| (*packet_id, value))
+    }
+
+    /// Remove every entry
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_capacity() {
+        let mut store = InflightStore::new(2);
+        assert!(store.has_capacity());
+        store.insert(1, "a");
+        store.insert(2, "b");
+        assert!(!store.has_capacity());
+        assert_eq!(store.len(), 2);
+
+        assert_eq!(store.remove(1), Some("a"));
+        assert!(store.has_capacity());
+        assert!(!store.contains(1));
+        assert_eq!(store.remove(1), None);
+    }
+
+    #[test]
+    fn test_shrinking_window_keeps_entries() {
+        let mut store = InflightStore::new(3);
+        for packet_id in 1..=3 {
+            store.insert(packet_id, packet_id);
+        }
+        store.set_window(1);
+        assert_eq!(store.len(), 3);
+        store.remove(1);
+        store.remove(2);
+        assert!(!store.has_capacity());
+        store.remove(3);
+        assert!(store.has_capacity());
+    }
+
+    #[test]
+    fn test_zero_window_allows_one() {
+        assert_eq!(InflightStore::<()>::new(0).window(), 1);
+        assert_eq!(InflightStore::<()>::default().window(), DEFAULT_RECEIVE_MAXIMUM);
+    }
+
+    #[test]
+    fn test_iter_in_send_order() {
+        let mut store = InflightStore::new(10);
+        for packet_id in [65535, 1, 7] {
+            store.insert(packet_id, packet_id);
+        }
+        // Updating an entry keeps its position
+        *store.get_mut(65535).unwrap() = 0;
+
+        let order: Vec<_> = store.iter().map(|(packet_id, value)| (packet_id, *value)).collect();
+        assert_eq!(order, [(65535, 0), (1, 1), (7, 7)]);
+        assert_eq!(store.get(7), Some(&7));
+
+        store.clear();
+        assert!(store.is_empty());
+    }
+}
@@ -11,6 +11,8 @@
 //! - **QoS Management**: Quality of Service level definitions and validation
 //! - **Connection Options**: Comprehensive connection configuration for clients
 //! - **Protocol Flags**: Retain and duplicate flags for message handling
+//! - **Inflight Window**: Unacknowledged QoS 1/2 messages limited by the peer's Receive Maximum
+//! - **Packet Identifiers**: Allocation skipping IDs still awaiting acknowledgment
 //! - **Builder Patterns**: Fluent interfaces for configuration objects
 //! - **Protocol Validation**: Automatic validation of protocol constraints
 //! 
@@ -209,6 +211,9 @@ pub use subscribe::SubscribeOptions;
 pub use publish::PublishOptions;
 pub use reason_codes::ReasonCode;
 pub use constants::*;
+pub use scram::SCRAM_SHA_256;
+pub use inflight::InflightStore;
+pub use packet_id::PacketIdAllocator;
 
 // Submodules
 mod qos;
@@ -218,3 +223,6 @@ mod subscribe;
 mod publish;
 mod reason_codes;
 mod constants;
+mod inflight;
+mod packet_id;
+pub(crate) mod scram;
@@ -0,0 +1,87 @@
+//! Packet identifier allocation
+//!
+//! Client and server number the QoS 1/2 publishes they send, and the client its
+//! SUBSCRIBE and UNSUBSCRIBE packets, with packet IDs. An ID stays taken until
+//! its exchange completes, so a new packet never reuses the ID of one the peer
+//! has yet to acknowledge, even once the counter has wrapped around. The server
+//! keeps an allocator in each session, so it carries on where it left off when
+//! the session is resumed.
+
+use crate::error::{Error, Result};
+
+/// Number of packet IDs, as zero is not a valid one
+const PACKET_ID_COUNT: usize = u16::MAX as usize;
+
+/// Hands out packet IDs not used by any exchange in progress
+#[derive(Debug, Clone)]
+pub struct PacketIdAllocator {
+    /// Where the search for the next free ID starts
+    next: u16,
+}
+
+impl Default for PacketIdAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PacketIdAllocator {
+    pub fn new() -> Self {
+        Self { next: 1 }
+    }
+
+    /// Take the first ID from the current position for which `in_use` is false
+    ///
+    /// Fails with [`Error::PacketIdsExhausted`] when all 65535 IDs are in use.
+    pub fn allocate(&mut self, in_use: impl Fn(u16) -> bool) -> Result<u16> {
+        for _ in 0..PACKET_ID_COUNT {
+            let packet_id = self.next;
+            self.next = self.next.wrapping_add(1).max(1);
+            if !in_use(packet_id) {
+                return Ok(packet_id);
+            }
+        }
+        Err(Error::PacketIdsExhausted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_allocate_in_order() {
+        let mut allocator = PacketIdAllocator::new();
+        assert_eq!(allocator.allocate(|_| false).unwrap(), 1);
+        assert_eq!(allocator.allocate(|_| false).unwrap(), 2);
+        assert_eq!(allocator.allocate(|_| false).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_wraps_around_skipping_zero() {
+        let mut allocator = PacketIdAllocator { next: u16::MAX };
+        assert_eq!(allocator.allocate(|_| false).unwrap(), u16::MAX);
+        assert_eq!(allocator.allocate(|_| false).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_skips_ids_in_use() {
+        let mut allocator = PacketIdAllocator { next: u16::MAX };
+        let mut in_use: HashSet<u16> = HashSet::from([u16::MAX, 1, 2, 4]);
+        assert_eq!(allocator.allocate(|packet_id| in_use.contains(&packet_id)).unwrap(), 3);
+        assert_eq!(allocator.allocate(|packet_id| in_use.contains(&packet_id)).unwrap(), 5);
+
+        // A freed ID is handed out again once the counter comes round to it
+        in_use.remove(&1);
+        allocator.next = u16::MAX;
+        assert_eq!(allocator.allocate(|packet_id| in_use.contains(&packet_id)).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_exhaustion_reported() {
+        let mut allocator = PacketIdAllocator::new();
+        assert!(matches!(allocator.allocate(|_| true), Err(Error::PacketIdsExhausted)));
+        assert_eq!(allocator.allocate(|packet_id| packet_id != 40000).unwrap(), 40000);
+    }
+}
@@ -65,8 +65,8 @@ mod tests {
         assert_eq!(options.topic, "test/topic");
         assert_eq!(options.payload, b"Hello MQTT!");
         assert_eq!(options.qos, QoS::AtMostOnce);
-        assert_eq!(options.retain, false);
-        assert_eq!(options.dup, false);
+        assert!(!options.retain);
+        assert!(!options.dup);
         assert_eq!(options.packet_id, None);
     }
 
@@ -81,8 +81,8 @@ mod tests {
         assert_eq!(options.topic, "test/topic");
         assert_eq!(options.payload, b"Hello MQTT!");
         assert_eq!(options.qos, QoS::AtLeastOnce);
-        assert_eq!(options.retain, true);
-        assert_eq!(options.dup, true);
+        assert!(options.retain);
+        assert!(options.dup);
         assert_eq!(options.packet_id, Some(789));
     }
 
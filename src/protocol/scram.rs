@@ -0,0 +1,136 @@
+//! SCRAM-SHA-256 primitives
+//!
+//! Shared by the client and server halves of the SCRAM-SHA-256 enhanced
+//! authentication method (RFC 5802, RFC 7677). Channel binding is not supported,
+//! so every exchange uses the `n,,` GS2 header.
+
+use crate::error::{Error, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+/// Authentication method name of SCRAM-SHA-256
+pub const SCRAM_SHA_256: &str = "SCRAM-SHA-256";
+
+/// PBKDF2 iterations used for new credentials, the minimum RFC 7677 recommends
+pub(crate) const DEFAULT_ITERATIONS: u32 = 4096;
+
+/// GS2 header of a client that does not support channel binding
+pub(crate) const GS2_HEADER: &str = "n,,";
+
+pub(crate) type Key = [u8; 32];
+
+/// Derive the salted password with PBKDF2-HMAC-SHA-256
+pub(crate) fn salted_password(password: &str, salt: &[u8], iterations: u32) -> Key {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut key);
+    key
+}
+
+pub(crate) fn hmac(key: &[u8], data: &[u8]) -> Key {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+pub(crate) fn sha256(data: &[u8]) -> Key {
+    Sha256::digest(data).into()
+}
+
+pub(crate) fn xor(a: &Key, b: &Key) -> Key {
+    let mut out = [0u8; 32];
+    for (out, (a, b)) in out.iter_mut().zip(a.iter().zip(b.iter())) {
+        *out = a ^ b;
+    }
+    out
+}
+
+/// Compare two keys in constant time
+pub(crate) fn keys_equal(a: &Key, b: &Key) -> bool {
+    a.iter().zip(b.iter()).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Client Key and Server Key derived from a salted password
+pub(crate) fn client_key(salted_password: &Key) -> Key {
+    hmac(salted_password, b"Client Key")
+}
+
+pub(crate) fn server_key(salted_password: &Key) -> Key {
+    hmac(salted_password, b"Server Key")
+}
+
+/// Random printable nonce
+pub(crate) fn nonce() -> String {
+    let mut bytes = [0u8; 18];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    STANDARD.encode(bytes)
+}
+
+pub(crate) fn encode_base64(data: &[u8]) -> String {
+    STANDARD.encode(data)
+}
+
+pub(crate) fn decode_base64(data: &str) -> Result<Vec<u8>> {
+    STANDARD.decode(data).map_err(|e| Error::Authentication(format!("Invalid SCRAM base64 value: {}", e)))
+}
+
+/// Escape `,` and `=` in a username, as SCRAM messages require
+pub(crate) fn escape_username(username: &str) -> String {
+    username.replace('=', "=3D").replace(',', "=2C")
+}
+
+pub(crate) fn unescape_username(username: &str) -> String {
+    username.replace("=2C", ",").replace("=3D", "=")
+}
+
+/// Value of the `name=` attribute of a SCRAM message
+pub(crate) fn attribute(message: &str, name: char) -> Result<&str> {
+    message
+        .split(',')
+        .find_map(|attribute| {
+            attribute.strip_prefix(name).and_then(|rest| rest.strip_prefix('='))
+        })
+        .ok_or_else(|| Error::Authentication(format!("SCRAM message is missing the '{}' attribute", name)))
+}
+
+/// Interpret exchanged data as a SCRAM message
+pub(crate) fn message(data: Option<&[u8]>) -> Result<&str> {
+    let data = data.ok_or_else(|| Error::Authentication("Missing SCRAM data".to_string()))?;
+    std::str::from_utf8(data).map_err(|_| Error::Authentication("SCRAM data is not UTF-8".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rfc7677_keys() {
+        let salt = decode_base64("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+        let salted = salted_password("pencil", &salt, 4096);
+        let auth_message = "n=user,r=rOprNGfwEbeRWgbNEkqO,\
+            r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096,\
+            c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0";
+
+        let client_key = client_key(&salted);
+        let proof = xor(&client_key, &hmac(&sha256(&client_key), auth_message.as_bytes()));
+        assert_eq!(encode_base64(&proof), "dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=");
+
+        let signature = hmac(&server_key(&salted), auth_message.as_bytes());
+        assert_eq!(encode_base64(&signature), "6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=");
+    }
+
+    #[test]
+    fn test_attributes_and_usernames() {
+        assert_eq!(attribute("r=abc,s=c2FsdA==,i=4096", 's').unwrap(), "c2FsdA==");
+        assert_eq!(attribute("r=abc,s=c2FsdA==,i=4096", 'i').unwrap(), "4096");
+        assert!(attribute("r=abc", 'p').is_err());
+
+        let escaped = escape_username("a,b=c");
+        assert_eq!(escaped, "a=2Cb=3Dc");
+        assert_eq!(unescape_username(&escaped), "a,b=c");
+        assert!(keys_equal(&sha256(b"x"), &sha256(b"x")));
+        assert!(!keys_equal(&sha256(b"x"), &sha256(b"y")));
+    }
+}
@@ -0,0 +1,300 @@
+//! Topic authorization module
+//!
+//! The [`Authorizer`] trait decides which topics a client may publish to and
+//! which topic filters it may subscribe to. `ServerConnection` consults the
+//! authorizer configured with `ServerConfig::authorizer` for every PUBLISH and
+//! for each filter of a SUBSCRIBE.
+//!
+//! [`AclFile`] implements it with a mosquitto-style ACL file:
+//!
+//! ```text
+//! # Rules before the first `user` line apply to anonymous clients
+//! topic read public/#
+//!
+//! user alice
+//! topic readwrite alice/#
+//! topic deny alice/secrets/#
+//!
+//! # Patterns apply to every client; %c is the client ID, %u the username
+//! pattern write devices/%c/status
+//! pattern read users/%u/inbox/#
+//! ```
+//!
+//! `read` allows subscribing, `write` allows publishing and `readwrite`, the
+//! default when the access is omitted, allows both. A matching `deny` rule wins
+//! over any rule that allows access, and anything not allowed is denied.
+
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+use super::router::MessageRouter;
+use crate::error::{Error, Result};
+
+/// Operation a client wants to perform on a topic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Publish to a topic name
+    Publish,
+    /// Subscribe to a topic filter
+    Subscribe,
+}
+
+/// Decides whether a client may publish to or subscribe to a topic
+#[async_trait]
+pub trait Authorizer: Send + Sync + fmt::Debug {
+    async fn authorize(&self, client_id: &str, username: Option<&str>, access: Access, topic: &str) -> bool;
+}
+
+/// Access granted by an ACL rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Permission {
+    Read,
+    Write,
+    ReadWrite,
+    Deny,
+}
+
+impl Permission {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "read" => Some(Self::Read),
+            "write" => Some(Self::Write),
+            "readwrite" => Some(Self::ReadWrite),
+            "deny" => Some(Self::Deny),
+            _ => None,
+        }
+    }
+
+    fn allows(self, access: Access) -> bool {
+        matches!(
+            (self, access),
+            (Self::ReadWrite, _) | (Self::Read, Access::Subscribe) | (Self::Write, Access::Publish)
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    permission: Permission,
+    topic: String,
+}
+
+/// Authorizer backed by a mosquitto-style ACL file
+#[derive(Debug, Clone, Default)]
+pub struct AclFile {
+    /// Rules for clients that connected without a username
+    anonymous: Vec<Rule>,
+    users: HashMap<String, Vec<Rule>>,
+    /// Rules for every client, with `%c` and `%u` substituted
+    patterns: Vec<Rule>,
+}
+
+impl AclFile {
+    /// Load an ACL file
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Parse the contents of an ACL file
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut acl = Self::default();
+        let mut user: Option<String> = None;
+
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: &str| Error::Server(format!("ACL file line {}: {}", index + 1, reason));
+
+            let (keyword, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let rest = rest.trim();
+            match keyword {
+                "user" if !rest.is_empty() => user = Some(rest.to_string()),
+                "topic" | "pattern" => {
+                    let parsed = rest.split_once(char::is_whitespace)
+                        .and_then(|(first, topic)| Some((Permission::parse(first)?, topic.trim())));
+                    let (permission, topic) = parsed.unwrap_or((Permission::ReadWrite, rest));
+                    if topic.is_empty() {
+                        return Err(invalid("missing topic"));
+                    }
+                    let rule = Rule { permission, topic: topic.to_string() };
+
+                    match (keyword, &user) {
+                        ("pattern", _) => acl.patterns.push(rule),
+                        (_, Some(user)) => acl.users.entry(user.clone()).or_default().push(rule),
+                        (_, None) => acl.anonymous.push(rule),
+                    }
+                }
+                _ => return Err(invalid(&format!("unexpected '{}'", line))),
+            }
+        }
+
+        Ok(acl)
+    }
+
+    /// Rules that apply to a client, with patterns substituted
+    fn rules_for(&self, client_id: &str, username: Option<&str>) -> Vec<Rule> {
+        let own = match username {
+            Some(username) => self.users.get(username).map(Vec::as_slice).unwrap_or_default(),
+            None => &self.anonymous,
+        };
+
+        let patterns = self.patterns.iter().filter_map(|rule| {
+            let topic = substitute(&rule.topic, 'c', Some(client_id))?;
+            let topic = substitute(&topic, 'u', username)?;
+            Some(Rule { permission: rule.permission, topic })
+        });
+
+        own.iter().cloned().chain(patterns).collect()
+    }
+
+    /// Check access against the rules; exposed for tests and tooling
+    pub fn check(&self, client_id: &str, username: Option<&str>, access: Access, topic: &str) -> bool {
+        let rules = self.rules_for(client_id, username);
+        let applies = |rule: &Rule| match access {
+            Access::Publish => MessageRouter::topic_matches(&rule.topic, topic),
+            Access::Subscribe => filter_covers(&rule.topic, topic),
+        };
+
+        let denied = rules.iter().any(|rule| {
+            // A deny rule for a filter blocks subscriptions overlapping it too
+            rule.permission == Permission::Deny
+                && (applies(rule) || (access == Access::Subscribe && filter_covers(topic, &rule.topic)))
+        });
+        !denied && rules.iter().any(|rule| rule.permission.allows(access) && applies(rule))
+    }
+}
+
+#[async_trait]
+impl Authorizer for AclFile {
+    async fn authorize(&self, client_id: &str, username: Option<&str>, access: Access, topic: &str) -> bool {
+        self.check(client_id, username, access, topic)
+    }
+}
+
+/// Replace `%<placeholder>` with a value
+///
+/// Returns `None` when the rule cannot apply: the value is missing, or contains
+/// characters that would let it reach other clients' topics.
+fn substitute(topic: &str, placeholder: char, value: Option<&str>) -> Option<String> {
+    let token = format!("%{}", placeholder);
+    if !topic.contains(&token) {
+        return Some(topic.to_string());
+    }
+    match value {
+        Some(value) if !value.is_empty() && !value.contains(['/', '+', '#']) => Some(topic.replace(&token, value)),
+        _ => None,
+    }
+}
+
+/// Whether every topic matched by `filter` is also matched by `rule`
+fn filter_covers(rule: &str, filter: &str) -> bool {
+    let mut rule_levels = rule.split('/');
+    let mut filter_levels = filter.split('/');
+    loop {
+        match (rule_levels.next(), filter_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(level)) if level != "#" => {}
+            (Some(rule_level), Some(level)) if rule_level == level => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ACL: &str = "\
+# anonymous clients may only read public topics
+topic read public/#
+
+user alice
+topic readwrite alice/#
+topic deny alice/secrets/#
+topic home/+/temperature
+
+user bob
+topic write telemetry/bob
+
+pattern write devices/%c/status
+pattern read users/%u/inbox/#
+";
+
+    fn acl() -> AclFile {
+        AclFile::parse(ACL).unwrap()
+    }
+
+    #[test]
+    fn test_user_rules() {
+        let acl = acl();
+        assert!(acl.check("c1", Some("alice"), Access::Publish, "alice/notes"));
+        assert!(acl.check("c1", Some("alice"), Access::Subscribe, "alice/+/x"));
+        assert!(acl.check("c1", Some("alice"), Access::Subscribe, "home/kitchen/temperature"));
+        assert!(acl.check("c1", Some("alice"), Access::Publish, "home/kitchen/temperature"));
+        assert!(!acl.check("c1", Some("alice"), Access::Publish, "bob/notes"));
+
+        // Bob may publish his telemetry but not read it back
+        assert!(acl.check("c2", Some("bob"), Access::Publish, "telemetry/bob"));
+        assert!(!acl.check("c2", Some("bob"), Access::Subscribe, "telemetry/bob"));
+        assert!(!acl.check("c2", Some("bob"), Access::Subscribe, "public/news"));
+    }
+
+    #[test]
+    fn test_deny_wins() {
+        let acl = acl();
+        assert!(!acl.check("c1", Some("alice"), Access::Publish, "alice/secrets/pin"));
+        assert!(!acl.check("c1", Some("alice"), Access::Subscribe, "alice/secrets/#"));
+        // Subscribing to a wider filter would also receive the denied topics
+        assert!(!acl.check("c1", Some("alice"), Access::Subscribe, "alice/#"));
+    }
+
+    #[test]
+    fn test_anonymous_rules() {
+        let acl = acl();
+        assert!(acl.check("anon", None, Access::Subscribe, "public/news"));
+        assert!(!acl.check("anon", None, Access::Publish, "public/news"));
+        // Subscriptions must stay within what the rule covers
+        assert!(!acl.check("anon", None, Access::Subscribe, "#"));
+        assert!(!acl.check("anon", None, Access::Subscribe, "+/news"));
+    }
+
+    #[test]
+    fn test_pattern_substitution() {
+        let acl = acl();
+        assert!(acl.check("sensor-7", None, Access::Publish, "devices/sensor-7/status"));
+        assert!(!acl.check("sensor-7", None, Access::Publish, "devices/sensor-8/status"));
+        assert!(acl.check("c3", Some("carol"), Access::Subscribe, "users/carol/inbox/#"));
+        assert!(!acl.check("c3", Some("carol"), Access::Subscribe, "users/dave/inbox/#"));
+
+        // %u rules do not apply without a username, and wildcards in IDs are not substituted
+        assert!(!acl.check("c3", None, Access::Subscribe, "users//inbox/#"));
+        assert!(!acl.check("+", None, Access::Publish, "devices/+/status"));
+        assert!(!acl.check("a/b", None, Access::Publish, "devices/a/b/status"));
+    }
+
+    #[test]
+    fn test_filter_covers() {
+        assert!(filter_covers("a/#", "a/b/c"));
+        assert!(filter_covers("a/#", "a/+/#"));
+        assert!(filter_covers("a/+", "a/b"));
+        assert!(filter_covers("a/+", "a/+"));
+        assert!(!filter_covers("a/+", "a/#"));
+        assert!(!filter_covers("a/b", "a/+"));
+        assert!(!filter_covers("a/b", "a/b/c"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(AclFile::parse("topic").is_err());
+        assert!(AclFile::parse("subscribe a/b").is_err());
+        assert!(AclFile::parse("user").is_err());
+        let acl = AclFile::parse("topic a/b").unwrap();
+        assert!(acl.check("c", None, Access::Publish, "a/b"));
+        assert!(acl.check("c", None, Access::Subscribe, "a/b"));
+    }
+}
@@ -0,0 +1,77 @@
+//! Closure-based authenticator
+
+use async_trait::async_trait;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+
+use super::{AuthRequest, Authenticator};
+use crate::error::Result;
+
+type Callback = dyn Fn(AuthRequest) -> Pin<Box<dyn Future<Output = Result<bool>> + Send>> + Send + Sync;
+
+/// Authenticator delegating every decision to an async closure
+///
+/// ```rust
+/// use dumq_mqtt::server::auth::CallbackAuthenticator;
+///
+/// let authenticator = CallbackAuthenticator::new(|request| async move {
+///     // Ask an identity service here
+///     Ok(request.username.as_deref() == Some("device") && request.peer_addr.is_local())
+/// });
+/// ```
+pub struct CallbackAuthenticator {
+    callback: Box<Callback>,
+}
+
+impl CallbackAuthenticator {
+    pub fn new<F, Fut>(callback: F) -> Self
+    where
+        F: Fn(AuthRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<bool>> + Send + 'static,
+    {
+        Self {
+            callback: Box::new(move |request| Box::pin(callback(request))),
+        }
+    }
+}
+
+impl fmt::Debug for CallbackAuthenticator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CallbackAuthenticator").finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl Authenticator for CallbackAuthenticator {
+    async fn authenticate(&self, request: &AuthRequest) -> Result<bool> {
+        (self.callback)(request.clone()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    #[tokio::test]
+    async fn test_callback_sees_whole_request() {
+        let authenticator = CallbackAuthenticator::new(|request: AuthRequest| async move {
+            if request.client_id == "broken" {
+                return Err(Error::Server("identity service unreachable".to_string()));
+            }
+            Ok(request.username.as_deref() == Some("device")
+                && request.password.as_deref() == Some("secret")
+                && request.peer_addr.is_local())
+        });
+
+        let local = AuthRequest::new("client", "127.0.0.1:4000".parse().unwrap());
+        let remote = AuthRequest::new("client", "192.0.2.1:4000".parse().unwrap());
+        assert!(authenticator.authenticate(&local.clone().credentials("device", "secret")).await.unwrap());
+        assert!(!authenticator.authenticate(&local.credentials("device", "wrong")).await.unwrap());
+        assert!(!authenticator.authenticate(&remote.credentials("device", "secret")).await.unwrap());
+
+        let broken = AuthRequest::new("broken", "127.0.0.1:4000".parse().unwrap());
+        assert!(authenticator.authenticate(&broken).await.is_err());
+    }
+}
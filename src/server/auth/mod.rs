@@ -0,0 +1,72 @@
+//! Authentication module
+//!
+//! The [`Authenticator`] trait decides whether a connecting client may use the
+//! username and password in its CONNECT. `ServerConnection` consults the
+//! authenticator configured with `ServerConfig::authenticator`.
+//!
+//! Three implementations are provided: [`PasswordFile`], which checks hashed
+//! passwords in a mosquitto-compatible password file, [`Authentication`], a
+//! static list of users, and [`CallbackAuthenticator`], which hands the decision
+//! to a closure, for instance to query an external identity service.
+
+pub mod static_list;
+pub mod password_file;
+pub mod callback;
+
+pub use static_list::Authentication;
+pub use password_file::PasswordFile;
+pub use callback::CallbackAuthenticator;
+
+use crate::error::Result;
+use async_trait::async_trait;
+use std::fmt;
+
+use crate::transport::PeerAddr;
+
+/// Credentials and origin of a connecting client
+#[derive(Clone)]
+pub struct AuthRequest {
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub peer_addr: PeerAddr,
+}
+
+impl AuthRequest {
+    pub fn new(client_id: impl Into<String>, peer_addr: PeerAddr) -> Self {
+        Self {
+            client_id: client_id.into(),
+            username: None,
+            password: None,
+            peer_addr,
+        }
+    }
+
+    /// Set username and password
+    pub fn credentials(mut self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        self.username = Some(username.into());
+        self.password = Some(password.into());
+        self
+    }
+}
+
+impl fmt::Debug for AuthRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthRequest")
+            .field("client_id", &self.client_id)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .field("peer_addr", &self.peer_addr)
+            .finish()
+    }
+}
+
+/// Decides whether a client may connect with the given credentials
+///
+/// Returning `Ok(false)` rejects the client with "bad username or password".
+/// An error means the decision could not be made, and the client is told the
+/// server is unavailable.
+#[async_trait]
+pub trait Authenticator: Send + Sync + fmt::Debug {
+    async fn authenticate(&self, request: &AuthRequest) -> Result<bool>;
+}
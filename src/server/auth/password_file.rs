@@ -0,0 +1,224 @@
+//! Hashed password file authenticator
+//!
+//! The file has one `username:hash` entry per line, as written by
+//! `mosquitto_passwd`. Blank lines and lines starting with `#` are ignored.
+//! Supported hashes:
+//!
+//! - argon2 PHC strings (`$argon2id$...`)
+//! - bcrypt (`$2a$`, `$2b$`, `$2y$`)
+//! - mosquitto 2.x PBKDF2-SHA512 (`$7$<iterations>$<salt>$<hash>`)
+//! - mosquitto 1.x salted SHA512 (`$6$<salt>$<hash>`)
+
+use argon2::password_hash::{PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::warn;
+use sha2::{Digest, Sha512};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+use super::{AuthRequest, Authenticator};
+use crate::error::{Error, Result};
+
+/// Stored password hash of one user
+#[derive(Clone)]
+enum PasswordHash {
+    Argon2(String),
+    Bcrypt(String),
+    Pbkdf2Sha512 { iterations: u32, salt: Vec<u8>, hash: Vec<u8> },
+    Sha512 { salt: Vec<u8>, hash: Vec<u8> },
+}
+
+impl PasswordHash {
+    fn parse(hash: &str) -> std::result::Result<Self, String> {
+        if hash.starts_with("$argon2") {
+            argon2::PasswordHash::new(hash).map_err(|e| e.to_string())?;
+            return Ok(Self::Argon2(hash.to_string()));
+        }
+        if ["$2a$", "$2b$", "$2y$"].iter().any(|prefix| hash.starts_with(prefix)) {
+            return Ok(Self::Bcrypt(hash.to_string()));
+        }
+
+        let decode = |value: &str| STANDARD.decode(value).map_err(|e| format!("invalid base64: {}", e));
+        let fields: Vec<&str> = hash.split('$').collect();
+        match fields.as_slice() {
+            ["", "7", iterations, salt, hash] => Ok(Self::Pbkdf2Sha512 {
+                iterations: iterations.parse().map_err(|_| "invalid iteration count".to_string())?,
+                salt: decode(salt)?,
+                hash: decode(hash)?,
+            }),
+            ["", "6", salt, hash] => Ok(Self::Sha512 {
+                salt: decode(salt)?,
+                hash: decode(hash)?,
+            }),
+            _ => Err("unsupported hash format".to_string()),
+        }
+    }
+
+    /// Check a password; slow by design, so run it off the async runtime
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            Self::Argon2(hash) => argon2::PasswordHash::new(hash)
+                .map(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+                .unwrap_or(false),
+            Self::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            Self::Pbkdf2Sha512 { iterations, salt, hash } => {
+                let mut derived = vec![0u8; hash.len()];
+                pbkdf2::pbkdf2_hmac::<Sha512>(password.as_bytes(), salt, *iterations, &mut derived);
+                constant_time_eq(&derived, hash)
+            }
+            Self::Sha512 { salt, hash } => {
+                let derived = Sha512::new().chain_update(password.as_bytes()).chain_update(salt).finalize();
+                constant_time_eq(&derived, hash)
+            }
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Authenticator backed by a file of hashed passwords
+#[derive(Clone, Default)]
+pub struct PasswordFile {
+    users: HashMap<String, PasswordHash>,
+}
+
+impl PasswordFile {
+    /// Load a password file
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Parse the contents of a password file
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut users = HashMap::new();
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (username, hash) = line.split_once(':')
+                .ok_or_else(|| Error::Server(format!("Password file line {}: expected 'username:hash'", index + 1)))?;
+            let hash = PasswordHash::parse(hash)
+                .map_err(|reason| Error::Server(format!("Password file line {}: {}", index + 1, reason)))?;
+            users.insert(username.to_string(), hash);
+        }
+        Ok(Self { users })
+    }
+
+    /// Hash a password with argon2id, for writing `username:hash` lines
+    pub fn hash_password(password: &str) -> Result<String> {
+        let salt = SaltString::generate(&mut rand::thread_rng());
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| Error::Server(format!("Failed to hash password: {}", e)))
+    }
+
+    /// Number of users in the file
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+}
+
+impl fmt::Debug for PasswordFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PasswordFile")
+            .field("users", &self.users.len())
+            .finish()
+    }
+}
+
+#[async_trait]
+impl Authenticator for PasswordFile {
+    async fn authenticate(&self, request: &AuthRequest) -> Result<bool> {
+        let (Some(username), Some(password)) = (&request.username, &request.password) else {
+            return Ok(false);
+        };
+        let Some(hash) = self.users.get(username).cloned() else {
+            warn!("Unknown user '{}' from {}", username, request.peer_addr);
+            return Ok(false);
+        };
+
+        let password = password.clone();
+        tokio::task::spawn_blocking(move || hash.verify(&password))
+            .await
+            .map_err(|e| Error::Server(format!("Password verification failed: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(username: &str, password: &str) -> AuthRequest {
+        AuthRequest::new("client", "127.0.0.1:4000".parse().unwrap()).credentials(username, password)
+    }
+
+    /// mosquitto 2.x style entry, built the way `mosquitto_passwd` does
+    fn pbkdf2_entry(password: &str, salt: &[u8], iterations: u32) -> String {
+        let mut hash = [0u8; 64];
+        pbkdf2::pbkdf2_hmac::<Sha512>(password.as_bytes(), salt, iterations, &mut hash);
+        format!("$7${}${}${}", iterations, STANDARD.encode(salt), STANDARD.encode(hash))
+    }
+
+    #[tokio::test]
+    async fn test_password_file_hash_formats() {
+        let sha512 = Sha512::new().chain_update(b"legacy").chain_update(b"salt1234salt").finalize();
+        let contents = format!(
+            "# broker users\n\
+             argon:{}\n\
+             \n\
+             crypt:{}\n\
+             mosquitto:{}\n\
+             old:$6${}${}\n",
+            PasswordFile::hash_password("argon-pass").unwrap(),
+            bcrypt::hash("bcrypt-pass", 4).unwrap(),
+            pbkdf2_entry("pbkdf2-pass", b"0123456789ab", 101),
+            STANDARD.encode(b"salt1234salt"),
+            STANDARD.encode(sha512),
+        );
+        let file = PasswordFile::parse(&contents).unwrap();
+        assert_eq!(file.len(), 4);
+
+        for (username, password) in [
+            ("argon", "argon-pass"),
+            ("crypt", "bcrypt-pass"),
+            ("mosquitto", "pbkdf2-pass"),
+            ("old", "legacy"),
+        ] {
+            assert!(file.authenticate(&request(username, password)).await.unwrap(), "{}", username);
+            assert!(!file.authenticate(&request(username, "wrong")).await.unwrap(), "{}", username);
+        }
+        assert!(!file.authenticate(&request("nobody", "argon-pass")).await.unwrap());
+        assert!(!file.authenticate(&AuthRequest::new("client", "127.0.0.1:4000".parse().unwrap())).await.unwrap());
+    }
+
+    #[test]
+    fn test_password_file_rejects_malformed_lines() {
+        assert!(PasswordFile::parse("no-separator").is_err());
+        assert!(PasswordFile::parse("user:plaintext").is_err());
+        assert!(PasswordFile::parse("user:$7$many$c2FsdA==$aGFzaA==").is_err());
+        assert!(PasswordFile::parse("user:$5$c2FsdA==$aGFzaA==").is_err());
+        assert!(PasswordFile::parse("# only a comment\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_password_file_open() {
+        let path = std::env::temp_dir().join(format!("dumq-passwd-{}", std::process::id()));
+        std::fs::write(&path, format!("user:{}\n", pbkdf2_entry("secret", b"salt", 101))).unwrap();
+        let file = PasswordFile::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(file.len(), 1);
+        assert!(PasswordFile::open(&path).is_err());
+    }
+}
@@ -1,9 +1,16 @@
-//! Authentication module
+//! Static user list authenticator
 
+use async_trait::async_trait;
 use std::collections::HashMap;
 
-/// Authentication configuration
-#[derive(Debug, Clone, Default)]
+use super::{AuthRequest, Authenticator};
+use crate::error::Result;
+
+/// Authenticator checking a fixed list of usernames and passwords
+///
+/// Passwords are kept in plain text; prefer [`PasswordFile`](super::PasswordFile)
+/// outside of tests and development.
+#[derive(Clone, Default)]
 pub struct Authentication {
     pub users: HashMap<String, String>, // username -> password
 }
@@ -27,6 +34,24 @@ impl Authentication {
     }
 }
 
+impl std::fmt::Debug for Authentication {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Authentication")
+            .field("users", &self.users.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+#[async_trait]
+impl Authenticator for Authentication {
+    async fn authenticate(&self, request: &AuthRequest) -> Result<bool> {
+        match (&request.username, &request.password) {
+            (Some(username), Some(password)) => Ok(Authentication::authenticate(self, username, password)),
+            _ => Ok(false),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -65,4 +90,16 @@ mod tests {
         assert_eq!(auth1.users.len(), auth2.users.len());
         assert_eq!(auth1.authenticate("user1", "pass1"), auth2.authenticate("user1", "pass1"));
     }
+
+    #[tokio::test]
+    async fn test_static_list_authenticator() {
+        let auth = Authentication::new().add_user("user1", "pass1");
+        let request = AuthRequest::new("client", "127.0.0.1:5000".parse().unwrap());
+
+        assert!(Authenticator::authenticate(&auth, &request.clone().credentials("user1", "pass1")).await.unwrap());
+        assert!(!Authenticator::authenticate(&auth, &request.clone().credentials("user1", "nope")).await.unwrap());
+        // A username without a password never matches
+        let no_password = AuthRequest { username: Some("user1".to_string()), ..request };
+        assert!(!Authenticator::authenticate(&auth, &no_password).await.unwrap());
+    }
 }
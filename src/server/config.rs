@@ -1,30 +1,119 @@
 //! Server configuration module
 
-use super::auth::Authentication;
+use super::acl::Authorizer;
+use super::auth::{Authentication, Authenticator};
+use super::enhanced_auth::AuthenticationProvider;
+use super::listener::ListenerConfig;
+use super::shared::SharedSubscriptionStrategy;
+use super::tls::TlsConfig;
+use crate::protocol::DEFAULT_RECEIVE_MAXIMUM;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// What to drop when a session's offline queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QueueDropPolicy {
+    /// Discard the oldest queued message to make room for the new one
+    #[default]
+    DropOldest,
+    /// Discard the incoming message
+    DropNewest,
+}
 
 /// MQTT server configuration
 #[derive(Debug, Clone)]
 pub struct ServerConfig {
     pub bind_addr: String,
+    /// Also accept MQTT over WebSocket on this address
+    pub websocket_bind_addr: Option<String>,
+    /// Accept TLS connections instead of plain TCP, on both addresses
+    pub tls: Option<TlsConfig>,
+    /// Further listeners with their own transport and limits
+    pub listeners: Vec<ListenerConfig>,
+    /// Maximum number of simultaneous connections per listener
     pub max_connections: usize,
     pub max_packet_size: usize,
     pub protocol_version: u8,
     pub allow_anonymous: bool,
-    pub authentication: Option<Authentication>,
+    /// Checks the username and password of connecting clients
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+    /// Enhanced authentication methods offered to MQTT 5.0 clients
+    pub authentication_providers: Vec<Arc<dyn AuthenticationProvider>>,
+    /// Decides which topics clients may publish and subscribe to; all are allowed when unset
+    pub authorizer: Option<Arc<dyn Authorizer>>,
+    /// Keep-alive imposed on MQTT 5.0 clients through the CONNACK, in seconds
+    pub server_keep_alive: Option<u16>,
+    /// Most QoS 1/2 messages inflight per client in each direction; announced
+    /// to MQTT 5.0 clients as the server's Receive Maximum
+    pub max_inflight: u16,
+    /// Maximum number of QoS 1/2 messages queued per session, while the client
+    /// is offline or its inflight window is full
+    pub max_pending_messages: usize,
+    pub queue_drop_policy: QueueDropPolicy,
+    /// How shared subscription groups pick the member receiving a message
+    pub shared_subscription_strategy: SharedSubscriptionStrategy,
+    /// Upper bound on the Session Expiry Interval requested by clients, in seconds
+    pub max_session_expiry_interval: Option<u32>,
+    /// How often disconnected sessions are checked for expiry
+    pub session_expiry_check_interval: Duration,
 }
 
 impl ServerConfig {
     pub fn new(bind_addr: impl Into<String>) -> Self {
         Self {
             bind_addr: bind_addr.into(),
+            websocket_bind_addr: None,
+            tls: None,
+            listeners: Vec::new(),
             max_connections: 1000,
             max_packet_size: 1024 * 1024, // 1MB
             protocol_version: 4, // MQTT 3.1.1
             allow_anonymous: true,
-            authentication: None,
+            authenticator: None,
+            authentication_providers: Vec::new(),
+            authorizer: None,
+            server_keep_alive: None,
+            max_inflight: DEFAULT_RECEIVE_MAXIMUM,
+            max_pending_messages: 1000,
+            queue_drop_policy: QueueDropPolicy::DropOldest,
+            shared_subscription_strategy: SharedSubscriptionStrategy::RoundRobin,
+            max_session_expiry_interval: None,
+            session_expiry_check_interval: Duration::from_secs(1),
         }
     }
 
+    /// Accept MQTT over WebSocket connections on a second address
+    pub fn websocket(mut self, bind_addr: impl Into<String>) -> Self {
+        self.websocket_bind_addr = Some(bind_addr.into());
+        self
+    }
+
+    /// Serve clients over TLS
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Accept connections on another listener as well
+    pub fn listener(mut self, listener: ListenerConfig) -> Self {
+        self.listeners.push(listener);
+        self
+    }
+
+    /// Every listener of the server: `bind_addr`, the WebSocket address and the added listeners
+    pub fn all_listeners(&self) -> Vec<ListenerConfig> {
+        let with_tls = |listener: ListenerConfig| match &self.tls {
+            Some(tls) => listener.tls(tls.clone()),
+            None => listener,
+        };
+        let mut listeners = vec![with_tls(ListenerConfig::tcp(&self.bind_addr))];
+        if let Some(addr) = &self.websocket_bind_addr {
+            listeners.push(with_tls(ListenerConfig::tcp(addr).websocket()));
+        }
+        listeners.extend(self.listeners.iter().cloned());
+        listeners
+    }
+
     pub fn max_connections(mut self, max: usize) -> Self {
         self.max_connections = max;
         self
@@ -45,8 +134,78 @@ impl ServerConfig {
         self
     }
 
-    pub fn authentication(mut self, auth: Authentication) -> Self {
-        self.authentication = Some(auth);
+    /// Authenticate clients against a static list of users
+    pub fn authentication(self, auth: Authentication) -> Self {
+        self.authenticator(Arc::new(auth))
+    }
+
+    /// Authenticate clients with the given authenticator
+    pub fn authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Offer an enhanced authentication method to MQTT 5.0 clients
+    pub fn authentication_provider(mut self, provider: Arc<dyn AuthenticationProvider>) -> Self {
+        self.authentication_providers.push(provider);
+        self
+    }
+
+    /// Provider for the named authentication method
+    pub fn find_authentication_provider(&self, method: &str) -> Option<&Arc<dyn AuthenticationProvider>> {
+        self.authentication_providers.iter().find(|provider| provider.method() == method)
+    }
+
+    /// Restrict publishing and subscribing with the given authorizer
+    pub fn authorizer(mut self, authorizer: Arc<dyn Authorizer>) -> Self {
+        self.authorizer = Some(authorizer);
+        self
+    }
+
+    /// Override the keep-alive requested by MQTT 5.0 clients
+    pub fn server_keep_alive(mut self, seconds: u16) -> Self {
+        self.server_keep_alive = Some(seconds);
+        self
+    }
+
+    /// Limit the QoS 1/2 messages awaiting acknowledgment per client
+    ///
+    /// Deliveries beyond the window, or beyond the client's own Receive Maximum
+    /// if lower, are queued until the client acknowledges earlier ones. MQTT 5.0
+    /// clients sending more unacknowledged QoS 2 messages than this are
+    /// disconnected.
+    pub fn max_inflight(mut self, max: u16) -> Self {
+        self.max_inflight = max.max(1);
+        self
+    }
+
+    /// Limit the number of messages queued per session
+    pub fn max_pending_messages(mut self, max: usize) -> Self {
+        self.max_pending_messages = max;
+        self
+    }
+
+    /// Choose which message is dropped once an offline queue is full
+    pub fn queue_drop_policy(mut self, policy: QueueDropPolicy) -> Self {
+        self.queue_drop_policy = policy;
+        self
+    }
+
+    /// Choose how shared subscription groups pick the member receiving a message
+    pub fn shared_subscription_strategy(mut self, strategy: SharedSubscriptionStrategy) -> Self {
+        self.shared_subscription_strategy = strategy;
+        self
+    }
+
+    /// Cap the Session Expiry Interval granted to clients
+    pub fn max_session_expiry_interval(mut self, seconds: u32) -> Self {
+        self.max_session_expiry_interval = Some(seconds);
+        self
+    }
+
+    /// Set how often expired sessions are removed
+    pub fn session_expiry_check_interval(mut self, interval: Duration) -> Self {
+        self.session_expiry_check_interval = interval;
         self
     }
 }
@@ -54,30 +213,85 @@ impl ServerConfig {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::server::{AclFile, ScramSha256Provider};
 
     #[test]
     fn test_server_config_new() {
         let config = ServerConfig::new("127.0.0.1:1883");
         assert_eq!(config.bind_addr, "127.0.0.1:1883");
+        assert!(config.websocket_bind_addr.is_none());
+        assert!(config.tls.is_none());
+        assert!(config.listeners.is_empty());
         assert_eq!(config.max_connections, 1000);
         assert_eq!(config.max_packet_size, 1024 * 1024);
         assert_eq!(config.protocol_version, 4);
-        assert_eq!(config.allow_anonymous, true);
-        assert!(config.authentication.is_none());
+        assert!(config.allow_anonymous);
+        assert!(config.authenticator.is_none());
+        assert!(config.authentication_providers.is_empty());
+        assert!(config.authorizer.is_none());
+        assert!(config.server_keep_alive.is_none());
+        assert_eq!(config.max_inflight, 65535);
+        assert_eq!(config.max_pending_messages, 1000);
+        assert_eq!(config.queue_drop_policy, QueueDropPolicy::DropOldest);
+        assert_eq!(config.shared_subscription_strategy, SharedSubscriptionStrategy::RoundRobin);
+        assert!(config.max_session_expiry_interval.is_none());
+        assert_eq!(config.session_expiry_check_interval, Duration::from_secs(1));
     }
 
     #[test]
     fn test_server_config_builder_pattern() {
         let config = ServerConfig::new("localhost:1883")
+            .websocket("localhost:8080")
+            .tls(TlsConfig::new("server.pem", "server.key").client_ca("ca.pem"))
+            .listener(ListenerConfig::unix("/run/mqtt.sock"))
             .max_connections(500)
             .max_packet_size(512 * 1024)
             .protocol_version(5)
-            .allow_anonymous(false);
+            .allow_anonymous(false)
+            .authentication(Authentication::new().add_user("user", "pass"))
+            .authentication_provider(Arc::new(ScramSha256Provider::new()))
+            .authorizer(Arc::new(AclFile::default()))
+            .server_keep_alive(30)
+            .max_inflight(20)
+            .max_pending_messages(10)
+            .queue_drop_policy(QueueDropPolicy::DropNewest)
+            .shared_subscription_strategy(SharedSubscriptionStrategy::LeastInflight)
+            .max_session_expiry_interval(3600)
+            .session_expiry_check_interval(Duration::from_secs(10));
 
+        assert_eq!(config.websocket_bind_addr.as_deref(), Some("localhost:8080"));
+        assert_eq!(config.tls.as_ref().and_then(|tls| tls.client_ca_path.clone()), Some("ca.pem".into()));
+        assert_eq!(config.listeners, vec![ListenerConfig::unix("/run/mqtt.sock")]);
         assert_eq!(config.max_connections, 500);
         assert_eq!(config.max_packet_size, 512 * 1024);
         assert_eq!(config.protocol_version, 5);
-        assert_eq!(config.allow_anonymous, false);
+        assert!(!config.allow_anonymous);
+        assert!(config.authenticator.is_some());
+        assert!(config.find_authentication_provider("SCRAM-SHA-256").is_some());
+        assert!(config.find_authentication_provider("KERBEROS").is_none());
+        assert!(config.authorizer.is_some());
+        assert_eq!(config.server_keep_alive, Some(30));
+        assert_eq!(config.max_inflight, 20);
+        assert_eq!(config.max_pending_messages, 10);
+        assert_eq!(config.queue_drop_policy, QueueDropPolicy::DropNewest);
+        assert_eq!(config.shared_subscription_strategy, SharedSubscriptionStrategy::LeastInflight);
+        assert_eq!(config.max_session_expiry_interval, Some(3600));
+        assert_eq!(config.session_expiry_check_interval, Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_all_listeners() {
+        let tls = TlsConfig::new("server.pem", "server.key");
+        let config = ServerConfig::new("0.0.0.0:8883")
+            .tls(tls.clone())
+            .websocket("0.0.0.0:8443")
+            .listener(ListenerConfig::tcp("127.0.0.1:1883"));
+
+        assert_eq!(config.all_listeners(), vec![
+            ListenerConfig::tcp("0.0.0.0:8883").tls(tls.clone()),
+            ListenerConfig::tcp("0.0.0.0:8443").tls(tls).websocket(),
+            ListenerConfig::tcp("127.0.0.1:1883"),
+        ]);
     }
 
     #[test]
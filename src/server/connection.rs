@@ -1,87 +1,266 @@
 //! Client connection handling module
+//!
+//! Each connection is served by two tasks: the reader, which decodes and
+//! handles inbound packets, and the writer, which drains the connection's
+//! outbound queue onto the socket. Acknowledgements produced by the reader and
+//! deliveries routed from other connections share that queue, so neither side
+//! ever blocks the other.
 
 use crate::codec::MqttCodec;
 use crate::error::{Error, Result};
-use crate::protocol::QoS;
+use crate::protocol::{QoS, ReasonCode, DEFAULT_RECEIVE_MAXIMUM};
+use crate::topic::{self, TopicName};
+use crate::transport::PeerAddr;
 use crate::types::*;
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use log::{debug, info, warn};
-use std::net::SocketAddr;
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
 
+use super::acl::Access;
+use super::auth::AuthRequest;
 use super::config::ServerConfig;
-use super::session::SessionManager;
+use super::enhanced_auth::{AuthStep, AuthenticationExchange, AuthenticationProvider};
+use super::session::{Outbound, OutboundSender, SessionManager, Subscription, Will, SESSION_NEVER_EXPIRES};
 use super::router::MessageRouter;
 
+/// Read half of a client's transport, whether plain TCP or TLS
+type TransportReader = Box<dyn AsyncRead + Send + Unpin>;
+/// Write half of a client's transport
+type TransportWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
 /// MQTT server connection handler
 pub struct ServerConnection {
-    stream: TcpStream,
+    stream: TransportReader,
+    peer_addr: PeerAddr,
     config: ServerConfig,
     codec: MqttCodec,
     read_buffer: BytesMut,
-    _write_buffer: BytesMut,
     client_id: Option<String>,
     username: Option<String>,
     session_manager: Arc<SessionManager>,
     message_router: Arc<MessageRouter>,
+    outbound_tx: OutboundSender,
+    /// QoS 2 packet IDs received from the client that are waiting for PUBREL
+    awaiting_pubrel: HashSet<u16>,
+    /// Negotiated keep-alive; `None` when the client disabled it
+    keep_alive: Option<Duration>,
+    /// Enhanced authentication method chosen in the CONNECT
+    auth_provider: Option<Arc<dyn AuthenticationProvider>>,
+    /// Authentication exchange waiting for the client's next AUTH packet
+    auth_exchange: Option<Box<dyn AuthenticationExchange>>,
+    /// CONNECT held back until its authentication exchange succeeds
+    pending_connect: Option<Box<ConnectPacket>>,
 }
 
 impl ServerConnection {
-    /// Handle a new client connection
-    pub async fn handle_connection(
-        stream: TcpStream,
-        _addr: SocketAddr,
+    /// Handle a new client connection over any byte stream, such as TCP or TLS
+    pub async fn handle_connection<S>(
+        stream: S,
+        addr: PeerAddr,
         config: ServerConfig,
         session_manager: Arc<SessionManager>,
         message_router: Arc<MessageRouter>,
-    ) -> Result<()> {
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let (read_half, write_half) = tokio::io::split(stream);
+        let (outbound_tx, outbound_rx) = mpsc::unbounded_channel();
+
+        let writer = ConnectionWriter::new(Box::new(write_half), MqttCodec::new(config.protocol_version));
+        let writer_handle = tokio::spawn(writer.run(outbound_rx));
+
         let mut connection = Self::new(
-            stream,
+            Box::new(read_half),
+            addr,
             config,
             session_manager,
             message_router,
+            outbound_tx,
         );
 
-        connection.handle().await
+        let result = connection.handle().await;
+        connection.cleanup().await;
+
+        // Let the writer flush everything queued so far, then close the socket
+        let _ = connection.outbound_tx.send(Outbound::Close);
+        match writer_handle.await {
+            Ok(Err(e)) => debug!("Connection writer error: {}", e),
+            Err(e) => debug!("Connection writer task failed: {}", e),
+            Ok(Ok(())) => {}
+        }
+
+        result
     }
 
     fn new(
-        stream: TcpStream,
+        stream: TransportReader,
+        peer_addr: PeerAddr,
         config: ServerConfig,
         session_manager: Arc<SessionManager>,
         message_router: Arc<MessageRouter>,
+        outbound_tx: OutboundSender,
     ) -> Self {
         let codec = MqttCodec::new(config.protocol_version);
         Self {
             stream,
+            peer_addr,
             config,
             codec,
             read_buffer: BytesMut::new(),
-            _write_buffer: BytesMut::new(),
             client_id: None,
             username: None,
             session_manager,
             message_router,
+            outbound_tx,
+            awaiting_pubrel: HashSet::new(),
+            keep_alive: None,
+            auth_provider: None,
+            auth_exchange: None,
+            pending_connect: None,
         }
     }
 
     async fn handle(&mut self) -> Result<()> {
+        // The writer stops once the connection is closed from our side, such as
+        // when another connection takes over the session
+        let outbound_tx = self.outbound_tx.clone();
         loop {
-            let packet = self.read_packet().await?;
+            let packet = tokio::select! {
+                packet = self.next_packet() => packet,
+                _ = outbound_tx.closed() => return Err(Error::Disconnected),
+            };
+            let packet = match packet {
+                Ok(packet) => packet,
+                Err(Error::InvalidPacket(reason)) => {
+                    warn!("Malformed packet from client: {}", reason);
+                    self.send_disconnect(ReasonCode::MalformedPacket);
+                    return Err(Error::InvalidPacket(reason));
+                }
+                Err(e) => return Err(e),
+            };
             self.handle_packet(packet).await?;
         }
     }
 
+    /// Read the next packet, enforcing the negotiated keep-alive
+    async fn next_packet(&mut self) -> Result<Packet> {
+        match self.keep_alive {
+            // Clients get one and a half keep-alive periods to send something
+            Some(keep_alive) => {
+                match tokio::time::timeout(keep_alive.mul_f32(1.5), self.read_packet()).await {
+                    Ok(packet) => packet,
+                    Err(_) => Err(self.handle_keep_alive_timeout()),
+                }
+            }
+            None => self.read_packet().await,
+        }
+    }
+
+    /// Evict a client that has been silent for too long
+    ///
+    /// The will message is published by `cleanup` like for any other
+    /// connection that ends without DISCONNECT.
+    fn handle_keep_alive_timeout(&mut self) -> Error {
+        warn!(
+            "Client '{}' exceeded its keep-alive, closing connection",
+            self.client_id.as_deref().unwrap_or("unknown")
+        );
+        self.send_disconnect(ReasonCode::KeepAliveTimeout);
+        Error::Timeout
+    }
+
+    /// Detach this connection from the session manager once it has ended
+    ///
+    /// A will still stored in the session means the client went away without
+    /// DISCONNECT or asked for its will on the way out, so it is published now
+    /// or once its delay interval expires.
+    /// Sessions with an expiry interval stay behind to collect messages until
+    /// the client reconnects or the session expires.
+    async fn cleanup(&mut self) {
+        let Some(client_id) = self.client_id.clone() else {
+            return;
+        };
+
+        // A connection that has been taken over leaves the session, and its will,
+        // to its successor
+        if !self.session_manager.unregister_connection(&client_id, &self.outbound_tx).await {
+            return;
+        }
+
+        let will = self.session_manager.take_will(&client_id).await;
+        let session_expiry = self.session_manager.detach_session(&client_id).await;
+
+        let Some(will) = will else {
+            return;
+        };
+
+        // The will goes out once its delay has passed or the session ends, whichever is first
+        let delay = will.delay.min(Duration::from_secs(session_expiry as u64));
+        if delay.is_zero() {
+            Self::publish_will(&self.session_manager, &self.message_router, &client_id, will.message).await;
+            return;
+        }
+
+        debug!("Delaying will of client '{}' by {:?}", client_id, delay);
+        let session_manager = Arc::clone(&self.session_manager);
+        let message_router = Arc::clone(&self.message_router);
+        let publisher = client_id.clone();
+        let task = tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            Self::publish_will(&session_manager, &message_router, &publisher, will.message).await;
+        });
+        self.session_manager.schedule_will(client_id, task).await;
+    }
+
+    /// Publish the will message of a client that went away
+    async fn publish_will(session_manager: &SessionManager, message_router: &MessageRouter, client_id: &str, will: Message) {
+        info!("Publishing will message to topic: {}", will.topic);
+        if will.retain {
+            if will.payload.is_empty() {
+                message_router.clear_retained_message(&will.topic).await;
+            } else {
+                message_router.store_retained_message(will.topic.clone(), will.clone()).await;
+            }
+        }
+        Self::publish_to_subscribers(session_manager, client_id, &will).await;
+    }
+
     async fn handle_packet(&mut self, packet: Packet) -> Result<()> {
+        // Nothing but the authentication exchange may happen before the CONNACK
+        if self.pending_connect.is_some()
+            && !matches!(packet.payload, PacketPayload::Auth(_) | PacketPayload::Disconnect(_))
+        {
+            warn!("Unexpected {:?} packet during authentication", packet.header.packet_type);
+            self.send_disconnect(ReasonCode::ProtocolError);
+            return Err(Error::Protocol("Authentication has not completed".to_string()));
+        }
+
         match packet.payload {
             PacketPayload::Connect(connect) => self.handle_connect(connect).await,
             PacketPayload::Publish(publish) => self.handle_publish(publish, &packet.header).await,
             PacketPayload::Subscribe(subscribe) => self.handle_subscribe(subscribe).await,
             PacketPayload::Unsubscribe(unsubscribe) => self.handle_unsubscribe(unsubscribe).await,
-            PacketPayload::PingReq => self.handle_pingreq().await,
-            PacketPayload::Disconnect(_) => self.handle_disconnect().await,
+            PacketPayload::PubAck(puback) => {
+                debug!("Received PUBACK for packet ID: {}", puback.packet_id);
+                self.complete_delivery(PacketType::PubAck, puback.packet_id).await;
+                Ok(())
+            }
+            PacketPayload::PubRec(pubrec) => self.handle_pubrec(pubrec).await,
+            PacketPayload::PubRel(pubrel) => self.handle_pubrel(pubrel),
+            PacketPayload::PubComp(pubcomp) => {
+                debug!("Received PUBCOMP for packet ID: {}", pubcomp.packet_id);
+                self.complete_delivery(PacketType::PubComp, pubcomp.packet_id).await;
+                Ok(())
+            }
+            PacketPayload::PingReq => self.handle_pingreq(),
+            PacketPayload::Disconnect(disconnect) => self.handle_disconnect(disconnect).await,
+            PacketPayload::Auth(auth) => self.handle_auth(auth).await,
             _ => {
                 warn!("Unhandled packet type: {:?}", packet.header.packet_type);
                 Ok(())
@@ -94,56 +273,362 @@ impl ServerConnection {
 
         // Validate protocol version
         if connect.protocol_version != self.config.protocol_version {
-            return self.send_connack(ConnectReturnCode::UnacceptableProtocolVersion, false).await;
+            return self.send_connack(ConnectReturnCode::UnacceptableProtocolVersion, false);
         }
 
         // Validate client ID
         if connect.client_id.is_empty() && !connect.clean_session {
-            return self.send_connack(ConnectReturnCode::IdentifierRejected, false).await;
+            return self.send_connack(ConnectReturnCode::IdentifierRejected, false);
         }
 
-        // Handle authentication
-        if let Some(ref auth) = self.config.authentication {
-            if connect.username.is_none() && !self.config.allow_anonymous {
-                return self.send_connack(ConnectReturnCode::NotAuthorized, false).await;
+        // The will is published like any other message, so its topic must be a
+        // valid topic name. MQTT 3.1.1 has no CONNACK code for this and treats it
+        // as a malformed packet.
+        if let Some(will_topic) = connect.will_topic.as_deref().filter(|_| connect.will_flag) {
+            if let Err(e) = TopicName::new(will_topic) {
+                warn!("Client '{}' set a will on an invalid topic: {}", connect.client_id, e);
+                if connect.protocol_version == 5 {
+                    self.send_connack(ConnectReturnCode::TopicNameInvalid, false)?;
+                }
+                return Err(e);
             }
+        }
+
+        // MQTT 5.0 enhanced authentication takes the place of the password check
+        let method = connect.properties.as_ref().and_then(|p| p.authentication_method.clone());
+        if let Some(method) = method {
+            return self.begin_connect_auth(connect, &method).await;
+        }
 
-            if let (Some(username), Some(password)) = (&connect.username, &connect.password) {
-                if !auth.authenticate(username, password) {
-                    return self.send_connack(ConnectReturnCode::BadUsernameOrPassword, false).await;
+        // Handle authentication
+        if let Some(authenticator) = self.config.authenticator.clone() {
+            if connect.username.is_none() {
+                if !self.config.allow_anonymous {
+                    return self.send_connack(ConnectReturnCode::NotAuthorized, false);
+                }
+            } else {
+                let request = AuthRequest {
+                    client_id: connect.client_id.clone(),
+                    username: connect.username.clone(),
+                    password: connect.password.clone(),
+                    peer_addr: self.peer_addr.clone(),
+                };
+                match authenticator.authenticate(&request).await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        warn!("Client '{}' from {} failed authentication", connect.client_id, self.peer_addr);
+                        self.send_connack(ConnectReturnCode::BadUsernameOrPassword, false)?;
+                        return Err(Error::Authentication(format!("Bad credentials for client '{}'", connect.client_id)));
+                    }
+                    Err(e) => {
+                        warn!("Could not authenticate client '{}': {}", connect.client_id, e);
+                        self.send_connack(ConnectReturnCode::ServerUnavailable, false)?;
+                        return Err(e);
+                    }
                 }
             }
         }
 
+        self.accept_connect(connect, None).await
+    }
+
+    /// Start the session of an authenticated client and send its CONNACK
+    ///
+    /// `auth_data` is the final data of an enhanced authentication exchange.
+    async fn accept_connect(&mut self, mut connect: ConnectPacket, auth_data: Option<Bytes>) -> Result<()> {
+        // Sessions are keyed by client ID, so a client leaving it to the server
+        // gets one of its own
+        let mut assigned_client_id = None;
+        if connect.client_id.is_empty() {
+            connect.client_id = Self::generate_client_id();
+            info!("Assigned client ID '{}' to client from {}", connect.client_id, self.peer_addr);
+            assigned_client_id = Some(connect.client_id.clone());
+        }
+
         // Store client information
         self.client_id = Some(connect.client_id.clone());
         self.username = connect.username.clone();
 
-        // Check for existing session
-        let session_present = {
-            let session = self.session_manager.get_session(&connect.client_id).await;
-            session.is_some()
+        // The broker publishes the will on the client's behalf, so the client
+        // needs permission to publish to its topic
+        if let Some(will_topic) = connect.will_topic.as_deref().filter(|_| connect.will_flag) {
+            if !self.authorized(Access::Publish, will_topic).await {
+                warn!("Client '{}' is not authorized to publish its will to '{}'", connect.client_id, will_topic);
+                self.send_connack(ConnectReturnCode::NotAuthorized, false)?;
+                return Err(Error::Authentication(format!("Will topic '{}' is not authorized", will_topic)));
+            }
+        }
+
+        // Reconnecting within the will delay interval means the will is not sent
+        if self.session_manager.cancel_will(&connect.client_id).await {
+            info!("Cancelled delayed will of reconnected client: {}", connect.client_id);
+        }
+
+        // MQTT 5.0 lets the server impose its own keep-alive through the CONNACK
+        let mut properties = ConnAckProperties::new();
+        let mut keep_alive = connect.keep_alive;
+        if connect.protocol_version == 5 {
+            if let Some(server_keep_alive) = self.config.server_keep_alive {
+                keep_alive = server_keep_alive;
+                properties = properties.server_keep_alive(server_keep_alive);
+            }
+        }
+        self.keep_alive = (keep_alive > 0).then(|| Duration::from_secs(keep_alive as u64));
+
+        // MQTT 5.0 clients request a Session Expiry Interval, 3.1.1 clients get one
+        // from their clean session flag
+        let requested_expiry = connect.properties.as_ref().and_then(|p| p.session_expiry_interval);
+        let session_expiry = if connect.protocol_version == 5 {
+            self.grant_session_expiry(requested_expiry.unwrap_or(0))
+        } else if connect.clean_session {
+            0
+        } else {
+            self.grant_session_expiry(SESSION_NEVER_EXPIRES)
+        };
+        if requested_expiry.is_some() {
+            properties = properties.session_expiry_interval(session_expiry);
+        }
+        if let Some(client_id) = assigned_client_id.filter(|_| connect.protocol_version == 5) {
+            properties = properties.assigned_client_identifier(client_id);
+        }
+        if connect.protocol_version == 5 && self.config.max_inflight != DEFAULT_RECEIVE_MAXIMUM {
+            properties = properties.receive_maximum(self.config.max_inflight);
+        }
+        if let Some(provider) = &self.auth_provider {
+            properties = properties.authentication_method(provider.method().to_string());
+            if let Some(data) = auth_data {
+                properties = properties.authentication_data(data);
+            }
+        }
+        let properties = (!properties.is_empty()).then_some(properties);
+
+        // A connection being taken over ends without DISCONNECT, so its will is
+        // not simply replaced by the one in this CONNECT
+        let replaced_will = match self.session_manager.get_connection(&connect.client_id).await {
+            Some(_) => self.session_manager.take_will(&connect.client_id).await,
+            None => None,
         };
 
-        // Create or update session
-        self.session_manager.create_session(
+        // Resume a persistent session or start a new one
+        let session_present = self.session_manager.create_session(
             connect.client_id.clone(),
             connect.username.clone(),
             connect.clean_session,
         ).await;
+        self.session_manager.set_session_expiry(&connect.client_id, session_expiry).await;
+        self.session_manager.set_will(&connect.client_id, Self::will_from_connect(&connect)).await;
+
+        // Deliveries awaiting acknowledgment are capped by the client's Receive Maximum
+        let receive_maximum = connect.properties.as_ref()
+            .and_then(|p| p.receive_maximum)
+            .unwrap_or(DEFAULT_RECEIVE_MAXIMUM);
+        let window = self.config.max_inflight.min(receive_maximum);
+        self.session_manager.set_inflight_window(&connect.client_id, window).await;
 
         // Send CONNACK
-        self.send_connack(ConnectReturnCode::Accepted, session_present).await
+        self.send_connack_with_properties(ConnectReturnCode::Accepted, session_present, properties)?;
+
+        // Route messages for this client to this connection; messages queued
+        // while it was offline follow the CONNACK
+        let previous = self.session_manager.register_connection(
+            connect.client_id.clone(),
+            self.outbound_tx.clone(),
+        ).await;
+
+        // Only one connection per client ID; the older one is closed
+        if let Some(previous) = previous {
+            info!("Client '{}' connected again, closing its previous connection", connect.client_id);
+            if self.config.protocol_version == 5 {
+                let _ = previous.send(Outbound::Packet(Box::new(Self::disconnect_packet(ReasonCode::SessionTakenOver))));
+            }
+            let _ = previous.send(Outbound::Close);
+        }
+
+        // The will of the replaced connection is published, unless the session
+        // carries on and has a will delay, which the new connection arrived within
+        if let Some(will) = replaced_will {
+            if session_present && !will.delay.is_zero() {
+                debug!("Client '{}' took over its session within the will delay, not publishing its will", connect.client_id);
+            } else {
+                Self::publish_will(&self.session_manager, &self.message_router, &connect.client_id, will.message).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Start the enhanced authentication exchange requested by a CONNECT
+    async fn begin_connect_auth(&mut self, connect: ConnectPacket, method: &str) -> Result<()> {
+        let Some(provider) = self.config.find_authentication_provider(method).cloned() else {
+            warn!("Client '{}' requested unsupported authentication method '{}'", connect.client_id, method);
+            self.send_connack(ConnectReturnCode::BadAuthenticationMethod, false)?;
+            return Err(Error::Authentication(format!("Unsupported authentication method: {}", method)));
+        };
+
+        let exchange = provider.begin(&connect.client_id);
+        let data = connect.properties.as_ref().and_then(|p| p.authentication_data.clone());
+        self.auth_provider = Some(provider);
+        self.pending_connect = Some(Box::new(connect));
+        self.auth_step(exchange, data).await
+    }
+
+    /// Handle an AUTH packet continuing an exchange or starting re-authentication
+    async fn handle_auth(&mut self, auth: AuthPacket) -> Result<()> {
+        let (method, data) = auth.properties
+            .map(|p| (p.authentication_method, p.authentication_data))
+            .unwrap_or_default();
+        let provider = match &self.auth_provider {
+            Some(provider) if method.as_deref() == Some(provider.method()) => Arc::clone(provider),
+            // No method was negotiated in the CONNECT, or the client switched methods
+            _ => return self.unexpected_auth(),
+        };
+
+        let exchange = match (ReasonCode::from_u8(auth.reason_code), self.auth_exchange.take()) {
+            (Some(ReasonCode::ContinueAuthentication), Some(exchange)) => exchange,
+            (Some(ReasonCode::ReAuthenticate), None) if self.pending_connect.is_none() => {
+                info!("Client '{}' is re-authenticating", self.client_id.as_deref().unwrap_or("unknown"));
+                provider.begin(self.client_id.as_deref().unwrap_or_default())
+            }
+            _ => return self.unexpected_auth(),
+        };
+        self.auth_step(exchange, data).await
+    }
+
+    /// Feed client data to an exchange and act on the outcome
+    async fn auth_step(&mut self, mut exchange: Box<dyn AuthenticationExchange>, data: Option<Bytes>) -> Result<()> {
+        let connecting = self.pending_connect.is_some();
+        match exchange.step(data.as_deref()) {
+            AuthStep::Continue(challenge) => {
+                self.auth_exchange = Some(exchange);
+                self.send_auth(ReasonCode::ContinueAuthentication, Some(challenge))
+            }
+            AuthStep::Success { username, data } => match self.pending_connect.take() {
+                Some(mut connect) => {
+                    // The CONNECT may not claim to be anyone but the proven user
+                    if connect.username.as_ref().is_some_and(|claimed| *claimed != username) {
+                        warn!("Client '{}' authenticated as '{}' but claimed another username", connect.client_id, username);
+                        self.send_connack(ConnectReturnCode::NotAuthorized, false)?;
+                        return Err(Error::Authentication(format!("Username does not match authenticated user '{}'", username)));
+                    }
+                    connect.username = Some(username);
+                    self.accept_connect(*connect, data).await
+                }
+                // Re-authentication must prove the identity bound at connect time
+                None if self.username.as_deref() != Some(username.as_str()) => {
+                    warn!(
+                        "Client '{}' re-authenticated as different user '{}'",
+                        self.client_id.as_deref().unwrap_or("unknown"),
+                        username
+                    );
+                    self.send_disconnect(ReasonCode::NotAuthorized);
+                    Err(Error::Authentication(format!("Re-authenticated as different user '{}'", username)))
+                }
+                None => {
+                    info!("Client '{}' re-authenticated", self.client_id.as_deref().unwrap_or("unknown"));
+                    self.send_auth(ReasonCode::Success, data)
+                }
+            },
+            AuthStep::Failure(reason) => {
+                warn!("Authentication failed: {}", reason);
+                if connecting {
+                    self.send_connack(ConnectReturnCode::NotAuthorized, false)?;
+                } else {
+                    self.send_disconnect(ReasonCode::NotAuthorized);
+                }
+                Err(Error::Authentication(reason))
+            }
+        }
+    }
+
+    fn unexpected_auth(&mut self) -> Result<()> {
+        warn!("Unexpected AUTH packet from client");
+        self.send_disconnect(ReasonCode::ProtocolError);
+        Err(Error::Protocol("Unexpected AUTH packet".to_string()))
+    }
+
+    /// Apply the configured cap to a requested Session Expiry Interval
+    fn grant_session_expiry(&self, requested: u32) -> u32 {
+        match self.config.max_session_expiry_interval {
+            Some(max) => requested.min(max),
+            None => requested,
+        }
+    }
+
+    /// Extract the will message carried by a CONNECT packet
+    fn will_from_connect(connect: &ConnectPacket) -> Option<Will> {
+        if !connect.will_flag {
+            return None;
+        }
+
+        let delay = connect.will_properties.as_ref()
+            .and_then(|properties| properties.will_delay_interval)
+            .map(|seconds| Duration::from_secs(seconds as u64))
+            .unwrap_or_default();
+
+        Some(Will {
+            message: Message {
+                topic: connect.will_topic.clone()?,
+                payload: connect.will_message.clone().unwrap_or_default(),
+                qos: connect.will_qos,
+                retain: connect.will_retain,
+                dup: false,
+                packet_id: None,
+                properties: None,
+                expires_at: None,
+            },
+            delay,
+        })
     }
 
     async fn handle_publish(&mut self, publish: PublishPacket, header: &PacketHeader) -> Result<()> {
         info!("Handling PUBLISH to topic: {}", publish.topic_name);
 
         // Get QoS level and retain flag from the packet header
-        let qos_level = header.qos as u8;
+        let qos_level = header.qos;
         let retain_flag = header.retain;
 
-        // Create message
+        // A retransmitted QoS 2 PUBLISH whose PUBREL has not arrived yet was already
+        // delivered; only the PUBREC needs repeating
+        if qos_level == 2 {
+            if let Some(packet_id) = publish.packet_id {
+                if self.awaiting_pubrel.contains(&packet_id) {
+                    debug!("Duplicate QoS 2 PUBLISH with packet ID: {}", packet_id);
+                    return self.send_pubrec(packet_id, ReasonCode::Success);
+                }
+            }
+
+            // MQTT 5.0 clients were told how many QoS 2 messages may await PUBREL
+            if self.config.protocol_version == 5 && self.awaiting_pubrel.len() >= self.config.max_inflight as usize {
+                warn!("Client {:?} exceeded the Receive Maximum", self.client_id);
+                self.send_disconnect(ReasonCode::ReceiveMaximumExceeded);
+                return Err(Error::Protocol("Receive Maximum exceeded".to_string()));
+            }
+        }
+
+        // A PUBLISH to an invalid topic name is a protocol violation
+        if let Err(e) = TopicName::new(publish.topic_name.as_str()) {
+            warn!("Client {:?} published to an invalid topic: {}", self.client_id, e);
+            self.send_disconnect(ReasonCode::TopicNameInvalid);
+            return Err(e);
+        }
+
+        // Unauthorized messages are acknowledged but neither retained nor routed
+        if !self.authorized(Access::Publish, &publish.topic_name).await {
+            warn!("Client {:?} is not authorized to publish to '{}'", self.client_id, publish.topic_name);
+            return self.acknowledge_publish(qos_level, publish.packet_id, ReasonCode::NotAuthorized);
+        }
+
+        // Create message, keeping the properties subscribers receive. A topic alias
+        // only applies to this connection and subscription identifiers are the
+        // broker's to set.
+        let properties = publish.properties.clone().map(|properties| PublishProperties {
+            topic_alias: None,
+            subscription_identifier: None,
+            ..properties
+        });
+        let expires_at = properties.as_ref()
+            .and_then(|properties| properties.message_expiry_interval)
+            .map(|seconds| Instant::now() + Duration::from_secs(seconds as u64));
         let message = Message {
             topic: publish.topic_name.clone(),
             payload: publish.payload.clone(),
@@ -151,6 +636,8 @@ impl ServerConnection {
             retain: retain_flag,
             dup: header.dup,
             packet_id: publish.packet_id,
+            properties,
+            expires_at,
         };
 
         // Handle retained message
@@ -167,65 +654,105 @@ impl ServerConnection {
         }
 
         // Publish to subscribers
-        self.publish_to_subscribers(&message).await?;
-
-        // Send acknowledgment for QoS 1 and 2
-        if let Some(packet_id) = publish.packet_id {
-            match qos_level {
-                1 => {
-                    // QoS 1: Send PUBACK
-                    info!("Sending PUBACK for QoS 1 message with packet ID: {}", packet_id);
-                    self.send_puback(packet_id).await?;
-                }
-                2 => {
-                    // QoS 2: Send PUBREC first
-                    info!("Sending PUBREC for QoS 2 message with packet ID: {}", packet_id);
-                    self.send_pubrec(packet_id).await?;
-                    
-                    // Wait for PUBREL
-                    info!("Waiting for PUBREL for packet ID: {}", packet_id);
-                    let packet = self.read_packet().await?;
-                    match packet.payload {
-                        PacketPayload::PubRel(pubrel) => {
-                            if pubrel.packet_id != packet_id {
-                                return Err(Error::Protocol("Mismatched PUBREL packet ID".to_string()));
-                            }
-                            // Send PUBCOMP
-                            info!("Sending PUBCOMP for packet ID: {}", packet_id);
-                            self.send_pubcomp(packet_id).await?;
-                        }
-                        _ => return Err(Error::Protocol("Expected PUBREL packet".to_string())),
-                    }
+        let publisher = self.client_id.clone().unwrap_or_default();
+        Self::publish_to_subscribers(&self.session_manager, &publisher, &message).await;
+
+        self.acknowledge_publish(qos_level, publish.packet_id, ReasonCode::Success)
+    }
+
+    /// Send the PUBACK or PUBREC a QoS 1 or 2 PUBLISH calls for
+    ///
+    /// MQTT 3.1.1 has no way to report a failure, so the reason code only
+    /// reaches MQTT 5.0 clients.
+    fn acknowledge_publish(&mut self, qos_level: u8, packet_id: Option<u16>, reason_code: ReasonCode) -> Result<()> {
+        let Some(packet_id) = packet_id else {
+            return Ok(());
+        };
+        match qos_level {
+            1 => {
+                // QoS 1: Send PUBACK
+                info!("Sending PUBACK for QoS 1 message with packet ID: {}", packet_id);
+                self.send_puback(packet_id, reason_code)
+            }
+            2 => {
+                // QoS 2: Send PUBREC; the PUBREL is handled whenever it arrives. A
+                // failure reason code ends the exchange, so no PUBREL follows it.
+                info!("Sending PUBREC for QoS 2 message with packet ID: {}", packet_id);
+                if reason_code == ReasonCode::Success || self.config.protocol_version != 5 {
+                    self.awaiting_pubrel.insert(packet_id);
                 }
-                _ => {}
+                self.send_pubrec(packet_id, reason_code)
             }
+            _ => Ok(()),
         }
+    }
 
-        Ok(())
+    /// Whether the configured authorizer lets this client access a topic
+    async fn authorized(&mut self, access: Access, topic: &str) -> bool {
+        match &self.config.authorizer {
+            Some(authorizer) => {
+                let client_id = self.client_id.as_deref().unwrap_or_default();
+                authorizer.authorize(client_id, self.username.as_deref(), access, topic).await
+            }
+            None => true,
+        }
+    }
+
+    /// Reason code carried by an acknowledgement, omitted when it adds nothing
+    fn ack_reason_code(&self, reason_code: ReasonCode) -> Option<u8> {
+        (self.config.protocol_version == 5 && reason_code != ReasonCode::Success).then_some(reason_code as u8)
     }
 
     async fn handle_subscribe(&mut self, subscribe: SubscribePacket) -> Result<()> {
         info!("Handling SUBSCRIBE with packet ID: {}", subscribe.packet_id);
 
         let mut return_codes = Vec::new();
+        let mut granted = Vec::new();
 
         for topic_filter in &subscribe.topic_filters {
+            // Only MQTT 5.0 clients can join shared subscription groups
+            let filter = match topic::TopicFilter::for_protocol_version(topic_filter.topic.as_str(), self.config.protocol_version) {
+                Ok(filter) => filter,
+                Err(e) => {
+                    warn!("Client {:?} sent an invalid topic filter: {}", self.client_id, e);
+                    return_codes.push(if self.config.protocol_version == 5 {
+                        ReasonCode::TopicFilterInvalid as u8
+                    } else {
+                        ReasonCode::UnspecifiedError as u8
+                    });
+                    continue;
+                }
+            };
+
+            // Shared subscriptions are authorized on the filter they match topics with
+            if !self.authorized(Access::Subscribe, filter.filter()).await {
+                warn!("Client {:?} is not authorized to subscribe to '{}'", self.client_id, topic_filter.topic);
+                // MQTT 3.1.1 only knows the generic 0x80 failure return code
+                return_codes.push(if self.config.protocol_version == 5 {
+                    ReasonCode::NotAuthorized as u8
+                } else {
+                    ReasonCode::UnspecifiedError as u8
+                });
+                continue;
+            }
+
             // Add subscription
             let qos = QoS::from_u8(topic_filter.qos).unwrap_or(QoS::AtMostOnce);
-            self.session_manager.add_subscription(
-                self.client_id.clone().unwrap_or_default(),
-                topic_filter.topic.clone(),
-                qos,
-            ).await;
+            let subscription = Subscription::new(self.client_id.clone().unwrap_or_default(), topic_filter.topic.clone(), qos);
+            self.session_manager.subscribe(Subscription { shared: filter.is_shared(), ..subscription }).await;
 
             return_codes.push(topic_filter.qos);
+            // Retained messages are not sent for shared subscriptions
+            if filter.share_name().is_none() {
+                granted.push(topic_filter.clone());
+            }
         }
 
         // Send SUBACK
-        self.send_suback(subscribe.packet_id, return_codes).await?;
+        self.send_suback(subscribe.packet_id, return_codes)?;
 
-        // Send retained messages for matching topics
-        self.send_retained_messages(&subscribe.topic_filters).await?;
+        // Send retained messages for the granted topic filters
+        self.send_retained_messages(&granted).await;
 
         Ok(())
     }
@@ -233,53 +760,144 @@ impl ServerConnection {
     async fn handle_unsubscribe(&mut self, unsubscribe: UnsubscribePacket) -> Result<()> {
         info!("Handling UNSUBSCRIBE with packet ID: {}", unsubscribe.packet_id);
 
+        let client_id = self.client_id.clone().unwrap_or_default();
+        let mut reason_codes = Vec::new();
         for topic_filter in &unsubscribe.topic_filters {
-            // Remove subscription
-            if let Some(client_id) = &self.client_id {
-                self.session_manager.remove_subscription(client_id, topic_filter).await;
-            }
+            let filter = topic::TopicFilter::for_protocol_version(topic_filter.as_str(), self.config.protocol_version);
+            let reason_code = if let Err(e) = filter {
+                warn!("Client '{}' sent an invalid topic filter: {}", client_id, e);
+                ReasonCode::TopicFilterInvalid
+            } else if self.session_manager.remove_subscription(&client_id, topic_filter).await {
+                ReasonCode::Success
+            } else {
+                ReasonCode::NoSubscriptionExisted
+            };
+            reason_codes.push(reason_code as u8);
         }
 
         // Send UNSUBACK
-        self.send_unsuback(unsubscribe.packet_id).await
+        self.send_unsuback(unsubscribe.packet_id, reason_codes)
+    }
+
+    async fn handle_pubrec(&mut self, pubrec: PubRecPacket) -> Result<()> {
+        debug!("Received PUBREC for packet ID: {}", pubrec.packet_id);
+
+        // A failure reason code ends the exchange without PUBREL
+        if pubrec.reason_code.is_some_and(|code| code >= 0x80) {
+            self.complete_delivery(PacketType::PubRec, pubrec.packet_id).await;
+            return Ok(());
+        }
+
+        if let Some(client_id) = &self.client_id {
+            if !self.session_manager.release_delivery(client_id, pubrec.packet_id).await {
+                debug!("PUBREC for unknown packet ID: {}", pubrec.packet_id);
+            }
+        }
+        self.send_pubrel(pubrec.packet_id)
     }
 
-    async fn handle_pingreq(&mut self) -> Result<()> {
+    fn handle_pubrel(&mut self, pubrel: PubRelPacket) -> Result<()> {
+        debug!("Received PUBREL for packet ID: {}", pubrel.packet_id);
+        if !self.awaiting_pubrel.remove(&pubrel.packet_id) {
+            debug!("PUBREL for unknown packet ID: {}", pubrel.packet_id);
+        }
+        info!("Sending PUBCOMP for packet ID: {}", pubrel.packet_id);
+        self.send_pubcomp(pubrel.packet_id)
+    }
+
+    fn handle_pingreq(&mut self) -> Result<()> {
         info!("Handling PINGREQ");
-        self.send_pingresp().await
+        self.send_pingresp()
     }
 
-    async fn handle_disconnect(&mut self) -> Result<()> {
+    async fn handle_disconnect(&mut self, disconnect: DisconnectPacket) -> Result<()> {
         info!("Handling DISCONNECT");
-        // Clean up session if clean_session is true
         if let Some(client_id) = &self.client_id {
-            let session = self.session_manager.get_session(client_id).await;
-            if let Some(session) = session {
-                if session.clean_session {
-                    self.session_manager.remove_session(client_id).await;
+            // A clean disconnect discards the will message, unless an MQTT 5.0
+            // client asks for it to be sent; `cleanup` then publishes it
+            let reason_code = disconnect.reason_code.and_then(ReasonCode::from_u8);
+            if reason_code == Some(ReasonCode::DisconnectWithWillMessage) {
+                debug!("Client '{}' disconnected with its will message", client_id);
+            } else if self.session_manager.take_will(client_id).await.is_some() {
+                debug!("Discarded will of client: {}", client_id);
+            }
+
+            // MQTT 5.0 clients may change their Session Expiry Interval on the way out,
+            // but not turn a session ending with the connection into a persistent one
+            let requested = disconnect.properties.as_ref().and_then(|p| p.session_expiry_interval);
+            if let Some(requested) = requested {
+                let current = self.session_manager.get_session(client_id).await
+                    .map_or(0, |session| session.expiry_interval);
+                if current == 0 && requested != 0 {
+                    warn!("Client '{}' cannot set a session expiry on DISCONNECT after connecting with none", client_id);
+                } else {
+                    let granted = self.grant_session_expiry(requested);
+                    self.session_manager.set_session_expiry(client_id, granted).await;
                 }
             }
         }
+
+        // The session itself is detached in `cleanup`
         Err(Error::Disconnected)
     }
 
-    async fn publish_to_subscribers(&self, message: &Message) -> Result<()> {
-        let subscriptions = self.session_manager.get_all_subscriptions().await;
-        
-        for (topic_filter, subs) in subscriptions.iter() {
-            if MessageRouter::topic_matches(topic_filter, &message.topic) {
-                for subscription in subs {
-                    // TODO: Send message to subscriber
-                    debug!("Would send message to client: {}", subscription.client_id);
-                }
+    /// Route a message published by `publisher` to the matching subscriptions
+    async fn publish_to_subscribers(session_manager: &SessionManager, publisher: &str, message: &Message) {
+        let subscriptions = session_manager.get_matching_subscriptions(&message.topic).await;
+
+        // A client with several overlapping subscriptions receives the message once,
+        // at the highest QoS granted by any of them. Each shared subscription group
+        // receives it once as well, independently of the other subscriptions.
+        let mut recipients: HashMap<String, QoS> = HashMap::new();
+        let mut groups: HashMap<String, Vec<Subscription>> = HashMap::new();
+        for subscription in subscriptions {
+            if subscription.is_shared() {
+                groups.entry(subscription.topic_filter.clone()).or_default().push(subscription);
+                continue;
+            }
+            let granted = recipients
+                .entry(subscription.client_id.clone())
+                .or_insert(subscription.qos);
+            if (subscription.qos as u8) > (*granted as u8) {
+                *granted = subscription.qos;
             }
         }
 
-        Ok(())
+        for (client_id, granted_qos) in recipients {
+            let delivery = Message {
+                topic: message.topic.clone(),
+                payload: message.payload.clone(),
+                qos: message.qos.min(granted_qos as u8),
+                retain: false,
+                dup: false,
+                packet_id: None,
+                properties: message.properties.clone(),
+                expires_at: message.expires_at,
+            };
+
+            session_manager.deliver(&client_id, delivery).await;
+        }
+
+        for (group, members) in groups {
+            let delivery = Message { retain: false, dup: false, packet_id: None, ..message.clone() };
+            session_manager.deliver_shared(&group, &members, publisher, &delivery).await;
+        }
+    }
+
+    /// End the delivery a PUBACK, PUBCOMP or failed PUBREC acknowledges
+    async fn complete_delivery(&mut self, kind: PacketType, packet_id: u16) {
+        if let Some(client_id) = &self.client_id {
+            if !self.session_manager.complete_delivery(client_id, packet_id, kind).await {
+                debug!("{:?} for unknown packet ID: {}", kind, packet_id);
+            }
+        }
     }
 
     /// Send retained messages for matching topic filters to the client
-    async fn send_retained_messages(&mut self, topic_filters: &[TopicFilter]) -> Result<()> {
+    ///
+    /// They are delivered through the session, so they take their place in the
+    /// client's inflight window like any other message.
+    async fn send_retained_messages(&mut self, topic_filters: &[TopicFilter]) {
         // Collect topic filter strings
         let topic_filter_strings: Vec<String> = topic_filters
             .iter()
@@ -290,45 +908,13 @@ impl ServerConnection {
         let messages = self.message_router.get_retained_messages_for_filters(&topic_filter_strings).await;
         
         // Send each retained message
+        let client_id = self.client_id.clone().unwrap_or_default();
         for message in messages {
-            info!("Sending retained message for topic '{}' to client '{}'", 
-                  message.topic, self.client_id.as_ref().unwrap_or(&"unknown".to_string()));
-            
-            // Create a publish packet for the retained message
-            let publish = PublishPacket {
-                topic_name: message.topic,
-                packet_id: if message.qos > 0 { Some(self.next_packet_id()) } else { None },
-                payload: message.payload,
-                properties: None, // No MQTT 5.0 properties for now
-            };
-
-            let packet = Packet {
-                header: PacketHeader {
-                    packet_type: PacketType::Publish,
-                    dup: false,
-                    qos: message.qos,
-                    retain: true, // Mark as retained
-                    remaining_length: 0, // Will be calculated by encoder
-                },
-                payload: PacketPayload::Publish(publish),
-            };
-
-            let data = self.codec.encode(&packet)?;
-            self.write_all(&data).await?;
-        }
-
-        Ok(())
-    }
+            info!("Sending retained message for topic '{}' to client '{}'", message.topic, client_id);
 
-    /// Get next packet ID for QoS 1 and 2 messages
-    fn next_packet_id(&mut self) -> u16 {
-        static mut COUNTER: u16 = 0;
-        unsafe {
-            COUNTER = COUNTER.wrapping_add(1);
-            if COUNTER == 0 {
-                COUNTER = 1;
-            }
-            COUNTER
+            // Mark as retained
+            let delivery = Message { retain: true, dup: false, packet_id: None, ..message };
+            self.session_manager.deliver(&client_id, delivery).await;
         }
     }
 
@@ -352,18 +938,68 @@ impl ServerConnection {
         }
     }
 
-    async fn write_all(&mut self, data: &[u8]) -> Result<()> {
-        self.stream.write_all(data).await.map_err(Error::Io)?;
-        self.stream.flush().await.map_err(Error::Io)?;
-        Ok(())
+    /// DISCONNECT packet sent by the server, carrying the reason for closing the connection
+    fn disconnect_packet(reason_code: ReasonCode) -> Packet {
+        Packet {
+            header: PacketHeader {
+                packet_type: PacketType::Disconnect,
+                dup: false,
+                qos: 0,
+                retain: false,
+                remaining_length: 0,
+            },
+            payload: PacketPayload::Disconnect(DisconnectPacket {
+                reason_code: Some(reason_code as u8),
+                properties: None,
+            }),
+        }
+    }
+
+    /// Client ID for a client that connected with an empty one
+    fn generate_client_id() -> String {
+        let suffix: String = rand::thread_rng()
+            .sample_iter(&rand::distributions::Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect();
+        format!("auto-{}", suffix)
+    }
+
+    /// Tell an MQTT 5.0 client why the server is closing the connection
+    ///
+    /// MQTT 3.1.1 has no server-initiated DISCONNECT, so the connection is just closed.
+    fn send_disconnect(&self, reason_code: ReasonCode) {
+        if self.config.protocol_version == 5 {
+            let _ = self.send_packet(Self::disconnect_packet(reason_code));
+        }
+    }
+
+    /// Queue an item for the writer task
+    fn queue(&self, outbound: Outbound) -> Result<()> {
+        self.outbound_tx.send(outbound)
+            .map_err(|_| Error::Connection("Connection writer has stopped".to_string()))
+    }
+
+    /// Queue a control packet for the writer task
+    fn send_packet(&self, packet: Packet) -> Result<()> {
+        self.queue(Outbound::Packet(Box::new(packet)))
     }
 
     // Response packet sending methods
-    async fn send_connack(&mut self, return_code: ConnectReturnCode, session_present: bool) -> Result<()> {
+    fn send_connack(&mut self, return_code: ConnectReturnCode, session_present: bool) -> Result<()> {
+        self.send_connack_with_properties(return_code, session_present, None)
+    }
+
+    fn send_connack_with_properties(
+        &mut self,
+        return_code: ConnectReturnCode,
+        session_present: bool,
+        properties: Option<ConnAckProperties>,
+    ) -> Result<()> {
         let connack = ConnAckPacket {
             session_present,
-            return_code,
-            properties: None,
+            return_code: return_code.for_version(self.config.protocol_version),
+            properties,
         };
 
         let packet = Packet {
@@ -377,14 +1013,38 @@ impl ServerConnection {
             payload: PacketPayload::ConnAck(connack),
         };
 
-        let data = self.codec.encode(&packet)?;
-        self.write_all(&data).await
+        self.send_packet(packet)
+    }
+
+    /// Send AUTH with the negotiated authentication method
+    fn send_auth(&mut self, reason_code: ReasonCode, data: Option<Bytes>) -> Result<()> {
+        let mut properties = AuthProperties::new();
+        if let Some(provider) = &self.auth_provider {
+            properties = properties.authentication_method(provider.method().to_string());
+        }
+        properties.authentication_data = data;
+
+        let packet = Packet {
+            header: PacketHeader {
+                packet_type: PacketType::Auth,
+                dup: false,
+                qos: 0,
+                retain: false,
+                remaining_length: 0,
+            },
+            payload: PacketPayload::Auth(AuthPacket {
+                reason_code: reason_code as u8,
+                properties: Some(properties),
+            }),
+        };
+
+        self.send_packet(packet)
     }
 
-    async fn send_puback(&mut self, packet_id: u16) -> Result<()> {
+    fn send_puback(&mut self, packet_id: u16, reason_code: ReasonCode) -> Result<()> {
         let puback = PubAckPacket {
             packet_id,
-            reason_code: None,
+            reason_code: self.ack_reason_code(reason_code),
             properties: None,
         };
 
@@ -399,11 +1059,10 @@ impl ServerConnection {
             payload: PacketPayload::PubAck(puback),
         };
 
-        let data = self.codec.encode(&packet)?;
-        self.write_all(&data).await
+        self.send_packet(packet)
     }
 
-    async fn send_suback(&mut self, packet_id: u16, return_codes: Vec<u8>) -> Result<()> {
+    fn send_suback(&mut self, packet_id: u16, return_codes: Vec<u8>) -> Result<()> {
         let suback = SubAckPacket {
             packet_id,
             return_codes,
@@ -421,14 +1080,13 @@ impl ServerConnection {
             payload: PacketPayload::SubAck(suback),
         };
 
-        let data = self.codec.encode(&packet)?;
-        self.write_all(&data).await
+        self.send_packet(packet)
     }
 
-    async fn send_unsuback(&mut self, packet_id: u16) -> Result<()> {
+    fn send_unsuback(&mut self, packet_id: u16, reason_codes: Vec<u8>) -> Result<()> {
         let unsuback = UnsubAckPacket {
             packet_id,
-            reason_codes: vec![0], // Success
+            reason_codes,
             properties: None,
         };
 
@@ -443,14 +1101,13 @@ impl ServerConnection {
             payload: PacketPayload::UnsubAck(unsuback),
         };
 
-        let data = self.codec.encode(&packet)?;
-        self.write_all(&data).await
+        self.send_packet(packet)
     }
 
-    async fn send_pubrec(&mut self, packet_id: u16) -> Result<()> {
+    fn send_pubrec(&mut self, packet_id: u16, reason_code: ReasonCode) -> Result<()> {
         let pubrec = PubRecPacket {
             packet_id,
-            reason_code: None,
+            reason_code: self.ack_reason_code(reason_code),
             properties: None,
         };
 
@@ -465,11 +1122,14 @@ impl ServerConnection {
             payload: PacketPayload::PubRec(pubrec),
         };
 
-        let data = self.codec.encode(&packet)?;
-        self.write_all(&data).await
+        self.send_packet(packet)
+    }
+
+    fn send_pubrel(&mut self, packet_id: u16) -> Result<()> {
+        self.queue(Outbound::Release(packet_id))
     }
 
-    async fn send_pubcomp(&mut self, packet_id: u16) -> Result<()> {
+    fn send_pubcomp(&mut self, packet_id: u16) -> Result<()> {
         let pubcomp = PubCompPacket {
             packet_id,
             reason_code: None,
@@ -487,11 +1147,10 @@ impl ServerConnection {
             payload: PacketPayload::PubComp(pubcomp),
         };
 
-        let data = self.codec.encode(&packet)?;
-        self.write_all(&data).await
+        self.send_packet(packet)
     }
 
-    async fn send_pingresp(&mut self) -> Result<()> {
+    fn send_pingresp(&mut self) -> Result<()> {
         let packet = Packet {
             header: PacketHeader {
                 packet_type: PacketType::PingResp,
@@ -503,40 +1162,122 @@ impl ServerConnection {
             payload: PacketPayload::PingResp,
         };
 
-        let data = self.codec.encode(&packet)?;
-        self.write_all(&data).await
+        self.send_packet(packet)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::protocol::QoS;
-    use crate::server::{Session, Subscription};
+/// Writer task of a connection
+///
+/// Drains the outbound queue in order until it is asked to close or the
+/// socket fails.
+struct ConnectionWriter {
+    stream: TransportWriter,
+    codec: MqttCodec,
+}
 
-    #[test]
-    fn test_topic_matches() {
-        // Exact match
-        assert!(MessageRouter::topic_matches("home/temp", "home/temp"));
-        
-        // Single level wildcard
-        assert!(MessageRouter::topic_matches("home/+/temp", "home/living/temp"));
-        assert!(MessageRouter::topic_matches("home/+/temp", "home/bedroom/temp"));
-        assert!(!MessageRouter::topic_matches("home/+/temp", "home/living/bedroom/temp"));
-        
-        // Multi-level wildcard
-        assert!(MessageRouter::topic_matches("home/#", "home/living/temp"));
-        assert!(MessageRouter::topic_matches("home/#", "home/bedroom/humidity"));
-        assert!(MessageRouter::topic_matches("home/#", "home"));
-        assert!(!MessageRouter::topic_matches("home/#", "office/temp"));
-        
-        // Edge cases
-        assert!(MessageRouter::topic_matches("#", "any/topic"));
-        assert!(MessageRouter::topic_matches("+", "single"));
-        assert!(!MessageRouter::topic_matches("home/+/temp", "home/temp"));
+impl ConnectionWriter {
+    fn new(stream: TransportWriter, codec: MqttCodec) -> Self {
+        Self { stream, codec }
     }
 
-    #[test]
+    async fn run(mut self, mut outbound_rx: mpsc::UnboundedReceiver<Outbound>) -> Result<()> {
+        while let Some(outbound) = outbound_rx.recv().await {
+            let packet = match outbound {
+                Outbound::Message(message) => Self::publish_packet(*message),
+                Outbound::Release(packet_id) => Self::pubrel_packet(packet_id),
+                Outbound::Packet(packet) => *packet,
+                Outbound::Close => break,
+            };
+
+            let data = self.codec.encode(&packet)?;
+            self.stream.write_all(&data).await.map_err(Error::Io)?;
+            self.stream.flush().await.map_err(Error::Io)?;
+        }
+
+        self.stream.shutdown().await.map_err(Error::Io)
+    }
+
+    /// Build the PUBLISH packet delivering an application message
+    ///
+    /// The Message Expiry Interval is reduced by the time the message has spent
+    /// on the broker.
+    fn publish_packet(message: Message) -> Packet {
+        let mut properties = message.properties;
+        if let (Some(properties), Some(expires_at)) = (properties.as_mut(), message.expires_at) {
+            let remaining = expires_at.saturating_duration_since(Instant::now());
+            properties.message_expiry_interval = Some(remaining.as_secs_f64().ceil().max(1.0) as u32);
+        }
+
+        let publish = PublishPacket {
+            topic_name: message.topic,
+            packet_id: message.packet_id,
+            payload: message.payload,
+            properties,
+        };
+
+        Packet {
+            header: PacketHeader {
+                packet_type: PacketType::Publish,
+                dup: message.dup,
+                qos: message.qos,
+                retain: message.retain,
+                remaining_length: 0, // Will be calculated by encoder
+            },
+            payload: PacketPayload::Publish(publish),
+        }
+    }
+
+    fn pubrel_packet(packet_id: u16) -> Packet {
+        let pubrel = PubRelPacket {
+            packet_id,
+            reason_code: None,
+            properties: None,
+        };
+
+        Packet {
+            header: PacketHeader {
+                packet_type: PacketType::PubRel,
+                dup: false,
+                qos: 1, // PUBREL must use QoS 1
+                retain: false,
+                remaining_length: 0,
+            },
+            payload: PacketPayload::PubRel(pubrel),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::QoS;
+    use crate::server::{Session, Subscription};
+    use std::net::SocketAddr;
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn test_topic_matches() {
+        // Exact match
+        assert!(MessageRouter::topic_matches("home/temp", "home/temp"));
+        
+        // Single level wildcard
+        assert!(MessageRouter::topic_matches("home/+/temp", "home/living/temp"));
+        assert!(MessageRouter::topic_matches("home/+/temp", "home/bedroom/temp"));
+        assert!(!MessageRouter::topic_matches("home/+/temp", "home/living/bedroom/temp"));
+        
+        // Multi-level wildcard
+        assert!(MessageRouter::topic_matches("home/#", "home/living/temp"));
+        assert!(MessageRouter::topic_matches("home/#", "home/bedroom/humidity"));
+        assert!(MessageRouter::topic_matches("home/#", "home"));
+        assert!(!MessageRouter::topic_matches("home/#", "office/temp"));
+        
+        // Edge cases
+        assert!(MessageRouter::topic_matches("#", "any/topic"));
+        assert!(MessageRouter::topic_matches("+", "single"));
+        assert!(!MessageRouter::topic_matches("home/+/temp", "home/temp"));
+    }
+
+    #[test]
     fn test_qos_handling_in_subscription() {
         let subscription1 = Subscription::new("client1".to_string(), "topic1".to_string(), QoS::AtMostOnce);
         let subscription2 = Subscription::new("client2".to_string(), "topic2".to_string(), QoS::AtLeastOnce);
@@ -552,14 +1293,15 @@ mod tests {
         let mut session = Session::new("client1".to_string(), None, false);
         
         // Add subscriptions with different QoS levels
-        session.subscriptions.insert("topic1".to_string(), QoS::AtMostOnce);
-        session.subscriptions.insert("topic2".to_string(), QoS::AtLeastOnce);
-        session.subscriptions.insert("topic3".to_string(), QoS::ExactlyOnce);
+        for (topic_filter, qos) in [("topic1", QoS::AtMostOnce), ("topic2", QoS::AtLeastOnce), ("topic3", QoS::ExactlyOnce)] {
+            let subscription = Subscription::new("client1".to_string(), topic_filter.to_string(), qos);
+            session.subscriptions.insert(topic_filter.to_string(), subscription);
+        }
 
         assert_eq!(session.subscriptions.len(), 3);
-        assert_eq!(session.subscriptions.get("topic1"), Some(&QoS::AtMostOnce));
-        assert_eq!(session.subscriptions.get("topic2"), Some(&QoS::AtLeastOnce));
-        assert_eq!(session.subscriptions.get("topic3"), Some(&QoS::ExactlyOnce));
+        assert_eq!(session.subscriptions.get("topic1").map(|sub| sub.qos), Some(QoS::AtMostOnce));
+        assert_eq!(session.subscriptions.get("topic2").map(|sub| sub.qos), Some(QoS::AtLeastOnce));
+        assert_eq!(session.subscriptions.get("topic3").map(|sub| sub.qos), Some(QoS::ExactlyOnce));
     }
 
     #[test]
@@ -576,4 +1318,1551 @@ mod tests {
         assert_eq!(QoS::from_u8(2), Some(QoS::ExactlyOnce));
         assert_eq!(QoS::from_u8(3), None);
     }
+
+    /// Minimal raw MQTT client used to drive a `ServerConnection` in tests
+    struct TestClient {
+        stream: TcpStream,
+        codec: MqttCodec,
+        buffer: BytesMut,
+    }
+
+    /// CONNECT packet with default settings for the given client
+    fn connect_packet(client_id: &str) -> ConnectPacket {
+        ConnectPacket {
+            protocol_name: "MQTT".to_string(),
+            protocol_version: 4,
+            clean_session: true,
+            will_flag: false,
+            will_qos: 0,
+            will_retain: false,
+            password_flag: false,
+            username_flag: false,
+            keep_alive: 60,
+            client_id: client_id.to_string(),
+            will_topic: None,
+            will_message: None,
+            will_properties: None,
+            username: None,
+            password: None,
+            properties: None,
+        }
+    }
+
+    impl TestClient {
+        async fn connect(addr: SocketAddr, client_id: &str) -> Self {
+            let (client, _) = Self::connect_with(addr, connect_packet(client_id)).await;
+            client
+        }
+
+        async fn connect_with(addr: SocketAddr, connect: ConnectPacket) -> (Self, ConnAckPacket) {
+            let mut client = Self {
+                stream: TcpStream::connect(addr).await.unwrap(),
+                codec: MqttCodec::new(connect.protocol_version),
+                buffer: BytesMut::new(),
+            };
+            client.send(PacketPayload::Connect(connect), 0).await;
+            match client.recv().await.payload {
+                PacketPayload::ConnAck(connack) => (client, connack),
+                other => panic!("Expected CONNACK, got {:?}", other),
+            }
+        }
+
+        /// Wait for the server to close the connection
+        async fn expect_closed(&mut self, within: std::time::Duration) {
+            tokio::time::timeout(within, async {
+                loop {
+                    let mut buf = vec![0u8; 1024];
+                    match self.stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(_) => continue,
+                    }
+                }
+            }).await.expect("Server did not close the connection");
+        }
+
+        async fn send(&mut self, payload: PacketPayload, qos: u8) {
+            let packet_type = match &payload {
+                PacketPayload::Connect(_) => PacketType::Connect,
+                PacketPayload::Publish(_) => PacketType::Publish,
+                PacketPayload::PubAck(_) => PacketType::PubAck,
+                PacketPayload::PubRel(_) => PacketType::PubRel,
+                PacketPayload::Subscribe(_) => PacketType::Subscribe,
+                PacketPayload::Unsubscribe(_) => PacketType::Unsubscribe,
+                PacketPayload::PingReq => PacketType::PingReq,
+                PacketPayload::Disconnect(_) => PacketType::Disconnect,
+                PacketPayload::Auth(_) => PacketType::Auth,
+                other => panic!("Unsupported test packet: {:?}", other),
+            };
+            let packet = Packet {
+                header: PacketHeader { packet_type, dup: false, qos, retain: false, remaining_length: 0 },
+                payload,
+            };
+            let data = self.codec.encode(&packet).unwrap();
+            self.stream.write_all(&data).await.unwrap();
+        }
+
+        async fn recv(&mut self) -> Packet {
+            tokio::time::timeout(std::time::Duration::from_secs(5), async {
+                loop {
+                    if let Some(packet) = self.codec.decode(&mut self.buffer).unwrap() {
+                        return packet;
+                    }
+                    let mut buf = vec![0u8; 1024];
+                    let n = self.stream.read(&mut buf).await.unwrap();
+                    assert!(n > 0, "Server closed the connection");
+                    self.buffer.extend_from_slice(&buf[..n]);
+                }
+            }).await.expect("Timed out waiting for packet")
+        }
+
+        async fn subscribe(&mut self, topic: &str, qos: u8) {
+            self.send(PacketPayload::Subscribe(SubscribePacket {
+                packet_id: 1,
+                topic_filters: vec![TopicFilter {
+                    topic: topic.to_string(),
+                    qos,
+                    no_local: false,
+                    retain_as_published: false,
+                    retain_handling: 0,
+                }],
+                properties: None,
+            }), 1).await;
+            assert!(matches!(self.recv().await.payload, PacketPayload::SubAck(_)));
+        }
+
+        async fn publish(&mut self, topic: &str, payload: &str, qos: u8, packet_id: Option<u16>) {
+            self.send(PacketPayload::Publish(PublishPacket {
+                topic_name: topic.to_string(),
+                packet_id,
+                payload: bytes::Bytes::from(payload.to_string()),
+                properties: None,
+            }), qos).await;
+        }
+    }
+
+    /// Start a listener that hands every accepted socket to `ServerConnection`
+    async fn start_test_server() -> SocketAddr {
+        start_test_server_with(|config| config).await
+    }
+
+    /// Start a test server with a customised configuration
+    async fn start_test_server_with(configure: impl FnOnce(ServerConfig) -> ServerConfig) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = configure(ServerConfig::new(addr.to_string()));
+        let session_manager = Arc::new(SessionManager::with_queue_limits(
+            config.max_pending_messages,
+            config.queue_drop_policy,
+        ).shared_subscription_strategy(config.shared_subscription_strategy));
+        let message_router = Arc::new(MessageRouter::new());
+        Arc::clone(&session_manager).spawn_expiry_reaper(config.session_expiry_check_interval);
+
+        tokio::spawn(async move {
+            while let Ok((stream, peer)) = listener.accept().await {
+                tokio::spawn(ServerConnection::handle_connection(
+                    stream,
+                    peer.into(),
+                    config.clone(),
+                    Arc::clone(&session_manager),
+                    Arc::clone(&message_router),
+                ));
+            }
+        });
+
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_publish_delivered_to_matching_subscribers() {
+        let addr = start_test_server().await;
+
+        let mut subscriber = TestClient::connect(addr, "subscriber").await;
+        subscriber.subscribe("home/+/temp", 1).await;
+
+        let mut other = TestClient::connect(addr, "other").await;
+        other.subscribe("office/#", 1).await;
+
+        let mut publisher = TestClient::connect(addr, "publisher").await;
+        publisher.publish("home/living/temp", "22.5", 1, Some(10)).await;
+        match publisher.recv().await.payload {
+            PacketPayload::PubAck(puback) => assert_eq!(puback.packet_id, 10),
+            other => panic!("Expected PUBACK, got {:?}", other),
+        }
+
+        let packet = subscriber.recv().await;
+        assert_eq!(packet.header.qos, 1);
+        assert!(!packet.header.retain);
+        match packet.payload {
+            PacketPayload::Publish(publish) => {
+                assert_eq!(publish.topic_name, "home/living/temp");
+                assert_eq!(publish.payload, bytes::Bytes::from("22.5"));
+                assert!(publish.packet_id.is_some());
+            }
+            other => panic!("Expected PUBLISH, got {:?}", other),
+        }
+
+        // A non-matching subscriber receives nothing
+        let nothing = tokio::time::timeout(std::time::Duration::from_millis(200), other.recv()).await;
+        assert!(nothing.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_publish_downgraded_to_granted_qos() {
+        let addr = start_test_server().await;
+
+        let mut subscriber = TestClient::connect(addr, "qos0_subscriber").await;
+        subscriber.subscribe("sensors/#", 0).await;
+
+        let mut publisher = TestClient::connect(addr, "qos1_publisher").await;
+        publisher.publish("sensors/humidity", "40", 1, Some(7)).await;
+
+        let packet = subscriber.recv().await;
+        assert_eq!(packet.header.qos, 0);
+        match packet.payload {
+            PacketPayload::Publish(publish) => {
+                assert_eq!(publish.topic_name, "sensors/humidity");
+                assert_eq!(publish.packet_id, None);
+            }
+            other => panic!("Expected PUBLISH, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_qos2_publish_does_not_block_other_packets() {
+        let addr = start_test_server().await;
+
+        let mut subscriber = TestClient::connect(addr, "qos2_subscriber").await;
+        subscriber.subscribe("alarms/#", 2).await;
+
+        let mut publisher = TestClient::connect(addr, "qos2_publisher").await;
+        publisher.publish("alarms/door", "open", 2, Some(42)).await;
+        match publisher.recv().await.payload {
+            PacketPayload::PubRec(pubrec) => assert_eq!(pubrec.packet_id, 42),
+            other => panic!("Expected PUBREC, got {:?}", other),
+        }
+
+        // The connection keeps serving other packets while PUBREL is outstanding
+        publisher.send(PacketPayload::PingReq, 0).await;
+        assert!(matches!(publisher.recv().await.payload, PacketPayload::PingResp));
+
+        // A retransmitted PUBLISH is acknowledged again but not delivered twice
+        publisher.publish("alarms/door", "open", 2, Some(42)).await;
+        assert!(matches!(publisher.recv().await.payload, PacketPayload::PubRec(_)));
+
+        publisher.send(PacketPayload::PubRel(PubRelPacket {
+            packet_id: 42,
+            reason_code: None,
+            properties: None,
+        }), 1).await;
+        match publisher.recv().await.payload {
+            PacketPayload::PubComp(pubcomp) => assert_eq!(pubcomp.packet_id, 42),
+            other => panic!("Expected PUBCOMP, got {:?}", other),
+        }
+
+        let packet = subscriber.recv().await;
+        assert_eq!(packet.header.qos, 2);
+        assert!(matches!(packet.payload, PacketPayload::Publish(_)));
+        let duplicate = tokio::time::timeout(std::time::Duration::from_millis(200), subscriber.recv()).await;
+        assert!(duplicate.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_idle_client_evicted_and_will_published() {
+        let addr = start_test_server().await;
+
+        let mut watcher = TestClient::connect(addr, "watcher").await;
+        watcher.subscribe("devices/+/status", 1).await;
+
+        let (mut device, _) = TestClient::connect_with(addr, ConnectPacket {
+            keep_alive: 1,
+            will_flag: true,
+            will_qos: 1,
+            will_topic: Some("devices/d1/status".to_string()),
+            will_message: Some(bytes::Bytes::from("offline")),
+            ..connect_packet("d1")
+        }).await;
+
+        // The device goes silent; it is evicted after 1.5 keep-alive periods
+        let started = std::time::Instant::now();
+        device.expect_closed(std::time::Duration::from_secs(5)).await;
+        assert!(started.elapsed() >= std::time::Duration::from_millis(1400));
+
+        let packet = watcher.recv().await;
+        match packet.payload {
+            PacketPayload::Publish(publish) => {
+                assert_eq!(publish.topic_name, "devices/d1/status");
+                assert_eq!(publish.payload, bytes::Bytes::from("offline"));
+            }
+            other => panic!("Expected will PUBLISH, got {:?}", other),
+        }
+    }
+
+    /// CONNECT packet registering a will on `status/<client_id>`
+    fn connect_with_will(client_id: &str, retain: bool) -> ConnectPacket {
+        ConnectPacket {
+            will_flag: true,
+            will_qos: 1,
+            will_retain: retain,
+            will_topic: Some(format!("status/{}", client_id)),
+            will_message: Some(bytes::Bytes::from("offline")),
+            ..connect_packet(client_id)
+        }
+    }
+
+    fn assert_will(packet: Packet, client_id: &str) {
+        match packet.payload {
+            PacketPayload::Publish(publish) => {
+                assert_eq!(publish.topic_name, format!("status/{}", client_id));
+                assert_eq!(publish.payload, bytes::Bytes::from("offline"));
+            }
+            other => panic!("Expected will PUBLISH, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_will_published_when_connection_drops() {
+        let addr = start_test_server().await;
+
+        let mut watcher = TestClient::connect(addr, "watcher").await;
+        watcher.subscribe("status/+", 1).await;
+
+        let (device, _) = TestClient::connect_with(addr, connect_with_will("sensor", true)).await;
+        drop(device);
+
+        let packet = watcher.recv().await;
+        assert!(!packet.header.retain);
+        assert_will(packet, "sensor");
+
+        // The will was retained, so later subscribers receive it too
+        let mut late = TestClient::connect(addr, "late").await;
+        late.subscribe("status/sensor", 1).await;
+        let packet = late.recv().await;
+        assert!(packet.header.retain);
+        assert_will(packet, "sensor");
+    }
+
+    #[tokio::test]
+    async fn test_invalid_will_topic_refused() {
+        let addr = start_test_server_with(|config| config.protocol_version(5)).await;
+        let (mut watcher, _) = TestClient::connect_with(addr, ConnectPacket {
+            protocol_version: 5,
+            ..connect_packet("watcher")
+        }).await;
+        watcher.subscribe("#", 1).await;
+
+        for will_topic in ["status/+", "#", ""] {
+            let (mut device, connack) = TestClient::connect_with(addr, ConnectPacket {
+                protocol_version: 5,
+                will_topic: Some(will_topic.to_string()),
+                ..connect_with_will("wildcard", true)
+            }).await;
+            assert_eq!(connack.return_code, ConnectReturnCode::TopicNameInvalid);
+            device.expect_closed(std::time::Duration::from_secs(5)).await;
+        }
+
+        let nothing = tokio::time::timeout(std::time::Duration::from_millis(300), watcher.recv()).await;
+        assert!(nothing.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_v3_invalid_will_topic_closes_connection() {
+        let addr = start_test_server().await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let codec = MqttCodec::new(4);
+        let connect = ConnectPacket { will_topic: Some("status/#".to_string()), ..connect_with_will("wildcard", false) };
+        let packet = Packet {
+            header: PacketHeader { packet_type: PacketType::Connect, dup: false, qos: 0, retain: false, remaining_length: 0 },
+            payload: PacketPayload::Connect(connect),
+        };
+        stream.write_all(&codec.encode(&packet).unwrap()).await.unwrap();
+
+        // Closed without a CONNACK
+        let mut buf = vec![0u8; 64];
+        let n = tokio::time::timeout(std::time::Duration::from_secs(5), stream.read(&mut buf)).await.unwrap();
+        assert!(matches!(n, Ok(0) | Err(_)));
+    }
+
+    #[tokio::test]
+    async fn test_will_discarded_on_clean_disconnect() {
+        let addr = start_test_server().await;
+
+        let mut watcher = TestClient::connect(addr, "watcher").await;
+        watcher.subscribe("status/+", 1).await;
+
+        let (mut device, _) = TestClient::connect_with(addr, connect_with_will("polite", false)).await;
+        device.send(PacketPayload::Disconnect(DisconnectPacket {
+            reason_code: None,
+            properties: None,
+        }), 0).await;
+        device.expect_closed(std::time::Duration::from_secs(5)).await;
+
+        let nothing = tokio::time::timeout(std::time::Duration::from_millis(300), watcher.recv()).await;
+        assert!(nothing.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_will_published_on_disconnect_with_will_message() {
+        let addr = start_test_server_with(|config| config.protocol_version(5)).await;
+
+        let (mut watcher, _) = TestClient::connect_with(addr, ConnectPacket {
+            protocol_version: 5,
+            ..connect_packet("watcher")
+        }).await;
+        watcher.subscribe("status/+", 1).await;
+
+        for (client_id, delay) in [("leaving", None), ("lingering", Some(1))] {
+            let (mut device, _) = TestClient::connect_with(addr, ConnectPacket {
+                protocol_version: 5,
+                will_properties: delay.map(|delay| WillProperties::new().will_delay_interval(delay)),
+                properties: Some(ConnectProperties { session_expiry_interval: Some(60), ..Default::default() }),
+                ..connect_with_will(client_id, false)
+            }).await;
+            device.send(PacketPayload::Disconnect(DisconnectPacket {
+                reason_code: Some(ReasonCode::DisconnectWithWillMessage as u8),
+                properties: None,
+            }), 0).await;
+            let disconnected = std::time::Instant::now();
+
+            // The will delay applies as if the connection had dropped
+            assert_will(watcher.recv().await, client_id);
+            if delay.is_some() {
+                assert!(disconnected.elapsed() >= std::time::Duration::from_millis(900));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_will_delay_interval() {
+        let addr = start_test_server_with(|config| config.protocol_version(5)).await;
+
+        let (mut watcher, _) = TestClient::connect_with(addr, ConnectPacket {
+            protocol_version: 5,
+            ..connect_packet("watcher")
+        }).await;
+        watcher.subscribe("status/+", 1).await;
+
+        let (device, _) = TestClient::connect_with(addr, ConnectPacket {
+            protocol_version: 5,
+            will_properties: Some(WillProperties::new().will_delay_interval(1)),
+            properties: Some(ConnectProperties { session_expiry_interval: Some(60), ..Default::default() }),
+            ..connect_with_will("delayed", false)
+        }).await;
+        let dropped = std::time::Instant::now();
+        drop(device);
+
+        assert_will(watcher.recv().await, "delayed");
+        assert!(dropped.elapsed() >= std::time::Duration::from_millis(900));
+    }
+
+    #[tokio::test]
+    async fn test_v5_unsuback_reason_codes() {
+        let addr = start_test_server_with(|config| config.protocol_version(5)).await;
+        let (mut client, _) = TestClient::connect_with(addr, ConnectPacket {
+            protocol_version: 5,
+            ..connect_packet("client")
+        }).await;
+        client.subscribe("a/+", 1).await;
+
+        client.send(PacketPayload::Unsubscribe(UnsubscribePacket {
+            packet_id: 2,
+            topic_filters: vec!["a/+".to_string(), "never/subscribed".to_string()],
+            properties: None,
+        }), 1).await;
+        match client.recv().await.payload {
+            PacketPayload::UnsubAck(unsuback) => {
+                assert_eq!(unsuback.packet_id, 2);
+                assert_eq!(unsuback.reason_codes, vec![
+                    ReasonCode::Success as u8,
+                    ReasonCode::NoSubscriptionExisted as u8,
+                ]);
+            }
+            other => panic!("Expected UNSUBACK, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delayed_will_cancelled_by_reconnect() {
+        let addr = start_test_server_with(|config| config.protocol_version(5)).await;
+
+        let (mut watcher, _) = TestClient::connect_with(addr, ConnectPacket {
+            protocol_version: 5,
+            ..connect_packet("watcher")
+        }).await;
+        watcher.subscribe("status/+", 1).await;
+
+        let connect = ConnectPacket {
+            protocol_version: 5,
+            will_properties: Some(WillProperties::new().will_delay_interval(1)),
+            properties: Some(ConnectProperties { session_expiry_interval: Some(60), ..Default::default() }),
+            ..connect_with_will("flaky", false)
+        };
+        let (device, _) = TestClient::connect_with(addr, connect.clone()).await;
+        drop(device);
+
+        // The client comes back before the delay expires
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        let (_device, _) = TestClient::connect_with(addr, connect).await;
+
+        let nothing = tokio::time::timeout(std::time::Duration::from_millis(1500), watcher.recv()).await;
+        assert!(nothing.is_err());
+    }
+
+    /// Read PUBLISH packets until `count` have arrived, returning their payloads
+    async fn recv_payloads(client: &mut TestClient, count: usize) -> Vec<String> {
+        let mut payloads = Vec::new();
+        while payloads.len() < count {
+            if let PacketPayload::Publish(publish) = client.recv().await.payload {
+                payloads.push(String::from_utf8(publish.payload.to_vec()).unwrap());
+            }
+        }
+        payloads
+    }
+
+    fn publish_of(packet: Packet) -> (PacketHeader, PublishPacket) {
+        match packet.payload {
+            PacketPayload::Publish(publish) => (packet.header, publish),
+            other => panic!("Expected PUBLISH, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deliveries_limited_by_receive_maximum() {
+        let addr = start_test_server_with(|config| config.protocol_version(5).max_inflight(10)).await;
+        let connect = ConnectPacket {
+            protocol_version: 5,
+            properties: Some(ConnectProperties { receive_maximum: Some(1), ..Default::default() }),
+            ..connect_packet("slow")
+        };
+        let (mut subscriber, connack) = TestClient::connect_with(addr, connect).await;
+        assert_eq!(connack.properties.unwrap().receive_maximum, Some(10));
+        subscriber.subscribe("jobs", 1).await;
+
+        let (mut publisher, _) = TestClient::connect_with(addr, ConnectPacket { protocol_version: 5, ..connect_packet("producer") }).await;
+        for (index, payload) in ["one", "two"].iter().enumerate() {
+            publisher.publish("jobs", payload, 1, Some(index as u16 + 1)).await;
+            assert!(matches!(publisher.recv().await.payload, PacketPayload::PubAck(_)));
+        }
+
+        // The second message waits until the first is acknowledged
+        let (_, first) = publish_of(subscriber.recv().await);
+        assert_eq!(first.payload, "one");
+        let nothing = tokio::time::timeout(std::time::Duration::from_millis(100), subscriber.recv()).await;
+        assert!(nothing.is_err());
+
+        subscriber.send(PacketPayload::PubAck(PubAckPacket {
+            packet_id: first.packet_id.unwrap(),
+            reason_code: None,
+            properties: None,
+        }), 0).await;
+        let (_, second) = publish_of(subscriber.recv().await);
+        assert_eq!(second.payload, "two");
+        assert_ne!(second.packet_id, first.packet_id);
+    }
+
+    #[tokio::test]
+    async fn test_unacknowledged_deliveries_retransmitted_on_resume() {
+        let addr = start_test_server().await;
+        let persistent = ConnectPacket { clean_session: false, ..connect_packet("forgetful") };
+
+        let (mut subscriber, _) = TestClient::connect_with(addr, persistent.clone()).await;
+        subscriber.subscribe("alerts", 1).await;
+        let mut publisher = TestClient::connect(addr, "alarm").await;
+        publisher.publish("alerts", "fire", 1, Some(1)).await;
+        assert!(matches!(publisher.recv().await.payload, PacketPayload::PubAck(_)));
+
+        // The subscriber goes away without acknowledging the delivery
+        let (header, delivered) = publish_of(subscriber.recv().await);
+        assert!(!header.dup);
+        drop(subscriber);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let (mut subscriber, connack) = TestClient::connect_with(addr, persistent.clone()).await;
+        assert!(connack.session_present);
+        let (header, resent) = publish_of(subscriber.recv().await);
+        assert!(header.dup);
+        assert_eq!(resent.packet_id, delivered.packet_id);
+        assert_eq!(resent.payload, "fire");
+
+        // Once acknowledged, it is not sent again
+        subscriber.send(PacketPayload::PubAck(PubAckPacket {
+            packet_id: resent.packet_id.unwrap(),
+            reason_code: None,
+            properties: None,
+        }), 0).await;
+        drop(subscriber);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let (mut subscriber, _) = TestClient::connect_with(addr, persistent).await;
+        let nothing = tokio::time::timeout(std::time::Duration::from_millis(100), subscriber.recv()).await;
+        assert!(nothing.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_qos2_beyond_receive_maximum_disconnects_v5_client() {
+        let addr = start_test_server_with(|config| config.protocol_version(5).max_inflight(1)).await;
+        let (mut client, _) = TestClient::connect_with(addr, ConnectPacket { protocol_version: 5, ..connect_packet("eager") }).await;
+
+        client.publish("jobs", "first", 2, Some(1)).await;
+        assert!(matches!(client.recv().await.payload, PacketPayload::PubRec(_)));
+        client.publish("jobs", "second", 2, Some(2)).await;
+        assert_disconnect(client.recv().await, ReasonCode::ReceiveMaximumExceeded);
+    }
+
+    #[tokio::test]
+    async fn test_packet_ids_allocated_per_subscriber() {
+        let addr = start_test_server().await;
+        let mut subscribers = Vec::new();
+        for client_id in ["reader-a", "reader-b"] {
+            let mut subscriber = TestClient::connect(addr, client_id).await;
+            subscriber.subscribe("jobs", 1).await;
+            subscribers.push(subscriber);
+        }
+
+        // Two publishers deliver to both subscribers at the same time
+        let publish = |client_id: &'static str| async move {
+            let mut publisher = TestClient::connect(addr, client_id).await;
+            for packet_id in 1..=5 {
+                publisher.publish("jobs", client_id, 1, Some(packet_id)).await;
+                assert!(matches!(publisher.recv().await.payload, PacketPayload::PubAck(_)));
+            }
+        };
+        tokio::join!(publish("producer-a"), publish("producer-b"));
+
+        // Each subscriber gets IDs of its own, none repeated while unacknowledged
+        for subscriber in &mut subscribers {
+            let mut packet_ids = Vec::new();
+            for _ in 0..10 {
+                let (_, publish) = publish_of(subscriber.recv().await);
+                packet_ids.push(publish.packet_id.unwrap());
+            }
+            packet_ids.sort_unstable();
+            assert_eq!(packet_ids, (1..=10).collect::<Vec<u16>>());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_persistent_session_receives_queued_messages() {
+        let addr = start_test_server().await;
+        let persistent = ConnectPacket { clean_session: false, ..connect_packet("persistent") };
+
+        let (mut subscriber, connack) = TestClient::connect_with(addr, persistent.clone()).await;
+        assert!(!connack.session_present);
+        subscriber.subscribe("news/#", 1).await;
+        drop(subscriber);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let mut publisher = TestClient::connect(addr, "publisher").await;
+        for (index, payload) in ["one", "two", "three"].iter().enumerate() {
+            publisher.publish("news/today", payload, 1, Some(index as u16 + 1)).await;
+            assert!(matches!(publisher.recv().await.payload, PacketPayload::PubAck(_)));
+        }
+        // QoS 0 messages are not queued for offline clients
+        publisher.publish("news/today", "lost", 0, None).await;
+
+        let (mut subscriber, connack) = TestClient::connect_with(addr, persistent).await;
+        assert!(connack.session_present);
+        assert_eq!(recv_payloads(&mut subscriber, 3).await, ["one", "two", "three"]);
+
+        // The subscription survived without subscribing again
+        publisher.publish("news/tomorrow", "four", 1, Some(4)).await;
+        assert_eq!(recv_payloads(&mut subscriber, 1).await, ["four"]);
+    }
+
+    #[tokio::test]
+    async fn test_offline_queue_drops_oldest_when_full() {
+        let addr = start_test_server_with(|config| config.max_pending_messages(2)).await;
+        let persistent = ConnectPacket { clean_session: false, ..connect_packet("capped") };
+
+        let (mut subscriber, _) = TestClient::connect_with(addr, persistent.clone()).await;
+        subscriber.subscribe("queue", 1).await;
+        drop(subscriber);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let mut publisher = TestClient::connect(addr, "publisher").await;
+        for (index, payload) in ["a", "b", "c"].iter().enumerate() {
+            publisher.publish("queue", payload, 1, Some(index as u16 + 1)).await;
+            assert!(matches!(publisher.recv().await.payload, PacketPayload::PubAck(_)));
+        }
+
+        let (mut subscriber, _) = TestClient::connect_with(addr, persistent).await;
+        assert_eq!(recv_payloads(&mut subscriber, 2).await, ["b", "c"]);
+    }
+
+    /// v5 PUBLISH carrying `properties`
+    fn publish_with_properties(topic: &str, payload: &'static str, packet_id: u16, properties: PublishProperties) -> PacketPayload {
+        PacketPayload::Publish(PublishPacket {
+            topic_name: topic.to_string(),
+            packet_id: Some(packet_id),
+            payload: bytes::Bytes::from(payload),
+            properties: Some(properties),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_publish_properties_forwarded_to_subscribers() {
+        let addr = start_test_server_with(|config| config.protocol_version(5)).await;
+        let (mut subscriber, _) = TestClient::connect_with(addr, ConnectPacket {
+            protocol_version: 5,
+            ..connect_packet("responder")
+        }).await;
+        subscriber.subscribe("requests/+", 1).await;
+
+        let (mut publisher, _) = TestClient::connect_with(addr, ConnectPacket {
+            protocol_version: 5,
+            ..connect_packet("requester")
+        }).await;
+        let properties = PublishProperties::new()
+            .payload_format_indicator(1)
+            .message_expiry_interval(60)
+            .content_type("text/plain".to_string())
+            .response_topic("responses/requester".to_string())
+            .correlation_data(bytes::Bytes::from_static(b"request-1"))
+            .user_property("origin".to_string(), "test".to_string());
+        publisher.send(publish_with_properties("requests/time", "now?", 1, properties), 1).await;
+        assert!(matches!(publisher.recv().await.payload, PacketPayload::PubAck(_)));
+
+        let (_, publish) = publish_of(subscriber.recv().await);
+        let forwarded = publish.properties.expect("PUBLISH properties were not forwarded");
+        assert_eq!(forwarded.payload_format_indicator, Some(1));
+        assert!(forwarded.message_expiry_interval.is_some_and(|seconds| (1..=60).contains(&seconds)));
+        assert_eq!(forwarded.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(forwarded.response_topic.as_deref(), Some("responses/requester"));
+        assert_eq!(forwarded.correlation_data.as_deref(), Some(&b"request-1"[..]));
+        assert_eq!(forwarded.user_properties.get("origin").map(String::as_str), Some("test"));
+        assert_eq!(forwarded.topic_alias, None);
+    }
+
+    #[tokio::test]
+    async fn test_queued_message_expires_and_interval_reduced() {
+        let addr = start_test_server_with(|config| config.protocol_version(5)).await;
+        let (mut subscriber, _) = TestClient::connect_with(addr, connect_v5_with_expiry("sleeper", 60)).await;
+        subscriber.subscribe("updates", 1).await;
+        drop(subscriber);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let (mut publisher, _) = TestClient::connect_with(addr, ConnectPacket {
+            protocol_version: 5,
+            ..connect_packet("updater")
+        }).await;
+        let short_lived = PublishProperties::new().message_expiry_interval(1);
+        publisher.send(publish_with_properties("updates", "stale", 1, short_lived), 1).await;
+        assert!(matches!(publisher.recv().await.payload, PacketPayload::PubAck(_)));
+        let long_lived = PublishProperties::new().message_expiry_interval(10);
+        publisher.send(publish_with_properties("updates", "fresh", 2, long_lived), 1).await;
+        assert!(matches!(publisher.recv().await.payload, PacketPayload::PubAck(_)));
+
+        tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+        let (mut subscriber, connack) = TestClient::connect_with(addr, connect_v5_with_expiry("sleeper", 60)).await;
+        assert!(connack.session_present);
+
+        // Only the unexpired message arrives, with the time it waited deducted
+        let (_, publish) = publish_of(subscriber.recv().await);
+        assert_eq!(publish.payload, "fresh");
+        assert_eq!(publish.properties.unwrap().message_expiry_interval, Some(9));
+        let nothing = tokio::time::timeout(std::time::Duration::from_millis(200), subscriber.recv()).await;
+        assert!(nothing.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_clean_session_discards_stored_state() {
+        let addr = start_test_server().await;
+
+        let (mut subscriber, _) = TestClient::connect_with(addr, ConnectPacket {
+            clean_session: false,
+            ..connect_packet("forgetful")
+        }).await;
+        subscriber.subscribe("news/#", 1).await;
+        drop(subscriber);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let mut publisher = TestClient::connect(addr, "publisher").await;
+        publisher.publish("news/today", "stale", 1, Some(1)).await;
+        assert!(matches!(publisher.recv().await.payload, PacketPayload::PubAck(_)));
+
+        let (mut subscriber, connack) = TestClient::connect_with(addr, connect_packet("forgetful")).await;
+        assert!(!connack.session_present);
+
+        // Neither the queued message nor the subscription survived
+        publisher.publish("news/today", "fresh", 1, Some(2)).await;
+        assert!(matches!(publisher.recv().await.payload, PacketPayload::PubAck(_)));
+        let nothing = tokio::time::timeout(std::time::Duration::from_millis(300), subscriber.recv()).await;
+        assert!(nothing.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_will_sent_when_session_ends_before_delay() {
+        let addr = start_test_server_with(|config| config.protocol_version(5)).await;
+
+        let (mut watcher, _) = TestClient::connect_with(addr, ConnectPacket {
+            protocol_version: 5,
+            ..connect_packet("watcher")
+        }).await;
+        watcher.subscribe("status/+", 1).await;
+
+        // Without a session expiry the session ends with the connection
+        let (device, _) = TestClient::connect_with(addr, ConnectPacket {
+            protocol_version: 5,
+            will_properties: Some(WillProperties::new().will_delay_interval(60)),
+            ..connect_with_will("short_lived", false)
+        }).await;
+        drop(device);
+
+        assert_will(watcher.recv().await, "short_lived");
+    }
+
+    /// v5 CONNECT resuming a session with the given expiry interval
+    fn connect_v5_with_expiry(client_id: &str, session_expiry_interval: u32) -> ConnectPacket {
+        ConnectPacket {
+            protocol_version: 5,
+            clean_session: false,
+            properties: Some(ConnectProperties {
+                session_expiry_interval: Some(session_expiry_interval),
+                ..Default::default()
+            }),
+            ..connect_packet(client_id)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_expires_after_interval() {
+        let addr = start_test_server_with(|config| {
+            config.protocol_version(5).session_expiry_check_interval(std::time::Duration::from_millis(100))
+        }).await;
+
+        let (mut client, connack) = TestClient::connect_with(addr, connect_v5_with_expiry("expiring", 1)).await;
+        assert!(!connack.session_present);
+        assert_eq!(connack.properties.unwrap().session_expiry_interval, Some(1));
+        client.subscribe("news/#", 1).await;
+        drop(client);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        // Back within the interval: the session is still there
+        let (client, connack) = TestClient::connect_with(addr, connect_v5_with_expiry("expiring", 1)).await;
+        assert!(connack.session_present);
+        drop(client);
+
+        // Gone once the interval has passed
+        tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+        let (_client, connack) = TestClient::connect_with(addr, connect_v5_with_expiry("expiring", 1)).await;
+        assert!(!connack.session_present);
+    }
+
+    #[tokio::test]
+    async fn test_session_expiry_capped_by_server() {
+        let addr = start_test_server_with(|config| config.protocol_version(5).max_session_expiry_interval(30)).await;
+
+        let (_client, connack) = TestClient::connect_with(addr, connect_v5_with_expiry("greedy", 3600)).await;
+        assert_eq!(connack.properties.unwrap().session_expiry_interval, Some(30));
+    }
+
+    #[tokio::test]
+    async fn test_active_client_not_evicted() {
+        let addr = start_test_server().await;
+        let (mut client, _) = TestClient::connect_with(addr, ConnectPacket {
+            keep_alive: 1,
+            ..connect_packet("pinger")
+        }).await;
+
+        for _ in 0..4 {
+            tokio::time::sleep(std::time::Duration::from_millis(600)).await;
+            client.send(PacketPayload::PingReq, 0).await;
+            assert!(matches!(client.recv().await.payload, PacketPayload::PingResp));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_keep_alive_overrides_v5_client() {
+        let addr = start_test_server_with(|config| config.protocol_version(5).server_keep_alive(1)).await;
+
+        let (mut client, connack) = TestClient::connect_with(addr, ConnectPacket {
+            protocol_version: 5,
+            keep_alive: 600,
+            ..connect_packet("v5_client")
+        }).await;
+        assert_eq!(connack.return_code, ConnectReturnCode::Accepted);
+        assert_eq!(connack.properties.unwrap().server_keep_alive, Some(1));
+
+        assert_disconnect(client.recv().await, ReasonCode::KeepAliveTimeout);
+        client.expect_closed(std::time::Duration::from_secs(5)).await;
+    }
+
+    fn assert_disconnect(packet: Packet, reason_code: ReasonCode) {
+        match packet.payload {
+            PacketPayload::Disconnect(disconnect) => {
+                assert_eq!(disconnect.reason_code, Some(reason_code as u8));
+            }
+            other => panic!("Expected DISCONNECT, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_empty_client_ids_get_separate_sessions() {
+        let addr = start_test_server().await;
+        let mut first = TestClient::connect(addr, "").await;
+        first.subscribe("rooms/first", 1).await;
+        let mut second = TestClient::connect(addr, "").await;
+        second.subscribe("rooms/second", 1).await;
+
+        // Neither connection took over the other, and deliveries do not cross
+        let mut publisher = TestClient::connect(addr, "publisher").await;
+        publisher.publish("rooms/first", "one", 0, None).await;
+        publisher.publish("rooms/second", "two", 0, None).await;
+        assert_eq!(recv_payloads(&mut first, 1).await, ["one"]);
+        assert_eq!(recv_payloads(&mut second, 1).await, ["two"]);
+        let nothing = tokio::time::timeout(std::time::Duration::from_millis(200), first.recv()).await;
+        assert!(nothing.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_v5_assigned_client_identifier() {
+        let addr = start_test_server_with(|config| config.protocol_version(5)).await;
+        let anonymous = || ConnectPacket { protocol_version: 5, ..connect_packet("") };
+
+        let (_first, connack) = TestClient::connect_with(addr, anonymous()).await;
+        let first_id = connack.properties.and_then(|p| p.assigned_client_identifier).unwrap();
+        let (_second, connack) = TestClient::connect_with(addr, anonymous()).await;
+        let second_id = connack.properties.and_then(|p| p.assigned_client_identifier).unwrap();
+        assert!(!first_id.is_empty());
+        assert_ne!(first_id, second_id);
+
+        // Clients that chose their own ID are not assigned one
+        let (_named, connack) = TestClient::connect_with(addr, ConnectPacket { protocol_version: 5, ..connect_packet("named") }).await;
+        assert!(connack.properties.and_then(|p| p.assigned_client_identifier).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_v5_session_taken_over() {
+        let addr = start_test_server_with(|config| config.protocol_version(5)).await;
+        let connect = ConnectPacket { protocol_version: 5, ..connect_packet("device") };
+
+        let (mut old, _) = TestClient::connect_with(addr, connect.clone()).await;
+        let (mut new, _) = TestClient::connect_with(addr, connect).await;
+
+        assert_disconnect(old.recv().await, ReasonCode::SessionTakenOver);
+        old.expect_closed(std::time::Duration::from_secs(5)).await;
+
+        // The new connection is unaffected once the old one is gone
+        new.send(PacketPayload::PingReq, 0).await;
+        assert!(matches!(new.recv().await.payload, PacketPayload::PingResp));
+    }
+
+    #[tokio::test]
+    async fn test_v3_session_taken_over_closes_old_connection() {
+        let addr = start_test_server().await;
+
+        let mut old = TestClient::connect(addr, "device").await;
+        let mut new = TestClient::connect(addr, "device").await;
+
+        // MQTT 3.1.1 has no server DISCONNECT; the old socket is simply closed
+        old.expect_closed(std::time::Duration::from_secs(5)).await;
+        new.send(PacketPayload::PingReq, 0).await;
+        assert!(matches!(new.recv().await.payload, PacketPayload::PingResp));
+    }
+
+    #[tokio::test]
+    async fn test_will_published_on_takeover() {
+        let addr = start_test_server().await;
+        let mut watcher = TestClient::connect(addr, "watcher").await;
+        watcher.subscribe("status/+", 1).await;
+
+        let (mut old, _) = TestClient::connect_with(addr, connect_with_will("device", false)).await;
+        let (mut new, _) = TestClient::connect_with(addr, ConnectPacket {
+            will_message: Some(bytes::Bytes::from("new offline")),
+            ..connect_with_will("device", false)
+        }).await;
+        old.expect_closed(std::time::Duration::from_secs(5)).await;
+        assert_will(watcher.recv().await, "device");
+
+        // The new connection's will is the one kept, and a clean disconnect discards it
+        new.send(PacketPayload::Disconnect(DisconnectPacket { reason_code: None, properties: None }), 0).await;
+        new.expect_closed(std::time::Duration::from_secs(5)).await;
+        let nothing = tokio::time::timeout(std::time::Duration::from_millis(300), watcher.recv()).await;
+        assert!(nothing.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_takeover_within_will_delay_keeps_will_back() {
+        let addr = start_test_server_with(|config| config.protocol_version(5)).await;
+        let (mut watcher, _) = TestClient::connect_with(addr, ConnectPacket {
+            protocol_version: 5,
+            ..connect_packet("watcher")
+        }).await;
+        watcher.subscribe("status/+", 1).await;
+
+        let connect = |client_id: &str, clean_session: bool| ConnectPacket {
+            protocol_version: 5,
+            clean_session,
+            will_properties: Some(WillProperties::new().will_delay_interval(60)),
+            properties: Some(ConnectProperties { session_expiry_interval: Some(60), ..Default::default() }),
+            ..connect_with_will(client_id, false)
+        };
+
+        // Resuming the session within the will delay means the will is never sent
+        let (_old, _) = TestClient::connect_with(addr, connect("resumed", true)).await;
+        let (_new, connack) = TestClient::connect_with(addr, connect("resumed", false)).await;
+        assert!(connack.session_present);
+        let nothing = tokio::time::timeout(std::time::Duration::from_millis(300), watcher.recv()).await;
+        assert!(nothing.is_err());
+
+        // Starting a new session ends the old one, which sends its will
+        let (_old, _) = TestClient::connect_with(addr, connect("restarted", true)).await;
+        let (_new, connack) = TestClient::connect_with(addr, connect("restarted", true)).await;
+        assert!(!connack.session_present);
+        assert_will(watcher.recv().await, "restarted");
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_auth_is_protocol_error() {
+        let addr = start_test_server_with(|config| config.protocol_version(5)).await;
+        let (mut client, _) = TestClient::connect_with(addr, ConnectPacket {
+            protocol_version: 5,
+            ..connect_packet("client")
+        }).await;
+
+        client.send(PacketPayload::Auth(AuthPacket {
+            reason_code: ReasonCode::ContinueAuthentication as u8,
+            properties: None,
+        }), 0).await;
+        assert_disconnect(client.recv().await, ReasonCode::ProtocolError);
+        client.expect_closed(std::time::Duration::from_secs(5)).await;
+    }
+
+    /// CONNECT naming an enhanced authentication method
+    fn connect_with_auth(client_id: &str, method: &str, data: &'static [u8]) -> ConnectPacket {
+        ConnectPacket {
+            protocol_version: 5,
+            properties: Some(ConnectProperties {
+                authentication_method: Some(method.to_string()),
+                authentication_data: Some(bytes::Bytes::from_static(data)),
+                ..Default::default()
+            }),
+            ..connect_packet(client_id)
+        }
+    }
+
+    async fn start_scram_server() -> SocketAddr {
+        start_test_server_with(|config| {
+            config
+                .protocol_version(5)
+                .authentication_provider(Arc::new(crate::server::ScramSha256Provider::new().add_user("user", "pencil")))
+        }).await
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_authentication_method_rejected() {
+        let addr = start_scram_server().await;
+        let (mut client, connack) = TestClient::connect_with(addr, connect_with_auth("client", "KERBEROS", b"")).await;
+        assert_eq!(connack.return_code, ConnectReturnCode::BadAuthenticationMethod);
+        client.expect_closed(std::time::Duration::from_secs(5)).await;
+    }
+
+    #[tokio::test]
+    async fn test_auth_challenge_precedes_connack() {
+        let addr = start_scram_server().await;
+        let mut client = TestClient {
+            stream: TcpStream::connect(addr).await.unwrap(),
+            codec: MqttCodec::new(5),
+            buffer: BytesMut::new(),
+        };
+        client.send(PacketPayload::Connect(connect_with_auth("client", "SCRAM-SHA-256", b"n,,n=user,r=abc")), 0).await;
+
+        match client.recv().await.payload {
+            PacketPayload::Auth(auth) => {
+                assert_eq!(auth.reason_code, ReasonCode::ContinueAuthentication as u8);
+                let properties = auth.properties.unwrap();
+                assert_eq!(properties.authentication_method.as_deref(), Some("SCRAM-SHA-256"));
+                assert!(properties.authentication_data.unwrap().starts_with(b"r=abc"));
+            }
+            other => panic!("Expected AUTH, got {:?}", other),
+        }
+
+        // Nothing but AUTH is accepted until the exchange completes
+        client.send(PacketPayload::PingReq, 0).await;
+        assert_disconnect(client.recv().await, ReasonCode::ProtocolError);
+        client.expect_closed(std::time::Duration::from_secs(5)).await;
+    }
+
+    #[tokio::test]
+    async fn test_failed_auth_exchange_not_authorized() {
+        let addr = start_scram_server().await;
+        let (mut client, connack) = TestClient::connect_with(addr, connect_with_auth("client", "SCRAM-SHA-256", b"p=tls-unique,,n=user,r=abc")).await;
+        assert_eq!(connack.return_code, ConnectReturnCode::NotAuthorizedV5);
+        client.expect_closed(std::time::Duration::from_secs(5)).await;
+    }
+
+    /// AUTH packet carrying SCRAM data
+    fn scram_auth(reason_code: ReasonCode, data: Option<bytes::Bytes>) -> PacketPayload {
+        PacketPayload::Auth(AuthPacket {
+            reason_code: reason_code as u8,
+            properties: Some(AuthProperties {
+                authentication_method: Some("SCRAM-SHA-256".to_string()),
+                authentication_data: data,
+                ..Default::default()
+            }),
+        })
+    }
+
+    /// Answer the server's SCRAM challenge and return the packet ending the exchange
+    async fn finish_scram(client: &mut TestClient, exchange: &mut dyn crate::client::ChallengeExchange) -> Packet {
+        let challenge = match client.recv().await.payload {
+            PacketPayload::Auth(auth) => auth.properties.unwrap().authentication_data,
+            other => panic!("Expected AUTH, got {:?}", other),
+        };
+        let response = exchange.respond(challenge.as_deref()).unwrap();
+        client.send(scram_auth(ReasonCode::ContinueAuthentication, response), 0).await;
+        client.recv().await
+    }
+
+    /// Connect over SCRAM as `user`, claiming `claimed` as the CONNECT username
+    async fn scram_connect(addr: SocketAddr, user: &str, claimed: Option<&str>) -> (TestClient, ConnAckPacket) {
+        use crate::client::{ChallengeHandler, ScramSha256};
+
+        let mut exchange = ScramSha256::new(user, "pencil").begin();
+        let connect = ConnectPacket {
+            protocol_version: 5,
+            username_flag: claimed.is_some(),
+            username: claimed.map(str::to_string),
+            properties: Some(ConnectProperties {
+                authentication_method: Some("SCRAM-SHA-256".to_string()),
+                authentication_data: exchange.initial_data().unwrap(),
+                ..Default::default()
+            }),
+            ..connect_packet(&format!("{}-device", user))
+        };
+        let mut client = TestClient {
+            stream: TcpStream::connect(addr).await.unwrap(),
+            codec: MqttCodec::new(5),
+            buffer: BytesMut::new(),
+        };
+        client.send(PacketPayload::Connect(connect), 0).await;
+        match finish_scram(&mut client, exchange.as_mut()).await.payload {
+            PacketPayload::ConnAck(connack) => (client, connack),
+            other => panic!("Expected CONNACK, got {:?}", other),
+        }
+    }
+
+    async fn start_scram_server_with_users(users: &[&str]) -> SocketAddr {
+        let provider = users.iter()
+            .fold(crate::server::ScramSha256Provider::new(), |provider, user| provider.add_user(*user, "pencil"));
+        start_test_server_with(|config| config.protocol_version(5).authentication_provider(Arc::new(provider))).await
+    }
+
+    #[tokio::test]
+    async fn test_scram_unknown_user_rejected_after_challenge() {
+        let addr = start_scram_server_with_users(&["bob"]).await;
+
+        // The exchange runs to the end, so an unknown user looks like a wrong password
+        let (mut client, connack) = scram_connect(addr, "mallory", None).await;
+        assert_eq!(connack.return_code, ConnectReturnCode::NotAuthorizedV5);
+        client.expect_closed(std::time::Duration::from_secs(5)).await;
+    }
+
+    #[tokio::test]
+    async fn test_scram_user_cannot_claim_other_username() {
+        let addr = start_scram_server_with_users(&["bob"]).await;
+
+        let (mut client, connack) = scram_connect(addr, "bob", Some("admin")).await;
+        assert_eq!(connack.return_code, ConnectReturnCode::NotAuthorizedV5);
+        client.expect_closed(std::time::Duration::from_secs(5)).await;
+
+        // Leaving the username out or naming the proven user is fine
+        let (_, connack) = scram_connect(addr, "bob", None).await;
+        assert_eq!(connack.return_code, ConnectReturnCode::Accepted);
+        let (_, connack) = scram_connect(addr, "bob", Some("bob")).await;
+        assert_eq!(connack.return_code, ConnectReturnCode::Accepted);
+    }
+
+    #[tokio::test]
+    async fn test_scram_identity_used_for_authorization() {
+        let acl = crate::server::AclFile::parse("user bob\ntopic read bob/#\n").unwrap();
+        let provider = crate::server::ScramSha256Provider::new().add_user("bob", "pencil");
+        let addr = start_test_server_with(|config| {
+            config.protocol_version(5).authentication_provider(Arc::new(provider)).authorizer(Arc::new(acl))
+        }).await;
+
+        // No username in the CONNECT, yet the rules of the proven user apply
+        let (mut client, connack) = scram_connect(addr, "bob", None).await;
+        assert_eq!(connack.return_code, ConnectReturnCode::Accepted);
+        let filter = |topic: &str| TopicFilter {
+            topic: topic.to_string(),
+            qos: 1,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling: 0,
+        };
+        client.send(PacketPayload::Subscribe(SubscribePacket {
+            packet_id: 1,
+            topic_filters: vec![filter("bob/inbox"), filter("alice/inbox")],
+            properties: None,
+        }), 1).await;
+        match client.recv().await.payload {
+            PacketPayload::SubAck(suback) => assert_eq!(suback.return_codes, vec![1, 0x87]),
+            other => panic!("Expected SUBACK, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reauthentication_as_other_user_disconnected() {
+        use crate::client::{ChallengeHandler, ScramSha256};
+
+        let addr = start_scram_server_with_users(&["bob", "admin"]).await;
+        let (mut client, connack) = scram_connect(addr, "bob", None).await;
+        assert_eq!(connack.return_code, ConnectReturnCode::Accepted);
+
+        // Re-authenticating as the same user succeeds
+        let mut exchange = ScramSha256::new("bob", "pencil").begin();
+        client.send(scram_auth(ReasonCode::ReAuthenticate, exchange.initial_data().unwrap()), 0).await;
+        match finish_scram(&mut client, exchange.as_mut()).await.payload {
+            PacketPayload::Auth(auth) => assert_eq!(auth.reason_code, ReasonCode::Success as u8),
+            other => panic!("Expected AUTH, got {:?}", other),
+        }
+
+        // Valid credentials of another user do not change who the client is
+        let mut exchange = ScramSha256::new("admin", "pencil").begin();
+        client.send(scram_auth(ReasonCode::ReAuthenticate, exchange.initial_data().unwrap()), 0).await;
+        assert_disconnect(finish_scram(&mut client, exchange.as_mut()).await, ReasonCode::NotAuthorized);
+        client.expect_closed(std::time::Duration::from_secs(5)).await;
+    }
+
+    /// Server whose authenticator records every request it sees
+    async fn start_recording_auth_server(
+        allow_anonymous: bool,
+    ) -> (SocketAddr, Arc<std::sync::Mutex<Vec<crate::server::AuthRequest>>>) {
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&seen);
+        let authenticator = crate::server::CallbackAuthenticator::new(move |request: crate::server::AuthRequest| {
+            recorded.lock().unwrap().push(request.clone());
+            async move {
+                match request.username.as_deref() {
+                    Some("offline") => Err(Error::Server("identity service down".to_string())),
+                    _ => Ok(request.password.as_deref() == Some("secret")),
+                }
+            }
+        });
+        let addr = start_test_server_with(|config| {
+            config.allow_anonymous(allow_anonymous).authenticator(Arc::new(authenticator))
+        }).await;
+        (addr, seen)
+    }
+
+    fn connect_as(client_id: &str, username: &str, password: &str) -> ConnectPacket {
+        ConnectPacket {
+            username_flag: true,
+            password_flag: true,
+            username: Some(username.to_string()),
+            password: Some(password.to_string()),
+            ..connect_packet(client_id)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_authenticator_receives_client_details() {
+        let (addr, seen) = start_recording_auth_server(false).await;
+
+        let (_client, connack) = TestClient::connect_with(addr, connect_as("device-1", "device", "secret")).await;
+        assert_eq!(connack.return_code, ConnectReturnCode::Accepted);
+
+        let request = seen.lock().unwrap()[0].clone();
+        assert_eq!(request.client_id, "device-1");
+        assert_eq!(request.username.as_deref(), Some("device"));
+        assert_eq!(request.password.as_deref(), Some("secret"));
+        assert!(request.peer_addr.is_local());
+    }
+
+    #[tokio::test]
+    async fn test_authenticator_rejections() {
+        let (addr, _) = start_recording_auth_server(false).await;
+
+        let (mut client, connack) = TestClient::connect_with(addr, connect_as("c1", "device", "wrong")).await;
+        assert_eq!(connack.return_code, ConnectReturnCode::BadUsernameOrPassword);
+        client.expect_closed(std::time::Duration::from_secs(5)).await;
+
+        // An authenticator that cannot decide makes the server unavailable
+        let (mut client, connack) = TestClient::connect_with(addr, connect_as("c2", "offline", "secret")).await;
+        assert_eq!(connack.return_code, ConnectReturnCode::ServerUnavailable);
+        client.expect_closed(std::time::Duration::from_secs(5)).await;
+
+        let (_, connack) = TestClient::connect_with(addr, connect_packet("anonymous")).await;
+        assert_eq!(connack.return_code, ConnectReturnCode::NotAuthorized);
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_clients_skip_authenticator() {
+        let (addr, seen) = start_recording_auth_server(true).await;
+        let (_client, connack) = TestClient::connect_with(addr, connect_packet("anonymous")).await;
+        assert_eq!(connack.return_code, ConnectReturnCode::Accepted);
+        assert!(seen.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_v5_authentication_failure_uses_v5_code() {
+        let users = crate::server::Authentication::new().add_user("device", "secret");
+        let addr = start_test_server_with(|config| config.protocol_version(5).authentication(users)).await;
+        let (_, connack) = TestClient::connect_with(addr, ConnectPacket {
+            protocol_version: 5,
+            ..connect_as("c1", "device", "wrong")
+        }).await;
+        assert_eq!(connack.return_code, ConnectReturnCode::BadUsernameOrPasswordV5);
+    }
+
+    #[tokio::test]
+    async fn test_unauthorized_subscriptions_fail_in_suback() {
+        let acl = crate::server::AclFile::parse("topic read public/#\ntopic write #\npattern read devices/%c/#\n").unwrap();
+        let addr = start_test_server_with(|config| config.authorizer(Arc::new(acl))).await;
+        let mut client = TestClient::connect(addr, "sensor").await;
+
+        let filter = |topic: &str| TopicFilter {
+            topic: topic.to_string(),
+            qos: 1,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling: 0,
+        };
+        client.send(PacketPayload::Subscribe(SubscribePacket {
+            packet_id: 3,
+            topic_filters: vec![filter("public/news"), filter("private/#"), filter("devices/sensor/#")],
+            properties: None,
+        }), 1).await;
+        match client.recv().await.payload {
+            PacketPayload::SubAck(suback) => assert_eq!(suback.return_codes, vec![1, 0x80, 1]),
+            other => panic!("Expected SUBACK, got {:?}", other),
+        }
+
+        // Only the granted filters were subscribed
+        let mut publisher = TestClient::connect(addr, "publisher").await;
+        publisher.publish("private/diary", "secret", 0, None).await;
+        publisher.publish("public/news", "hello", 0, None).await;
+        match client.recv().await.payload {
+            PacketPayload::Publish(publish) => assert_eq!(publish.topic_name, "public/news"),
+            other => panic!("Expected PUBLISH, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_v5_unauthorized_publish_not_authorized() {
+        let acl = crate::server::AclFile::parse("topic read #\ntopic write allowed/#\n").unwrap();
+        let addr = start_test_server_with(|config| config.protocol_version(5).authorizer(Arc::new(acl))).await;
+        let v5 = |client_id: &str| ConnectPacket { protocol_version: 5, ..connect_packet(client_id) };
+
+        let (mut subscriber, _) = TestClient::connect_with(addr, v5("subscriber")).await;
+        subscriber.subscribe("#", 1).await;
+        let (mut publisher, _) = TestClient::connect_with(addr, v5("publisher")).await;
+
+        publisher.publish("forbidden/a", "dropped", 1, Some(1)).await;
+        match publisher.recv().await.payload {
+            PacketPayload::PubAck(puback) => {
+                assert_eq!(puback.packet_id, 1);
+                assert_eq!(puback.reason_code, Some(ReasonCode::NotAuthorized as u8));
+            }
+            other => panic!("Expected PUBACK, got {:?}", other),
+        }
+        publisher.publish("forbidden/b", "dropped", 2, Some(2)).await;
+        match publisher.recv().await.payload {
+            PacketPayload::PubRec(pubrec) => assert_eq!(pubrec.reason_code, Some(ReasonCode::NotAuthorized as u8)),
+            other => panic!("Expected PUBREC, got {:?}", other),
+        }
+
+        publisher.publish("allowed/a", "delivered", 1, Some(3)).await;
+        match publisher.recv().await.payload {
+            PacketPayload::PubAck(puback) => assert_eq!(puback.reason_code, None),
+            other => panic!("Expected PUBACK, got {:?}", other),
+        }
+        match subscriber.recv().await.payload {
+            PacketPayload::Publish(publish) => assert_eq!(publish.topic_name, "allowed/a"),
+            other => panic!("Expected PUBLISH, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unauthorized_will_topic_rejected() {
+        let acl = crate::server::AclFile::parse("topic read #\ntopic write status/allowed\n").unwrap();
+        let addr = start_test_server_with(|config| config.authorizer(Arc::new(acl))).await;
+        let mut watcher = TestClient::connect(addr, "watcher").await;
+        watcher.subscribe("status/+", 1).await;
+
+        let (mut device, connack) = TestClient::connect_with(addr, connect_with_will("sneaky", true)).await;
+        assert_eq!(connack.return_code, ConnectReturnCode::NotAuthorized);
+        device.expect_closed(std::time::Duration::from_secs(5)).await;
+
+        // Neither published nor retained
+        let nothing = tokio::time::timeout(std::time::Duration::from_millis(300), watcher.recv()).await;
+        assert!(nothing.is_err());
+        let mut late = TestClient::connect(addr, "late").await;
+        late.subscribe("status/+", 1).await;
+        let nothing = tokio::time::timeout(std::time::Duration::from_millis(300), late.recv()).await;
+        assert!(nothing.is_err());
+
+        // A permitted will topic is still accepted
+        let (_device, connack) = TestClient::connect_with(addr, connect_with_will("allowed", false)).await;
+        assert_eq!(connack.return_code, ConnectReturnCode::Accepted);
+    }
+
+    #[tokio::test]
+    async fn test_v5_unauthorized_will_topic_rejected() {
+        let acl = crate::server::AclFile::parse("topic write allowed/#\n").unwrap();
+        let addr = start_test_server_with(|config| config.protocol_version(5).authorizer(Arc::new(acl))).await;
+
+        let (_device, connack) = TestClient::connect_with(addr, ConnectPacket {
+            protocol_version: 5,
+            ..connect_with_will("sneaky", false)
+        }).await;
+        assert_eq!(connack.return_code, ConnectReturnCode::NotAuthorizedV5);
+    }
+
+    #[tokio::test]
+    async fn test_share_prefix_is_an_ordinary_filter_for_v3_clients() {
+        let addr = start_test_server().await;
+
+        let mut first = TestClient::connect(addr, "worker-a").await;
+        first.subscribe("$share/workers/jobs", 1).await;
+        let mut second = TestClient::connect(addr, "worker-b").await;
+        second.subscribe("$share/workers/jobs", 1).await;
+
+        // The filter matches the topic it names, for every subscriber
+        let mut publisher = TestClient::connect(addr, "publisher").await;
+        publisher.publish("jobs", "not shared", 0, None).await;
+        publisher.publish("$share/workers/jobs", "literal", 0, None).await;
+        assert_eq!(recv_payloads(&mut first, 1).await, ["literal"]);
+        assert_eq!(recv_payloads(&mut second, 1).await, ["literal"]);
+    }
+
+    #[tokio::test]
+    async fn test_shared_subscription_delivers_to_one_member() {
+        let addr = start_test_server_with(|config| config.protocol_version(5)).await;
+        let v5 = |client_id: &str| ConnectPacket { protocol_version: 5, ..connect_packet(client_id) };
+
+        let (mut first, _) = TestClient::connect_with(addr, v5("worker-a")).await;
+        first.subscribe("$share/workers/jobs/#", 1).await;
+        let (mut second, _) = TestClient::connect_with(addr, v5("worker-b")).await;
+        second.subscribe("$share/workers/jobs/#", 1).await;
+        let (mut monitor, _) = TestClient::connect_with(addr, v5("monitor")).await;
+        monitor.subscribe("jobs/#", 0).await;
+
+        let (mut publisher, _) = TestClient::connect_with(addr, v5("publisher")).await;
+        for index in 0..4 {
+            publisher.publish("jobs/build", &format!("job-{}", index), 0, None).await;
+        }
+
+        // Round-robin splits the jobs, while ordinary subscribers see all of them
+        assert_eq!(recv_payloads(&mut first, 2).await, ["job-0", "job-2"]);
+        assert_eq!(recv_payloads(&mut second, 2).await, ["job-1", "job-3"]);
+        assert_eq!(recv_payloads(&mut monitor, 4).await, ["job-0", "job-1", "job-2", "job-3"]);
+    }
+
+    #[tokio::test]
+    async fn test_shared_subscription_messages_wait_for_a_member() {
+        let addr = start_test_server_with(|config| config.protocol_version(5)).await;
+        let persistent = |client_id: &str| connect_v5_with_expiry(client_id, 3600);
+
+        let (mut first, _) = TestClient::connect_with(addr, persistent("worker-a")).await;
+        first.subscribe("$share/workers/jobs", 1).await;
+        let (mut second, _) = TestClient::connect_with(addr, persistent("worker-b")).await;
+        second.subscribe("$share/workers/jobs", 1).await;
+        drop(first);
+        drop(second);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let (mut publisher, _) = TestClient::connect_with(addr, ConnectPacket { protocol_version: 5, ..connect_packet("publisher") }).await;
+        for (index, payload) in ["one", "two", "three"].iter().enumerate() {
+            publisher.publish("jobs", payload, 1, Some(index as u16 + 1)).await;
+            assert!(matches!(publisher.recv().await.payload, PacketPayload::PubAck(_)));
+        }
+
+        // The first member back takes over everything the group missed
+        let (mut second, _) = TestClient::connect_with(addr, persistent("worker-b")).await;
+        assert_eq!(recv_payloads(&mut second, 3).await, ["one", "two", "three"]);
+
+        // Offline members are skipped while another member is connected
+        publisher.publish("jobs", "four", 1, Some(4)).await;
+        assert_eq!(recv_payloads(&mut second, 1).await, ["four"]);
+        let (mut first, connack) = TestClient::connect_with(addr, persistent("worker-a")).await;
+        assert!(connack.session_present);
+        let nothing = tokio::time::timeout(std::time::Duration::from_millis(200), first.recv()).await;
+        assert!(nothing.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shared_subscription_messages_of_departed_member_redistributed() {
+        let addr = start_test_server_with(|config| config.protocol_version(5)).await;
+        let v5 = |client_id: &str| ConnectPacket { protocol_version: 5, ..connect_packet(client_id) };
+
+        // The first member takes one delivery at a time and never acknowledges it
+        let (mut first, _) = TestClient::connect_with(addr, ConnectPacket {
+            properties: Some(ConnectProperties { receive_maximum: Some(1), ..Default::default() }),
+            ..v5("worker-a")
+        }).await;
+        first.subscribe("$share/workers/jobs", 1).await;
+        let (mut second, _) = TestClient::connect_with(addr, v5("worker-b")).await;
+        second.subscribe("$share/workers/jobs", 1).await;
+
+        let (mut publisher, _) = TestClient::connect_with(addr, v5("publisher")).await;
+        for index in 0..4 {
+            publisher.publish("jobs", &format!("job-{}", index), 1, Some(index + 1)).await;
+            assert!(matches!(publisher.recv().await.payload, PacketPayload::PubAck(_)));
+        }
+        assert_eq!(recv_payloads(&mut first, 1).await, ["job-0"]);
+        assert_eq!(recv_payloads(&mut second, 2).await, ["job-1", "job-3"]);
+
+        // Its unacknowledged and queued jobs move to the remaining member
+        drop(first);
+        assert_eq!(recv_payloads(&mut second, 2).await, ["job-0", "job-2"]);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_shared_subscription_fails_in_suback() {
+        let addr = start_test_server_with(|config| config.protocol_version(5)).await;
+        let (mut client, _) = TestClient::connect_with(addr, ConnectPacket { protocol_version: 5, ..connect_packet("worker") }).await;
+
+        let filter = |topic: &str| TopicFilter {
+            topic: topic.to_string(),
+            qos: 1,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling: 0,
+        };
+        client.send(PacketPayload::Subscribe(SubscribePacket {
+            packet_id: 5,
+            topic_filters: vec![filter("$share/workers"), filter("$share/work#/jobs"), filter("$share/workers/jobs")],
+            properties: None,
+        }), 1).await;
+        match client.recv().await.payload {
+            PacketPayload::SubAck(suback) => assert_eq!(suback.return_codes, vec![0x8F, 0x8F, 1]),
+            other => panic!("Expected SUBACK, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_v5_invalid_topic_filters_fail_in_suback() {
+        let addr = start_test_server_with(|config| config.protocol_version(5)).await;
+        let connect = ConnectPacket { protocol_version: 5, ..connect_packet("validator") };
+        let (mut client, _) = TestClient::connect_with(addr, connect).await;
+
+        let filter = |topic: &str| TopicFilter {
+            topic: topic.to_string(),
+            qos: 0,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling: 0,
+        };
+        client.send(PacketPayload::Subscribe(SubscribePacket {
+            packet_id: 8,
+            topic_filters: vec![filter("a/#/b"), filter("foo+"), filter(""), filter("a/+/b")],
+            properties: None,
+        }), 1).await;
+        let invalid = ReasonCode::TopicFilterInvalid as u8;
+        match client.recv().await.payload {
+            PacketPayload::SubAck(suback) => assert_eq!(suback.return_codes, vec![invalid, invalid, invalid, 0]),
+            other => panic!("Expected SUBACK, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish_with_wildcard_topic_closes_connection() {
+        let addr = start_test_server_with(|config| config.protocol_version(5)).await;
+        let connect = ConnectPacket { protocol_version: 5, ..connect_packet("wildcard_publisher") };
+        let (mut client, _) = TestClient::connect_with(addr, connect).await;
+
+        client.publish("sensors/+", "bad", 1, Some(1)).await;
+        assert_disconnect(client.recv().await, ReasonCode::TopicNameInvalid);
+        client.expect_closed(std::time::Duration::from_secs(1)).await;
+    }
 }
@@ -0,0 +1,369 @@
+//! Enhanced authentication module
+//!
+//! MQTT 5.0 clients can name an authentication method in their CONNECT and then
+//! exchange AUTH packets with the server until it accepts or rejects them. Each
+//! method is implemented by an [`AuthenticationProvider`], which starts a fresh
+//! [`AuthenticationExchange`] for every connection attempt or re-authentication.
+//!
+//! [`ScramSha256Provider`] answers unknown users with made-up but stable
+//! credentials and fails them at the final message, exactly like a wrong
+//! password, so the exchange does not reveal which usernames exist.
+
+use crate::protocol::scram::{self, Key};
+use crate::protocol::SCRAM_SHA_256;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// Outcome of one step of an authentication exchange
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthStep {
+    /// Send this challenge to the client and wait for its answer
+    Continue(Bytes),
+    /// The client proved to be `username`; the data, if any, goes with the CONNACK or AUTH
+    Success { username: String, data: Option<Bytes> },
+    /// The client is rejected for the given reason
+    Failure(String),
+}
+
+/// Server side of an authentication method
+pub trait AuthenticationProvider: Send + Sync + fmt::Debug {
+    /// Authentication method name, as carried in the Authentication Method property
+    fn method(&self) -> &str;
+
+    /// Start an exchange for the given client
+    fn begin(&self, client_id: &str) -> Box<dyn AuthenticationExchange>;
+}
+
+/// State of a single authentication exchange
+pub trait AuthenticationExchange: Send {
+    /// Handle Authentication Data from the client's CONNECT or AUTH packet
+    fn step(&mut self, data: Option<&[u8]>) -> AuthStep;
+}
+
+/// Stored SCRAM credentials of a user; the password itself is not kept
+#[derive(Clone)]
+pub struct ScramCredentials {
+    salt: Vec<u8>,
+    iterations: u32,
+    stored_key: Key,
+    server_key: Key,
+}
+
+impl ScramCredentials {
+    /// Derive credentials from a password with a random salt
+    pub fn new(password: &str) -> Self {
+        let salt: [u8; 16] = rand::random();
+        Self::with_salt(password, &salt, scram::DEFAULT_ITERATIONS)
+    }
+
+    /// Derive credentials from a password with the given salt and iteration count
+    pub fn with_salt(password: &str, salt: &[u8], iterations: u32) -> Self {
+        let salted = scram::salted_password(password, salt, iterations);
+        Self {
+            salt: salt.to_vec(),
+            iterations,
+            stored_key: scram::sha256(&scram::client_key(&salted)),
+            server_key: scram::server_key(&salted),
+        }
+    }
+}
+
+impl fmt::Debug for ScramCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScramCredentials")
+            .field("iterations", &self.iterations)
+            .finish_non_exhaustive()
+    }
+}
+
+/// SCRAM-SHA-256 authentication provider
+#[derive(Clone)]
+pub struct ScramSha256Provider {
+    users: Arc<HashMap<String, ScramCredentials>>,
+    /// Key deriving the salt and keys offered for unknown users
+    secret: Key,
+}
+
+impl Default for ScramSha256Provider {
+    fn default() -> Self {
+        Self {
+            users: Arc::default(),
+            secret: rand::random(),
+        }
+    }
+}
+
+impl fmt::Debug for ScramSha256Provider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScramSha256Provider")
+            .field("users", &self.users)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ScramSha256Provider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the secret the credentials offered for unknown users are derived from
+    ///
+    /// A random secret is used by default. Keeping the same secret across
+    /// restarts keeps the salt offered for an unknown user from changing, which
+    /// would give it away as unknown.
+    pub fn secret(mut self, secret: &[u8]) -> Self {
+        self.secret = scram::sha256(secret);
+        self
+    }
+
+    /// Add a user, deriving its credentials from the password
+    pub fn add_user(self, username: impl Into<String>, password: &str) -> Self {
+        self.add_credentials(username, ScramCredentials::new(password))
+    }
+
+    /// Add a user with precomputed credentials
+    pub fn add_credentials(mut self, username: impl Into<String>, credentials: ScramCredentials) -> Self {
+        Arc::make_mut(&mut self.users).insert(username.into(), credentials);
+        self
+    }
+}
+
+impl AuthenticationProvider for ScramSha256Provider {
+    fn method(&self) -> &str {
+        SCRAM_SHA_256
+    }
+
+    fn begin(&self, _client_id: &str) -> Box<dyn AuthenticationExchange> {
+        Box::new(ScramServerExchange {
+            users: Arc::clone(&self.users),
+            secret: self.secret,
+            server_nonce: scram::nonce(),
+            state: ScramServerState::ClientFirst,
+        })
+    }
+}
+
+enum ScramServerState {
+    /// Waiting for `n,,n=<user>,r=<client nonce>`
+    ClientFirst,
+    /// Waiting for `c=<binding>,r=<nonce>,p=<proof>`
+    ClientFinal {
+        username: String,
+        credentials: ScramCredentials,
+        /// Whether the credentials are made up for an unknown user
+        unknown: bool,
+        nonce: String,
+        /// `client-first-bare,server-first`, completed by the client final message
+        auth_message: String,
+    },
+    Done,
+}
+
+struct ScramServerExchange {
+    users: Arc<HashMap<String, ScramCredentials>>,
+    secret: Key,
+    server_nonce: String,
+    state: ScramServerState,
+}
+
+impl ScramServerExchange {
+    /// Credentials offered for an unknown user, the same every time for a username
+    fn unknown_user_credentials(&self, username: &str) -> ScramCredentials {
+        let derive = |label: &str| scram::hmac(&self.secret, format!("{}:{}", label, username).as_bytes());
+        ScramCredentials {
+            salt: derive("salt")[..16].to_vec(),
+            iterations: scram::DEFAULT_ITERATIONS,
+            stored_key: derive("stored-key"),
+            server_key: derive("server-key"),
+        }
+    }
+
+    fn client_first(&self, message: &str) -> Result<(ScramServerState, Bytes), String> {
+        let bare = message
+            .strip_prefix(scram::GS2_HEADER)
+            .ok_or("unsupported GS2 header")?;
+        let username = scram::unescape_username(scram::attribute(bare, 'n').map_err(|e| e.to_string())?);
+        let client_nonce = scram::attribute(bare, 'r').map_err(|e| e.to_string())?;
+        let (credentials, unknown) = match self.users.get(&username) {
+            Some(credentials) => (credentials.clone(), false),
+            None => (self.unknown_user_credentials(&username), true),
+        };
+
+        let nonce = format!("{}{}", client_nonce, self.server_nonce);
+        let server_first = format!(
+            "r={},s={},i={}",
+            nonce,
+            scram::encode_base64(&credentials.salt),
+            credentials.iterations
+        );
+        let auth_message = format!("{},{}", bare, server_first);
+        let state = ScramServerState::ClientFinal { username, credentials, unknown, nonce, auth_message };
+        Ok((state, Bytes::from(server_first)))
+    }
+
+    fn client_final(
+        credentials: &ScramCredentials,
+        unknown: bool,
+        nonce: &str,
+        auth_message: &str,
+        message: &str,
+    ) -> Result<Bytes, String> {
+        let binding = scram::attribute(message, 'c').map_err(|e| e.to_string())?;
+        if binding != scram::encode_base64(scram::GS2_HEADER.as_bytes()) {
+            return Err("channel binding mismatch".to_string());
+        }
+        if scram::attribute(message, 'r').map_err(|e| e.to_string())? != nonce {
+            return Err("nonce mismatch".to_string());
+        }
+        let (without_proof, proof) = message.rsplit_once(",p=").ok_or("missing proof")?;
+        let proof: Key = scram::decode_base64(proof)
+            .map_err(|e| e.to_string())?
+            .try_into()
+            .map_err(|_| "invalid proof length")?;
+
+        let auth_message = format!("{},{}", auth_message, without_proof);
+        let client_signature = scram::hmac(&credentials.stored_key, auth_message.as_bytes());
+        let client_key = scram::xor(&proof, &client_signature);
+        let proven = scram::keys_equal(&scram::sha256(&client_key), &credentials.stored_key);
+        if !proven || unknown {
+            return Err("invalid proof".to_string());
+        }
+
+        let server_signature = scram::hmac(&credentials.server_key, auth_message.as_bytes());
+        Ok(Bytes::from(format!("v={}", scram::encode_base64(&server_signature))))
+    }
+}
+
+impl AuthenticationExchange for ScramServerExchange {
+    fn step(&mut self, data: Option<&[u8]>) -> AuthStep {
+        let message = match scram::message(data) {
+            Ok(message) => message,
+            Err(e) => return AuthStep::Failure(e.to_string()),
+        };
+
+        match std::mem::replace(&mut self.state, ScramServerState::Done) {
+            ScramServerState::ClientFirst => match self.client_first(message) {
+                Ok((state, server_first)) => {
+                    self.state = state;
+                    AuthStep::Continue(server_first)
+                }
+                Err(reason) => AuthStep::Failure(reason),
+            },
+            ScramServerState::ClientFinal { username, credentials, unknown, nonce, auth_message } => {
+                match Self::client_final(&credentials, unknown, &nonce, &auth_message, message) {
+                    Ok(server_final) => AuthStep::Success { username, data: Some(server_final) },
+                    Err(reason) => AuthStep::Failure(reason),
+                }
+            }
+            ScramServerState::Done => AuthStep::Failure("exchange already finished".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Exchange with the RFC 7677 example user and server nonce
+    fn rfc7677_exchange() -> ScramServerExchange {
+        let salt = scram::decode_base64("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+        let provider = ScramSha256Provider::new()
+            .secret(b"test secret")
+            .add_credentials("user", ScramCredentials::with_salt("pencil", &salt, 4096));
+        ScramServerExchange {
+            users: provider.users,
+            secret: provider.secret,
+            server_nonce: "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0".to_string(),
+            state: ScramServerState::ClientFirst,
+        }
+    }
+
+    #[test]
+    fn test_scram_server_rfc7677_exchange() {
+        let mut exchange = rfc7677_exchange();
+
+        assert_eq!(
+            exchange.step(Some(b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO")),
+            AuthStep::Continue(Bytes::from_static(
+                b"r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096"
+            ))
+        );
+        assert_eq!(
+            exchange.step(Some(
+                b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+            )),
+            AuthStep::Success {
+                username: "user".to_string(),
+                data: Some(Bytes::from_static(b"v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=")),
+            }
+        );
+        assert!(matches!(exchange.step(Some(b"n,,n=user,r=x")), AuthStep::Failure(_)));
+    }
+
+    #[test]
+    fn test_scram_server_rejects_bad_proof() {
+        let mut exchange = rfc7677_exchange();
+        assert!(matches!(exchange.step(Some(b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO")), AuthStep::Continue(_)));
+
+        let forged = format!(
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p={}",
+            scram::encode_base64(&[0u8; 32])
+        );
+        assert!(matches!(exchange.step(Some(forged.as_bytes())), AuthStep::Failure(_)));
+    }
+
+    /// Run a whole exchange as `username` with `password`, returning the final step
+    fn exchange_as(exchange: &mut ScramServerExchange, username: &str, password: &str) -> (String, AuthStep) {
+        use crate::client::{ChallengeHandler, ScramSha256};
+
+        let mut client = ScramSha256::new(username, password).begin();
+        let initial = client.initial_data().unwrap();
+        let AuthStep::Continue(server_first) = exchange.step(initial.as_deref()) else {
+            panic!("Expected a challenge for '{}'", username);
+        };
+        let client_final = client.respond(Some(&server_first)).unwrap();
+        (String::from_utf8(server_first.to_vec()).unwrap(), exchange.step(client_final.as_deref()))
+    }
+
+    #[test]
+    fn test_scram_server_hides_unknown_users() {
+        // An unknown user is challenged like a known one and fails like a wrong password
+        let (_, wrong_password) = exchange_as(&mut rfc7677_exchange(), "user", "wrong");
+        let (server_first, unknown) = exchange_as(&mut rfc7677_exchange(), "nobody", "pencil");
+        assert_eq!(unknown, wrong_password);
+        assert_eq!(unknown, AuthStep::Failure("invalid proof".to_string()));
+        assert!(server_first.ends_with(&format!(",i={}", scram::DEFAULT_ITERATIONS)));
+
+        // The salt offered for a username stays the same, and differs between usernames
+        let salt = |server_first: &str| server_first.split(",s=").nth(1).unwrap().split(',').next().unwrap().to_string();
+        let (again, _) = exchange_as(&mut rfc7677_exchange(), "nobody", "pencil");
+        let (other, _) = exchange_as(&mut rfc7677_exchange(), "somebody", "pencil");
+        assert_eq!(salt(&again), salt(&server_first));
+        assert_ne!(salt(&other), salt(&server_first));
+
+        // Providers sharing a secret offer the same salt
+        let provider = |secret: &[u8]| ScramSha256Provider::new().secret(secret);
+        let first_salt = |provider: ScramSha256Provider| {
+            let mut exchange = provider.begin("client");
+            let AuthStep::Continue(server_first) = exchange.step(Some(b"n,,n=nobody,r=abc")) else {
+                panic!("Expected a challenge");
+            };
+            salt(std::str::from_utf8(&server_first).unwrap())
+        };
+        assert_eq!(first_salt(provider(b"shared")), first_salt(provider(b"shared")));
+        assert_ne!(first_salt(provider(b"shared")), first_salt(provider(b"other")));
+    }
+
+    #[test]
+    fn test_scram_server_rejects_bad_input() {
+        assert!(matches!(rfc7677_exchange().step(Some(b"p=tls-unique,,n=user,r=abc")), AuthStep::Failure(_)));
+        assert!(matches!(rfc7677_exchange().step(None), AuthStep::Failure(_)));
+
+        // The final message must echo the combined nonce
+        let mut exchange = rfc7677_exchange();
+        exchange.step(Some(b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO"));
+        assert!(matches!(exchange.step(Some(b"c=biws,r=other,p=AAAA")), AuthStep::Failure(_)));
+    }
+}
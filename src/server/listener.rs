@@ -0,0 +1,302 @@
+//! Listener configuration module
+//!
+//! A `Server` accepts connections on `ServerConfig::bind_addr` and on every
+//! [`ListenerConfig`] added with `ServerConfig::listener`. Each listener can
+//! override the protocol version, anonymous access and connection limit of the
+//! server, while all of them share its sessions and subscriptions.
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use tokio::net::TcpListener;
+
+use super::config::ServerConfig;
+use super::tls::TlsConfig;
+use crate::error::{Error, Result};
+use crate::transport::{PeerAddr, Transport};
+
+/// Where a listener accepts connections
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenerAddr {
+    /// TCP address such as `0.0.0.0:1883`
+    Tcp(String),
+    /// Path of a Unix domain socket
+    Unix(PathBuf),
+}
+
+impl fmt::Display for ListenerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Address, transport and per-listener limits of one listener
+///
+/// Settings left unset fall back to those of the `ServerConfig`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenerConfig {
+    pub addr: ListenerAddr,
+    /// Accept TLS connections
+    pub tls: Option<TlsConfig>,
+    /// Accept MQTT over WebSocket instead of raw MQTT
+    pub websocket: bool,
+    /// MQTT version spoken by clients of this listener
+    pub protocol_version: Option<u8>,
+    /// Whether clients may connect without credentials
+    pub allow_anonymous: Option<bool>,
+    /// Maximum number of simultaneous connections on this listener
+    pub max_connections: Option<usize>,
+}
+
+impl ListenerConfig {
+    /// Listen on a TCP address
+    pub fn tcp(addr: impl Into<String>) -> Self {
+        Self::new(ListenerAddr::Tcp(addr.into()))
+    }
+
+    /// Listen on a Unix domain socket, replacing a stale socket file at `path`
+    pub fn unix(path: impl Into<PathBuf>) -> Self {
+        Self::new(ListenerAddr::Unix(path.into()))
+    }
+
+    fn new(addr: ListenerAddr) -> Self {
+        Self {
+            addr,
+            tls: None,
+            websocket: false,
+            protocol_version: None,
+            allow_anonymous: None,
+            max_connections: None,
+        }
+    }
+
+    /// Serve clients of this listener over TLS
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Carry MQTT in WebSocket messages
+    pub fn websocket(mut self) -> Self {
+        self.websocket = true;
+        self
+    }
+
+    pub fn protocol_version(mut self, version: u8) -> Self {
+        self.protocol_version = Some(version);
+        self
+    }
+
+    pub fn allow_anonymous(mut self, allow: bool) -> Self {
+        self.allow_anonymous = Some(allow);
+        self
+    }
+
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Server configuration seen by connections accepted on this listener
+    pub fn apply(&self, config: &ServerConfig) -> ServerConfig {
+        let mut config = config.clone();
+        if let Some(version) = self.protocol_version {
+            config.protocol_version = version;
+        }
+        if let Some(allow) = self.allow_anonymous {
+            config.allow_anonymous = allow;
+        }
+        if let Some(max) = self.max_connections {
+            config.max_connections = max;
+        }
+        config
+    }
+}
+
+/// Bound socket of a listener
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    /// Unix domain socket with the path it is bound to
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, PathBuf),
+}
+
+impl Listener {
+    pub(crate) async fn bind(addr: &ListenerAddr) -> Result<Self> {
+        let bind_error = |e: io::Error| Error::Server(format!("Failed to bind to {}: {}", addr, e));
+        match addr {
+            ListenerAddr::Tcp(tcp_addr) => Ok(Self::Tcp(TcpListener::bind(tcp_addr).await.map_err(bind_error)?)),
+            #[cfg(unix)]
+            ListenerAddr::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
+
+                // A previous run leaves its socket file behind
+                if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                    std::fs::remove_file(path).map_err(bind_error)?;
+                }
+                Ok(Self::Unix(tokio::net::UnixListener::bind(path).map_err(bind_error)?, path.clone()))
+            }
+            #[cfg(not(unix))]
+            ListenerAddr::Unix(_) => Err(Error::Server(format!("Cannot bind to {}: Unix sockets are not supported", addr))),
+        }
+    }
+
+    /// Address the listener is bound to, with the port resolved for TCP
+    pub(crate) fn local_addr(&self) -> Result<ListenerAddr> {
+        match self {
+            Self::Tcp(listener) => {
+                let addr = listener.local_addr().map_err(|e| Error::Server(format!("Failed to get listener address: {}", e)))?;
+                Ok(ListenerAddr::Tcp(addr.to_string()))
+            }
+            #[cfg(unix)]
+            Self::Unix(_, path) => Ok(ListenerAddr::Unix(path.clone())),
+        }
+    }
+
+    /// Accept the next connection
+    ///
+    /// Unix socket peers are usually unnamed, so they are known by the path of
+    /// the listening socket.
+    pub(crate) async fn accept(&self) -> io::Result<(Box<dyn Transport>, PeerAddr)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Box::new(stream), PeerAddr::Tcp(addr)))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener, path) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), PeerAddr::Unix(path.clone())))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{ClientConfig, ClientConnection, ConnectOptions, PublishOptions, QoS};
+    use crate::server::{Authentication, Server};
+    use crate::types::{ConnectReturnCode, PacketPayload};
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn test_listener_settings_override_server() {
+        let server = ServerConfig::new("127.0.0.1:1883").max_connections(100);
+        let listener = ListenerConfig::tcp("0.0.0.0:8883")
+            .tls(TlsConfig::new("server.pem", "server.key"))
+            .protocol_version(5)
+            .allow_anonymous(false)
+            .max_connections(10);
+
+        let config = listener.apply(&server);
+        assert_eq!(config.protocol_version, 5);
+        assert!(!config.allow_anonymous);
+        assert_eq!(config.max_connections, 10);
+
+        // Unset settings are inherited
+        let config = ListenerConfig::unix("/tmp/mqtt.sock").websocket().apply(&server);
+        assert_eq!(config.protocol_version, 4);
+        assert!(config.allow_anonymous);
+        assert_eq!(config.max_connections, 100);
+    }
+
+    #[test]
+    fn test_listener_addr_display() {
+        assert_eq!(ListenerConfig::tcp("0.0.0.0:1883").addr.to_string(), "0.0.0.0:1883");
+        assert_eq!(ListenerConfig::unix("/run/mqtt.sock").addr.to_string(), "unix:/run/mqtt.sock");
+    }
+
+    #[cfg(unix)]
+    async fn connect<S: Transport + 'static>(stream: S, client_id: &str) -> (ClientConnection<S>, ConnectReturnCode) {
+        let mut connection = ClientConnection::new(stream, ClientConfig::new("unused"));
+        let connack = connection.connect(ConnectOptions::new(client_id)).await.unwrap();
+        (connection, connack.return_code)
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_listeners_share_sessions_and_routes() {
+        let dir = std::env::temp_dir().join(format!("dumq-listeners-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("mqtt.sock");
+
+        let config = ServerConfig::new("127.0.0.1:0")
+            .authentication(Authentication::new().add_user("agent", "secret"))
+            .listener(ListenerConfig::tcp("127.0.0.1:0").allow_anonymous(false))
+            .listener(ListenerConfig::tcp("127.0.0.1:0").max_connections(1))
+            .listener(ListenerConfig::unix(&socket));
+        let mut server = Server::new(config);
+        server.bind().await.unwrap();
+        let addrs = server.local_addrs();
+        assert_eq!(addrs[3], ListenerAddr::Unix(socket.clone()));
+        let [open_addr, secured_addr, limited_addr] = [0, 1, 2].map(|index| match &addrs[index] {
+            ListenerAddr::Tcp(addr) => addr.clone(),
+            other => panic!("Expected a TCP listener, got {}", other),
+        });
+        tokio::spawn(async move { server.start().await });
+
+        // Anonymous clients are only turned away by the secured listener
+        let (_, code) = connect(TcpStream::connect(&secured_addr).await.unwrap(), "anonymous").await;
+        assert_eq!(code, ConnectReturnCode::NotAuthorized);
+
+        // The limited listener accepts a single connection at a time
+        let (_first, code) = connect(TcpStream::connect(&limited_addr).await.unwrap(), "first").await;
+        assert_eq!(code, ConnectReturnCode::Accepted);
+        let mut second = ClientConnection::new(TcpStream::connect(&limited_addr).await.unwrap(), ClientConfig::new("unused"));
+        assert!(second.connect(ConnectOptions::new("second")).await.is_err());
+
+        // A subscriber on the Unix socket receives what is published over TCP
+        let (mut subscriber, code) = connect(tokio::net::UnixStream::connect(&socket).await.unwrap(), "local_agent").await;
+        assert_eq!(code, ConnectReturnCode::Accepted);
+        subscriber.subscribe("agents/#", QoS::AtMostOnce, 1).await.unwrap();
+        subscriber.read_packet().await.unwrap(); // SUBACK
+
+        let (mut publisher, _) = connect(TcpStream::connect(&open_addr).await.unwrap(), "publisher").await;
+        publisher.publish(PublishOptions::new("agents/status", "up")).await.unwrap();
+
+        let packet = tokio::time::timeout(Duration::from_secs(5), subscriber.read_packet()).await.unwrap().unwrap();
+        match packet.payload {
+            PacketPayload::Publish(publish) => assert_eq!(publish.topic_name, "agents/status"),
+            other => panic!("Expected PUBLISH, got {:?}", other),
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket_peer_addr() {
+        let dir = std::env::temp_dir().join(format!("dumq-peer-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("mqtt.sock");
+
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorder = std::sync::Arc::clone(&seen);
+        let authenticator = crate::server::CallbackAuthenticator::new(move |request| {
+            recorder.lock().unwrap().push(request.peer_addr);
+            async { Ok(true) }
+        });
+        let config = ServerConfig::new("127.0.0.1:0")
+            .authenticator(std::sync::Arc::new(authenticator))
+            .listener(ListenerConfig::unix(&socket));
+        let mut server = Server::new(config);
+        server.bind().await.unwrap();
+        tokio::spawn(async move { server.start().await });
+
+        // The authenticator sees the socket path rather than a made-up IP address
+        let stream = tokio::net::UnixStream::connect(&socket).await.unwrap();
+        let mut connection = ClientConnection::new(stream, ClientConfig::new("unused"));
+        let options = ConnectOptions::new("local_agent").username("agent").password("secret");
+        assert_eq!(connection.connect(options).await.unwrap().return_code, ConnectReturnCode::Accepted);
+        let peer = seen.lock().unwrap()[0].clone();
+        assert_eq!(peer, PeerAddr::Unix(socket.clone()));
+        assert!(peer.is_local());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -6,87 +6,226 @@
 
 pub mod config;
 pub mod auth;
+pub mod acl;
+pub mod enhanced_auth;
 pub mod session;
 pub mod connection;
 pub mod router;
+pub mod shared;
+pub mod trie;
+pub mod storage;
+pub mod tls;
+pub mod listener;
 
-pub use config::ServerConfig;
-pub use auth::Authentication;
-pub use session::{Session, Subscription};
+pub use config::{QueueDropPolicy, ServerConfig};
+pub use acl::{Access, AclFile, Authorizer};
+pub use auth::{AuthRequest, Authentication, Authenticator, CallbackAuthenticator, PasswordFile};
+pub use enhanced_auth::{AuthStep, AuthenticationExchange, AuthenticationProvider, ScramCredentials, ScramSha256Provider};
+pub use session::{Session, Subscription, Will, SESSION_NEVER_EXPIRES};
 pub use connection::ServerConnection;
 pub use router::MessageRouter;
+pub use shared::SharedSubscriptionStrategy;
+pub use trie::TopicTrie;
+pub use crate::protocol::PacketIdAllocator;
+pub use storage::{FileStorage, MemoryStorage, Storage, StoredSession};
+pub use tls::TlsConfig;
+pub use listener::{ListenerAddr, ListenerConfig};
+pub use crate::transport::PeerAddr;
 
 use crate::error::Result;
-use log::info;
-use tokio::net::TcpListener;
+use log::{info, warn};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Semaphore;
+use tokio_rustls::TlsAcceptor;
 use std::sync::Arc;
 
 
+use self::listener::Listener;
 use self::session::SessionManager;
 use self::router::MessageRouter as Router;
 
 /// MQTT server
 pub struct Server {
     config: ServerConfig,
-    listener: Option<TcpListener>,
     session_manager: Arc<SessionManager>,
     message_router: Arc<Router>,
+    /// Listeners bound by `bind`, waiting for `start` to accept connections
+    bound: Vec<Bound>,
+}
+
+/// Bound listener with everything its accept loop needs
+struct Bound {
+    listener: Listener,
+    /// Address the listener is bound to, with the port resolved
+    addr: ListenerAddr,
+    /// Server configuration with the listener's overrides applied
+    config: ServerConfig,
+    tls: Option<TlsAcceptor>,
+    websocket: bool,
 }
 
 impl Server {
     /// Create a new MQTT server
     pub fn new(config: ServerConfig) -> Self {
+        let session_manager = SessionManager::with_queue_limits(
+            config.max_pending_messages,
+            config.queue_drop_policy,
+        ).shared_subscription_strategy(config.shared_subscription_strategy);
         Self {
             config,
-            listener: None,
-            session_manager: Arc::new(SessionManager::new()),
+            session_manager: Arc::new(session_manager),
             message_router: Arc::new(Router::new()),
+            bound: Vec::new(),
+        }
+    }
+
+    /// Persist sessions and retained messages to the given storage backend
+    ///
+    /// Whatever the storage already holds is restored when the server starts.
+    pub fn storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        let session_manager = SessionManager::with_queue_limits(
+            self.config.max_pending_messages,
+            self.config.queue_drop_policy,
+        ).shared_subscription_strategy(self.config.shared_subscription_strategy);
+        self.session_manager = Arc::new(session_manager.storage(Arc::clone(&storage)));
+        self.message_router = Arc::new(Router::new().storage(storage));
+        self
+    }
+
+    /// Restore stored state and bind every listener, without accepting connections yet
+    ///
+    /// `start` binds the listeners itself if this was not called before. Binding
+    /// first lets the caller learn the addresses with `local_addrs`, such as the
+    /// port picked for a listener on port 0. Does nothing if already bound.
+    pub async fn bind(&mut self) -> Result<()> {
+        if !self.bound.is_empty() {
+            return Ok(());
+        }
+        info!("Starting MQTT server on {}", self.config.bind_addr);
+
+        // Load certificates up front so a bad TLS setup fails the start
+        let listeners = self.config.all_listeners();
+        let acceptors = listeners.iter()
+            .map(|listener| listener.tls.as_ref().map(TlsConfig::acceptor).transpose())
+            .collect::<Result<Vec<_>>>()?;
+
+        let sessions = self.session_manager.restore().await?;
+        let retained = self.message_router.restore().await?;
+        if sessions > 0 || retained > 0 {
+            info!("Restored {} sessions and {} retained messages from storage", sessions, retained);
+        }
+
+        let mut bound = Vec::with_capacity(listeners.len());
+        for (listener_config, tls) in listeners.into_iter().zip(acceptors) {
+            let listener = Listener::bind(&listener_config.addr).await?;
+            let addr = listener.local_addr()?;
+            info!(
+                "Listening on {}{}{}",
+                addr,
+                if tls.is_some() { " with TLS" } else { "" },
+                if listener_config.websocket { " for MQTT over WebSocket" } else { "" },
+            );
+            bound.push(Bound {
+                listener,
+                addr,
+                config: listener_config.apply(&self.config),
+                tls,
+                websocket: listener_config.websocket,
+            });
         }
+        self.bound = bound;
+        Ok(())
+    }
+
+    /// Addresses of the bound listeners, `bind_addr` first
+    ///
+    /// Empty until the server is bound.
+    pub fn local_addrs(&self) -> Vec<ListenerAddr> {
+        self.bound.iter().map(|bound| bound.addr.clone()).collect()
     }
 
     /// Start the server
     pub async fn start(&mut self) -> Result<()> {
-        info!("Starting MQTT server on {}", self.config.bind_addr);
-        
-        let listener = TcpListener::bind(&self.config.bind_addr).await
-            .map_err(|e| crate::error::Error::Server(format!("Failed to bind to {}: {}", self.config.bind_addr, e)))?;
-        
-        self.listener = Some(listener);
+        self.bind().await?;
         info!("MQTT server started successfully");
 
-        self.accept_connections().await
+        Arc::clone(&self.session_manager).spawn_expiry_reaper(self.config.session_expiry_check_interval);
+
+        // The first listener runs on this task, the others on their own
+        let mut bound = std::mem::take(&mut self.bound).into_iter();
+        let first = bound.next().expect("bind_addr is always a listener");
+        for listener in bound {
+            tokio::spawn(self.accept_connections(listener));
+        }
+        self.accept_connections(first).await;
+        Ok(())
     }
 
-    /// Accept incoming connections
-    async fn accept_connections(&self) -> Result<()> {
-        let listener = self.listener.as_ref()
-            .ok_or_else(|| crate::error::Error::Server("Server not started".to_string()))?;
-
-        loop {
-            match listener.accept().await {
-                Ok((stream, addr)) => {
-                    info!("New connection from {}", addr);
-                    
-                    let config = self.config.clone();
-                    let session_manager = Arc::clone(&self.session_manager);
-                    let message_router = Arc::clone(&self.message_router);
-                    
-                    tokio::spawn(async move {
-                        if let Err(e) = ServerConnection::handle_connection(
-                            stream,
-                            addr,
-                            config,
-                            session_manager,
-                            message_router,
-                        ).await {
-                            log::error!("Connection error: {}", e);
-                        }
-                    });
-                }
-                Err(e) => {
-                    log::error!("Accept error: {}", e);
+    /// Accept incoming connections on one listener
+    fn accept_connections(&self, bound: Bound) -> impl std::future::Future<Output = ()> + Send + 'static {
+        let session_manager = Arc::clone(&self.session_manager);
+        let message_router = Arc::clone(&self.message_router);
+        let Bound { listener, config, tls, websocket, .. } = bound;
+        let slots = Arc::new(Semaphore::new(config.max_connections));
+
+        async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, addr)) => {
+                        let Ok(slot) = Arc::clone(&slots).try_acquire_owned() else {
+                            warn!("Rejecting connection from {}: limit of {} connections reached", addr, config.max_connections);
+                            continue;
+                        };
+                        info!("New connection from {}", addr);
+
+                        let config = config.clone();
+                        let session_manager = Arc::clone(&session_manager);
+                        let message_router = Arc::clone(&message_router);
+                        let tls = tls.clone();
+
+                        // Handshakes run in the connection's task so a slow
+                        // client cannot hold up the accept loop
+                        tokio::spawn(async move {
+                            let result = match tls {
+                                Some(acceptor) => match acceptor.accept(stream).await {
+                                    Ok(stream) => {
+                                        Self::serve(stream, addr, websocket, config, session_manager, message_router).await
+                                    }
+                                    Err(e) => Err(crate::error::Error::Tls(format!("Handshake with {} failed: {}", addr, e))),
+                                },
+                                None => Self::serve(stream, addr, websocket, config, session_manager, message_router).await,
+                            };
+                            if let Err(e) = result {
+                                log::error!("Connection error: {}", e);
+                            }
+                            drop(slot);
+                        });
+                    }
+                    Err(e) => {
+                        log::error!("Accept error: {}", e);
+                    }
                 }
             }
         }
     }
+
+    /// Run a connection, unwrapping WebSocket framing first if needed
+    async fn serve<S>(
+        stream: S,
+        addr: PeerAddr,
+        websocket: bool,
+        config: ServerConfig,
+        session_manager: Arc<SessionManager>,
+        message_router: Arc<Router>,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        if websocket {
+            let stream = crate::websocket::accept(stream).await?;
+            ServerConnection::handle_connection(stream, addr, config, session_manager, message_router).await
+        } else {
+            ServerConnection::handle_connection(stream, addr, config, session_manager, message_router).await
+        }
+    }
 }
@@ -1,32 +1,72 @@
 //! Message routing module
 
+use crate::error::Result;
+use crate::topic;
 use crate::types::Message;
-use std::collections::HashMap;
+use log::warn;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
+use super::storage::{MemoryStorage, Storage};
+use super::trie::TopicTrie;
+
 
 /// Message router for handling message distribution and retained messages
 pub struct MessageRouter {
-    retained_messages: Arc<RwLock<HashMap<String, Message>>>,
+    retained_messages: Arc<RwLock<TopicTrie<Message>>>,
+    storage: Arc<dyn Storage>,
+}
+
+impl Default for MessageRouter {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MessageRouter {
     pub fn new() -> Self {
         Self {
-            retained_messages: Arc::new(RwLock::new(HashMap::new())),
+            retained_messages: Arc::new(RwLock::new(TopicTrie::new())),
+            storage: Arc::new(MemoryStorage::new()),
+        }
+    }
+
+    /// Persist retained messages to the given storage backend
+    pub fn storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Load the retained messages kept in storage
+    ///
+    /// Returns the number of restored messages.
+    pub async fn restore(&self) -> Result<usize> {
+        let messages = self.storage.retained_messages()?;
+        let mut retained = self.retained_messages.write().await;
+        let count = messages.len();
+        for message in messages {
+            let topic = message.topic.clone();
+            retained.insert(&topic, message);
         }
+        Ok(count)
     }
 
     /// Store a retained message
     pub async fn store_retained_message(&self, topic: String, message: Message) {
         let mut retained = self.retained_messages.write().await;
-        retained.insert(topic, message);
+        if let Err(e) = self.storage.put_retained(&message) {
+            warn!("Failed to persist retained message on '{}': {}", topic, e);
+        }
+        retained.insert(&topic, message);
     }
 
     /// Clear a retained message (empty payload with retain flag)
     pub async fn clear_retained_message(&self, topic: &str) {
         let mut retained = self.retained_messages.write().await;
+        if let Err(e) = self.storage.delete_retained(topic) {
+            warn!("Failed to remove retained message on '{}' from storage: {}", topic, e);
+        }
         retained.remove(topic);
     }
 
@@ -39,80 +79,39 @@ impl MessageRouter {
     /// Get all retained messages
     pub async fn get_all_retained_messages(&self) -> HashMap<String, Message> {
         let retained = self.retained_messages.read().await;
-        retained.clone()
+        retained.entries()
+            .into_iter()
+            .map(|(topic, message)| (topic, message.clone()))
+            .collect()
     }
 
     /// Check if a topic matches a topic filter (with wildcards)
+    ///
+    /// Wildcards at the first level do not match topics starting with `$`.
     pub fn topic_matches(filter: &str, topic: &str) -> bool {
-        // MQTT topic matching with wildcards
-        let filter_parts: Vec<&str> = filter.split('/').collect();
-        let topic_parts: Vec<&str> = topic.split('/').collect();
-        
-        let mut filter_idx = 0;
-        let mut topic_idx = 0;
-        
-        while filter_idx < filter_parts.len() && topic_idx < topic_parts.len() {
-            let filter_part = filter_parts[filter_idx];
-            let topic_part = topic_parts[topic_idx];
-            
-            if filter_part == "#" {
-                // Multi-level wildcard - matches everything
-                return true;
-            } else if filter_part == "+" {
-                // Single-level wildcard - matches any single level
-                filter_idx += 1;
-                topic_idx += 1;
-            } else if filter_part == topic_part {
-                // Exact match
-                filter_idx += 1;
-                topic_idx += 1;
-            } else {
-                // No match
-                return false;
-            }
-        }
-        
-        // Check if we've processed all parts
-        if filter_idx == filter_parts.len() && topic_idx == topic_parts.len() {
-            return true;
-        }
-        
-        // Handle trailing # wildcard
-        if filter_idx == filter_parts.len() - 1 && filter_parts[filter_idx] == "#" {
-            return true;
-        }
-        
-        false
+        topic::matches(filter, topic)
     }
 
     /// Find matching topics for a given topic filter
     pub async fn find_matching_topics(&self, topic_filter: &str) -> Vec<String> {
         let retained = self.retained_messages.read().await;
-        let mut matching_topics = Vec::new();
-        
-        for topic in retained.keys() {
-            if Self::topic_matches(topic_filter, topic) {
-                matching_topics.push(topic.clone());
-            }
-        }
-        
-        matching_topics
+        retained.match_filter(topic_filter)
+            .into_iter()
+            .map(|message| message.topic.clone())
+            .collect()
     }
 
     /// Get retained messages for matching topic filters
+    ///
+    /// A topic matched by several overlapping filters is returned once.
     pub async fn get_retained_messages_for_filters(&self, topic_filters: &[String]) -> Vec<Message> {
-        let mut messages = Vec::new();
-        
-        for topic_filter in topic_filters {
-            let matching_topics = self.find_matching_topics(topic_filter).await;
-            for topic in matching_topics {
-                if let Some(message) = self.get_retained_message(&topic).await {
-                    messages.push(message);
-                }
-            }
-        }
-        
-        messages
+        let retained = self.retained_messages.read().await;
+        let mut seen = HashSet::new();
+        topic_filters.iter()
+            .flat_map(|topic_filter| retained.match_filter(topic_filter))
+            .filter(|message| seen.insert(message.topic.as_str()))
+            .cloned()
+            .collect()
     }
 }
 
@@ -141,6 +140,11 @@ mod tests {
         assert!(MessageRouter::topic_matches("#", "any/topic"));
         assert!(MessageRouter::topic_matches("+", "single"));
         assert!(!MessageRouter::topic_matches("home/+/temp", "home/temp"));
+
+        // Topics starting with $ are not matched by leading wildcards
+        assert!(!MessageRouter::topic_matches("#", "$SYS/broker/uptime"));
+        assert!(!MessageRouter::topic_matches("+/broker/uptime", "$SYS/broker/uptime"));
+        assert!(MessageRouter::topic_matches("$SYS/#", "$SYS/broker/uptime"));
     }
 
     #[tokio::test]
@@ -155,6 +159,8 @@ mod tests {
             retain: true,
             dup: false,
             packet_id: None,
+            properties: None,
+            expires_at: None,
         };
         
         // Store retained message
@@ -190,6 +196,8 @@ mod tests {
             retain: true,
             dup: false,
             packet_id: None,
+            properties: None,
+            expires_at: None,
         };
         
         let message2 = Message {
@@ -199,6 +207,8 @@ mod tests {
             retain: true,
             dup: false,
             packet_id: None,
+            properties: None,
+            expires_at: None,
         };
         
         // Store messages
@@ -215,5 +225,42 @@ mod tests {
         let messages = router.get_retained_messages_for_filters(&topic_filters).await;
         assert_eq!(messages.len(), 1);
         assert_eq!(messages[0].topic, "home/living/temp");
+
+        // Overlapping filters return each message once
+        let topic_filters = vec!["home/#".to_string(), "home/+/temp".to_string(), "home/living/temp".to_string()];
+        let mut topics: Vec<String> = router.get_retained_messages_for_filters(&topic_filters).await
+            .into_iter()
+            .map(|message| message.topic)
+            .collect();
+        topics.sort();
+        assert_eq!(topics, ["home/bedroom/temp", "home/living/temp"]);
+    }
+
+    #[tokio::test]
+    async fn test_retained_messages_restored_from_storage() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let router = MessageRouter::new().storage(Arc::clone(&storage));
+
+        for topic in ["sensors/1", "sensors/2"] {
+            let message = Message {
+                topic: topic.to_string(),
+                payload: Bytes::from("on"),
+                qos: 1,
+                retain: true,
+                dup: false,
+                packet_id: None,
+                properties: None,
+                expires_at: None,
+            };
+            router.store_retained_message(topic.to_string(), message).await;
+        }
+        router.clear_retained_message("sensors/2").await;
+
+        // A router started later on the same storage sees the surviving message
+        let restarted = MessageRouter::new().storage(storage);
+        assert_eq!(restarted.restore().await.unwrap(), 1);
+        let retained = restarted.get_retained_message("sensors/1").await.unwrap();
+        assert_eq!(retained.payload, Bytes::from("on"));
+        assert!(restarted.get_retained_message("sensors/2").await.is_none());
     }
 }
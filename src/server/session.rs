@@ -1,10 +1,54 @@
 //! Session management module
 
-use crate::protocol::QoS;
-use crate::types::Message;
-use std::collections::HashMap;
+use crate::error::Result;
+use crate::protocol::{InflightStore, PacketIdAllocator, QoS};
+use crate::types::{Message, Packet, PacketType};
+use log::{debug, info, warn};
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, RwLock};
+use tokio::task::JoinHandle;
+
+use super::config::QueueDropPolicy;
+use super::shared::{self, SharedGroups, SharedSubscriptionStrategy, SHARE_PREFIX};
+use super::storage::{MemoryStorage, Storage, StoredSession};
+use super::trie::TopicTrie;
+
+/// Item queued for a connection's writer task
+#[derive(Debug)]
+pub enum Outbound {
+    /// Application message to deliver, carrying its packet ID if QoS 1/2
+    Message(Box<Message>),
+    /// PUBREL for a QoS 2 message the client has acknowledged with PUBREC
+    Release(u16),
+    /// Fully built control packet, such as an acknowledgement
+    Packet(Box<Packet>),
+    /// Flush everything queued before this item and close the connection
+    Close,
+}
+
+/// Sending half of a connection's outbound queue
+pub type OutboundSender = mpsc::UnboundedSender<Outbound>;
+
+/// Session Expiry Interval of a session that never expires
+pub const SESSION_NEVER_EXPIRES: u32 = u32::MAX;
+
+/// Will message registered by a client in its CONNECT packet
+#[derive(Debug, Clone)]
+pub struct Will {
+    pub message: Message,
+    /// MQTT 5.0 Will Delay Interval; zero publishes the will immediately
+    pub delay: Duration,
+}
+
+/// QoS 1/2 message delivered to a client and not acknowledged yet
+#[derive(Debug, Clone)]
+pub struct InflightMessage {
+    pub message: Message,
+    /// PUBACK, PUBREC, or PUBCOMP once PUBREL was sent
+    pub awaiting: PacketType,
+}
 
 /// MQTT session
 #[derive(Debug, Clone)]
@@ -12,19 +56,77 @@ pub struct Session {
     pub client_id: String,
     pub username: Option<String>,
     pub clean_session: bool,
-    pub subscriptions: HashMap<String, QoS>,
-    pub pending_messages: Vec<Message>,
+    pub subscriptions: HashMap<String, Subscription>,
+    /// Deliveries awaiting acknowledgment, limited by the client's Receive Maximum
+    pub inflight: InflightStore<InflightMessage>,
+    /// Packet IDs of the deliveries, kept across reconnects
+    pub packet_ids: PacketIdAllocator,
+    /// QoS 1/2 messages waiting for the client to connect or for room in the
+    /// inflight window, oldest first
+    pub pending_messages: VecDeque<Message>,
+    /// Storage sequence number of the first queued message
+    pub pending_seq: u64,
+    /// Shared subscription group of queued messages, by storage sequence number
+    pub pending_groups: HashMap<u64, String>,
+    /// Shared subscription group of deliveries, by packet ID
+    pub inflight_groups: HashMap<u16, String>,
+    pub will: Option<Will>,
+    /// Session Expiry Interval in seconds; zero ends the session with the connection
+    pub expiry_interval: u32,
+    /// When the client disconnected; `None` while it is connected
+    pub disconnected_at: Option<Instant>,
 }
 
 impl Session {
+    /// Create a session, expiring like an MQTT 3.1.1 session would
     pub fn new(client_id: String, username: Option<String>, clean_session: bool) -> Self {
         Self {
             client_id,
             username,
             clean_session,
             subscriptions: HashMap::new(),
-            pending_messages: Vec::new(),
+            inflight: InflightStore::default(),
+            packet_ids: PacketIdAllocator::new(),
+            pending_messages: VecDeque::new(),
+            pending_seq: 0,
+            pending_groups: HashMap::new(),
+            inflight_groups: HashMap::new(),
+            will: None,
+            expiry_interval: if clean_session { 0 } else { SESSION_NEVER_EXPIRES },
+            disconnected_at: None,
+        }
+    }
+
+    /// Check whether the session outlives its network connection
+    pub fn is_persistent(&self) -> bool {
+        self.expiry_interval > 0
+    }
+
+    /// Check whether a disconnected session has outlived its expiry interval
+    pub fn is_expired(&self, now: Instant) -> bool {
+        match self.disconnected_at {
+            Some(disconnected_at) if self.expiry_interval != SESSION_NEVER_EXPIRES => {
+                now.saturating_duration_since(disconnected_at) >= Duration::from_secs(self.expiry_interval as u64)
+            }
+            _ => false,
+        }
+    }
+
+    /// Queue a message until the client connects or its inflight window has room
+    ///
+    /// Returns `false` if the queue was full and a message had to be dropped.
+    pub fn queue_message(&mut self, message: Message, limit: usize, policy: QueueDropPolicy) -> bool {
+        if self.pending_messages.len() < limit {
+            self.pending_messages.push_back(message);
+            return true;
+        }
+
+        if policy == QueueDropPolicy::DropOldest && limit > 0 {
+            self.pending_messages.pop_front();
+            self.pending_seq += 1;
+            self.pending_messages.push_back(message);
         }
+        false
     }
 }
 
@@ -34,37 +136,178 @@ pub struct Subscription {
     pub client_id: String,
     pub topic_filter: String,
     pub qos: QoS,
+    /// Whether this is a `$share/<group>/<filter>` shared subscription; MQTT 3.1.1
+    /// clients subscribe to such filters as ordinary ones
+    pub shared: bool,
 }
 
 impl Subscription {
+    /// Create a subscription, shared if the filter starts with `$share/`
     pub fn new(client_id: String, topic_filter: String, qos: QoS) -> Self {
         Self {
+            shared: topic_filter.starts_with(SHARE_PREFIX),
             client_id,
             topic_filter,
             qos,
         }
     }
+
+    /// Check whether this is a `$share/<group>/<filter>` subscription
+    pub fn is_shared(&self) -> bool {
+        self.shared
+    }
+
+    /// Part of the topic filter matched against topic names
+    pub fn matching_filter(&self) -> &str {
+        match self.shared {
+            true => shared::matching_filter(&self.topic_filter),
+            false => &self.topic_filter,
+        }
+    }
 }
 
 /// Session manager for handling multiple client sessions
 pub struct SessionManager {
     sessions: Arc<RwLock<HashMap<String, Session>>>,
-    subscriptions: Arc<RwLock<HashMap<String, Vec<Subscription>>>>,
+    /// Subscriptions indexed by the filter they match topics with
+    subscriptions: Arc<RwLock<TopicTrie<Vec<Subscription>>>>,
+    connections: Arc<RwLock<HashMap<String, OutboundSender>>>,
+    delayed_wills: Arc<RwLock<HashMap<String, JoinHandle<()>>>>,
+    shared_groups: SharedGroups,
+    shared_subscription_strategy: SharedSubscriptionStrategy,
+    max_pending_messages: usize,
+    queue_drop_policy: QueueDropPolicy,
+    storage: Arc<dyn Storage>,
+}
+
+impl Default for SessionManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SessionManager {
     pub fn new() -> Self {
+        Self::with_queue_limits(1000, QueueDropPolicy::default())
+    }
+
+    /// Create a session manager with the given offline queue limits
+    pub fn with_queue_limits(max_pending_messages: usize, queue_drop_policy: QueueDropPolicy) -> Self {
         Self {
             sessions: Arc::new(RwLock::new(HashMap::new())),
-            subscriptions: Arc::new(RwLock::new(HashMap::new())),
+            subscriptions: Arc::new(RwLock::new(TopicTrie::new())),
+            connections: Arc::new(RwLock::new(HashMap::new())),
+            delayed_wills: Arc::new(RwLock::new(HashMap::new())),
+            shared_groups: SharedGroups::new(),
+            shared_subscription_strategy: SharedSubscriptionStrategy::default(),
+            max_pending_messages,
+            queue_drop_policy,
+            storage: Arc::new(MemoryStorage::new()),
+        }
+    }
+
+    /// Persist sessions to the given storage backend
+    pub fn storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Choose how shared subscription groups pick the member receiving a message
+    pub fn shared_subscription_strategy(mut self, strategy: SharedSubscriptionStrategy) -> Self {
+        self.shared_subscription_strategy = strategy;
+        self
+    }
+
+    /// Load the persistent sessions kept in storage
+    ///
+    /// Restored sessions start out disconnected, with their subscriptions, queued
+    /// messages and the deliveries still awaiting acknowledgment, which are
+    /// retransmitted once the client reconnects. Returns the number of restored
+    /// sessions.
+    pub async fn restore(&self) -> Result<usize> {
+        let mut subscriptions = self.subscriptions.write().await;
+        let mut sessions = self.sessions.write().await;
+
+        let stored_sessions = self.storage.sessions()?;
+        let count = stored_sessions.len();
+        for stored in stored_sessions {
+            let mut session = Session::new(stored.client_id.clone(), stored.username, false);
+            session.expiry_interval = stored.expiry_interval;
+            session.disconnected_at = Some(Instant::now());
+
+            for subscription in self.storage.get_subscriptions(&stored.client_id)? {
+                session.subscriptions.insert(subscription.topic_filter.clone(), subscription.clone());
+                subscriptions.get_or_insert_with(subscription.matching_filter(), Vec::new).push(subscription);
+            }
+
+            let inflight = self.storage.get_inflight(&stored.client_id)?;
+            if let Some((seq, _)) = inflight.first() {
+                session.pending_seq = *seq;
+            }
+            session.pending_messages = inflight.into_iter().map(|(_, message)| message).collect();
+            for (packet_id, delivery) in self.storage.get_deliveries(&stored.client_id)? {
+                session.inflight.insert(packet_id, delivery);
+            }
+
+            sessions.insert(stored.client_id, session);
+        }
+        Ok(count)
+    }
+
+    /// Write a session to storage, or remove it once it no longer outlives its connection
+    fn persist_session(&self, session: &Session) {
+        if session.is_persistent() {
+            self.persist(self.storage.put_session(&StoredSession {
+                client_id: session.client_id.clone(),
+                username: session.username.clone(),
+                expiry_interval: session.expiry_interval,
+            }));
+        } else {
+            self.persist(self.storage.delete_session(&session.client_id));
+        }
+    }
+
+    /// Log a failed write to storage; the in-memory state stays authoritative
+    fn persist(&self, result: Result<()>) {
+        if let Err(e) = result {
+            warn!("Failed to persist broker state: {}", e);
         }
     }
 
-    /// Create or update a session
-    pub async fn create_session(&self, client_id: String, username: Option<String>, clean_session: bool) {
+    /// Create a session, or resume the stored one
+    ///
+    /// A client connecting with `clean_session=false` resumes its previous
+    /// persistent session, keeping subscriptions and queued messages, unless it
+    /// has expired. Otherwise any stored state is discarded. Returns `true` if a
+    /// session was resumed.
+    pub async fn create_session(&self, client_id: String, username: Option<String>, clean_session: bool) -> bool {
+        let mut subscriptions = self.subscriptions.write().await;
         let mut sessions = self.sessions.write().await;
+
+        if !clean_session {
+            if let Some(session) = sessions.get_mut(&client_id) {
+                if session.is_persistent() && !session.is_expired(Instant::now()) {
+                    session.username = username;
+                    session.clean_session = false;
+                    session.disconnected_at = None;
+                    self.persist_session(session);
+                    return true;
+                }
+            }
+        }
+
+        if let Some(previous) = sessions.remove(&client_id) {
+            self.purge_subscriptions(&mut subscriptions, &previous);
+            if previous.is_persistent() {
+                self.persist(self.storage.delete_session(&client_id));
+            }
+        }
         let session = Session::new(client_id.clone(), username, clean_session);
+        if session.is_persistent() {
+            self.persist_session(&session);
+        }
         sessions.insert(client_id, session);
+        false
     }
 
     /// Get a session by client ID
@@ -73,56 +316,622 @@ impl SessionManager {
         sessions.get(client_id).cloned()
     }
 
-    /// Remove a session
+    /// Remove a session along with its subscriptions
     pub async fn remove_session(&self, client_id: &str) {
+        let mut subscriptions = self.subscriptions.write().await;
         let mut sessions = self.sessions.write().await;
-        sessions.remove(client_id);
+        if let Some(session) = sessions.remove(client_id) {
+            self.purge_subscriptions(&mut subscriptions, &session);
+            self.persist(self.storage.delete_session(client_id));
+        }
     }
 
-    /// Add a subscription
-    pub async fn add_subscription(&self, client_id: String, topic_filter: String, qos: QoS) {
+    /// Set the Session Expiry Interval of a session, in seconds
+    pub async fn set_session_expiry(&self, client_id: &str, interval: u32) {
+        let mut sessions = self.sessions.write().await;
+        if let Some(session) = sessions.get_mut(client_id) {
+            session.expiry_interval = interval;
+            self.persist_session(session);
+        }
+    }
+
+    /// Detach a session from its ended network connection
+    ///
+    /// Sessions without an expiry interval are removed straight away, others are
+    /// kept until they expire. Messages of shared subscription groups the client
+    /// has not received go back to the group, to be delivered to another member.
+    /// Returns the session's expiry interval.
+    pub async fn detach_session(&self, client_id: &str) -> u32 {
+        let (expiry_interval, redistributed) = {
+            let mut subscriptions = self.subscriptions.write().await;
+            let mut sessions = self.sessions.write().await;
+
+            let Some(session) = sessions.get_mut(client_id) else {
+                return 0;
+            };
+            let expiry_interval = session.expiry_interval;
+            let messages = self.take_shared_messages(session, expiry_interval == 0);
+            if expiry_interval > 0 {
+                session.disconnected_at = Some(Instant::now());
+            } else if let Some(session) = sessions.remove(client_id) {
+                self.purge_subscriptions(&mut subscriptions, &session);
+            }
+
+            let redistributed: Vec<(String, Vec<Subscription>, Message)> = messages.into_iter()
+                .map(|(group, message)| {
+                    let members = subscriptions.get(shared::matching_filter(&group))
+                        .into_iter()
+                        .flatten()
+                        .filter(|sub| sub.shared && sub.topic_filter == group)
+                        .cloned()
+                        .collect();
+                    (group, members, message)
+                })
+                .collect();
+            (expiry_interval, redistributed)
+        };
+
+        for (group, members, message) in redistributed {
+            if members.is_empty() {
+                debug!("Shared subscription '{}' has no members left, dropping message", group);
+                continue;
+            }
+            self.deliver_shared(&group, &members, client_id, &message).await;
+        }
+        expiry_interval
+    }
+
+    /// Take the shared subscription messages a disconnected client has not received
+    ///
+    /// Queued messages and deliveries awaiting PUBACK are taken. QoS 2 deliveries
+    /// awaiting PUBREC are only taken if the session ends, as the client must be
+    /// able to complete them when it reconnects. Returns each message with the
+    /// shared subscription group it was delivered for.
+    fn take_shared_messages(&self, session: &mut Session, session_ends: bool) -> Vec<(String, Message)> {
+        let persistent = session.is_persistent();
+        let mut taken = Vec::new();
+
+        let mut packet_ids: Vec<u16> = session.inflight_groups.keys().copied().collect();
+        packet_ids.sort_unstable();
+        for packet_id in packet_ids {
+            let awaiting = session.inflight.get(packet_id).map(|inflight| inflight.awaiting);
+            let take = match awaiting {
+                Some(PacketType::PubAck) => true,
+                Some(PacketType::PubRec) => session_ends,
+                _ => false,
+            };
+            if !take {
+                continue;
+            }
+
+            let group = session.inflight_groups.remove(&packet_id);
+            if let (Some(group), Some(inflight)) = (group, session.inflight.remove(packet_id)) {
+                if persistent {
+                    self.persist(self.storage.delete_delivery(&session.client_id, packet_id));
+                }
+                taken.push((group, Message { packet_id: None, dup: false, ..inflight.message }));
+            }
+        }
+
+        if !session.pending_groups.is_empty() {
+            let first_seq = session.pending_seq;
+            let queued = std::mem::take(&mut session.pending_messages);
+            for (seq, message) in (first_seq..).zip(queued) {
+                match session.pending_groups.remove(&seq) {
+                    Some(group) => taken.push((group, message)),
+                    None => session.pending_messages.push_back(message),
+                }
+            }
+            session.pending_groups.clear();
+
+            // Renumber the messages left in storage, which are numbered by position
+            if persistent {
+                self.persist(self.storage.clear_inflight(&session.client_id));
+                for (seq, message) in (first_seq..).zip(&session.pending_messages) {
+                    self.persist(self.storage.put_inflight(&session.client_id, seq, message));
+                }
+            }
+        }
+
+        if !taken.is_empty() {
+            debug!("Redistributing {} shared subscription messages of client '{}'", taken.len(), session.client_id);
+        }
+        taken
+    }
+
+    /// Remove every disconnected session whose expiry interval has passed
+    ///
+    /// Returns the client IDs of the removed sessions.
+    pub async fn remove_expired_sessions(&self) -> Vec<String> {
+        self.remove_expired_sessions_at(Instant::now()).await
+    }
+
+    async fn remove_expired_sessions_at(&self, now: Instant) -> Vec<String> {
         let mut subscriptions = self.subscriptions.write().await;
-        let subscription = Subscription::new(client_id.clone(), topic_filter.clone(), qos);
-        
-        subscriptions
-            .entry(topic_filter.clone())
-            .or_insert_with(Vec::new)
-            .push(subscription);
+        let mut sessions = self.sessions.write().await;
 
-        // Update session
+        let expired: Vec<String> = sessions.values()
+            .filter(|session| session.is_expired(now))
+            .map(|session| session.client_id.clone())
+            .collect();
+        for client_id in &expired {
+            if let Some(session) = sessions.remove(client_id) {
+                self.purge_subscriptions(&mut subscriptions, &session);
+                self.persist(self.storage.delete_session(client_id));
+            }
+        }
+        expired
+    }
+
+    /// Spawn a background task removing expired sessions every `period`
+    pub fn spawn_expiry_reaper(self: Arc<Self>, period: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                for client_id in self.remove_expired_sessions().await {
+                    info!("Session of client '{}' expired", client_id);
+                }
+            }
+        })
+    }
+
+    /// Remove a session's entries from the subscription table
+    fn purge_subscriptions(&self, subscriptions: &mut TopicTrie<Vec<Subscription>>, session: &Session) {
+        for subscription in session.subscriptions.values() {
+            self.unsubscribe(subscriptions, subscription);
+        }
+    }
+
+    /// Remove one subscription from the subscription table
+    ///
+    /// Returns `false` if the client had no such subscription.
+    fn unsubscribe(&self, subscriptions: &mut TopicTrie<Vec<Subscription>>, subscription: &Subscription) -> bool {
+        let filter = subscription.matching_filter();
+        let Some(subs) = subscriptions.get_mut(filter) else {
+            return false;
+        };
+
+        let before = subs.len();
+        subs.retain(|sub| sub.client_id != subscription.client_id || sub.topic_filter != subscription.topic_filter);
+        let existed = subs.len() < before;
+
+        // A shared subscription group ends with its last member
+        let topic_filter = subscription.topic_filter.as_str();
+        if subscription.shared && !subs.iter().any(|sub| sub.shared && sub.topic_filter == topic_filter) {
+            self.shared_groups.forget(topic_filter);
+        }
+        if subs.is_empty() {
+            subscriptions.remove(filter);
+        }
+        existed
+    }
+
+    /// Set or clear the will message of a session
+    pub async fn set_will(&self, client_id: &str, will: Option<Will>) {
         let mut sessions = self.sessions.write().await;
-        if let Some(session) = sessions.get_mut(&client_id) {
-            session.subscriptions.insert(topic_filter, qos);
+        if let Some(session) = sessions.get_mut(client_id) {
+            session.will = will;
         }
     }
 
-    /// Remove a subscription
-    pub async fn remove_subscription(&self, client_id: &str, topic_filter: &str) {
-        let mut subscriptions = self.subscriptions.write().await;
-        if let Some(subs) = subscriptions.get_mut(topic_filter) {
-            subs.retain(|sub| sub.client_id != client_id);
-            if subs.is_empty() {
-                subscriptions.remove(topic_filter);
+    /// Remove and return the will message of a session
+    pub async fn take_will(&self, client_id: &str) -> Option<Will> {
+        let mut sessions = self.sessions.write().await;
+        sessions.get_mut(client_id).and_then(|session| session.will.take())
+    }
+
+    /// Track the task publishing a client's will after its delay interval
+    pub async fn schedule_will(&self, client_id: String, task: JoinHandle<()>) {
+        let mut delayed_wills = self.delayed_wills.write().await;
+        if let Some(previous) = delayed_wills.insert(client_id, task) {
+            previous.abort();
+        }
+    }
+
+    /// Cancel a delayed will that has not been published yet
+    ///
+    /// Returns `true` if a pending will was cancelled.
+    pub async fn cancel_will(&self, client_id: &str) -> bool {
+        let mut delayed_wills = self.delayed_wills.write().await;
+        match delayed_wills.remove(client_id) {
+            Some(task) if !task.is_finished() => {
+                task.abort();
+                true
             }
+            _ => false,
         }
+    }
+
+    /// Add a subscription, shared if the filter starts with `$share/`
+    pub async fn add_subscription(&self, client_id: String, topic_filter: String, qos: QoS) {
+        self.subscribe(Subscription::new(client_id, topic_filter, qos)).await;
+    }
+
+    /// Add a subscription, replacing the client's existing one on the same filter
+    pub async fn subscribe(&self, subscription: Subscription) {
+        let mut subscriptions = self.subscriptions.write().await;
+        let mut sessions = self.sessions.write().await;
+        let session = sessions.get_mut(&subscription.client_id);
+        if let Some(existing) = session.as_ref().and_then(|session| session.subscriptions.get(&subscription.topic_filter)) {
+            self.unsubscribe(&mut subscriptions, existing);
+        }
+
+        let subs = subscriptions.get_or_insert_with(subscription.matching_filter(), Vec::new);
+        subs.retain(|sub| sub.client_id != subscription.client_id || sub.topic_filter != subscription.topic_filter);
+        subs.push(subscription.clone());
 
         // Update session
+        if let Some(session) = session {
+            if session.is_persistent() {
+                self.persist(self.storage.put_subscription(&subscription));
+            }
+            session.subscriptions.insert(subscription.topic_filter.clone(), subscription);
+        }
+    }
+
+    /// Remove a subscription
+    ///
+    /// Returns `false` if the client had no subscription on the filter.
+    pub async fn remove_subscription(&self, client_id: &str, topic_filter: &str) -> bool {
+        let mut subscriptions = self.subscriptions.write().await;
         let mut sessions = self.sessions.write().await;
-        if let Some(session) = sessions.get_mut(client_id) {
+        let session = sessions.get_mut(client_id);
+        let subscription = session.as_ref()
+            .and_then(|session| session.subscriptions.get(topic_filter).cloned())
+            .unwrap_or_else(|| Subscription::new(client_id.to_string(), topic_filter.to_string(), QoS::AtMostOnce));
+        let existed = self.unsubscribe(&mut subscriptions, &subscription);
+
+        // Update session
+        if let Some(session) = session {
             session.subscriptions.remove(topic_filter);
+            if existed && session.is_persistent() {
+                self.persist(self.storage.delete_subscription(client_id, topic_filter));
+            }
+        }
+        existed
+    }
+
+    /// Get all subscriptions on a topic filter
+    ///
+    /// A filter starting with `$share/` finds both the shared subscriptions and
+    /// MQTT 3.1.1 subscriptions to the same filter.
+    pub async fn get_subscriptions(&self, topic_filter: &str) -> Vec<Subscription> {
+        let subscriptions = self.subscriptions.read().await;
+        let mut filters = vec![topic_filter];
+        if shared::matching_filter(topic_filter) != topic_filter {
+            filters.push(shared::matching_filter(topic_filter));
         }
+        filters.into_iter()
+            .filter_map(|filter| subscriptions.get(filter))
+            .flatten()
+            .filter(|sub| sub.topic_filter == topic_filter)
+            .cloned()
+            .collect()
     }
 
-    /// Get all subscriptions for a topic
-    pub async fn get_subscriptions(&self, topic: &str) -> Vec<Subscription> {
+    /// Get the subscriptions whose topic filter matches a published topic
+    pub async fn get_matching_subscriptions(&self, topic: &str) -> Vec<Subscription> {
         let subscriptions = self.subscriptions.read().await;
-        subscriptions.get(topic).cloned().unwrap_or_default()
+        subscriptions.match_topic(topic)
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect()
     }
 
     /// Get all subscriptions
     pub async fn get_all_subscriptions(&self) -> HashMap<String, Vec<Subscription>> {
         let subscriptions = self.subscriptions.read().await;
-        subscriptions.clone()
+        subscriptions.entries()
+            .into_iter()
+            .map(|(topic_filter, subs)| (topic_filter, subs.clone()))
+            .collect()
+    }
+
+    /// Register the outbound channel of a connected client
+    ///
+    /// Deliveries a previous connection left unacknowledged are retransmitted
+    /// first, with the DUP flag set. Messages queued while the client was offline
+    /// follow in the order they were published, then those waiting for a member
+    /// of its shared subscription groups, as far as the inflight window allows.
+    /// Returns the channel of the connection being taken over, if the client was
+    /// already connected.
+    pub async fn register_connection(&self, client_id: String, sender: OutboundSender) -> Option<OutboundSender> {
+        let mut connections = self.connections.write().await;
+
+        let mut sessions = self.sessions.write().await;
+        if let Some(session) = sessions.get_mut(&client_id) {
+            if !session.inflight.is_empty() {
+                debug!("Retransmitting {} unacknowledged messages to client '{}'", session.inflight.len(), client_id);
+            }
+            for (packet_id, inflight) in session.inflight.iter() {
+                let outbound = if inflight.awaiting == PacketType::PubComp {
+                    Outbound::Release(packet_id)
+                } else {
+                    Outbound::Message(Box::new(Message { dup: true, ..inflight.message.clone() }))
+                };
+                let _ = sender.send(outbound);
+            }
+
+            let topic_filters: Vec<(String, QoS)> = session.subscriptions.values()
+                .filter(|subscription| subscription.shared)
+                .map(|subscription| (subscription.topic_filter.clone(), subscription.qos))
+                .collect();
+            for (topic_filter, qos) in topic_filters {
+                for message in self.shared_groups.take(&topic_filter) {
+                    let message = Message { qos: message.qos.min(qos as u8), ..message };
+                    self.enqueue(session, message, Some(&topic_filter));
+                }
+            }
+
+            if !session.pending_messages.is_empty() {
+                debug!("Replaying {} queued messages to client '{}'", session.pending_messages.len(), client_id);
+            }
+            self.fill_window(session, &sender);
+        }
+        drop(sessions);
+
+        connections.insert(client_id, sender)
+    }
+
+    /// Unregister a client's outbound channel
+    ///
+    /// The channel is only removed if it is still the one registered for the client,
+    /// so a connection that has been taken over does not unregister its successor.
+    /// Returns `true` if the channel was removed.
+    pub async fn unregister_connection(&self, client_id: &str, sender: &OutboundSender) -> bool {
+        let mut connections = self.connections.write().await;
+        if connections.get(client_id).is_some_and(|current| current.same_channel(sender)) {
+            connections.remove(client_id);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Get the outbound channel of a connected client
+    pub async fn get_connection(&self, client_id: &str) -> Option<OutboundSender> {
+        let connections = self.connections.read().await;
+        connections.get(client_id).cloned()
+    }
+
+    /// Set how many deliveries may await a client's acknowledgment at once
+    pub async fn set_inflight_window(&self, client_id: &str, window: u16) {
+        let mut sessions = self.sessions.write().await;
+        if let Some(session) = sessions.get_mut(client_id) {
+            session.inflight.set_window(window);
+        }
+    }
+
+    /// Record the PUBREC of a QoS 2 delivery, which now waits for PUBCOMP
+    ///
+    /// Returns `false` if no delivery was waiting for a PUBREC with this packet ID.
+    pub async fn release_delivery(&self, client_id: &str, packet_id: u16) -> bool {
+        let mut sessions = self.sessions.write().await;
+        let Some(session) = sessions.get_mut(client_id) else {
+            return false;
+        };
+        let persistent = session.is_persistent();
+        match session.inflight.get_mut(packet_id).filter(|inflight| inflight.awaiting == PacketType::PubRec) {
+            Some(inflight) => {
+                inflight.awaiting = PacketType::PubComp;
+                if persistent {
+                    self.persist(self.storage.put_delivery(client_id, packet_id, inflight));
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// End a delivery acknowledged by `kind`, freeing its slot in the inflight window
+    ///
+    /// The next queued message takes the slot. Returns `false` if no delivery was
+    /// waiting for this acknowledgment.
+    pub async fn complete_delivery(&self, client_id: &str, packet_id: u16, kind: PacketType) -> bool {
+        let connections = self.connections.read().await;
+        let mut sessions = self.sessions.write().await;
+        let Some(session) = sessions.get_mut(client_id) else {
+            return false;
+        };
+        if !session.inflight.get(packet_id).is_some_and(|inflight| inflight.awaiting == kind) {
+            return false;
+        }
+
+        session.inflight.remove(packet_id);
+        session.inflight_groups.remove(&packet_id);
+        if session.is_persistent() {
+            self.persist(self.storage.delete_delivery(client_id, packet_id));
+        }
+        if let Some(sender) = connections.get(client_id) {
+            self.fill_window(session, sender);
+        }
+        true
+    }
+
+    /// Deliver a message to a client
+    ///
+    /// QoS 1/2 messages beyond the client's inflight window are queued in its
+    /// session, as are those arriving while the client is offline if the session
+    /// outlives the connection. QoS 0 messages are dropped while it is offline,
+    /// and messages past their Message Expiry Interval are not delivered at all.
+    pub async fn deliver(&self, client_id: &str, message: Message) {
+        self.deliver_for(client_id, message, None).await;
+    }
+
+    /// Deliver a message to a client, for the shared subscription `group` if any
+    async fn deliver_for(&self, client_id: &str, message: Message, group: Option<&str>) {
+        if message.is_expired() {
+            debug!("Message to '{}' has expired, dropping it", client_id);
+            return;
+        }
+
+        // Holding the connection table keeps delivery ordered with `register_connection`
+        let connections = self.connections.read().await;
+        let sender = connections.get(client_id);
+        if message.qos == 0 {
+            match sender {
+                Some(sender) => {
+                    let _ = sender.send(Outbound::Message(Box::new(message)));
+                }
+                None => debug!("Client '{}' is not connected, dropping QoS 0 message", client_id),
+            }
+            return;
+        }
+
+        let mut sessions = self.sessions.write().await;
+        match (sessions.get_mut(client_id), sender) {
+            (Some(session), Some(sender)) => {
+                if session.inflight.has_capacity() && session.pending_messages.is_empty() {
+                    match session.packet_ids.allocate(|packet_id| session.inflight.contains(packet_id)) {
+                        Ok(packet_id) => {
+                            if let Some(group) = group {
+                                session.inflight_groups.insert(packet_id, group.to_string());
+                            }
+                            return self.send_inflight(session, sender, packet_id, message);
+                        }
+                        Err(e) => warn!("Cannot deliver to client '{}' yet: {}", client_id, e),
+                    }
+                }
+                debug!("Inflight window of client '{}' is full, queueing message", client_id);
+                self.enqueue(session, message, group);
+            }
+            (Some(session), None) if session.is_persistent() => self.enqueue(session, message, group),
+            _ => debug!("Client '{}' has no persistent session, dropping message", client_id),
+        }
+    }
+
+    /// Send a QoS 1/2 message, taking a slot in the inflight window
+    ///
+    /// Persistent sessions store the delivery until the client acknowledges it,
+    /// so it is retransmitted even after a broker restart.
+    fn send_inflight(&self, session: &mut Session, sender: &OutboundSender, packet_id: u16, message: Message) {
+        let message = Message { packet_id: Some(packet_id), ..message };
+        let awaiting = if message.qos == 1 { PacketType::PubAck } else { PacketType::PubRec };
+        let delivery = InflightMessage { message: message.clone(), awaiting };
+        if session.is_persistent() {
+            self.persist(self.storage.put_delivery(&session.client_id, packet_id, &delivery));
+        }
+        session.inflight.insert(packet_id, delivery);
+        // A closed channel leaves the message inflight, to be retransmitted on resume
+        let _ = sender.send(Outbound::Message(Box::new(message)));
+    }
+
+    /// Send queued messages while the inflight window has room
+    ///
+    /// Messages that expired while queued are discarded on the way.
+    fn fill_window(&self, session: &mut Session, sender: &OutboundSender) {
+        while session.inflight.has_capacity() {
+            if session.pending_messages.front().is_some_and(Message::is_expired) {
+                debug!("Queued message to '{}' has expired, dropping it", session.client_id);
+                session.pending_messages.pop_front();
+                session.pending_groups.remove(&session.pending_seq);
+                self.persist(self.storage.delete_inflight(&session.client_id, session.pending_seq));
+                session.pending_seq += 1;
+                continue;
+            }
+
+            // Shared subscription messages may have been downgraded to QoS 0
+            let packet_id = match session.pending_messages.front() {
+                None => break,
+                Some(message) if message.qos == 0 => None,
+                Some(_) => match session.packet_ids.allocate(|packet_id| session.inflight.contains(packet_id)) {
+                    Ok(packet_id) => Some(packet_id),
+                    Err(e) => {
+                        warn!("Cannot deliver to client '{}' yet: {}", session.client_id, e);
+                        break;
+                    }
+                },
+            };
+
+            let Some(message) = session.pending_messages.pop_front() else {
+                break;
+            };
+            self.persist(self.storage.delete_inflight(&session.client_id, session.pending_seq));
+            let group = session.pending_groups.remove(&session.pending_seq);
+            session.pending_seq += 1;
+            match packet_id {
+                Some(packet_id) => {
+                    if let Some(group) = group {
+                        session.inflight_groups.insert(packet_id, group);
+                    }
+                    self.send_inflight(session, sender, packet_id, message);
+                }
+                None => {
+                    let _ = sender.send(Outbound::Message(Box::new(message)));
+                }
+            }
+        }
+    }
+
+    /// Queue a message in a session, persisting it if the session outlives its connection
+    ///
+    /// `group` is the shared subscription the message was delivered for, if any.
+    fn enqueue(&self, session: &mut Session, message: Message, group: Option<&str>) {
+        let first_seq = session.pending_seq;
+        let end_seq = first_seq + session.pending_messages.len() as u64;
+        if !session.queue_message(message.clone(), self.max_pending_messages, self.queue_drop_policy) {
+            warn!("Message queue of client '{}' is full, dropped a message", session.client_id);
+        }
+        let pending_seq = session.pending_seq;
+        session.pending_groups.retain(|seq, _| *seq >= pending_seq);
+        let queued = session.pending_seq + session.pending_messages.len() as u64 > end_seq;
+        if let Some(group) = group.filter(|_| queued) {
+            session.pending_groups.insert(end_seq, group.to_string());
+        }
+        if !session.is_persistent() {
+            return;
+        }
+
+        for seq in first_seq..session.pending_seq {
+            self.persist(self.storage.delete_inflight(&session.client_id, seq));
+        }
+        if queued {
+            self.persist(self.storage.put_inflight(&session.client_id, end_seq, &message));
+        }
+    }
+
+    /// Deliver a message to one member of a shared subscription group
+    ///
+    /// `group` is the full `$share/<group>/<filter>` topic filter and `members`
+    /// its subscriptions matching the message. Connected members are preferred;
+    /// while none is connected, QoS 1/2 messages are queued for the group.
+    pub async fn deliver_shared(&self, group: &str, members: &[Subscription], publisher: &str, message: &Message) {
+        let connections = self.connections.read().await;
+        let connected: Vec<&Subscription> = members.iter()
+            .filter(|member| connections.contains_key(&member.client_id))
+            .collect();
+
+        if connected.is_empty() {
+            drop(connections);
+            if message.qos == 0 {
+                debug!("No member of '{}' is connected, dropping QoS 0 message", group);
+            } else if !self.shared_groups.queue(group, message.clone(), self.max_pending_messages, self.queue_drop_policy) {
+                warn!("Queue of shared subscription '{}' is full, dropped a message", group);
+            }
+            return;
+        }
+
+        let member = {
+            let sessions = self.sessions.read().await;
+            let inflight: HashMap<String, usize> = connected.iter()
+                .map(|member| {
+                    let count = sessions.get(&member.client_id)
+                        .map_or(0, |session| session.inflight.len() + session.pending_messages.len());
+                    (member.client_id.clone(), count)
+                })
+                .collect();
+            self.shared_groups.pick(self.shared_subscription_strategy, group, &connected, publisher, &inflight)
+        };
+        let delivery = Message {
+            qos: message.qos.min(member.qos as u8),
+            ..message.clone()
+        };
+        let client_id = member.client_id.clone();
+        drop(connections);
+        self.deliver_for(&client_id, delivery, Some(group)).await;
     }
 }
 
@@ -136,7 +945,7 @@ mod tests {
         let session = Session::new("client1".to_string(), Some("user1".to_string()), true);
         assert_eq!(session.client_id, "client1");
         assert_eq!(session.username, Some("user1".to_string()));
-        assert_eq!(session.clean_session, true);
+        assert!(session.clean_session);
         assert!(session.subscriptions.is_empty());
         assert!(session.pending_messages.is_empty());
     }
@@ -144,12 +953,14 @@ mod tests {
     #[test]
     fn test_session_add_subscription() {
         let mut session = Session::new("client1".to_string(), None, false);
-        session.subscriptions.insert("topic1".to_string(), QoS::AtLeastOnce);
-        session.subscriptions.insert("topic2".to_string(), QoS::ExactlyOnce);
+        for (topic_filter, qos) in [("topic1", QoS::AtLeastOnce), ("topic2", QoS::ExactlyOnce)] {
+            let subscription = Subscription::new("client1".to_string(), topic_filter.to_string(), qos);
+            session.subscriptions.insert(topic_filter.to_string(), subscription);
+        }
 
         assert_eq!(session.subscriptions.len(), 2);
-        assert_eq!(session.subscriptions.get("topic1"), Some(&QoS::AtLeastOnce));
-        assert_eq!(session.subscriptions.get("topic2"), Some(&QoS::ExactlyOnce));
+        assert_eq!(session.subscriptions.get("topic1").map(|sub| sub.qos), Some(QoS::AtLeastOnce));
+        assert_eq!(session.subscriptions.get("topic2").map(|sub| sub.qos), Some(QoS::ExactlyOnce));
     }
 
     #[test]
@@ -202,8 +1013,444 @@ mod tests {
         assert_eq!(subs[0].topic_filter, "topic1");
         
         // Remove subscription
-        manager.remove_subscription("client1", "topic1").await;
+        assert!(manager.remove_subscription("client1", "topic1").await);
+        assert!(!manager.remove_subscription("client1", "topic1").await);
         let subs = manager.get_subscriptions("topic1").await;
         assert_eq!(subs.len(), 0);
     }
+
+    #[tokio::test]
+    async fn test_connection_registry() {
+        let manager = SessionManager::new();
+        let (old_tx, _old_rx) = mpsc::unbounded_channel();
+        let (new_tx, _new_rx) = mpsc::unbounded_channel();
+
+        assert!(manager.register_connection("client1".to_string(), old_tx.clone()).await.is_none());
+        assert!(manager.get_connection("client1").await.is_some());
+
+        // A newer connection with the same client ID replaces the old one
+        let replaced = manager.register_connection("client1".to_string(), new_tx.clone()).await;
+        assert!(replaced.unwrap().same_channel(&old_tx));
+
+        // The old connection going away must not unregister the new one
+        assert!(!manager.unregister_connection("client1", &old_tx).await);
+        let current = manager.get_connection("client1").await.unwrap();
+        assert!(current.same_channel(&new_tx));
+
+        assert!(manager.unregister_connection("client1", &new_tx).await);
+        assert!(manager.get_connection("client1").await.is_none());
+    }
+
+    fn message(payload: &str, qos: u8) -> Message {
+        Message {
+            topic: "topic1".to_string(),
+            payload: payload.to_string().into(),
+            qos,
+            retain: false,
+            dup: false,
+            packet_id: None,
+            properties: None,
+            expires_at: None,
+        }
+    }
+
+    fn payloads(session: &Session) -> Vec<String> {
+        session.pending_messages.iter()
+            .map(|message| String::from_utf8(message.payload.to_vec()).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_sessions_restored_from_storage() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let manager = SessionManager::with_queue_limits(2, QueueDropPolicy::DropOldest)
+            .storage(Arc::clone(&storage));
+
+        manager.create_session("device".to_string(), Some("user".to_string()), false).await;
+        manager.set_session_expiry("device", 3600).await;
+        manager.add_subscription("device".to_string(), "cmd/#".to_string(), QoS::AtLeastOnce).await;
+        manager.add_subscription("device".to_string(), "old".to_string(), QoS::AtMostOnce).await;
+        assert!(manager.remove_subscription("device", "old").await);
+        manager.detach_session("device").await;
+        for payload in ["a", "b", "c"] {
+            manager.deliver("device", message(payload, 1)).await;
+        }
+
+        // Clean sessions never reach storage
+        manager.create_session("transient".to_string(), None, true).await;
+        manager.add_subscription("transient".to_string(), "x".to_string(), QoS::AtMostOnce).await;
+
+        let restarted = SessionManager::new().storage(storage);
+        assert_eq!(restarted.restore().await.unwrap(), 1);
+        assert!(restarted.get_session("transient").await.is_none());
+
+        let session = restarted.get_session("device").await.unwrap();
+        assert_eq!(session.username.as_deref(), Some("user"));
+        assert_eq!(session.expiry_interval, 3600);
+        assert!(session.disconnected_at.is_some());
+        assert_eq!(session.subscriptions.keys().collect::<Vec<_>>(), ["cmd/#"]);
+        assert_eq!(payloads(&session), ["b", "c"]);
+        assert_eq!(restarted.get_subscriptions("cmd/#").await.len(), 1);
+
+        // Replaying the queue removes it from storage, and new messages follow on
+        let (tx, _rx) = mpsc::unbounded_channel();
+        assert!(restarted.create_session("device".to_string(), None, false).await);
+        restarted.register_connection("device".to_string(), tx.clone()).await;
+        restarted.unregister_connection("device", &tx).await;
+        restarted.detach_session("device").await;
+        restarted.deliver("device", message("d", 1)).await;
+
+        let session = restarted.get_session("device").await.unwrap();
+        assert_eq!(session.pending_seq, 3);
+        assert_eq!(restarted.storage.get_inflight("device").unwrap().len(), 1);
+
+        // Ending the session removes it from storage
+        restarted.remove_session("device").await;
+        assert!(restarted.storage.sessions().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unacknowledged_deliveries_survive_restart() {
+        let path = std::env::temp_dir().join(format!("dumq_mqtt_deliveries_{}.log", std::process::id()));
+        let manager = SessionManager::new().storage(Arc::new(crate::server::FileStorage::open(&path).unwrap()));
+        manager.create_session("device".to_string(), None, false).await;
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        manager.register_connection("device".to_string(), tx).await;
+        for (payload, qos) in [("acked", 1), ("unacked", 1), ("released", 2)] {
+            manager.deliver("device", message(payload, qos)).await;
+        }
+        assert_eq!(outbound(&mut rx), ["acked#1", "unacked#2", "released#3"]);
+        assert!(manager.complete_delivery("device", 1, PacketType::PubAck).await);
+        assert!(manager.release_delivery("device", 3).await);
+
+        // The broker goes down before the client acknowledges the rest
+        drop(manager);
+        let restarted = SessionManager::new().storage(Arc::new(crate::server::FileStorage::open(&path).unwrap()));
+        assert_eq!(restarted.restore().await.unwrap(), 1);
+        assert!(restarted.create_session("device".to_string(), None, false).await);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        restarted.register_connection("device".to_string(), tx).await;
+        assert_eq!(outbound(&mut rx), ["dup:unacked#2", "pubrel#3"]);
+
+        // New deliveries do not reuse the restored packet IDs
+        restarted.deliver("device", message("next", 1)).await;
+        assert_eq!(outbound(&mut rx), ["next#1"]);
+        assert!(restarted.complete_delivery("device", 2, PacketType::PubAck).await);
+        assert!(restarted.complete_delivery("device", 3, PacketType::PubComp).await);
+        let remaining: Vec<u16> = restarted.storage.get_deliveries("device").unwrap()
+            .into_iter()
+            .map(|(packet_id, _)| packet_id)
+            .collect();
+        assert_eq!(remaining, [1]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_queue_message_drop_policies() {
+        let mut session = Session::new("client1".to_string(), None, false);
+        assert!(session.queue_message(message("a", 1), 2, QueueDropPolicy::DropOldest));
+        assert!(session.queue_message(message("b", 1), 2, QueueDropPolicy::DropOldest));
+        assert!(!session.queue_message(message("c", 1), 2, QueueDropPolicy::DropOldest));
+        assert_eq!(payloads(&session), ["b", "c"]);
+
+        assert!(!session.queue_message(message("d", 1), 2, QueueDropPolicy::DropNewest));
+        assert_eq!(payloads(&session), ["b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn test_persistent_session_resumed() {
+        let manager = SessionManager::new();
+
+        assert!(!manager.create_session("client1".to_string(), None, false).await);
+        manager.add_subscription("client1".to_string(), "topic1".to_string(), QoS::AtLeastOnce).await;
+
+        // Resuming keeps the subscription, and resubscribing does not duplicate it
+        assert!(manager.create_session("client1".to_string(), None, false).await);
+        manager.add_subscription("client1".to_string(), "topic1".to_string(), QoS::ExactlyOnce).await;
+        let subs = manager.get_subscriptions("topic1").await;
+        assert_eq!(subs.len(), 1);
+        assert_eq!(subs[0].qos, QoS::ExactlyOnce);
+
+        // A clean session discards the stored state
+        assert!(!manager.create_session("client1".to_string(), None, true).await);
+        assert!(manager.get_subscriptions("topic1").await.is_empty());
+        assert!(manager.get_session("client1").await.unwrap().subscriptions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_matching_subscriptions() {
+        let manager = SessionManager::new();
+        for client_id in ["client1", "client2"] {
+            manager.create_session(client_id.to_string(), None, true).await;
+        }
+        manager.add_subscription("client1".to_string(), "home/+/temp".to_string(), QoS::AtLeastOnce).await;
+        manager.add_subscription("client2".to_string(), "home/#".to_string(), QoS::AtMostOnce).await;
+        manager.add_subscription("client2".to_string(), "office/temp".to_string(), QoS::AtMostOnce).await;
+
+        let mut filters: Vec<String> = manager.get_matching_subscriptions("home/kitchen/temp").await
+            .into_iter()
+            .map(|sub| sub.topic_filter)
+            .collect();
+        filters.sort();
+        assert_eq!(filters, ["home/#", "home/+/temp"]);
+
+        // Removing the session takes its filters out of the index
+        manager.remove_session("client2").await;
+        assert_eq!(manager.get_matching_subscriptions("home/kitchen/temp").await.len(), 1);
+        assert!(manager.get_matching_subscriptions("office/temp").await.is_empty());
+        assert_eq!(manager.get_all_subscriptions().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_deliver_queues_for_offline_session() {
+        let manager = SessionManager::new();
+        manager.create_session("client1".to_string(), None, false).await;
+
+        manager.deliver("client1", message("first", 1)).await;
+        manager.deliver("client1", message("dropped", 0)).await;
+        manager.deliver("client1", message("second", 2)).await;
+        assert_eq!(payloads(&manager.get_session("client1").await.unwrap()), ["first", "second"]);
+
+        // Registering the connection replays the queue in order
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        manager.register_connection("client1".to_string(), tx).await;
+        manager.deliver("client1", message("live", 0)).await;
+
+        let mut received = Vec::new();
+        while let Ok(Outbound::Message(message)) = rx.try_recv() {
+            received.push(String::from_utf8(message.payload.to_vec()).unwrap());
+        }
+        assert_eq!(received, ["first", "second", "live"]);
+        assert!(manager.get_session("client1").await.unwrap().pending_messages.is_empty());
+    }
+
+    /// Drain the outbound items queued so far, rendered as `payload#id`, `dup:payload#id` or `pubrel#id`
+    fn outbound(rx: &mut mpsc::UnboundedReceiver<Outbound>) -> Vec<String> {
+        let mut items = Vec::new();
+        while let Ok(item) = rx.try_recv() {
+            items.push(match item {
+                Outbound::Message(message) => format!(
+                    "{}{}#{}",
+                    if message.dup { "dup:" } else { "" },
+                    String::from_utf8(message.payload.to_vec()).unwrap(),
+                    message.packet_id.unwrap_or(0),
+                ),
+                Outbound::Release(packet_id) => format!("pubrel#{}", packet_id),
+                other => panic!("Unexpected outbound item: {:?}", other),
+            });
+        }
+        items
+    }
+
+    #[tokio::test]
+    async fn test_inflight_window() {
+        let manager = SessionManager::new();
+        manager.create_session("client1".to_string(), None, true).await;
+        manager.set_inflight_window("client1", 2).await;
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        manager.register_connection("client1".to_string(), tx).await;
+
+        for (payload, qos) in [("a", 1), ("b", 2), ("c", 1), ("live", 0)] {
+            manager.deliver("client1", message(payload, qos)).await;
+        }
+        // QoS 0 messages do not take a slot
+        assert_eq!(outbound(&mut rx), ["a#1", "b#2", "live#0"]);
+
+        // PUBREC keeps the slot taken until PUBCOMP
+        assert!(manager.release_delivery("client1", 2).await);
+        assert!(!manager.release_delivery("client1", 1).await);
+        assert!(outbound(&mut rx).is_empty());
+        assert!(!manager.complete_delivery("client1", 2, PacketType::PubAck).await);
+        assert!(manager.complete_delivery("client1", 2, PacketType::PubComp).await);
+        assert_eq!(outbound(&mut rx), ["c#3"]);
+
+        assert!(manager.complete_delivery("client1", 1, PacketType::PubAck).await);
+        assert!(!manager.complete_delivery("client1", 1, PacketType::PubAck).await);
+        assert!(outbound(&mut rx).is_empty());
+        assert_eq!(manager.get_session("client1").await.unwrap().inflight.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_inflight_retransmitted_on_resume() {
+        let manager = SessionManager::new();
+        manager.create_session("client1".to_string(), None, false).await;
+        manager.set_inflight_window("client1", 2).await;
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        manager.register_connection("client1".to_string(), tx.clone()).await;
+
+        for (payload, qos) in [("a", 2), ("b", 1), ("c", 1)] {
+            manager.deliver("client1", message(payload, qos)).await;
+        }
+        assert!(manager.release_delivery("client1", 1).await);
+        assert_eq!(outbound(&mut rx), ["a#1", "b#2"]);
+
+        manager.unregister_connection("client1", &tx).await;
+        manager.detach_session("client1").await;
+        manager.deliver("client1", message("d", 1)).await;
+
+        // PUBREL is repeated for the released message, the rest is sent again as a duplicate
+        assert!(manager.create_session("client1".to_string(), None, false).await);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        manager.register_connection("client1".to_string(), tx).await;
+        assert_eq!(outbound(&mut rx), ["pubrel#1", "dup:b#2"]);
+
+        assert!(manager.complete_delivery("client1", 1, PacketType::PubComp).await);
+        assert_eq!(outbound(&mut rx), ["c#3"]);
+        assert!(manager.complete_delivery("client1", 2, PacketType::PubAck).await);
+        assert_eq!(outbound(&mut rx), ["d#4"]);
+    }
+
+    #[tokio::test]
+    async fn test_packet_ids_skip_inflight_after_resume() {
+        let manager = SessionManager::new();
+        manager.create_session("client1".to_string(), None, false).await;
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        manager.register_connection("client1".to_string(), tx.clone()).await;
+        manager.deliver("client1", message("held", 1)).await;
+        assert_eq!(outbound(&mut rx), ["held#1"]);
+
+        manager.unregister_connection("client1", &tx).await;
+        manager.detach_session("client1").await;
+        assert!(manager.create_session("client1".to_string(), None, false).await);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        manager.register_connection("client1".to_string(), tx).await;
+        assert_eq!(outbound(&mut rx), ["dup:held#1"]);
+
+        // The allocator carries on from the previous connection and, once
+        // wrapped, steps over the ID still awaiting its PUBACK
+        for packet_id in 2..=u16::MAX {
+            manager.deliver("client1", message("m", 1)).await;
+            assert!(manager.complete_delivery("client1", packet_id, PacketType::PubAck).await);
+        }
+        assert_eq!(outbound(&mut rx).last().map(String::as_str), Some("m#65535"));
+        manager.deliver("client1", message("wrapped", 1)).await;
+        assert_eq!(outbound(&mut rx), ["wrapped#2"]);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_deliveries_get_unique_packet_ids() {
+        let manager = Arc::new(SessionManager::new());
+        let mut receivers = Vec::new();
+        for client_id in ["client1", "client2"] {
+            manager.create_session(client_id.to_string(), None, true).await;
+            let (tx, rx) = mpsc::unbounded_channel();
+            manager.register_connection(client_id.to_string(), tx).await;
+            receivers.push(rx);
+        }
+
+        let tasks: Vec<_> = (0..8)
+            .map(|task| {
+                let manager = manager.clone();
+                tokio::spawn(async move {
+                    let client_id = if task % 2 == 0 { "client1" } else { "client2" };
+                    for _ in 0..50 {
+                        manager.deliver(client_id, message("m", 1)).await;
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        // Each session numbers its own deliveries, without gaps or repeats
+        for rx in &mut receivers {
+            let mut packet_ids = Vec::new();
+            while let Ok(Outbound::Message(message)) = rx.try_recv() {
+                packet_ids.push(message.packet_id.unwrap());
+            }
+            packet_ids.sort_unstable();
+            assert_eq!(packet_ids, (1..=200).collect::<Vec<u16>>());
+        }
+    }
+
+    #[test]
+    fn test_session_expiry() {
+        let mut session = Session::new("client1".to_string(), None, true);
+        assert!(!session.is_persistent());
+
+        let session_v311 = Session::new("client2".to_string(), None, false);
+        assert_eq!(session_v311.expiry_interval, SESSION_NEVER_EXPIRES);
+
+        session.expiry_interval = 10;
+        let now = Instant::now();
+        assert!(!session.is_expired(now));
+
+        session.disconnected_at = Some(now);
+        assert!(!session.is_expired(now + Duration::from_secs(9)));
+        assert!(session.is_expired(now + Duration::from_secs(10)));
+
+        session.expiry_interval = SESSION_NEVER_EXPIRES;
+        assert!(!session.is_expired(now + Duration::from_secs(u32::MAX as u64)));
+    }
+
+    #[tokio::test]
+    async fn test_expired_sessions_removed() {
+        let manager = SessionManager::new();
+
+        manager.create_session("short".to_string(), None, false).await;
+        manager.set_session_expiry("short", 5).await;
+        manager.add_subscription("short".to_string(), "topic1".to_string(), QoS::AtLeastOnce).await;
+        manager.create_session("forever".to_string(), None, false).await;
+        manager.create_session("transient".to_string(), None, true).await;
+
+        assert_eq!(manager.detach_session("short").await, 5);
+        assert_eq!(manager.detach_session("forever").await, SESSION_NEVER_EXPIRES);
+        assert_eq!(manager.detach_session("transient").await, 0);
+        assert!(manager.get_session("transient").await.is_none());
+
+        assert!(manager.remove_expired_sessions().await.is_empty());
+        let later = Instant::now() + Duration::from_secs(6);
+        assert_eq!(manager.remove_expired_sessions_at(later).await, ["short"]);
+        assert!(manager.get_session("short").await.is_none());
+        assert!(manager.get_subscriptions("topic1").await.is_empty());
+        assert!(manager.get_session("forever").await.is_some());
+
+        // An expired session is not resumed even before the reaper runs
+        manager.create_session("stale".to_string(), None, false).await;
+        manager.set_session_expiry("stale", 1).await;
+        manager.detach_session("stale").await;
+        let mut sessions = manager.sessions.write().await;
+        sessions.get_mut("stale").unwrap().disconnected_at = Some(Instant::now() - Duration::from_secs(2));
+        drop(sessions);
+        assert!(!manager.create_session("stale".to_string(), None, false).await);
+    }
+
+    #[tokio::test]
+    async fn test_session_will() {
+        let manager = SessionManager::new();
+        manager.create_session("client1".to_string(), None, false).await;
+
+        let will = Will {
+            message: Message {
+                topic: "status/client1".to_string(),
+                payload: "offline".into(),
+                qos: 1,
+                retain: true,
+                dup: false,
+                packet_id: None,
+                properties: None,
+                expires_at: None,
+            },
+            delay: Duration::ZERO,
+        };
+        manager.set_will("client1", Some(will)).await;
+        assert!(manager.get_session("client1").await.unwrap().will.is_some());
+
+        let taken = manager.take_will("client1").await.unwrap();
+        assert_eq!(taken.message.topic, "status/client1");
+        assert!(manager.take_will("client1").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_delayed_will() {
+        let manager = SessionManager::new();
+        assert!(!manager.cancel_will("client1").await);
+
+        let task = tokio::spawn(tokio::time::sleep(Duration::from_secs(60)));
+        manager.schedule_will("client1".to_string(), task).await;
+        assert!(manager.cancel_will("client1").await);
+        assert!(!manager.cancel_will("client1").await);
+    }
 }
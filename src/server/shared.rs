@@ -0,0 +1,218 @@
+//! Shared subscription module
+//!
+//! Subscribing to `$share/<group>/<filter>` makes an MQTT 5.0 client a member of
+//! a shared subscription group. Each message matching the filter is delivered to one
+//! member of the group only, picked by the server's
+//! [`SharedSubscriptionStrategy`]. Groups are identified by their full topic
+//! filter, so `$share/workers/jobs/#` and `$share/workers/jobs/+` are two groups.
+//!
+//! Connected members are always preferred. While none is connected, QoS 1/2
+//! messages wait in a queue of the group rather than in the session of one
+//! offline member, so whichever member reconnects first receives them.
+
+use crate::types::Message;
+use rand::Rng;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+use super::config::QueueDropPolicy;
+use super::session::Subscription;
+
+pub use crate::topic::SHARE_PREFIX;
+
+/// How a shared subscription group picks the member receiving a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SharedSubscriptionStrategy {
+    /// Take turns, in order of client ID
+    #[default]
+    RoundRobin,
+    /// Pick a member at random
+    Random,
+    /// Send every message of a publishing client to the same member
+    Sticky,
+    /// Pick the member with the fewest QoS 1/2 messages inflight or queued
+    LeastInflight,
+}
+
+/// Part of a topic filter that is matched against topic names
+///
+/// This is the filter itself, or the filter of a shared subscription.
+pub fn matching_filter(topic_filter: &str) -> &str {
+    topic_filter.strip_prefix(SHARE_PREFIX)
+        .and_then(|rest| rest.split_once('/'))
+        .map_or(topic_filter, |(_, filter)| filter)
+}
+
+/// Member selection state and offline queues of the shared subscription groups
+#[derive(Debug, Default)]
+pub(crate) struct SharedGroups {
+    /// Round-robin position of each group
+    cursors: Mutex<HashMap<String, usize>>,
+    /// Messages waiting for a member of the group to connect
+    queues: Mutex<HashMap<String, VecDeque<Message>>>,
+}
+
+impl SharedGroups {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pick the member of a group that receives the next message
+    ///
+    /// `members` must not be empty. `inflight` gives the number of QoS 1/2
+    /// messages inflight or queued for each client.
+    pub fn pick<'a>(
+        &self,
+        strategy: SharedSubscriptionStrategy,
+        group: &str,
+        members: &[&'a Subscription],
+        publisher: &str,
+        inflight: &HashMap<String, usize>,
+    ) -> &'a Subscription {
+        let mut members = members.to_vec();
+        members.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+
+        let index = match strategy {
+            SharedSubscriptionStrategy::RoundRobin => self.advance(group) % members.len(),
+            SharedSubscriptionStrategy::Random => rand::thread_rng().gen_range(0..members.len()),
+            SharedSubscriptionStrategy::Sticky => {
+                let mut hasher = DefaultHasher::new();
+                publisher.hash(&mut hasher);
+                (hasher.finish() % members.len() as u64) as usize
+            }
+            SharedSubscriptionStrategy::LeastInflight => {
+                // Ties are broken round-robin so idle members share the load
+                let start = self.advance(group);
+                (0..members.len())
+                    .map(|offset| (start + offset) % members.len())
+                    .min_by_key(|&index| inflight.get(&members[index].client_id).copied().unwrap_or(0))
+                    .unwrap_or(0)
+            }
+        };
+        members[index]
+    }
+
+    fn advance(&self, group: &str) -> usize {
+        let mut cursors = self.cursors.lock().unwrap();
+        let cursor = cursors.entry(group.to_string()).or_insert(0);
+        let current = *cursor;
+        *cursor = cursor.wrapping_add(1);
+        current
+    }
+
+    /// Queue a message until a member of the group connects
+    ///
+    /// Returns `false` if the queue was full and a message had to be dropped.
+    pub fn queue(&self, group: &str, message: Message, limit: usize, policy: QueueDropPolicy) -> bool {
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues.entry(group.to_string()).or_default();
+        if queue.len() < limit {
+            queue.push_back(message);
+            return true;
+        }
+
+        if policy == QueueDropPolicy::DropOldest && limit > 0 {
+            queue.pop_front();
+            queue.push_back(message);
+        }
+        false
+    }
+
+    /// Take the messages queued for a group
+    pub fn take(&self, group: &str) -> VecDeque<Message> {
+        let mut queues = self.queues.lock().unwrap();
+        queues.remove(group).unwrap_or_default()
+    }
+
+    /// Drop the state of a group that has lost its last member
+    pub fn forget(&self, group: &str) {
+        self.cursors.lock().unwrap().remove(group);
+        self.queues.lock().unwrap().remove(group);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::QoS;
+
+    fn members(client_ids: &[&str]) -> Vec<Subscription> {
+        client_ids.iter()
+            .map(|client_id| Subscription::new(client_id.to_string(), "$share/g/jobs".to_string(), QoS::AtLeastOnce))
+            .collect()
+    }
+
+    fn pick_many(groups: &SharedGroups, strategy: SharedSubscriptionStrategy, members: &[Subscription], publisher: &str, inflight: &HashMap<String, usize>) -> Vec<String> {
+        let members: Vec<&Subscription> = members.iter().collect();
+        (0..4)
+            .map(|_| groups.pick(strategy, "$share/g/jobs", &members, publisher, inflight).client_id.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_matching_filter() {
+        assert_eq!(matching_filter("$share/workers/jobs/+"), "jobs/+");
+        assert_eq!(matching_filter("jobs/+"), "jobs/+");
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let groups = SharedGroups::new();
+        let members = members(&["b", "a", "c"]);
+        let picked = pick_many(&groups, SharedSubscriptionStrategy::RoundRobin, &members, "publisher", &HashMap::new());
+        assert_eq!(picked, ["a", "b", "c", "a"]);
+    }
+
+    #[test]
+    fn test_sticky_by_publisher() {
+        let groups = SharedGroups::new();
+        let members = members(&["a", "b", "c"]);
+        let picked = pick_many(&groups, SharedSubscriptionStrategy::Sticky, &members, "sensor-1", &HashMap::new());
+        assert!(picked.iter().all(|client_id| *client_id == picked[0]));
+    }
+
+    #[test]
+    fn test_random_picks_members() {
+        let groups = SharedGroups::new();
+        let members = members(&["a", "b"]);
+        let picked = pick_many(&groups, SharedSubscriptionStrategy::Random, &members, "publisher", &HashMap::new());
+        assert!(picked.iter().all(|client_id| client_id == "a" || client_id == "b"));
+    }
+
+    #[test]
+    fn test_least_inflight() {
+        let groups = SharedGroups::new();
+        let members = members(&["a", "b", "c"]);
+        let inflight = HashMap::from([("a".to_string(), 3), ("b".to_string(), 1), ("c".to_string(), 1)]);
+        let picked = pick_many(&groups, SharedSubscriptionStrategy::LeastInflight, &members, "publisher", &inflight);
+        assert_eq!(picked, ["b", "b", "c", "b"]);
+    }
+
+    #[test]
+    fn test_group_queue() {
+        let groups = SharedGroups::new();
+        let message = |payload: &'static str| Message {
+            topic: "jobs".to_string(),
+            payload: bytes::Bytes::from(payload),
+            qos: 1,
+            retain: false,
+            dup: false,
+            packet_id: None,
+            properties: None,
+            expires_at: None,
+        };
+
+        assert!(groups.queue("$share/g/jobs", message("one"), 2, QueueDropPolicy::DropOldest));
+        assert!(groups.queue("$share/g/jobs", message("two"), 2, QueueDropPolicy::DropOldest));
+        assert!(!groups.queue("$share/g/jobs", message("three"), 2, QueueDropPolicy::DropOldest));
+        let queued: Vec<_> = groups.take("$share/g/jobs").into_iter().map(|m| m.payload).collect();
+        assert_eq!(queued, ["two", "three"]);
+        assert!(groups.take("$share/g/jobs").is_empty());
+
+        groups.queue("$share/g/jobs", message("four"), 2, QueueDropPolicy::DropOldest);
+        groups.forget("$share/g/jobs");
+        assert!(groups.take("$share/g/jobs").is_empty());
+    }
+}
@@ -0,0 +1,660 @@
+//! Append-only log storage backend
+//!
+//! Every change is appended to the log as a length-prefixed record. Opening the
+//! log replays it into memory, and reads are served from there. A record torn
+//! by a crash at the end of the log is discarded on open; `compact` rewrites the
+//! log to hold only the current state.
+
+use crate::codec::properties::{decode_publish_properties, encode_publish_properties};
+use crate::codec::utils::{decode_string, encode_string};
+use crate::error::{Error, Result};
+use crate::protocol::QoS;
+use crate::types::{Message, PacketType};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use log::{info, warn};
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::{MemoryStorage, Storage, StoredSession};
+use crate::server::session::{InflightMessage, Subscription};
+
+const PUT_SESSION: u8 = 1;
+const DELETE_SESSION: u8 = 2;
+const PUT_SUBSCRIPTION: u8 = 3;
+const DELETE_SUBSCRIPTION: u8 = 4;
+const PUT_RETAINED: u8 = 5;
+const DELETE_RETAINED: u8 = 6;
+const PUT_INFLIGHT: u8 = 7;
+const DELETE_INFLIGHT: u8 = 8;
+const CLEAR_INFLIGHT: u8 = 9;
+const PUT_DELIVERY: u8 = 10;
+const DELETE_DELIVERY: u8 = 11;
+
+/// A single change recorded in the log
+#[derive(Debug)]
+enum Record {
+    PutSession(StoredSession),
+    DeleteSession(String),
+    PutSubscription(Subscription),
+    DeleteSubscription(String, String),
+    PutRetained(Message),
+    DeleteRetained(String),
+    PutInflight(String, u64, Message),
+    DeleteInflight(String, u64),
+    ClearInflight(String),
+    PutDelivery(String, u16, InflightMessage),
+    DeleteDelivery(String, u16),
+}
+
+impl Record {
+    /// Encode the record with its length prefix
+    fn encode(&self, buf: &mut BytesMut) -> Result<()> {
+        let mut body = BytesMut::new();
+        match self {
+            Record::PutSession(session) => {
+                body.put_u8(PUT_SESSION);
+                encode_string(&session.client_id, &mut body)?;
+                match &session.username {
+                    Some(username) => {
+                        body.put_u8(1);
+                        encode_string(username, &mut body)?;
+                    }
+                    None => body.put_u8(0),
+                }
+                body.put_u32(session.expiry_interval);
+            }
+            Record::DeleteSession(client_id) => {
+                body.put_u8(DELETE_SESSION);
+                encode_string(client_id, &mut body)?;
+            }
+            Record::PutSubscription(subscription) => {
+                body.put_u8(PUT_SUBSCRIPTION);
+                encode_string(&subscription.client_id, &mut body)?;
+                encode_string(&subscription.topic_filter, &mut body)?;
+                body.put_u8(subscription.qos as u8);
+                body.put_u8(subscription.shared as u8);
+            }
+            Record::DeleteSubscription(client_id, topic_filter) => {
+                body.put_u8(DELETE_SUBSCRIPTION);
+                encode_string(client_id, &mut body)?;
+                encode_string(topic_filter, &mut body)?;
+            }
+            Record::PutRetained(message) => {
+                body.put_u8(PUT_RETAINED);
+                encode_message(message, &mut body)?;
+            }
+            Record::DeleteRetained(topic) => {
+                body.put_u8(DELETE_RETAINED);
+                encode_string(topic, &mut body)?;
+            }
+            Record::PutInflight(client_id, seq, message) => {
+                body.put_u8(PUT_INFLIGHT);
+                encode_string(client_id, &mut body)?;
+                body.put_u64(*seq);
+                encode_message(message, &mut body)?;
+            }
+            Record::DeleteInflight(client_id, seq) => {
+                body.put_u8(DELETE_INFLIGHT);
+                encode_string(client_id, &mut body)?;
+                body.put_u64(*seq);
+            }
+            Record::ClearInflight(client_id) => {
+                body.put_u8(CLEAR_INFLIGHT);
+                encode_string(client_id, &mut body)?;
+            }
+            Record::PutDelivery(client_id, packet_id, delivery) => {
+                body.put_u8(PUT_DELIVERY);
+                encode_string(client_id, &mut body)?;
+                body.put_u16(*packet_id);
+                body.put_u8(delivery.awaiting as u8);
+                encode_message(&delivery.message, &mut body)?;
+            }
+            Record::DeleteDelivery(client_id, packet_id) => {
+                body.put_u8(DELETE_DELIVERY);
+                encode_string(client_id, &mut body)?;
+                body.put_u16(*packet_id);
+            }
+        }
+
+        buf.put_u32(body.len() as u32);
+        buf.extend_from_slice(&body);
+        Ok(())
+    }
+
+    /// Decode a record body, without its length prefix
+    fn decode(buf: &mut BytesMut) -> Result<Self> {
+        if !buf.has_remaining() {
+            return Err(Error::Deserialization("Empty storage record".to_string()));
+        }
+
+        let record = match buf.get_u8() {
+            PUT_SESSION => {
+                let client_id = decode_string(buf)?;
+                let username = if get_u8(buf)? == 1 { Some(decode_string(buf)?) } else { None };
+                let expiry_interval = get_u32(buf)?;
+                Record::PutSession(StoredSession { client_id, username, expiry_interval })
+            }
+            DELETE_SESSION => Record::DeleteSession(decode_string(buf)?),
+            PUT_SUBSCRIPTION => {
+                let client_id = decode_string(buf)?;
+                let topic_filter = decode_string(buf)?;
+                let qos = get_u8(buf)?;
+                let qos = QoS::from_u8(qos).ok_or(Error::InvalidQoS(qos))?;
+                let shared = get_u8(buf)? != 0;
+                Record::PutSubscription(Subscription { shared, ..Subscription::new(client_id, topic_filter, qos) })
+            }
+            DELETE_SUBSCRIPTION => Record::DeleteSubscription(decode_string(buf)?, decode_string(buf)?),
+            PUT_RETAINED => Record::PutRetained(decode_message(buf)?),
+            DELETE_RETAINED => Record::DeleteRetained(decode_string(buf)?),
+            PUT_INFLIGHT => {
+                let client_id = decode_string(buf)?;
+                let seq = get_u64(buf)?;
+                Record::PutInflight(client_id, seq, decode_message(buf)?)
+            }
+            DELETE_INFLIGHT => Record::DeleteInflight(decode_string(buf)?, get_u64(buf)?),
+            CLEAR_INFLIGHT => Record::ClearInflight(decode_string(buf)?),
+            PUT_DELIVERY => {
+                let client_id = decode_string(buf)?;
+                let packet_id = get_u16(buf)?;
+                let awaiting = get_u8(buf)?;
+                let awaiting = PacketType::from_u8(awaiting)
+                    .ok_or_else(|| Error::Deserialization(format!("Unknown packet type: {}", awaiting)))?;
+                let message = Message { packet_id: Some(packet_id), ..decode_message(buf)? };
+                Record::PutDelivery(client_id, packet_id, InflightMessage { message, awaiting })
+            }
+            DELETE_DELIVERY => Record::DeleteDelivery(decode_string(buf)?, get_u16(buf)?),
+            kind => return Err(Error::Deserialization(format!("Unknown storage record type: {}", kind))),
+        };
+        Ok(record)
+    }
+
+    /// Apply the change to the in-memory state
+    fn apply(&self, state: &MemoryStorage) -> Result<()> {
+        match self {
+            Record::PutSession(session) => state.put_session(session),
+            Record::DeleteSession(client_id) => state.delete_session(client_id),
+            Record::PutSubscription(subscription) => state.put_subscription(subscription),
+            Record::DeleteSubscription(client_id, topic_filter) => state.delete_subscription(client_id, topic_filter),
+            Record::PutRetained(message) => state.put_retained(message),
+            Record::DeleteRetained(topic) => state.delete_retained(topic),
+            Record::PutInflight(client_id, seq, message) => state.put_inflight(client_id, *seq, message),
+            Record::DeleteInflight(client_id, seq) => state.delete_inflight(client_id, *seq),
+            Record::ClearInflight(client_id) => state.clear_inflight(client_id),
+            Record::PutDelivery(client_id, packet_id, delivery) => state.put_delivery(client_id, *packet_id, delivery),
+            Record::DeleteDelivery(client_id, packet_id) => state.delete_delivery(client_id, *packet_id),
+        }
+    }
+}
+
+/// Encode a message along with its PUBLISH properties and expiry time
+fn encode_message(message: &Message, buf: &mut BytesMut) -> Result<()> {
+    encode_string(&message.topic, buf)?;
+    buf.put_u8(message.qos);
+    buf.put_u8(message.retain as u8);
+    buf.put_u32(message.payload.len() as u32);
+    buf.put_slice(&message.payload);
+    match &message.properties {
+        Some(properties) => {
+            buf.put_u8(1);
+            encode_publish_properties(properties, buf)?;
+        }
+        None => buf.put_u8(0),
+    }
+    match message.expires_at {
+        Some(expires_at) => {
+            buf.put_u8(1);
+            buf.put_u64(expiry_to_unix_millis(expires_at));
+        }
+        None => buf.put_u8(0),
+    }
+    Ok(())
+}
+
+fn decode_message(buf: &mut BytesMut) -> Result<Message> {
+    let topic = decode_string(buf)?;
+    let qos = get_u8(buf)?;
+    let retain = get_u8(buf)? != 0;
+    let len = get_u32(buf)? as usize;
+    if buf.remaining() < len {
+        return Err(Error::Deserialization("Truncated message payload".to_string()));
+    }
+    let payload: Bytes = buf.split_to(len).freeze();
+    let properties = if get_u8(buf)? == 1 { Some(decode_publish_properties(buf)?) } else { None };
+    let expires_at = if get_u8(buf)? == 1 { Some(expiry_from_unix_millis(get_u64(buf)?)) } else { None };
+    Ok(Message { topic, payload, qos, retain, dup: false, packet_id: None, properties, expires_at })
+}
+
+/// Convert an expiry time to milliseconds since the Unix epoch, which unlike an
+/// `Instant` keep their meaning across a restart
+fn expiry_to_unix_millis(expires_at: Instant) -> u64 {
+    let remaining = expires_at.saturating_duration_since(Instant::now());
+    (SystemTime::now() + remaining).duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_millis() as u64)
+}
+
+/// Convert milliseconds since the Unix epoch back to an expiry time
+fn expiry_from_unix_millis(millis: u64) -> Instant {
+    let expires_at = UNIX_EPOCH + Duration::from_millis(millis);
+    Instant::now() + expires_at.duration_since(SystemTime::now()).unwrap_or_default()
+}
+
+fn get_u8(buf: &mut BytesMut) -> Result<u8> {
+    if buf.remaining() < 1 {
+        return Err(Error::Deserialization("Truncated storage record".to_string()));
+    }
+    Ok(buf.get_u8())
+}
+
+fn get_u16(buf: &mut BytesMut) -> Result<u16> {
+    if buf.remaining() < 2 {
+        return Err(Error::Deserialization("Truncated storage record".to_string()));
+    }
+    Ok(buf.get_u16())
+}
+
+fn get_u32(buf: &mut BytesMut) -> Result<u32> {
+    if buf.remaining() < 4 {
+        return Err(Error::Deserialization("Truncated storage record".to_string()));
+    }
+    Ok(buf.get_u32())
+}
+
+fn get_u64(buf: &mut BytesMut) -> Result<u64> {
+    if buf.remaining() < 8 {
+        return Err(Error::Deserialization("Truncated storage record".to_string()));
+    }
+    Ok(buf.get_u64())
+}
+
+/// Storage backend persisting every change to an append-only log file
+///
+/// Records are handed to the operating system as they are written but are not
+/// synced to disk one by one.
+#[derive(Debug)]
+pub struct FileStorage {
+    path: PathBuf,
+    state: MemoryStorage,
+    log: Mutex<File>,
+}
+
+impl FileStorage {
+    /// Open the log at `path`, creating it if needed and replaying its records
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(Error::Io(e)),
+        };
+
+        let state = MemoryStorage::new();
+        let mut buf = BytesMut::from(&data[..]);
+        let mut valid_len = 0;
+        let mut records = 0;
+        while buf.len() >= 4 {
+            let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+            if buf.len() < 4 + len {
+                break;
+            }
+            buf.advance(4);
+            let mut body = buf.split_to(len);
+            Record::decode(&mut body)
+                .and_then(|record| record.apply(&state))
+                .map_err(|e| Error::Deserialization(format!(
+                    "Corrupt record at offset {} of {}: {}", valid_len, path.display(), e
+                )))?;
+            valid_len += 4 + len;
+            records += 1;
+        }
+
+        let log = OpenOptions::new().create(true).append(true).open(&path)?;
+        if valid_len < data.len() {
+            warn!(
+                "Discarding {} bytes of an incomplete record at the end of {}",
+                data.len() - valid_len,
+                path.display()
+            );
+            log.set_len(valid_len as u64)?;
+        }
+        info!("Replayed {} records from {}", records, path.display());
+
+        Ok(Self { path, state, log: Mutex::new(log) })
+    }
+
+    /// Rewrite the log so it only holds the current state
+    pub fn compact(&self) -> Result<()> {
+        let mut log = self.log();
+
+        let mut buf = BytesMut::new();
+        for session in self.state.sessions()? {
+            for subscription in self.state.get_subscriptions(&session.client_id)? {
+                Record::PutSubscription(subscription).encode(&mut buf)?;
+            }
+            for (seq, message) in self.state.get_inflight(&session.client_id)? {
+                Record::PutInflight(session.client_id.clone(), seq, message).encode(&mut buf)?;
+            }
+            for (packet_id, delivery) in self.state.get_deliveries(&session.client_id)? {
+                Record::PutDelivery(session.client_id.clone(), packet_id, delivery).encode(&mut buf)?;
+            }
+            Record::PutSession(session).encode(&mut buf)?;
+        }
+        for message in self.state.retained_messages()? {
+            Record::PutRetained(message).encode(&mut buf)?;
+        }
+
+        let mut compacted_path = self.path.clone().into_os_string();
+        compacted_path.push(".compact");
+        let compacted_path = PathBuf::from(compacted_path);
+        let mut compacted = File::create(&compacted_path)?;
+        compacted.write_all(&buf)?;
+        compacted.sync_all()?;
+        fs::rename(&compacted_path, &self.path)?;
+
+        *log = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+
+    fn log(&self) -> MutexGuard<'_, File> {
+        self.log.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Append a record to the log, then apply it to the in-memory state
+    fn append(&self, record: Record) -> Result<()> {
+        let mut buf = BytesMut::new();
+        record.encode(&mut buf)?;
+
+        let mut log = self.log();
+        log.write_all(&buf)?;
+        record.apply(&self.state)
+    }
+}
+
+impl Storage for FileStorage {
+    fn put_session(&self, session: &StoredSession) -> Result<()> {
+        self.append(Record::PutSession(session.clone()))
+    }
+
+    fn get_session(&self, client_id: &str) -> Result<Option<StoredSession>> {
+        self.state.get_session(client_id)
+    }
+
+    fn delete_session(&self, client_id: &str) -> Result<()> {
+        self.append(Record::DeleteSession(client_id.to_string()))
+    }
+
+    fn sessions(&self) -> Result<Vec<StoredSession>> {
+        self.state.sessions()
+    }
+
+    fn put_subscription(&self, subscription: &Subscription) -> Result<()> {
+        self.append(Record::PutSubscription(subscription.clone()))
+    }
+
+    fn delete_subscription(&self, client_id: &str, topic_filter: &str) -> Result<()> {
+        self.append(Record::DeleteSubscription(client_id.to_string(), topic_filter.to_string()))
+    }
+
+    fn get_subscriptions(&self, client_id: &str) -> Result<Vec<Subscription>> {
+        self.state.get_subscriptions(client_id)
+    }
+
+    fn put_retained(&self, message: &Message) -> Result<()> {
+        self.append(Record::PutRetained(message.clone()))
+    }
+
+    fn get_retained(&self, topic: &str) -> Result<Option<Message>> {
+        self.state.get_retained(topic)
+    }
+
+    fn delete_retained(&self, topic: &str) -> Result<()> {
+        self.append(Record::DeleteRetained(topic.to_string()))
+    }
+
+    fn retained_messages(&self) -> Result<Vec<Message>> {
+        self.state.retained_messages()
+    }
+
+    fn put_inflight(&self, client_id: &str, seq: u64, message: &Message) -> Result<()> {
+        self.append(Record::PutInflight(client_id.to_string(), seq, message.clone()))
+    }
+
+    fn delete_inflight(&self, client_id: &str, seq: u64) -> Result<()> {
+        self.append(Record::DeleteInflight(client_id.to_string(), seq))
+    }
+
+    fn clear_inflight(&self, client_id: &str) -> Result<()> {
+        self.append(Record::ClearInflight(client_id.to_string()))
+    }
+
+    fn get_inflight(&self, client_id: &str) -> Result<Vec<(u64, Message)>> {
+        self.state.get_inflight(client_id)
+    }
+
+    fn put_delivery(&self, client_id: &str, packet_id: u16, delivery: &InflightMessage) -> Result<()> {
+        self.append(Record::PutDelivery(client_id.to_string(), packet_id, delivery.clone()))
+    }
+
+    fn delete_delivery(&self, client_id: &str, packet_id: u16) -> Result<()> {
+        self.append(Record::DeleteDelivery(client_id.to_string(), packet_id))
+    }
+
+    fn get_deliveries(&self, client_id: &str) -> Result<Vec<(u16, InflightMessage)>> {
+        self.state.get_deliveries(client_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::PublishProperties;
+
+    /// Unique log path in the system temp directory
+    fn temp_log(name: &str) -> PathBuf {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        std::env::temp_dir().join(format!("dumq_mqtt_{}_{}_{}.log", name, std::process::id(), nanos))
+    }
+
+    fn message(topic: &str, payload: &str) -> Message {
+        Message {
+            topic: topic.to_string(),
+            payload: Bytes::from(payload.to_string()),
+            qos: 1,
+            retain: true,
+            dup: false,
+            packet_id: None,
+            properties: None,
+            expires_at: None,
+        }
+    }
+
+    fn populate(storage: &FileStorage) {
+        storage.put_session(&StoredSession {
+            client_id: "device".to_string(),
+            username: Some("user".to_string()),
+            expiry_interval: 3600,
+        }).unwrap();
+        storage.put_subscription(&Subscription::new("device".to_string(), "cmd/#".to_string(), QoS::ExactlyOnce)).unwrap();
+        storage.put_subscription(&Subscription::new("device".to_string(), "old".to_string(), QoS::AtMostOnce)).unwrap();
+        let plain = Subscription::new("device".to_string(), "$share/g/plain".to_string(), QoS::AtLeastOnce);
+        storage.put_subscription(&Subscription { shared: false, ..plain }).unwrap();
+        storage.put_subscription(&Subscription::new("device".to_string(), "$share/g/jobs".to_string(), QoS::AtLeastOnce)).unwrap();
+        storage.delete_subscription("device", "old").unwrap();
+        storage.put_inflight("device", 7, &message("cmd/reboot", "now")).unwrap();
+        storage.put_inflight("device", 8, &message("cmd/update", "v2")).unwrap();
+        storage.delete_inflight("device", 7).unwrap();
+        for (packet_id, payload) in [(4, "sent"), (2, "acked"), (1, "released")] {
+            let delivery = InflightMessage { message: message("cmd/set", payload), awaiting: PacketType::PubRec };
+            storage.put_delivery("device", packet_id, &delivery).unwrap();
+        }
+        storage.delete_delivery("device", 2).unwrap();
+        let released = InflightMessage { message: message("cmd/set", "released"), awaiting: PacketType::PubComp };
+        storage.put_delivery("device", 1, &released).unwrap();
+        storage.put_retained(&message("status", "up")).unwrap();
+        storage.put_retained(&message("gone", "soon")).unwrap();
+        storage.delete_retained("gone").unwrap();
+        storage.put_session(&StoredSession {
+            client_id: "removed".to_string(),
+            username: None,
+            expiry_interval: 10,
+        }).unwrap();
+        storage.delete_session("removed").unwrap();
+    }
+
+    fn assert_populated(storage: &FileStorage) {
+        let sessions = storage.sessions().unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].client_id, "device");
+        assert_eq!(sessions[0].username.as_deref(), Some("user"));
+        assert_eq!(sessions[0].expiry_interval, 3600);
+
+        let subscriptions: Vec<(String, QoS, bool)> = storage.get_subscriptions("device").unwrap()
+            .into_iter()
+            .map(|subscription| (subscription.topic_filter, subscription.qos, subscription.shared))
+            .collect();
+        assert_eq!(subscriptions, [
+            ("$share/g/jobs".to_string(), QoS::AtLeastOnce, true),
+            ("$share/g/plain".to_string(), QoS::AtLeastOnce, false),
+            ("cmd/#".to_string(), QoS::ExactlyOnce, false),
+        ]);
+
+        let inflight = storage.get_inflight("device").unwrap();
+        assert_eq!(inflight.len(), 1);
+        assert_eq!(inflight[0].0, 8);
+        assert_eq!(inflight[0].1.payload, Bytes::from("v2"));
+
+        let deliveries: Vec<(u16, PacketType)> = storage.get_deliveries("device").unwrap()
+            .into_iter()
+            .map(|(packet_id, delivery)| (packet_id, delivery.awaiting))
+            .collect();
+        assert_eq!(deliveries, [(4, PacketType::PubRec), (1, PacketType::PubComp)]);
+
+        let retained = storage.retained_messages().unwrap();
+        assert_eq!(retained.len(), 1);
+        assert_eq!(retained[0].topic, "status");
+        assert!(retained[0].retain);
+    }
+
+    #[test]
+    fn test_log_replayed_on_open() {
+        let path = temp_log("replay");
+        populate(&FileStorage::open(&path).unwrap());
+
+        let reopened = FileStorage::open(&path).unwrap();
+        assert_populated(&reopened);
+
+        // Appending after a replay keeps extending the same log
+        reopened.put_retained(&message("later", "1")).unwrap();
+        drop(reopened);
+        assert_eq!(FileStorage::open(&path).unwrap().retained_messages().unwrap().len(), 2);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_message_properties_persisted() {
+        let path = temp_log("properties");
+        let properties = PublishProperties::new()
+            .payload_format_indicator(1)
+            .message_expiry_interval(60)
+            .response_topic("reply/device".to_string())
+            .correlation_data(Bytes::from_static(b"req-1"))
+            .content_type("text/plain".to_string())
+            .user_property("origin".to_string(), "sensor".to_string());
+        let storage = FileStorage::open(&path).unwrap();
+        storage.put_retained(&Message { properties: Some(properties), ..message("status", "up") }).unwrap();
+        storage.put_inflight("device", 1, &message("cmd/plain", "none")).unwrap();
+        drop(storage);
+
+        let reopened = FileStorage::open(&path).unwrap();
+        let retained = reopened.retained_messages().unwrap();
+        let properties = retained[0].properties.as_ref().unwrap();
+        assert_eq!(properties.payload_format_indicator, Some(1));
+        assert_eq!(properties.message_expiry_interval, Some(60));
+        assert_eq!(properties.response_topic.as_deref(), Some("reply/device"));
+        assert_eq!(properties.correlation_data, Some(Bytes::from_static(b"req-1")));
+        assert_eq!(properties.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(properties.user_properties.get("origin").map(String::as_str), Some("sensor"));
+        assert!(reopened.get_inflight("device").unwrap()[0].1.properties.is_none());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_message_expiry_persisted() {
+        let path = temp_log("expiry");
+        let storage = FileStorage::open(&path).unwrap();
+        let expiring = |payload: &str, expires_at: Instant| Message { expires_at: Some(expires_at), ..message("cmd/set", payload) };
+        storage.put_inflight("device", 1, &expiring("later", Instant::now() + Duration::from_secs(60))).unwrap();
+        storage.put_inflight("device", 2, &expiring("stale", Instant::now())).unwrap();
+        storage.put_inflight("device", 3, &message("cmd/set", "forever")).unwrap();
+        drop(storage);
+
+        // The expiry is kept as a point in time, so it does not restart with the broker
+        let inflight = FileStorage::open(&path).unwrap().get_inflight("device").unwrap();
+        let remaining = inflight[0].1.expires_at.unwrap().saturating_duration_since(Instant::now());
+        assert!(remaining > Duration::from_secs(55) && remaining <= Duration::from_secs(60));
+        assert!(inflight[1].1.is_expired());
+        assert!(inflight[2].1.expires_at.is_none());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_torn_record_discarded() {
+        let path = temp_log("torn");
+        populate(&FileStorage::open(&path).unwrap());
+        let complete_len = fs::metadata(&path).unwrap().len();
+
+        // Simulate a crash in the middle of writing a record
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 0, 40, PUT_RETAINED, 0, 3]).unwrap();
+        drop(file);
+
+        let reopened = FileStorage::open(&path).unwrap();
+        assert_populated(&reopened);
+        assert_eq!(fs::metadata(&path).unwrap().len(), complete_len);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_record_rejected() {
+        let path = temp_log("corrupt");
+        fs::write(&path, [0, 0, 0, 1, 0xFF]).unwrap();
+
+        match FileStorage::open(&path) {
+            Err(Error::Deserialization(_)) => {}
+            other => panic!("Expected a deserialization error, got {:?}", other),
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_compact_keeps_state() {
+        let path = temp_log("compact");
+        let storage = FileStorage::open(&path).unwrap();
+        populate(&storage);
+        let before = fs::metadata(&path).unwrap().len();
+
+        storage.compact().unwrap();
+        assert!(fs::metadata(&path).unwrap().len() < before);
+
+        // The compacted log still accepts appends
+        storage.put_retained(&message("after", "compact")).unwrap();
+        drop(storage);
+
+        let reopened = FileStorage::open(&path).unwrap();
+        assert_eq!(reopened.retained_messages().unwrap().len(), 2);
+        reopened.delete_retained("after").unwrap();
+        assert_populated(&reopened);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
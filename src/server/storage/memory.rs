@@ -0,0 +1,274 @@
+//! In-memory storage backend
+
+use crate::error::Result;
+use crate::protocol::InflightStore;
+use crate::types::Message;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard};
+
+use super::{Storage, StoredSession};
+use crate::server::session::{InflightMessage, Subscription};
+
+#[derive(Debug, Default)]
+struct State {
+    sessions: HashMap<String, StoredSession>,
+    subscriptions: HashMap<String, BTreeMap<String, Subscription>>,
+    retained: HashMap<String, Message>,
+    inflight: HashMap<String, BTreeMap<u64, Message>>,
+    deliveries: HashMap<String, InflightStore<InflightMessage>>,
+}
+
+/// Storage backend keeping everything in memory
+///
+/// State is lost when the process exits. This is the default backend.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    state: Mutex<State>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        // The state stays consistent even if a panic poisoned the lock
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn put_session(&self, session: &StoredSession) -> Result<()> {
+        self.state().sessions.insert(session.client_id.clone(), session.clone());
+        Ok(())
+    }
+
+    fn get_session(&self, client_id: &str) -> Result<Option<StoredSession>> {
+        Ok(self.state().sessions.get(client_id).cloned())
+    }
+
+    fn delete_session(&self, client_id: &str) -> Result<()> {
+        let mut state = self.state();
+        state.sessions.remove(client_id);
+        state.subscriptions.remove(client_id);
+        state.inflight.remove(client_id);
+        state.deliveries.remove(client_id);
+        Ok(())
+    }
+
+    fn sessions(&self) -> Result<Vec<StoredSession>> {
+        Ok(self.state().sessions.values().cloned().collect())
+    }
+
+    fn put_subscription(&self, subscription: &Subscription) -> Result<()> {
+        self.state()
+            .subscriptions
+            .entry(subscription.client_id.clone())
+            .or_default()
+            .insert(subscription.topic_filter.clone(), subscription.clone());
+        Ok(())
+    }
+
+    fn delete_subscription(&self, client_id: &str, topic_filter: &str) -> Result<()> {
+        let mut state = self.state();
+        if let Some(filters) = state.subscriptions.get_mut(client_id) {
+            filters.remove(topic_filter);
+            if filters.is_empty() {
+                state.subscriptions.remove(client_id);
+            }
+        }
+        Ok(())
+    }
+
+    fn get_subscriptions(&self, client_id: &str) -> Result<Vec<Subscription>> {
+        let state = self.state();
+        let subscriptions = state.subscriptions.get(client_id)
+            .map(|filters| filters.values().cloned().collect())
+            .unwrap_or_default();
+        Ok(subscriptions)
+    }
+
+    fn put_retained(&self, message: &Message) -> Result<()> {
+        self.state().retained.insert(message.topic.clone(), message.clone());
+        Ok(())
+    }
+
+    fn get_retained(&self, topic: &str) -> Result<Option<Message>> {
+        Ok(self.state().retained.get(topic).cloned())
+    }
+
+    fn delete_retained(&self, topic: &str) -> Result<()> {
+        self.state().retained.remove(topic);
+        Ok(())
+    }
+
+    fn retained_messages(&self) -> Result<Vec<Message>> {
+        Ok(self.state().retained.values().cloned().collect())
+    }
+
+    fn put_inflight(&self, client_id: &str, seq: u64, message: &Message) -> Result<()> {
+        self.state()
+            .inflight
+            .entry(client_id.to_string())
+            .or_default()
+            .insert(seq, message.clone());
+        Ok(())
+    }
+
+    fn delete_inflight(&self, client_id: &str, seq: u64) -> Result<()> {
+        let mut state = self.state();
+        if let Some(messages) = state.inflight.get_mut(client_id) {
+            messages.remove(&seq);
+            if messages.is_empty() {
+                state.inflight.remove(client_id);
+            }
+        }
+        Ok(())
+    }
+
+    fn clear_inflight(&self, client_id: &str) -> Result<()> {
+        self.state().inflight.remove(client_id);
+        Ok(())
+    }
+
+    fn get_inflight(&self, client_id: &str) -> Result<Vec<(u64, Message)>> {
+        let state = self.state();
+        let messages = state.inflight.get(client_id)
+            .map(|messages| messages.iter().map(|(seq, message)| (*seq, message.clone())).collect())
+            .unwrap_or_default();
+        Ok(messages)
+    }
+
+    fn put_delivery(&self, client_id: &str, packet_id: u16, delivery: &InflightMessage) -> Result<()> {
+        let mut state = self.state();
+        let deliveries = state.deliveries.entry(client_id.to_string()).or_default();
+        match deliveries.get_mut(packet_id) {
+            Some(existing) => *existing = delivery.clone(),
+            None => {
+                deliveries.insert(packet_id, delivery.clone());
+            }
+        }
+        Ok(())
+    }
+
+    fn delete_delivery(&self, client_id: &str, packet_id: u16) -> Result<()> {
+        let mut state = self.state();
+        if let Some(deliveries) = state.deliveries.get_mut(client_id) {
+            deliveries.remove(packet_id);
+            if deliveries.is_empty() {
+                state.deliveries.remove(client_id);
+            }
+        }
+        Ok(())
+    }
+
+    fn get_deliveries(&self, client_id: &str) -> Result<Vec<(u16, InflightMessage)>> {
+        let state = self.state();
+        let deliveries = state.deliveries.get(client_id)
+            .map(|deliveries| deliveries.iter().map(|(packet_id, delivery)| (packet_id, delivery.clone())).collect())
+            .unwrap_or_default();
+        Ok(deliveries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::QoS;
+    use crate::types::PacketType;
+    use bytes::Bytes;
+
+    fn message(topic: &str, payload: &str) -> Message {
+        Message {
+            topic: topic.to_string(),
+            payload: Bytes::from(payload.to_string()),
+            qos: 1,
+            retain: false,
+            dup: false,
+            packet_id: None,
+            properties: None,
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn test_sessions_and_subscriptions() {
+        let storage = MemoryStorage::new();
+        let session = StoredSession {
+            client_id: "client1".to_string(),
+            username: Some("user".to_string()),
+            expiry_interval: 60,
+        };
+        storage.put_session(&session).unwrap();
+        storage.put_subscription(&Subscription::new("client1".to_string(), "a/#".to_string(), QoS::AtLeastOnce)).unwrap();
+        storage.put_subscription(&Subscription::new("client1".to_string(), "b".to_string(), QoS::AtMostOnce)).unwrap();
+        storage.put_inflight("client1", 0, &message("a/1", "x")).unwrap();
+
+        assert_eq!(storage.get_session("client1").unwrap(), Some(session));
+        let filters: Vec<String> = storage.get_subscriptions("client1").unwrap()
+            .into_iter()
+            .map(|subscription| subscription.topic_filter)
+            .collect();
+        assert_eq!(filters, ["a/#", "b"]);
+
+        storage.delete_subscription("client1", "b").unwrap();
+        assert_eq!(storage.get_subscriptions("client1").unwrap().len(), 1);
+
+        // Deleting the session takes its subscriptions and queued messages with it
+        storage.delete_session("client1").unwrap();
+        assert!(storage.get_session("client1").unwrap().is_none());
+        assert!(storage.get_subscriptions("client1").unwrap().is_empty());
+        assert!(storage.get_inflight("client1").unwrap().is_empty());
+        assert!(storage.sessions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_retained_messages() {
+        let storage = MemoryStorage::new();
+        storage.put_retained(&message("a", "1")).unwrap();
+        storage.put_retained(&message("a", "2")).unwrap();
+        storage.put_retained(&message("b", "3")).unwrap();
+
+        assert_eq!(storage.get_retained("a").unwrap().unwrap().payload, Bytes::from("2"));
+        assert_eq!(storage.retained_messages().unwrap().len(), 2);
+
+        storage.delete_retained("a").unwrap();
+        assert!(storage.get_retained("a").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_inflight_ordered_by_sequence() {
+        let storage = MemoryStorage::new();
+        storage.put_inflight("client1", 2, &message("t", "c")).unwrap();
+        storage.put_inflight("client1", 0, &message("t", "a")).unwrap();
+        storage.put_inflight("client1", 1, &message("t", "b")).unwrap();
+        storage.delete_inflight("client1", 1).unwrap();
+
+        let seqs: Vec<u64> = storage.get_inflight("client1").unwrap().into_iter().map(|(seq, _)| seq).collect();
+        assert_eq!(seqs, [0, 2]);
+
+        storage.clear_inflight("client1").unwrap();
+        assert!(storage.get_inflight("client1").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_deliveries_kept_in_send_order() {
+        let storage = MemoryStorage::new();
+        let delivery = |payload: &str, awaiting| InflightMessage { message: message("t", payload), awaiting };
+        storage.put_delivery("client1", 9, &delivery("a", PacketType::PubRec)).unwrap();
+        storage.put_delivery("client1", 3, &delivery("b", PacketType::PubAck)).unwrap();
+        storage.put_delivery("client1", 5, &delivery("c", PacketType::PubAck)).unwrap();
+        storage.delete_delivery("client1", 3).unwrap();
+
+        // Updating a delivery keeps its position
+        storage.put_delivery("client1", 9, &delivery("a", PacketType::PubComp)).unwrap();
+        let deliveries: Vec<(u16, PacketType)> = storage.get_deliveries("client1").unwrap()
+            .into_iter()
+            .map(|(packet_id, delivery)| (packet_id, delivery.awaiting))
+            .collect();
+        assert_eq!(deliveries, [(9, PacketType::PubComp), (5, PacketType::PubAck)]);
+
+        storage.delete_session("client1").unwrap();
+        assert!(storage.get_deliveries("client1").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,78 @@
+//! Broker persistence module
+//!
+//! The [`Storage`] trait is the persistence backend behind `SessionManager` and
+//! `MessageRouter`. Every change to a persistent session, its subscriptions,
+//! queued messages and unacknowledged deliveries, or to the retained messages is
+//! written through to storage, and `Server::start` replays it so broker restarts
+//! do not lose device state.
+//!
+//! Two implementations are provided: [`MemoryStorage`], which keeps everything
+//! in memory, and [`FileStorage`], an append-only log on disk.
+
+pub mod memory;
+pub mod file;
+
+pub use memory::MemoryStorage;
+pub use file::FileStorage;
+
+use crate::error::Result;
+use crate::types::Message;
+
+use super::session::{InflightMessage, Subscription};
+
+/// Persisted state of a session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredSession {
+    pub client_id: String,
+    pub username: Option<String>,
+    /// Session Expiry Interval in seconds
+    pub expiry_interval: u32,
+}
+
+/// Persistence backend for broker state
+///
+/// Messages queued for an offline session ("inflight" messages) are keyed by a
+/// per-session sequence number that increases in delivery order. Deliveries sent
+/// to the client and awaiting its acknowledgment are keyed by packet ID.
+pub trait Storage: Send + Sync {
+    /// Store or replace a session
+    fn put_session(&self, session: &StoredSession) -> Result<()>;
+    /// Get a stored session
+    fn get_session(&self, client_id: &str) -> Result<Option<StoredSession>>;
+    /// Delete a session together with its subscriptions, inflight messages and deliveries
+    fn delete_session(&self, client_id: &str) -> Result<()>;
+    /// Get all stored sessions
+    fn sessions(&self) -> Result<Vec<StoredSession>>;
+
+    /// Store or replace a subscription
+    fn put_subscription(&self, subscription: &Subscription) -> Result<()>;
+    /// Delete a subscription
+    fn delete_subscription(&self, client_id: &str, topic_filter: &str) -> Result<()>;
+    /// Get the subscriptions of a session
+    fn get_subscriptions(&self, client_id: &str) -> Result<Vec<Subscription>>;
+
+    /// Store or replace the retained message of a topic
+    fn put_retained(&self, message: &Message) -> Result<()>;
+    /// Get the retained message of a topic
+    fn get_retained(&self, topic: &str) -> Result<Option<Message>>;
+    /// Delete the retained message of a topic
+    fn delete_retained(&self, topic: &str) -> Result<()>;
+    /// Get all retained messages
+    fn retained_messages(&self) -> Result<Vec<Message>>;
+
+    /// Store a message queued for a session
+    fn put_inflight(&self, client_id: &str, seq: u64, message: &Message) -> Result<()>;
+    /// Delete a queued message
+    fn delete_inflight(&self, client_id: &str, seq: u64) -> Result<()>;
+    /// Delete every message queued for a session
+    fn clear_inflight(&self, client_id: &str) -> Result<()>;
+    /// Get the messages queued for a session, ordered by sequence number
+    fn get_inflight(&self, client_id: &str) -> Result<Vec<(u64, Message)>>;
+
+    /// Store a delivery awaiting acknowledgment, or update it in place
+    fn put_delivery(&self, client_id: &str, packet_id: u16, delivery: &InflightMessage) -> Result<()>;
+    /// Delete an acknowledged delivery
+    fn delete_delivery(&self, client_id: &str, packet_id: u16) -> Result<()>;
+    /// Get the deliveries of a session awaiting acknowledgment, in the order they were sent
+    fn get_deliveries(&self, client_id: &str) -> Result<Vec<(u16, InflightMessage)>>;
+}
@@ -0,0 +1,239 @@
+//! TLS listener configuration module
+//!
+//! With [`TlsConfig`] set through `ServerConfig::tls`, the server performs a
+//! rustls handshake on every accepted socket before handing it to
+//! `ServerConnection`. Client certificates are only requested when a CA bundle
+//! is configured.
+
+use rustls::server::WebPkiClientVerifier;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
+
+use crate::error::{Error, Result};
+use crate::tls;
+
+/// Certificate, key and client verification settings of a TLS listener
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first
+    pub cert_path: PathBuf,
+    /// PEM private key of the leaf certificate
+    pub key_path: PathBuf,
+    /// PEM bundle of CAs trusted to sign client certificates
+    pub client_ca_path: Option<PathBuf>,
+    /// Reject clients that present no certificate; only used with a client CA
+    pub require_client_cert: bool,
+}
+
+impl TlsConfig {
+    pub fn new(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        Self {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            client_ca_path: None,
+            require_client_cert: true,
+        }
+    }
+
+    /// Verify client certificates against the given CA bundle
+    pub fn client_ca(mut self, path: impl Into<PathBuf>) -> Self {
+        self.client_ca_path = Some(path.into());
+        self
+    }
+
+    /// Whether clients must present a certificate when a client CA is set
+    pub fn require_client_cert(mut self, require: bool) -> Self {
+        self.require_client_cert = require;
+        self
+    }
+
+    /// Load the certificates and build the acceptor for incoming connections
+    pub fn acceptor(&self) -> Result<TlsAcceptor> {
+        let provider = tls::crypto_provider();
+        let certs = tls::load_certs(&self.cert_path)?;
+        let key = tls::load_private_key(&self.key_path)?;
+
+        let builder = rustls::ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| Error::Tls(e.to_string()))?;
+
+        let builder = match &self.client_ca_path {
+            Some(path) => {
+                let roots = Arc::new(tls::load_root_store(path)?);
+                let verifier = WebPkiClientVerifier::builder_with_provider(roots, provider);
+                let verifier = if self.require_client_cert {
+                    verifier
+                } else {
+                    verifier.allow_unauthenticated()
+                };
+                let verifier = verifier.build()
+                    .map_err(|e| Error::Tls(format!("Invalid client CA bundle: {}", e)))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let config = builder.with_single_cert(certs, key)
+            .map_err(|e| Error::Tls(format!("Invalid certificate or key: {}", e)))?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::MqttCodec;
+    use crate::server::session::SessionManager;
+    use crate::server::{MessageRouter, ServerConfig, ServerConnection};
+    use crate::tls::test_pki::TestPki;
+    use crate::types::*;
+    use bytes::BytesMut;
+    use rustls::pki_types::ServerName;
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::TlsConnector;
+
+    /// Accept TLS connections and serve them with `ServerConnection`
+    async fn start_tls_server(tls: TlsConfig) -> SocketAddr {
+        let acceptor = tls.acceptor().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ServerConfig::new(addr.to_string()).tls(tls);
+        let session_manager = Arc::new(SessionManager::new());
+        let message_router = Arc::new(MessageRouter::new());
+
+        tokio::spawn(async move {
+            while let Ok((stream, peer)) = listener.accept().await {
+                let Ok(stream) = acceptor.accept(stream).await else {
+                    continue;
+                };
+                tokio::spawn(ServerConnection::handle_connection(
+                    stream,
+                    peer.into(),
+                    config.clone(),
+                    Arc::clone(&session_manager),
+                    Arc::clone(&message_router),
+                ));
+            }
+        });
+
+        addr
+    }
+
+    fn connector(pki: &TestPki, client_cert: bool) -> TlsConnector {
+        let roots = tls::load_root_store(&pki.path("ca.pem")).unwrap();
+        let builder = rustls::ClientConfig::builder_with_provider(tls::crypto_provider())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let config = if client_cert {
+            builder.with_client_auth_cert(
+                tls::load_certs(&pki.path("client.pem")).unwrap(),
+                tls::load_private_key(&pki.path("client.key")).unwrap(),
+            ).unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+        TlsConnector::from(Arc::new(config))
+    }
+
+    /// Send a CONNECT and return the CONNACK, or `None` if the connection fails
+    async fn mqtt_connect<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S) -> Option<ConnAckPacket> {
+        let codec = MqttCodec::new(4);
+        let connect = Packet {
+            header: PacketHeader {
+                packet_type: PacketType::Connect,
+                dup: false,
+                qos: 0,
+                retain: false,
+                remaining_length: 0,
+            },
+            payload: PacketPayload::Connect(ConnectPacket {
+                protocol_name: "MQTT".to_string(),
+                protocol_version: 4,
+                clean_session: true,
+                will_flag: false,
+                will_qos: 0,
+                will_retain: false,
+                password_flag: false,
+                username_flag: false,
+                keep_alive: 60,
+                client_id: "tls-client".to_string(),
+                will_topic: None,
+                will_message: None,
+                will_properties: None,
+                username: None,
+                password: None,
+                properties: None,
+            }),
+        };
+        stream.write_all(&codec.encode(&connect).unwrap()).await.ok()?;
+
+        let mut buffer = BytesMut::new();
+        loop {
+            if let Some(packet) = codec.decode(&mut buffer).unwrap() {
+                return match packet.payload {
+                    PacketPayload::ConnAck(connack) => Some(connack),
+                    _ => None,
+                };
+            }
+            let mut buf = [0u8; 256];
+            match stream.read(&mut buf).await {
+                Ok(n) if n > 0 => buffer.extend_from_slice(&buf[..n]),
+                _ => return None,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mqtt_over_tls() {
+        let pki = TestPki::generate("server-tls");
+        let addr = start_tls_server(TlsConfig::new(pki.path("server.pem"), pki.path("server.key"))).await;
+
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let name = ServerName::try_from("localhost").unwrap();
+        let stream = connector(&pki, false).connect(name, tcp).await.unwrap();
+        let connack = mqtt_connect(stream).await.unwrap();
+        assert_eq!(connack.return_code, ConnectReturnCode::Accepted);
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate_required() {
+        let pki = TestPki::generate("server-mtls");
+        let tls = TlsConfig::new(pki.path("server.pem"), pki.path("server.key")).client_ca(pki.path("ca.pem"));
+        let addr = start_tls_server(tls).await;
+        let name = ServerName::try_from("localhost").unwrap();
+
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let stream = connector(&pki, true).connect(name.clone(), tcp).await.unwrap();
+        assert!(mqtt_connect(stream).await.is_some());
+
+        // TLS 1.3 reports the missing certificate after the client's handshake completes
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        if let Ok(stream) = connector(&pki, false).connect(name, tcp).await {
+            assert!(mqtt_connect(stream).await.is_none());
+        }
+    }
+
+    #[test]
+    fn test_optional_client_certificate() {
+        let pki = TestPki::generate("server-optional");
+        let tls = TlsConfig::new(pki.path("server.pem"), pki.path("server.key"))
+            .client_ca(pki.path("ca.pem"))
+            .require_client_cert(false);
+        assert!(!tls.require_client_cert);
+        assert!(tls.acceptor().is_ok());
+    }
+
+    #[test]
+    fn test_invalid_tls_files() {
+        let pki = TestPki::generate("server-invalid");
+        // Certificate and key that do not belong together
+        let mismatched = TlsConfig::new(pki.path("server.pem"), pki.path("client.key"));
+        assert!(matches!(mismatched.acceptor(), Err(Error::Tls(_))));
+        let missing = TlsConfig::new(pki.path("missing.pem"), pki.path("server.key"));
+        assert!(matches!(missing.acceptor(), Err(Error::Tls(_))));
+    }
+}
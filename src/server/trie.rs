@@ -0,0 +1,316 @@
+//! Topic trie module
+//!
+//! [`TopicTrie`] indexes values by topic level, so looking up what matches a
+//! topic costs time proportional to its depth rather than to the number of
+//! entries. The subscription table keys it by topic filter and asks which
+//! filters match a published topic; the retained store keys it by topic name
+//! and asks which topics match a subscription's filter.
+//!
+//! Following the MQTT specification, wildcards at the first level never match
+//! topics starting with `$`, such as `$SYS/broker/uptime`.
+
+use std::collections::HashMap;
+
+/// Values stored under `/`-separated topic names or filters
+#[derive(Debug, Clone)]
+pub struct TopicTrie<T> {
+    root: Node<T>,
+    len: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Node<T> {
+    value: Option<T>,
+    children: HashMap<String, Node<T>>,
+}
+
+impl<T> Default for Node<T> {
+    fn default() -> Self {
+        Self { value: None, children: HashMap::new() }
+    }
+}
+
+impl<T> Default for TopicTrie<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> TopicTrie<T> {
+    pub fn new() -> Self {
+        Self { root: Node::default(), len: 0 }
+    }
+
+    /// Number of stored values
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Value stored under exactly this key
+    pub fn get(&self, key: &str) -> Option<&T> {
+        key.split('/')
+            .try_fold(&self.root, |node, level| node.children.get(level))
+            .and_then(|node| node.value.as_ref())
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut T> {
+        key.split('/')
+            .try_fold(&mut self.root, |node, level| node.children.get_mut(level))
+            .and_then(|node| node.value.as_mut())
+    }
+
+    /// Store a value, returning the one it replaces
+    pub fn insert(&mut self, key: &str, value: T) -> Option<T> {
+        let previous = Self::walk_mut(&mut self.root, key).value.replace(value);
+        if previous.is_none() {
+            self.len += 1;
+        }
+        previous
+    }
+
+    /// Value under `key`, inserting the result of `f` if there is none
+    pub fn get_or_insert_with(&mut self, key: &str, f: impl FnOnce() -> T) -> &mut T {
+        let node = Self::walk_mut(&mut self.root, key);
+        if node.value.is_none() {
+            self.len += 1;
+        }
+        node.value.get_or_insert_with(f)
+    }
+
+    /// Remove a value, pruning the levels left empty
+    pub fn remove(&mut self, key: &str) -> Option<T> {
+        let levels: Vec<&str> = key.split('/').collect();
+        let removed = Self::remove_from(&mut self.root, &levels);
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        removed
+    }
+
+    fn remove_from(node: &mut Node<T>, levels: &[&str]) -> Option<T> {
+        let Some((level, rest)) = levels.split_first() else {
+            return node.value.take();
+        };
+        let child = node.children.get_mut(*level)?;
+        let removed = Self::remove_from(child, rest);
+        if child.value.is_none() && child.children.is_empty() {
+            node.children.remove(*level);
+        }
+        removed
+    }
+
+    fn walk_mut<'a>(root: &'a mut Node<T>, key: &str) -> &'a mut Node<T> {
+        key.split('/').fold(root, |node, level| node.children.entry(level.to_string()).or_default())
+    }
+
+    /// Values stored under topic filters that match a topic name
+    pub fn match_topic(&self, topic: &str) -> Vec<&T> {
+        let levels: Vec<&str> = topic.split('/').collect();
+        let mut matches = Vec::new();
+        let system = topic.starts_with('$');
+        Self::collect_filters(&self.root, &levels, !system, &mut matches);
+        matches
+    }
+
+    fn collect_filters<'a>(node: &'a Node<T>, levels: &[&str], wildcards: bool, matches: &mut Vec<&'a T>) {
+        // `#` also matches the parent level: `home/#` matches `home`
+        if wildcards {
+            if let Some(value) = node.children.get("#").and_then(|child| child.value.as_ref()) {
+                matches.push(value);
+            }
+        }
+
+        let Some((level, rest)) = levels.split_first() else {
+            if let Some(value) = &node.value {
+                matches.push(value);
+            }
+            return;
+        };
+
+        if let Some(child) = node.children.get(*level) {
+            Self::collect_filters(child, rest, true, matches);
+        }
+        if wildcards {
+            if let Some(child) = node.children.get("+") {
+                Self::collect_filters(child, rest, true, matches);
+            }
+        }
+    }
+
+    /// Values stored under topic names that match a topic filter
+    pub fn match_filter(&self, filter: &str) -> Vec<&T> {
+        let levels: Vec<&str> = filter.split('/').collect();
+        let mut matches = Vec::new();
+        Self::collect_topics(&self.root, &levels, true, &mut matches);
+        matches
+    }
+
+    fn collect_topics<'a>(node: &'a Node<T>, levels: &[&str], root: bool, matches: &mut Vec<&'a T>) {
+        let Some((level, rest)) = levels.split_first() else {
+            if let Some(value) = &node.value {
+                matches.push(value);
+            }
+            return;
+        };
+
+        match *level {
+            "#" => {
+                // Also matches the parent level
+                if let Some(value) = &node.value {
+                    matches.push(value);
+                }
+                for (name, child) in &node.children {
+                    if !(root && name.starts_with('$')) {
+                        Self::collect_all(child, matches);
+                    }
+                }
+            }
+            "+" => {
+                for (name, child) in &node.children {
+                    if !(root && name.starts_with('$')) {
+                        Self::collect_topics(child, rest, false, matches);
+                    }
+                }
+            }
+            level => {
+                if let Some(child) = node.children.get(level) {
+                    Self::collect_topics(child, rest, false, matches);
+                }
+            }
+        }
+    }
+
+    fn collect_all<'a>(node: &'a Node<T>, matches: &mut Vec<&'a T>) {
+        if let Some(value) = &node.value {
+            matches.push(value);
+        }
+        for child in node.children.values() {
+            Self::collect_all(child, matches);
+        }
+    }
+
+    /// Every stored value with its key
+    pub fn entries(&self) -> Vec<(String, &T)> {
+        let mut entries = Vec::with_capacity(self.len);
+        for (level, child) in &self.root.children {
+            Self::collect_entries(child, level.clone(), &mut entries);
+        }
+        entries
+    }
+
+    fn collect_entries<'a>(node: &'a Node<T>, key: String, entries: &mut Vec<(String, &'a T)>) {
+        if let Some(value) = &node.value {
+            entries.push((key.clone(), value));
+        }
+        for (level, child) in &node.children {
+            Self::collect_entries(child, format!("{}/{}", key, level), entries);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::MessageRouter;
+
+    fn sorted<'a>(values: Vec<&&'a str>) -> Vec<&'a str> {
+        let mut values: Vec<&str> = values.into_iter().copied().collect();
+        values.sort();
+        values
+    }
+
+    fn filters() -> TopicTrie<&'static str> {
+        let mut trie = TopicTrie::new();
+        for filter in ["home/+/temp", "home/#", "home/living/temp", "#", "+/+", "+", "$SYS/#", "/+", "home/+"] {
+            trie.insert(filter, filter);
+        }
+        trie
+    }
+
+    #[test]
+    fn test_match_topic() {
+        let trie = filters();
+        assert_eq!(sorted(trie.match_topic("home/living/temp")), ["#", "home/#", "home/+/temp", "home/living/temp"]);
+        assert_eq!(sorted(trie.match_topic("home")), ["#", "+", "home/#"]);
+        assert_eq!(sorted(trie.match_topic("home/kitchen")), ["#", "+/+", "home/#", "home/+"]);
+        assert_eq!(sorted(trie.match_topic("/finance")), ["#", "+/+", "/+"]);
+        assert_eq!(sorted(trie.match_topic("office/desk/lamp")), ["#"]);
+    }
+
+    #[test]
+    fn test_wildcards_skip_system_topics() {
+        let trie = filters();
+        assert_eq!(sorted(trie.match_topic("$SYS/broker/uptime")), ["$SYS/#"]);
+        assert_eq!(sorted(trie.match_topic("$SYS")), ["$SYS/#"]);
+
+        let mut topics = TopicTrie::new();
+        for topic in ["$SYS/broker/uptime", "home/temp", "home"] {
+            topics.insert(topic, topic);
+        }
+        assert_eq!(sorted(topics.match_filter("#")), ["home", "home/temp"]);
+        assert_eq!(sorted(topics.match_filter("+/broker/+")), Vec::<&str>::new());
+        assert_eq!(sorted(topics.match_filter("$SYS/#")), ["$SYS/broker/uptime"]);
+    }
+
+    #[test]
+    fn test_match_filter() {
+        let mut trie = TopicTrie::new();
+        for topic in ["home/living/temp", "home/kitchen/temp", "home/kitchen", "home", "office/temp", "/temp"] {
+            trie.insert(topic, topic);
+        }
+        assert_eq!(sorted(trie.match_filter("home/+/temp")), ["home/kitchen/temp", "home/living/temp"]);
+        assert_eq!(sorted(trie.match_filter("home/#")), ["home", "home/kitchen", "home/kitchen/temp", "home/living/temp"]);
+        assert_eq!(sorted(trie.match_filter("+/temp")), ["/temp", "office/temp"]);
+        assert_eq!(sorted(trie.match_filter("home")), ["home"]);
+        assert_eq!(trie.match_filter("#").len(), 6);
+        assert!(trie.match_filter("garden/+").is_empty());
+    }
+
+    #[test]
+    fn test_insert_get_remove() {
+        let mut trie = TopicTrie::new();
+        assert!(trie.is_empty());
+        assert_eq!(trie.insert("a/b/c", 1), None);
+        assert_eq!(trie.insert("a/b/c", 2), Some(1));
+        assert_eq!(trie.insert("a", 3), None);
+        *trie.get_or_insert_with("a/b", || 0) += 4;
+        assert_eq!(trie.len(), 3);
+        assert_eq!(trie.get("a/b"), Some(&4));
+        assert_eq!(trie.get("a/b/c/d"), None);
+
+        *trie.get_mut("a").unwrap() = 5;
+        let mut entries = trie.entries();
+        entries.sort();
+        assert_eq!(entries, [("a".to_string(), &5), ("a/b".to_string(), &4), ("a/b/c".to_string(), &2)]);
+
+        assert_eq!(trie.remove("a/b/c"), Some(2));
+        assert_eq!(trie.remove("a/b/c"), None);
+        assert_eq!(trie.remove("a/b"), Some(4));
+        assert!(trie.root.children["a"].children.is_empty());
+        assert_eq!(trie.remove("a"), Some(5));
+        assert!(trie.root.children.is_empty());
+        assert!(trie.is_empty());
+    }
+
+    #[test]
+    fn test_agrees_with_linear_scan() {
+        let filters = ["#", "+", "a/#", "a/+", "a/+/c", "+/b/#", "a/b/c", "+/+/+", "$SYS/+", "/#", "a//c"];
+        let topics = ["a", "a/b", "a/b/c", "a/x/c", "x/b/y/z", "/", "/a", "a//c", "$SYS/load", "$SYS", "b"];
+
+        let mut trie = TopicTrie::new();
+        for filter in filters {
+            trie.insert(filter, filter);
+        }
+        for topic in topics {
+            let mut expected: Vec<&str> = filters.iter().copied()
+                .filter(|filter| MessageRouter::topic_matches(filter, topic))
+                .collect();
+            expected.sort();
+            assert_eq!(sorted(trie.match_topic(topic)), expected, "topic {}", topic);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! # TLS Helpers
+//!
+//! PEM loading shared by the TLS listener of the server and the TLS transport
+//! of the client. Both sides use rustls with the `ring` crypto provider.
+
+use crate::error::{Error, Result};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::RootCertStore;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| Error::Tls(format!("Failed to open {}: {}", path.display(), e)))
+}
+
+/// Load every certificate of a PEM file, in order
+pub(crate) fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| Error::Tls(format!("Invalid certificate in {}: {}", path.display(), e)))?;
+    if certs.is_empty() {
+        return Err(Error::Tls(format!("No certificates found in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+/// Load the first private key of a PEM file (PKCS#8, PKCS#1 or SEC1)
+pub(crate) fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut open(path)?)
+        .map_err(|e| Error::Tls(format!("Invalid private key in {}: {}", path.display(), e)))?
+        .ok_or_else(|| Error::Tls(format!("No private key found in {}", path.display())))
+}
+
+/// Build a trust store from a PEM bundle of CA certificates
+pub(crate) fn load_root_store(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)
+            .map_err(|e| Error::Tls(format!("Invalid CA certificate in {}: {}", path.display(), e)))?;
+    }
+    Ok(roots)
+}
+
+/// rustls provider used by every TLS configuration of the crate
+pub(crate) fn crypto_provider() -> std::sync::Arc<rustls::crypto::CryptoProvider> {
+    std::sync::Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Certificates and keys generated for TLS tests
+#[cfg(test)]
+pub(crate) mod test_pki {
+    use rcgen::{BasicConstraints, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+    use std::path::{Path, PathBuf};
+
+    /// A CA, a `localhost` server certificate and a client certificate, written as PEM files
+    pub(crate) struct TestPki {
+        dir: PathBuf,
+    }
+
+    impl TestPki {
+        pub fn generate(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("dumq-pki-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+
+            let ca_key = KeyPair::generate().unwrap();
+            let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+            ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca = ca_params.self_signed(&ca_key).unwrap();
+            std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+
+            for (file, purpose) in [
+                ("server", ExtendedKeyUsagePurpose::ServerAuth),
+                ("client", ExtendedKeyUsagePurpose::ClientAuth),
+            ] {
+                let key = KeyPair::generate().unwrap();
+                let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+                params.extended_key_usages = vec![purpose];
+                let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+                std::fs::write(dir.join(format!("{}.pem", file)), cert.pem()).unwrap();
+                std::fs::write(dir.join(format!("{}.key", file)), key.serialize_pem()).unwrap();
+            }
+
+            Self { dir }
+        }
+
+        pub fn path(&self, file: &str) -> PathBuf {
+            self.dir.join(file)
+        }
+
+        pub fn dir(&self) -> &Path {
+            &self.dir
+        }
+    }
+
+    impl Drop for TestPki {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_pki::TestPki;
+
+    #[test]
+    fn test_load_pem_files() {
+        let pki = TestPki::generate("load");
+        assert_eq!(load_certs(&pki.path("server.pem")).unwrap().len(), 1);
+        assert!(load_private_key(&pki.path("server.key")).is_ok());
+        assert_eq!(load_root_store(&pki.path("ca.pem")).unwrap().len(), 1);
+
+        // A key file holds no certificates and a certificate holds no key
+        assert!(matches!(load_certs(&pki.path("server.key")), Err(Error::Tls(_))));
+        assert!(matches!(load_private_key(&pki.path("server.pem")), Err(Error::Tls(_))));
+        assert!(matches!(load_certs(&pki.dir().join("missing.pem")), Err(Error::Tls(_))));
+    }
+}
@@ -0,0 +1,394 @@
+//! # Topic Names and Filters
+//!
+//! [`TopicName`] and [`TopicFilter`] hold strings checked against the topic
+//! rules of the MQTT specification, and [`matches`] decides whether a topic
+//! filter matches a topic name. The client validates topics before sending
+//! them and the server validates every topic it receives.
+//!
+//! Both kinds of topic are non-empty UTF-8 strings of at most 65535 bytes,
+//! without the null character. Control characters and Unicode non-characters
+//! are rejected as well. Topic names carry no wildcards. In topic filters, `+`
+//! takes up a whole level and `#` a whole level at the end of the filter, and
+//! `$share/<group>/<filter>` subscribes to a shared subscription group. Shared
+//! subscriptions are an MQTT 5.0 feature; for MQTT 3.1.1 such a filter is an
+//! ordinary one.
+//!
+//! Topics starting with `$`, such as `$SYS/broker/uptime`, are reserved for the
+//! server. Filters starting with a wildcard do not match them.
+
+use crate::error::{Error, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// Longest topic name or filter, in bytes
+pub const MAX_TOPIC_LENGTH: usize = 65535;
+
+/// Prefix of shared subscription topic filters
+pub const SHARE_PREFIX: &str = "$share/";
+
+/// Validated topic name, as published to
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TopicName(String);
+
+impl TopicName {
+    /// Validate a topic name
+    pub fn new(name: impl Into<String>) -> Result<Self> {
+        let name = name.into();
+        validate_topic_name(&name)?;
+        Ok(Self(name))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+
+    /// Check whether the topic is reserved for the server, like `$SYS/...`
+    pub fn is_reserved(&self) -> bool {
+        self.0.starts_with('$')
+    }
+}
+
+/// Validated topic filter, as subscribed to
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TopicFilter {
+    filter: String,
+    /// Whether this is a `$share/<group>/<filter>` shared subscription
+    shared: bool,
+}
+
+impl TopicFilter {
+    /// Validate a topic filter, reading `$share/` filters as shared subscriptions
+    pub fn new(filter: impl Into<String>) -> Result<Self> {
+        Self::for_protocol_version(filter, 5)
+    }
+
+    /// Validate a topic filter sent by a client of the given protocol version
+    ///
+    /// Only MQTT 5.0 has shared subscriptions, so for earlier versions a filter
+    /// starting with `$share/` is an ordinary filter.
+    pub fn for_protocol_version(filter: impl Into<String>, protocol_version: u8) -> Result<Self> {
+        let filter = filter.into();
+        let shared = protocol_version >= 5 && filter.starts_with(SHARE_PREFIX);
+        validate_filter(&filter, shared)?;
+        Ok(Self { filter, shared })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.filter
+    }
+
+    pub fn into_string(self) -> String {
+        self.filter
+    }
+
+    /// Check whether this is a shared subscription
+    pub fn is_shared(&self) -> bool {
+        self.shared
+    }
+
+    /// Share name of a `$share/<group>/<filter>` subscription
+    pub fn share_name(&self) -> Option<&str> {
+        self.shared.then(|| split_shared(&self.filter).map(|(group, _)| group)).flatten()
+    }
+
+    /// Part of the filter matched against topic names
+    ///
+    /// This is the filter itself, or the filter of a shared subscription.
+    pub fn filter(&self) -> &str {
+        match self.shared {
+            true => split_shared(&self.filter).map_or(&self.filter, |(_, filter)| filter),
+            false => &self.filter,
+        }
+    }
+
+    /// Check whether the filter contains `+` or `#`
+    pub fn has_wildcards(&self) -> bool {
+        self.filter().contains(['+', '#'])
+    }
+
+    /// Check whether the filter matches a topic name
+    pub fn matches(&self, topic: &TopicName) -> bool {
+        matches(self.filter(), topic.as_str())
+    }
+}
+
+macro_rules! topic_conversions {
+    ($type:ty) => {
+        impl AsRef<str> for $type {
+            fn as_ref(&self) -> &str {
+                self.as_str()
+            }
+        }
+
+        impl fmt::Display for $type {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(self.as_str())
+            }
+        }
+
+        impl FromStr for $type {
+            type Err = Error;
+
+            fn from_str(s: &str) -> Result<Self> {
+                Self::new(s)
+            }
+        }
+
+        impl TryFrom<String> for $type {
+            type Error = Error;
+
+            fn try_from(s: String) -> Result<Self> {
+                Self::new(s)
+            }
+        }
+
+        impl TryFrom<&str> for $type {
+            type Error = Error;
+
+            fn try_from(s: &str) -> Result<Self> {
+                Self::new(s)
+            }
+        }
+
+        impl From<$type> for String {
+            fn from(topic: $type) -> String {
+                topic.into_string()
+            }
+        }
+    };
+}
+
+topic_conversions!(TopicName);
+topic_conversions!(TopicFilter);
+
+/// Check a topic name against the MQTT topic rules
+pub fn validate_topic_name(name: &str) -> Result<()> {
+    validate_text(name, "Topic name")?;
+    if name.contains(['+', '#']) {
+        return Err(Error::InvalidTopic(format!("Topic name contains a wildcard: {}", name)));
+    }
+    Ok(())
+}
+
+/// Check a topic filter against the MQTT topic rules
+///
+/// Filters starting with `$share/` must be valid shared subscriptions.
+pub fn validate_topic_filter(filter: &str) -> Result<()> {
+    validate_filter(filter, filter.starts_with(SHARE_PREFIX))
+}
+
+/// Check a topic filter, as a shared subscription if `shared` is set
+fn validate_filter(filter: &str, shared: bool) -> Result<()> {
+    validate_text(filter, "Topic filter")?;
+
+    let filter = match filter.strip_prefix(SHARE_PREFIX).filter(|_| shared) {
+        Some(rest) => {
+            let Some((group, filter)) = rest.split_once('/') else {
+                return Err(Error::InvalidTopic(format!("Shared subscription without a filter: {}", filter)));
+            };
+            if group.is_empty() || group.contains(['+', '#']) {
+                return Err(Error::InvalidTopic(format!("Invalid share name: {}", group)));
+            }
+            if filter.is_empty() {
+                return Err(Error::InvalidTopic(format!("Shared subscription without a filter: {}", rest)));
+            }
+            filter
+        }
+        None => filter,
+    };
+
+    let mut levels = filter.split('/').peekable();
+    while let Some(level) = levels.next() {
+        if level.contains('#') && (level != "#" || levels.peek().is_some()) {
+            return Err(Error::InvalidTopic(format!("'#' must be the whole last level: {}", filter)));
+        }
+        if level.contains('+') && level != "+" {
+            return Err(Error::InvalidTopic(format!("'+' must be a whole level: {}", filter)));
+        }
+    }
+    Ok(())
+}
+
+/// Rules shared by topic names and filters
+fn validate_text(text: &str, what: &str) -> Result<()> {
+    if text.is_empty() {
+        return Err(Error::InvalidTopic(format!("{} is empty", what)));
+    }
+    if text.len() > MAX_TOPIC_LENGTH {
+        return Err(Error::InvalidTopic(format!("{} is longer than {} bytes", what, MAX_TOPIC_LENGTH)));
+    }
+    if let Some(c) = text.chars().find(|&c| is_disallowed(c)) {
+        return Err(Error::InvalidTopic(format!("{} contains disallowed character U+{:04X}", what, c as u32)));
+    }
+    Ok(())
+}
+
+/// Null, control characters and Unicode non-characters
+fn is_disallowed(c: char) -> bool {
+    let code = c as u32;
+    c.is_control() || (0xFDD0..=0xFDEF).contains(&code) || code & 0xFFFE == 0xFFFE
+}
+
+fn split_shared(filter: &str) -> Option<(&str, &str)> {
+    filter.strip_prefix(SHARE_PREFIX)?.split_once('/')
+}
+
+/// Check if a topic filter matches a topic name
+///
+/// Wildcards at the first level do not match topics starting with `$`.
+pub fn matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            // `#` also matches the parent level: `home/#` matches `home`
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(filter_level), Some(topic_level)) if filter_level == topic_level => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_topic_names() {
+        for name in ["home/temp", "/", "home//temp", "$SYS/broker", "ünïcødé/🌡", " "] {
+            assert!(TopicName::new(name).is_ok(), "{}", name);
+        }
+        for name in ["", "home/+", "home/#", "home+", "a\0b", "bell\u{7}", "\u{FFFF}", "\u{FDD0}"] {
+            assert!(TopicName::new(name).is_err(), "{:?}", name);
+        }
+        assert!(TopicName::new("a".repeat(MAX_TOPIC_LENGTH)).is_ok());
+        assert!(TopicName::new("a".repeat(MAX_TOPIC_LENGTH + 1)).is_err());
+        assert!(TopicName::new("$SYS/uptime").unwrap().is_reserved());
+    }
+
+    #[test]
+    fn test_topic_filters() {
+        for filter in ["#", "+", "home/#", "home/+/temp", "+/+", "/+", "home//#", "$share/group/jobs/#"] {
+            assert!(TopicFilter::new(filter).is_ok(), "{}", filter);
+        }
+        for filter in ["", "a/#/b", "foo+", "home/temp#", "#/", "+a/b", "a\0", "$share/group", "$share//jobs", "$share/gr+up/jobs", "$share/group/"] {
+            assert!(TopicFilter::new(filter).is_err(), "{:?}", filter);
+        }
+    }
+
+    #[test]
+    fn test_shared_filter_parts() {
+        let filter = TopicFilter::new("$share/workers/jobs/+").unwrap();
+        assert_eq!(filter.share_name(), Some("workers"));
+        assert_eq!(filter.filter(), "jobs/+");
+        assert!(filter.has_wildcards());
+        assert!(filter.matches(&TopicName::new("jobs/build").unwrap()));
+
+        let filter: TopicFilter = "jobs/build".parse().unwrap();
+        assert_eq!(filter.share_name(), None);
+        assert!(!filter.has_wildcards());
+    }
+
+    #[test]
+    fn test_shared_filters_need_mqtt5() {
+        let filter = TopicFilter::for_protocol_version("$share/workers/jobs", 4).unwrap();
+        assert!(!filter.is_shared());
+        assert_eq!(filter.share_name(), None);
+        assert_eq!(filter.filter(), "$share/workers/jobs");
+        assert!(filter.matches(&TopicName::new("$share/workers/jobs").unwrap()));
+        assert!(!filter.matches(&TopicName::new("jobs").unwrap()));
+
+        // Ordinary filter rules apply instead of the shared subscription ones
+        assert!(TopicFilter::for_protocol_version("$share/workers", 4).is_ok());
+        assert!(TopicFilter::for_protocol_version("$share/workers", 5).is_err());
+        assert!(TopicFilter::for_protocol_version("$share/work#/jobs", 4).is_err());
+
+        assert!(TopicFilter::for_protocol_version("$share/workers/jobs", 5).unwrap().is_shared());
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("home/+/temp", "home/living/temp"));
+        assert!(!matches("home/+/temp", "home/temp"));
+        assert!(matches("home/#", "home"));
+        assert!(matches("home/+", "home/"));
+        assert!(!matches("home/+", "home"));
+        assert!(!matches("#", "$SYS/broker/uptime"));
+        assert!(!matches("+/broker/uptime", "$SYS/broker/uptime"));
+        assert!(matches("$SYS/#", "$SYS/broker/uptime"));
+    }
+
+    /// Topic names built from levels without wildcards or disallowed characters
+    fn topic_name() -> impl Strategy<Value = String> {
+        prop::collection::vec("[a-z0-9 _$-]{0,6}", 1..6)
+            .prop_map(|levels| levels.join("/"))
+            .prop_filter("topic names are not empty", |name| !name.is_empty())
+    }
+
+    proptest! {
+        #[test]
+        fn valid_names_are_valid_filters_matching_themselves(name in topic_name()) {
+            prop_assert!(TopicName::new(name.clone()).is_ok());
+            let filter = TopicFilter::new(name.clone()).unwrap();
+            prop_assert!(filter.matches(&TopicName::new(name).unwrap()));
+        }
+
+        #[test]
+        fn single_level_wildcard_matches_any_level(name in topic_name(), index in 0usize..6) {
+            let mut levels: Vec<&str> = name.split('/').collect();
+            let index = index % levels.len();
+            levels[index] = "+";
+            let filter = levels.join("/");
+            prop_assert!(TopicFilter::new(filter.clone()).is_ok());
+            prop_assert_eq!(matches(&filter, &name), !(index == 0 && name.starts_with('$')));
+        }
+
+        #[test]
+        fn multi_level_wildcard_matches_descendants(name in topic_name(), depth in 0usize..6) {
+            let levels: Vec<&str> = name.split('/').collect();
+            let depth = depth % levels.len();
+            let mut filter = levels[..depth].join("/");
+            filter.push_str(if depth == 0 { "#" } else { "/#" });
+            prop_assert!(TopicFilter::new(filter.clone()).is_ok());
+            prop_assert_eq!(matches(&filter, &name), !(depth == 0 && name.starts_with('$')));
+        }
+
+        #[test]
+        fn wildcards_are_rejected_in_names(name in topic_name(), wildcard in "[+#]", at in any::<prop::sample::Index>()) {
+            let mut name = name;
+            name.insert_str(at.index(name.len() + 1), &wildcard);
+            prop_assert!(TopicName::new(name).is_err());
+        }
+
+        #[test]
+        fn hash_before_the_last_level_is_rejected(prefix in topic_name(), suffix in topic_name()) {
+            let filter = format!("{}/#/{}", prefix, suffix);
+            prop_assert!(TopicFilter::new(filter).is_err());
+        }
+
+        #[test]
+        fn wildcards_sharing_a_level_are_rejected(level in "[a-z]{1,5}", wildcard in "[+#]", suffix in any::<bool>()) {
+            let filter = if suffix { format!("{}{}", level, wildcard) } else { format!("{}{}", wildcard, level) };
+            prop_assert!(TopicFilter::new(filter).is_err());
+        }
+
+        #[test]
+        fn null_character_is_rejected(name in topic_name(), at in any::<prop::sample::Index>()) {
+            let mut name = name;
+            name.insert(at.index(name.len() + 1), '\0');
+            prop_assert!(TopicName::new(name.clone()).is_err());
+            prop_assert!(TopicFilter::new(name).is_err());
+        }
+    }
+}
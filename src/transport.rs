@@ -0,0 +1,91 @@
+//! # Transports
+//!
+//! Byte streams MQTT connections run over, shared by the client and the server,
+//! and the addresses of the peers at their other end.
+
+use std::fmt;
+use std::net::{AddrParseError, IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Byte stream an MQTT connection can run over
+pub trait Transport: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Transport for T {}
+
+/// Address of the peer at the other end of a connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerAddr {
+    /// TCP peer, also when TLS or WebSocket run on top
+    Tcp(SocketAddr),
+    /// Peer on a Unix domain socket, known by the socket path it connected to
+    Unix(PathBuf),
+}
+
+impl PeerAddr {
+    /// IP address of a TCP peer; Unix socket peers have none
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Self::Tcp(addr) => Some(addr.ip()),
+            Self::Unix(_) => None,
+        }
+    }
+
+    /// Check whether the peer runs on this host, over loopback or a Unix socket
+    pub fn is_local(&self) -> bool {
+        match self {
+            Self::Tcp(addr) => addr.ip().is_loopback(),
+            Self::Unix(_) => true,
+        }
+    }
+}
+
+impl From<SocketAddr> for PeerAddr {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Tcp(addr)
+    }
+}
+
+impl FromStr for PeerAddr {
+    type Err = AddrParseError;
+
+    /// Parse a socket address, or `unix:<path>` for a Unix socket peer
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(Self::Unix(PathBuf::from(path))),
+            None => s.parse().map(Self::Tcp),
+        }
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_addr() {
+        let tcp: PeerAddr = "192.0.2.1:4000".parse().unwrap();
+        assert_eq!(tcp, PeerAddr::from(SocketAddr::from(([192, 0, 2, 1], 4000))));
+        assert_eq!(tcp.to_string(), "192.0.2.1:4000");
+        assert_eq!(tcp.ip(), Some("192.0.2.1".parse().unwrap()));
+        assert!(!tcp.is_local());
+        assert!("127.0.0.1:4000".parse::<PeerAddr>().unwrap().is_local());
+        assert!("localhost".parse::<PeerAddr>().is_err());
+
+        let unix: PeerAddr = "unix:/run/mqtt.sock".parse().unwrap();
+        assert_eq!(unix, PeerAddr::Unix(PathBuf::from("/run/mqtt.sock")));
+        assert_eq!(unix.to_string(), "unix:/run/mqtt.sock");
+        assert_eq!(unix.ip(), None);
+        assert!(unix.is_local());
+    }
+}
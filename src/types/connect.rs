@@ -21,6 +21,8 @@ pub struct ConnectPacket {
     pub client_id: String,
     pub will_topic: Option<String>,
     pub will_message: Option<Bytes>,
+    // MQTT 5.0 will properties
+    pub will_properties: Option<WillProperties>,
     pub username: Option<String>,
     pub password: Option<String>,
     // MQTT 5.0 properties
@@ -180,6 +182,23 @@ impl ConnectReturnCode {
             _ => None,
         }
     }
+
+    /// Equivalent code for the given protocol version
+    ///
+    /// MQTT 5.0 reports the MQTT 3.1.1 failures with different values.
+    pub fn for_version(self, protocol_version: u8) -> Self {
+        if protocol_version != 5 {
+            return self;
+        }
+        match self {
+            ConnectReturnCode::UnacceptableProtocolVersion => ConnectReturnCode::UnsupportedProtocolVersion,
+            ConnectReturnCode::IdentifierRejected => ConnectReturnCode::ClientIdentifierNotValid,
+            ConnectReturnCode::ServerUnavailable => ConnectReturnCode::ServerUnavailableV5,
+            ConnectReturnCode::BadUsernameOrPassword => ConnectReturnCode::BadUsernameOrPasswordV5,
+            ConnectReturnCode::NotAuthorized => ConnectReturnCode::NotAuthorizedV5,
+            other => other,
+        }
+    }
 }
 
 /// Disconnect packet
@@ -278,6 +297,7 @@ mod tests {
             client_id: "test_client".to_string(),
             will_topic: None,
             will_message: None,
+            will_properties: None,
             username: None,
             password: None,
             properties: None,
@@ -285,12 +305,12 @@ mod tests {
 
         assert_eq!(connect_packet.protocol_name, "MQTT");
         assert_eq!(connect_packet.protocol_version, 4);
-        assert_eq!(connect_packet.clean_session, true);
-        assert_eq!(connect_packet.will_flag, false);
+        assert!(connect_packet.clean_session);
+        assert!(!connect_packet.will_flag);
         assert_eq!(connect_packet.will_qos, 0);
-        assert_eq!(connect_packet.will_retain, false);
-        assert_eq!(connect_packet.password_flag, false);
-        assert_eq!(connect_packet.username_flag, false);
+        assert!(!connect_packet.will_retain);
+        assert!(!connect_packet.password_flag);
+        assert!(!connect_packet.username_flag);
         assert_eq!(connect_packet.keep_alive, 60);
         assert_eq!(connect_packet.client_id, "test_client");
         assert_eq!(connect_packet.will_topic, None);
@@ -316,12 +336,13 @@ mod tests {
             client_id: "will_client".to_string(),
             will_topic: Some("test/will".to_string()),
             will_message: Some(will_message.clone()),
+            will_properties: None,
             username: Some("test_user".to_string()),
             password: Some("test_pass".to_string()),
             properties: None,
         };
 
-        assert_eq!(connect_packet.will_flag, true);
+        assert!(connect_packet.will_flag);
         assert_eq!(connect_packet.will_topic, Some("test/will".to_string()));
         assert_eq!(connect_packet.will_message, Some(will_message));
         assert_eq!(connect_packet.username, Some("test_user".to_string()));
@@ -336,7 +357,7 @@ mod tests {
             properties: None,
         };
 
-        assert_eq!(conn_ack.session_present, true);
+        assert!(conn_ack.session_present);
         assert_eq!(conn_ack.return_code, ConnectReturnCode::Accepted);
         assert!(conn_ack.properties.is_none());
     }
@@ -347,7 +368,7 @@ mod tests {
             .session_present(true)
             .return_code(ConnectReturnCode::Accepted);
 
-        assert_eq!(conn_ack.session_present, true);
+        assert!(conn_ack.session_present);
         assert_eq!(conn_ack.return_code, ConnectReturnCode::Accepted);
         assert!(conn_ack.is_success());
         assert!(!conn_ack.is_error());
@@ -401,6 +422,7 @@ mod tests {
             client_id: "".to_string(),
             will_topic: None,
             will_message: None,
+            will_properties: None,
             username: None,
             password: None,
             properties: None,
@@ -410,4 +432,13 @@ mod tests {
         assert_eq!(connect_packet.client_id, "");
         assert_eq!(connect_packet.keep_alive, 0);
     }
+
+    #[test]
+    fn test_connect_return_code_for_version() {
+        assert_eq!(ConnectReturnCode::NotAuthorized.for_version(4), ConnectReturnCode::NotAuthorized);
+        assert_eq!(ConnectReturnCode::NotAuthorized.for_version(5), ConnectReturnCode::NotAuthorizedV5);
+        assert_eq!(ConnectReturnCode::BadUsernameOrPassword.for_version(5), ConnectReturnCode::BadUsernameOrPasswordV5);
+        assert_eq!(ConnectReturnCode::Accepted.for_version(5), ConnectReturnCode::Accepted);
+        assert_eq!(ConnectReturnCode::Banned.for_version(5), ConnectReturnCode::Banned);
+    }
 }
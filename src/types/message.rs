@@ -4,6 +4,9 @@
 //! message handling and processing.
 
 use bytes::Bytes;
+use std::time::Instant;
+
+use super::properties::PublishProperties;
 
 /// MQTT message
 #[derive(Debug, Clone)]
@@ -14,6 +17,17 @@ pub struct Message {
     pub retain: bool,
     pub dup: bool,
     pub packet_id: Option<u16>,
+    /// MQTT 5.0 PUBLISH properties, which the broker forwards to subscribers
+    pub properties: Option<PublishProperties>,
+    /// When the broker drops the message, going by its Message Expiry Interval
+    pub expires_at: Option<Instant>,
+}
+
+impl Message {
+    /// Check whether the message has outlived its Message Expiry Interval
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| Instant::now() >= expires_at)
+    }
 }
 
 #[cfg(test)]
@@ -31,13 +45,15 @@ mod tests {
             retain: false,
             dup: false,
             packet_id: Some(123),
+            properties: None,
+            expires_at: None,
         };
 
         assert_eq!(message.topic, "test/topic");
         assert_eq!(message.payload, payload);
         assert_eq!(message.qos, 1);
-        assert_eq!(message.retain, false);
-        assert_eq!(message.dup, false);
+        assert!(!message.retain);
+        assert!(!message.dup);
         assert_eq!(message.packet_id, Some(123));
     }
 
@@ -51,6 +67,8 @@ mod tests {
             retain: false,
             dup: false,
             packet_id: None,
+            properties: None,
+            expires_at: None,
         };
 
         assert_eq!(message.qos, 0);
@@ -68,13 +86,15 @@ mod tests {
             retain: false,
             dup: false,
             packet_id: None,
+            properties: None,
+            expires_at: None,
         };
 
         assert_eq!(message.topic, "");
         assert_eq!(message.payload, empty_payload);
         assert_eq!(message.qos, 0);
-        assert_eq!(message.retain, false);
-        assert_eq!(message.dup, false);
+        assert!(!message.retain);
+        assert!(!message.dup);
         assert_eq!(message.packet_id, None);
 
         // Test with maximum values
@@ -85,11 +105,13 @@ mod tests {
             retain: true,
             dup: true,
             packet_id: Some(u16::MAX),
+            properties: None,
+            expires_at: None,
         };
 
         assert_eq!(max_message.qos, 2);
-        assert_eq!(max_message.retain, true);
-        assert_eq!(max_message.dup, true);
+        assert!(max_message.retain);
+        assert!(max_message.dup);
         assert_eq!(max_message.packet_id, Some(u16::MAX));
     }
 
@@ -102,6 +124,8 @@ mod tests {
             retain: false,
             dup: false,
             packet_id: Some(456),
+            properties: None,
+            expires_at: None,
         };
 
         let cloned = original.clone();
@@ -124,6 +148,8 @@ mod tests {
             retain: false,
             dup: false,
             packet_id: None,
+            properties: None,
+            expires_at: None,
         };
         assert_eq!(qos0_message.qos, 0);
         assert_eq!(qos0_message.packet_id, None);
@@ -136,6 +162,8 @@ mod tests {
             retain: false,
             dup: false,
             packet_id: Some(123),
+            properties: None,
+            expires_at: None,
         };
         assert_eq!(qos1_message.qos, 1);
         assert_eq!(qos1_message.packet_id, Some(123));
@@ -148,6 +176,8 @@ mod tests {
             retain: false,
             dup: false,
             packet_id: Some(456),
+            properties: None,
+            expires_at: None,
         };
         assert_eq!(qos2_message.qos, 2);
         assert_eq!(qos2_message.packet_id, Some(456));
@@ -73,6 +73,7 @@
 //!     client_id: "test_client".to_string(),
 //!     will_topic: Some("will/topic".to_string()),
 //!     will_message: Some(b"will message".to_vec().into()),
+//!     will_properties: None,
 //!     username: Some("user".to_string()),
 //!     password: Some("pass".to_string()),
 //!     properties: None,
@@ -143,6 +144,8 @@
 //!     retain: false,
 //!     dup: false,
 //!     packet_id: Some(1),
+//!     properties: None,
+//!     expires_at: None,
 //! };
 //! 
 //! // Access message properties
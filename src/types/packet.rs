@@ -68,6 +68,8 @@ pub struct Packet {
 }
 
 /// MQTT packet payload
+// CONNECT is by far the largest payload, but it is only ever sent once per connection
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone)]
 pub enum PacketPayload {
     Connect(ConnectPacket),
@@ -143,9 +145,9 @@ mod tests {
         };
 
         assert_eq!(header.packet_type, PacketType::Publish);
-        assert_eq!(header.dup, false);
+        assert!(!header.dup);
         assert_eq!(header.qos, 1);
-        assert_eq!(header.retain, false);
+        assert!(!header.retain);
         assert_eq!(header.remaining_length, 100);
     }
 
@@ -241,9 +243,9 @@ mod tests {
             remaining_length: usize::MAX,
         };
 
-        assert_eq!(header.dup, true);
+        assert!(header.dup);
         assert_eq!(header.qos, 2);
-        assert_eq!(header.retain, true);
+        assert!(header.retain);
         assert_eq!(header.remaining_length, usize::MAX);
     }
 }
@@ -8,7 +8,7 @@ use std::collections::HashMap;
 use super::constants::*;
 
 /// Connect properties for MQTT 5.0
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct ConnectProperties {
     pub session_expiry_interval: Option<u32>,
     pub receive_maximum: Option<u16>,
@@ -21,23 +21,50 @@ pub struct ConnectProperties {
     pub authentication_data: Option<Bytes>,
 }
 
-impl Default for ConnectProperties {
-    fn default() -> Self {
-        Self {
-            session_expiry_interval: None,
-            receive_maximum: None,
-            max_packet_size: None,
-            topic_alias_maximum: None,
-            request_response_information: None,
-            request_problem_information: None,
-            user_properties: HashMap::new(),
-            authentication_method: None,
-            authentication_data: None,
-        }
+/// Will properties for MQTT 5.0, carried in the CONNECT payload
+#[derive(Debug, Clone, Default)]
+pub struct WillProperties {
+    pub will_delay_interval: Option<u32>,
+    pub payload_format_indicator: Option<u8>,
+    pub message_expiry_interval: Option<u32>,
+    pub content_type: Option<String>,
+    pub response_topic: Option<String>,
+    pub correlation_data: Option<Bytes>,
+    pub user_properties: HashMap<String, String>,
+}
+
+impl WillProperties {
+    /// Create a new WillProperties with default values
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set will delay interval in seconds
+    pub fn will_delay_interval(mut self, interval: u32) -> Self {
+        self.will_delay_interval = Some(interval);
+        self
+    }
+
+    /// Set message expiry interval
+    pub fn message_expiry_interval(mut self, interval: u32) -> Self {
+        self.message_expiry_interval = Some(interval);
+        self
+    }
+
+    /// Set content type
+    pub fn content_type(mut self, content_type: String) -> Self {
+        self.content_type = Some(content_type);
+        self
+    }
+
+    /// Add user property
+    pub fn user_property(mut self, key: String, value: String) -> Self {
+        self.user_properties.insert(key, value);
+        self
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct ConnAckProperties {
     pub session_expiry_interval: Option<u32>,
     pub receive_maximum: Option<u16>,
@@ -58,30 +85,6 @@ pub struct ConnAckProperties {
     pub authentication_data: Option<Bytes>,
 }
 
-impl Default for ConnAckProperties {
-    fn default() -> Self {
-        Self {
-            session_expiry_interval: None,
-            receive_maximum: None,
-            max_qos: None,
-            retain_available: None,
-            max_packet_size: None,
-            assigned_client_identifier: None,
-            topic_alias_maximum: None,
-            reason_string: None,
-            user_properties: HashMap::new(),
-            wildcard_subscription_available: None,
-            subscription_identifiers_available: None,
-            shared_subscription_available: None,
-            server_keep_alive: None,
-            response_information: None,
-            server_reference: None,
-            authentication_method: None,
-            authentication_data: None,
-        }
-    }
-}
-
 impl ConnAckProperties {
     /// Create a new ConnAckProperties with default values
     pub fn new() -> Self {
@@ -212,7 +215,7 @@ impl ConnAckProperties {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct PublishProperties {
     pub payload_format_indicator: Option<u8>,
     pub message_expiry_interval: Option<u32>,
@@ -224,21 +227,6 @@ pub struct PublishProperties {
     pub content_type: Option<String>,
 }
 
-impl Default for PublishProperties {
-    fn default() -> Self {
-        Self {
-            payload_format_indicator: None,
-            message_expiry_interval: None,
-            topic_alias: None,
-            response_topic: None,
-            correlation_data: None,
-            user_properties: HashMap::new(),
-            subscription_identifier: None,
-            content_type: None,
-        }
-    }
-}
-
 impl PublishProperties {
     /// Create a new empty PublishProperties
     pub fn new() -> Self {
@@ -349,30 +337,120 @@ pub struct PubCompProperties {
     pub user_properties: HashMap<String, String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct SubscribeProperties {
     pub subscription_identifier: Option<u32>,
     pub user_properties: HashMap<String, String>,
 }
 
-#[derive(Debug, Clone)]
+impl SubscribeProperties {
+    /// Create a new empty SubscribeProperties
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the subscription identifier
+    pub fn subscription_identifier(mut self, id: u32) -> Self {
+        self.subscription_identifier = Some(id);
+        self
+    }
+
+    /// Add a user property
+    pub fn user_property(mut self, key: String, value: String) -> Self {
+        self.user_properties.insert(key, value);
+        self
+    }
+
+    /// Check if all properties are None/empty
+    pub fn is_empty(&self) -> bool {
+        self.subscription_identifier.is_none() && self.user_properties.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct SubAckProperties {
     pub reason_string: Option<String>,
     pub user_properties: HashMap<String, String>,
 }
 
-#[derive(Debug, Clone)]
+impl SubAckProperties {
+    /// Create a new empty SubAckProperties
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the reason string
+    pub fn reason_string(mut self, reason: String) -> Self {
+        self.reason_string = Some(reason);
+        self
+    }
+
+    /// Add a user property
+    pub fn user_property(mut self, key: String, value: String) -> Self {
+        self.user_properties.insert(key, value);
+        self
+    }
+
+    /// Check if all properties are None/empty
+    pub fn is_empty(&self) -> bool {
+        self.reason_string.is_none() && self.user_properties.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct UnsubscribeProperties {
     pub user_properties: HashMap<String, String>,
 }
 
-#[derive(Debug, Clone)]
+impl UnsubscribeProperties {
+    /// Create a new empty UnsubscribeProperties
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a user property
+    pub fn user_property(mut self, key: String, value: String) -> Self {
+        self.user_properties.insert(key, value);
+        self
+    }
+
+    /// Check if all properties are None/empty
+    pub fn is_empty(&self) -> bool {
+        self.user_properties.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct UnsubAckProperties {
     pub reason_string: Option<String>,
     pub user_properties: HashMap<String, String>,
 }
 
-#[derive(Debug, Clone)]
+impl UnsubAckProperties {
+    /// Create a new empty UnsubAckProperties
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the reason string
+    pub fn reason_string(mut self, reason: String) -> Self {
+        self.reason_string = Some(reason);
+        self
+    }
+
+    /// Add a user property
+    pub fn user_property(mut self, key: String, value: String) -> Self {
+        self.user_properties.insert(key, value);
+        self
+    }
+
+    /// Check if all properties are None/empty
+    pub fn is_empty(&self) -> bool {
+        self.reason_string.is_none() && self.user_properties.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct DisconnectProperties {
     pub session_expiry_interval: Option<u32>,
     pub reason_string: Option<String>,
@@ -380,7 +458,46 @@ pub struct DisconnectProperties {
     pub server_reference: Option<String>,
 }
 
-#[derive(Debug, Clone)]
+impl DisconnectProperties {
+    /// Create a new empty DisconnectProperties
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the session expiry interval in seconds
+    pub fn session_expiry_interval(mut self, interval: u32) -> Self {
+        self.session_expiry_interval = Some(interval);
+        self
+    }
+
+    /// Set the reason string
+    pub fn reason_string(mut self, reason: String) -> Self {
+        self.reason_string = Some(reason);
+        self
+    }
+
+    /// Add a user property
+    pub fn user_property(mut self, key: String, value: String) -> Self {
+        self.user_properties.insert(key, value);
+        self
+    }
+
+    /// Set the server reference
+    pub fn server_reference(mut self, reference: String) -> Self {
+        self.server_reference = Some(reference);
+        self
+    }
+
+    /// Check if all properties are None/empty
+    pub fn is_empty(&self) -> bool {
+        self.session_expiry_interval.is_none() &&
+        self.reason_string.is_none() &&
+        self.user_properties.is_empty() &&
+        self.server_reference.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct AuthProperties {
     pub authentication_method: Option<String>,
     pub authentication_data: Option<Bytes>,
@@ -388,6 +505,45 @@ pub struct AuthProperties {
     pub user_properties: HashMap<String, String>,
 }
 
+impl AuthProperties {
+    /// Create a new empty AuthProperties
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the authentication method
+    pub fn authentication_method(mut self, method: String) -> Self {
+        self.authentication_method = Some(method);
+        self
+    }
+
+    /// Set the authentication data
+    pub fn authentication_data(mut self, data: Bytes) -> Self {
+        self.authentication_data = Some(data);
+        self
+    }
+
+    /// Set the reason string
+    pub fn reason_string(mut self, reason: String) -> Self {
+        self.reason_string = Some(reason);
+        self
+    }
+
+    /// Add a user property
+    pub fn user_property(mut self, key: String, value: String) -> Self {
+        self.user_properties.insert(key, value);
+        self
+    }
+
+    /// Check if all properties are None/empty
+    pub fn is_empty(&self) -> bool {
+        self.authentication_method.is_none() &&
+        self.authentication_data.is_none() &&
+        self.reason_string.is_none() &&
+        self.user_properties.is_empty()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -5,7 +5,6 @@
 
 use bytes::Bytes;
 use super::properties::*;
-use super::constants::*;
 
 /// Publish packet
 #[derive(Debug, Clone)]
@@ -140,7 +139,7 @@ impl PublishPacket {
 
     /// Check if the packet has any properties
     pub fn has_properties(&self) -> bool {
-        self.properties.as_ref().map_or(false, |p| !p.is_empty())
+        self.properties.as_ref().is_some_and(|p| !p.is_empty())
     }
 
     /// Get QoS level (0 if no packet_id, 1 or 2 if packet_id exists)
@@ -184,6 +183,7 @@ pub struct PubCompPacket {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::types::constants::*;
     use bytes::Bytes;
 
     #[test]
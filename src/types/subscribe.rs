@@ -96,8 +96,8 @@ mod tests {
 
         assert_eq!(topic_filter.topic, "test/+/wildcard");
         assert_eq!(topic_filter.qos, 2);
-        assert_eq!(topic_filter.no_local, true);
-        assert_eq!(topic_filter.retain_as_published, true);
+        assert!(topic_filter.no_local);
+        assert!(topic_filter.retain_as_published);
         assert_eq!(topic_filter.retain_handling, 2);
     }
 
@@ -0,0 +1,220 @@
+//! # MQTT over WebSocket
+//!
+//! MQTT packets travel in binary WebSocket messages negotiated with the `mqtt`
+//! subprotocol. [`WsStream`] turns a WebSocket back into a byte stream, so the
+//! codec reassembles packets split across several messages, or several packets
+//! sharing one, exactly as it does on TCP.
+
+use bytes::{Buf, Bytes};
+use futures_util::{Sink, Stream};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::WebSocketStream;
+
+use crate::error::{Error, Result};
+
+/// WebSocket subprotocol of MQTT 3.1.1 and 5.0
+pub(crate) const SUBPROTOCOL: &str = "mqtt";
+
+/// Byte stream carried in the binary messages of a WebSocket
+pub struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    /// Unread rest of the last binary message
+    pending: Bytes,
+}
+
+impl<S> WsStream<S> {
+    fn new(inner: WebSocketStream<S>) -> Self {
+        Self { inner, pending: Bytes::new() }
+    }
+}
+
+fn io_error(error: WsError) -> io::Error {
+    match error {
+        WsError::Io(error) => error,
+        error => io::Error::other(error),
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.pending.is_empty() {
+                let n = this.pending.len().min(buf.remaining());
+                buf.put_slice(&this.pending[..n]);
+                this.pending.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => this.pending = data,
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "MQTT over WebSocket requires binary messages",
+                    )));
+                }
+                // Pings are answered by tungstenite itself
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => continue,
+                Some(Ok(Message::Close(_))) | None | Some(Err(WsError::ConnectionClosed)) => {
+                    return Poll::Ready(Ok(()));
+                }
+                Some(Err(error)) => return Poll::Ready(Err(io_error(error))),
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+    /// Every write becomes one binary message
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(Pin::new(&mut this.inner).poll_ready(cx)).map_err(io_error)?;
+        Pin::new(&mut this.inner)
+            .start_send(Message::Binary(Bytes::copy_from_slice(buf)))
+            .map_err(io_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx).map_err(io_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match ready!(Pin::new(&mut self.get_mut().inner).poll_close(cx)) {
+            Ok(()) | Err(WsError::ConnectionClosed) => Poll::Ready(Ok(())),
+            Err(error) => Poll::Ready(Err(io_error(error))),
+        }
+    }
+}
+
+/// Complete the server side of the handshake, insisting on the `mqtt` subprotocol
+// tungstenite dictates the callback's error type
+#[allow(clippy::result_large_err)]
+pub(crate) async fn accept<S>(stream: S) -> Result<WsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let callback = |request: &Request, mut response: Response| -> std::result::Result<Response, ErrorResponse> {
+        let offers_mqtt = request.headers()
+            .get_all("Sec-WebSocket-Protocol")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|protocol| protocol.trim() == SUBPROTOCOL);
+        if !offers_mqtt {
+            let mut error = ErrorResponse::new(Some("The mqtt subprotocol is required".to_string()));
+            *error.status_mut() = StatusCode::BAD_REQUEST;
+            return Err(error);
+        }
+        response.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static(SUBPROTOCOL));
+        Ok(response)
+    };
+
+    let stream = tokio_tungstenite::accept_hdr_async(stream, callback).await
+        .map_err(|e| Error::Connection(format!("WebSocket handshake failed: {}", e)))?;
+    Ok(WsStream::new(stream))
+}
+
+/// Open a WebSocket to `url` over an established stream
+pub(crate) async fn connect<S>(url: &str, stream: S) -> Result<WsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut request = url.into_client_request()
+        .map_err(|e| Error::Connection(format!("Invalid WebSocket URL {}: {}", url, e)))?;
+    request.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static(SUBPROTOCOL));
+
+    let (stream, _) = tokio_tungstenite::client_async(request, stream).await
+        .map_err(|e| Error::Connection(format!("WebSocket handshake failed: {}", e)))?;
+    Ok(WsStream::new(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::MqttCodec;
+    use crate::types::{Packet, PacketHeader, PacketPayload, PacketType};
+    use bytes::BytesMut;
+    use futures_util::SinkExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn pingreq() -> Packet {
+        Packet {
+            header: PacketHeader {
+                packet_type: PacketType::PingReq,
+                dup: false,
+                qos: 0,
+                retain: false,
+                remaining_length: 0,
+            },
+            payload: PacketPayload::PingReq,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_packets_split_and_joined_across_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/mqtt", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut stream = accept(tcp).await.unwrap();
+            let codec = MqttCodec::new(4);
+            let mut buffer = BytesMut::new();
+            let mut packets = Vec::new();
+            while packets.len() < 3 {
+                match codec.decode(&mut buffer).unwrap() {
+                    Some(packet) => packets.push(packet.header.packet_type),
+                    None => {
+                        let mut buf = [0u8; 64];
+                        let n = stream.read(&mut buf).await.unwrap();
+                        assert!(n > 0, "Client closed the WebSocket");
+                        buffer.extend_from_slice(&buf[..n]);
+                    }
+                }
+            }
+            stream.write_all(&codec.encode(&pingreq()).unwrap()).await.unwrap();
+            stream.flush().await.unwrap();
+            packets
+        });
+
+        let tcp = TcpStream::connect(url.trim_start_matches("ws://").trim_end_matches("/mqtt")).await.unwrap();
+        let mut client = connect(&url, tcp).await.unwrap();
+        let packet = MqttCodec::new(4).encode(&pingreq()).unwrap();
+
+        // One packet split over two messages, then two packets in one message
+        client.inner.send(Message::Binary(Bytes::copy_from_slice(&packet[..1]))).await.unwrap();
+        client.inner.send(Message::Binary(Bytes::copy_from_slice(&packet[1..]))).await.unwrap();
+        let mut joined = packet.to_vec();
+        joined.extend_from_slice(&packet);
+        client.inner.send(Message::Binary(Bytes::from(joined))).await.unwrap();
+
+        assert_eq!(server.await.unwrap(), vec![PacketType::PingReq; 3]);
+        let mut buf = [0u8; 2];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, &packet[..]);
+    }
+
+    #[tokio::test]
+    async fn test_mqtt_subprotocol_required() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            assert!(accept(tcp).await.is_err());
+        });
+
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let result = tokio_tungstenite::client_async(format!("ws://{}/mqtt", addr), tcp).await;
+        assert!(result.is_err());
+    }
+}